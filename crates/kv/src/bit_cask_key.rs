@@ -30,6 +30,12 @@ impl From<String> for UUIDWasiKey {
     }
 }
 
+impl From<UUIDWasiKey> for String {
+    fn from(value: UUIDWasiKey) -> Self {
+        value.0
+    }
+}
+
 pub fn UUIDWasiKeyFrom(value: &[u8]) -> UUIDWasiKey {
         UUIDWasiKey(String::from_utf8(value.to_vec()).unwrap())
 }
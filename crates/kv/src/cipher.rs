@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::errors::Error;
+
+/// Length of the keys the host encrypts a store with
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+pub const KEY_ID_SIZE: usize = 8;
+const NONCE_SIZE: usize = 24;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Cipher seals records with XChaCha20-Poly1305 under one key of the host.
+/// The encryption key, the key hashing key and the nonce key are all derived from that key with HMAC-SHA256, along with an id that segments are tagged with.
+pub struct Cipher {
+    key_id: [u8; KEY_ID_SIZE],
+    aead: XChaCha20Poly1305,
+    key_hash_key: Vec<u8>,
+    nonce_key: Vec<u8>,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != ENCRYPTION_KEY_SIZE {
+            return Err(Error::InvalidConfig);
+        }
+        let mut key_id = [0; KEY_ID_SIZE];
+        key_id.copy_from_slice(&hmac(key, &[b"kv key id"])[..KEY_ID_SIZE]);
+        let encryption_key = hmac(key, &[b"kv encryption key"]);
+        Ok(Cipher {
+            key_id,
+            aead: XChaCha20Poly1305::new(Key::from_slice(&encryption_key)),
+            key_hash_key: hmac(key, &[b"kv key hash key"]),
+            nonce_key: hmac(key, &[b"kv nonce key"]),
+        })
+    }
+
+    pub fn key_id(&self) -> [u8; KEY_ID_SIZE] {
+        self.key_id
+    }
+
+    /// hash_key returns the HMAC of a key, which is what gets written in place of the key so that key names do not leak
+    pub fn hash_key(&self, key: &[u8]) -> Vec<u8> {
+        hmac(&self.key_hash_key, &[key])
+    }
+
+    /// seal encrypts `plaintext` and authenticates it along with `aad`, the nonce is put in front of the ciphertext.
+    /// The nonce is derived from everything that is sealed, so it only repeats for the very same content, which then seals to the very same bytes.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = hmac(&self.nonce_key, &[&(aad.len() as u64).to_le_bytes(), aad, plaintext]);
+        let nonce = XNonce::from_slice(&nonce[..NONCE_SIZE]);
+        let ciphertext = self.aead.encrypt(nonce, Payload { msg: plaintext, aad })
+            .expect("encrypting in memory does not fail");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// open decrypts what `seal` returned, it returns None if the content was not sealed with this key or was tampered with
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok()
+    }
+}
+
+/// Keyring holds the key new segments are sealed with and the keys of segments sealed before a rotation.
+/// Segments are sealed with the current key again when a merge rewrites them, after which a previous key is no longer needed.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    current: Option<Arc<Cipher>>,
+    previous: Vec<Arc<Cipher>>,
+}
+
+impl Keyring {
+    pub fn new(current: &[u8], previous: &[Vec<u8>]) -> Result<Self, Error> {
+        Ok(Keyring {
+            current: Some(Arc::new(Cipher::new(current)?)),
+            previous: previous.iter().map(|key| Cipher::new(key).map(Arc::new)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// current returns the cipher of new segments, None if the store is not encrypted
+    pub fn current(&self) -> Option<Arc<Cipher>> {
+        self.current.clone()
+    }
+
+    /// find returns the cipher of the key a segment was sealed with
+    pub fn find(&self, key_id: [u8; KEY_ID_SIZE]) -> Option<Arc<Cipher>> {
+        self.current.iter()
+            .chain(self.previous.iter())
+            .find(|cipher| cipher.key_id == key_id)
+            .cloned()
+    }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let cipher = Cipher::new(&[1; ENCRYPTION_KEY_SIZE]).unwrap();
+        let sealed = cipher.seal(b"value", b"header");
+        assert_eq!(cipher.open(&sealed, b"header").unwrap(), b"value");
+        assert_eq!(sealed, cipher.seal(b"value", b"header"));
+        assert_ne!(sealed, cipher.seal(b"value", b"other header"));
+        assert!(cipher.open(&sealed, b"other header").is_none());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(cipher.open(&tampered, b"header").is_none());
+        assert!(Cipher::new(&[2; ENCRYPTION_KEY_SIZE]).unwrap().open(&sealed, b"header").is_none());
+        assert!(matches!(Cipher::new(&[1; 16]), Err(Error::InvalidConfig)));
+    }
+
+    #[test]
+    fn keyring() {
+        let keyring = Keyring::new(&[1; ENCRYPTION_KEY_SIZE], &[vec![2; ENCRYPTION_KEY_SIZE]]).unwrap();
+        let previous_id = Cipher::new(&[2; ENCRYPTION_KEY_SIZE]).unwrap().key_id();
+        let current_id = keyring.current().unwrap().key_id();
+        assert_ne!(current_id, previous_id);
+        assert_eq!(keyring.find(previous_id).unwrap().key_id(), previous_id);
+        assert_eq!(keyring.find(current_id).unwrap().key_id(), current_id);
+        assert!(keyring.find([0; KEY_ID_SIZE]).is_none());
+        assert!(Keyring::default().current().is_none());
+    }
+}
@@ -1,4 +1,6 @@
 use wasi::clocks::{self};
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Copy, Clone)]
 pub struct Duration(pub u64);
@@ -19,3 +21,34 @@ impl Clock for WasiClock {
         clocks::monotonic_clock::now()
     }
 }
+
+/// FakeClock is a clock for the tests that only moves when it is advanced
+#[cfg(test)]
+pub struct FakeClock {
+    now: AtomicU64,
+    monotonic_now: AtomicU64,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: u64) -> Self {
+        FakeClock { now: AtomicU64::new(now), monotonic_now: AtomicU64::new(0) }
+    }
+
+    /// advance moves both clocks forward by `seconds`
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+        self.monotonic_now.fetch_add(seconds * 1_000_000_000, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    fn monotonic_now(&self) -> u64 {
+        self.monotonic_now.load(Ordering::SeqCst)
+    }
+}
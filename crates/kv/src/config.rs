@@ -1,19 +1,35 @@
 use std::sync::Arc;
 
-use crate::{bit_cask_key::BitCaskKey, clock, merge_config::MergeConfig};
+use crate::{bit_cask_key::BitCaskKey, cipher::Keyring, clock, merge_config::MergeConfig, store::Directory};
 
+/// SyncPolicy decides when appends to the active segment are flushed to disk.
+/// Rolled-over segments, merged segments and the manifest are synced whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// leaves flushing to the operating system, writes made just before a crash can be lost
+    #[default]
+    OsDefault,
+    /// syncs after every write and every batch
+    Always,
+    /// syncs after the given number of writes, a batch counting as one write
+    EveryWrites(u64),
+    /// syncs on the first write once the given number of milliseconds passed since the last sync
+    EveryMillis(u64),
+}
 
 pub struct Config<K: BitCaskKey> {
-    directory: String,
+    directory: Directory,
     max_segment_size_bytes: u64, // size of file to be used as a segment
     key_directory_capacity: u64, // number of entries in a file (segment)
     merge_config: Option<MergeConfig<K>>,
     clock: Arc<dyn clock::Clock>,
+    sync_policy: SyncPolicy,
+    keyring: Keyring,
 }
 
 impl<K: BitCaskKey> Config<K> {
     pub fn new(
-        directory: String,
+        directory: Directory,
         max_segment_size_bytes: u64,
         key_directory_capacity: u64,
         merge_config: Option<MergeConfig<K>>,
@@ -25,11 +41,24 @@ impl<K: BitCaskKey> Config<K> {
             key_directory_capacity,
             merge_config,
             clock,
+            sync_policy: SyncPolicy::default(),
+            keyring: Keyring::default(),
         }
     }
 
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
     // Getter methods
-    pub fn directory(&self) -> &str {
+    /// with_keyring encrypts the store with the keys of the keyring
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    pub fn directory(&self) -> &Directory {
         &self.directory
     }
 
@@ -45,6 +74,14 @@ impl<K: BitCaskKey> Config<K> {
         Arc::clone(&self.clock)
     }
 
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn keyring(&self) -> Keyring {
+        self.keyring.clone()
+    }
+
     pub fn merge_config(&self) -> Option<MergeConfig<K>> {
         self.merge_config.clone()
     }
@@ -2,13 +2,87 @@ use std::mem;
 use std::sync::Arc;
 use byteorder::{ByteOrder, LittleEndian};
 use crate::clock::Clock;
-use crate::bit_cask_key::Serializable;
+use crate::cipher::{Cipher, KEY_ID_SIZE};
 use crate::bit_cask_key::BitCaskKey;
+use crate::errors::Error;
 
+const RESERVED_CHECKSUM_SIZE: u32 = mem::size_of::<u32>() as u32;
 const RESERVED_KEY_SIZE: u32 = mem::size_of::<u32>() as u32;
 const RESERVED_VALUE_SIZE: u32 = mem::size_of::<u32>() as u32;
 const RESERVED_TIMESTAMP_SIZE: u32 = mem::size_of::<u32>() as u32;
 const TOMBSTONE_MARKER_SIZE: u32 = mem::size_of::<u8>() as u32;
+const BATCH_OP_COUNT_SIZE: usize = mem::size_of::<u32>();
+const RESERVED_EXPIRY_SIZE: usize = mem::size_of::<u32>();
+const RESERVED_SEQUENCE_SIZE: usize = mem::size_of::<u64>();
+
+// Bits of the tombstone byte. Besides deletes, it marks the records that make up a write batch
+const TOMBSTONE_FLAG: u8 = 0x01;
+const BATCH_BEGIN_FLAG: u8 = 0x02;
+const BATCH_COMMIT_FLAG: u8 = 0x04;
+const BATCH_OP_FLAG: u8 = 0x08;
+// set when the value starts with the time the entry expires at
+const EXPIRES_FLAG: u8 = 0x10;
+// set when the value starts with the sequence number of the write, after the expiry if there is one
+const SEQUENCE_FLAG: u8 = 0x20;
+
+/// SEGMENT_MAGIC starts every segment holding checksummed records, followed by a byte for the record format version.
+/// Segments written before checksums start straight with the timestamp of their first entry, which would have to date from 2010 to read as the magic.
+pub const SEGMENT_MAGIC: &[u8; 4] = b"BCSK";
+pub const SEGMENT_HEADER_SIZE: u32 = SEGMENT_MAGIC.len() as u32 + 1;
+/// SEGMENT_HEADER_MAX_SIZE is the size of the longest segment header, that of sealed segments which end with the id of their key
+pub const SEGMENT_HEADER_MAX_SIZE: u32 = SEGMENT_HEADER_SIZE + KEY_ID_SIZE as u32;
+
+/// RecordFormat identifies how the entries of a segment are laid out, so that segments written by older versions stay readable
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// timestamp, key_size, value_size, key and value without a segment header
+    Legacy,
+    /// a CRC32 of the rest of the record, followed by the legacy layout
+    #[default]
+    Checksummed,
+    /// checksummed records whose key is replaced by its HMAC and whose value seals the key and the value, the segment header ends with the id of the key
+    Sealed { key_id: [u8; KEY_ID_SIZE] },
+}
+
+impl RecordFormat {
+    fn version(&self) -> u8 {
+        match self {
+            RecordFormat::Legacy => 0,
+            RecordFormat::Checksummed => 1,
+            RecordFormat::Sealed { .. } => 2,
+        }
+    }
+
+    /// segment_header returns the bytes a new segment in this format starts with
+    pub fn segment_header(&self) -> Vec<u8> {
+        match self {
+            RecordFormat::Legacy => vec![],
+            RecordFormat::Checksummed => [SEGMENT_MAGIC.as_slice(), &[self.version()]].concat(),
+            RecordFormat::Sealed { key_id } => [SEGMENT_MAGIC.as_slice(), &[self.version()], key_id].concat(),
+        }
+    }
+
+    /// header_size returns the number of bytes before the first entry of a segment
+    pub fn header_size(&self) -> u32 {
+        self.segment_header().len() as u32
+    }
+
+    /// detect determines the format of a segment from its first bytes.
+    /// A segment from a newer version of the store fails with InvalidData rather than being misread.
+    pub fn detect(header: &[u8]) -> Result<Self, Error> {
+        if !header.starts_with(SEGMENT_MAGIC) {
+            return Ok(RecordFormat::Legacy);
+        }
+        match header.get(SEGMENT_MAGIC.len()) {
+            Some(1) => Ok(RecordFormat::Checksummed),
+            Some(2) => {
+                let key_id = header.get(SEGMENT_HEADER_SIZE as usize..SEGMENT_HEADER_MAX_SIZE as usize).ok_or(Error::InvalidData)?;
+                Ok(RecordFormat::Sealed { key_id: key_id.try_into().map_err(|_| Error::InvalidData)? })
+            }
+            _ => Err(Error::InvalidData),
+        }
+    }
+}
 
 #[derive(Clone)]
 struct ValueReference {
@@ -22,6 +96,8 @@ pub struct Entry<K: BitCaskKey> {
     pub key: K,
     value: ValueReference,
     timestamp: u32,
+    expires_at: Option<u32>,
+    sequence: u64,
     clock: Arc<dyn Clock>,
 }
 
@@ -32,6 +108,8 @@ impl<K: BitCaskKey> Entry<K> {
             key,
             value: ValueReference { value, tombstone: 0 },
             timestamp: 0,
+            expires_at: None,
+            sequence: 0,
             clock,
         }
     }
@@ -41,6 +119,8 @@ impl<K: BitCaskKey> Entry<K> {
             key,
             value: ValueReference { value, tombstone: 0 },
             timestamp: ts,
+            expires_at: None,
+            sequence: 0,
             clock,
         }
     }
@@ -49,126 +129,437 @@ impl<K: BitCaskKey> Entry<K> {
     pub fn new_deleted_entry(key: K, clock: Arc<dyn Clock>) -> Self {
         Entry {
             key,
-            value: ValueReference { value: vec![], tombstone: 1 },
+            value: ValueReference { value: vec![], tombstone: TOMBSTONE_FLAG },
             timestamp: 0,
+            expires_at: None,
+            sequence: 0,
+            clock,
+        }
+    }
+
+    /// NewDeletedEntryPreservingTimestamp creates a new instance of Entry with tombstone byte set to 1 (0000 0001) and keeping the provided timestamp
+    pub fn new_deleted_preserving_timestamp(key: K, ts: u32, clock: Arc<dyn Clock>) -> Self {
+        Entry {
+            key,
+            value: ValueReference { value: vec![], tombstone: TOMBSTONE_FLAG },
+            timestamp: ts,
+            expires_at: None,
+            sequence: 0,
             clock,
         }
     }
 
+    /// WithExpiry sets the expiry bit of the tombstone byte (0001 0000) if the entry expires, `expires_at` being seconds since the epoch like the timestamp
+    pub fn with_expiry(mut self, expires_at: Option<u32>) -> Self {
+        if expires_at.is_some() {
+            self.value.tombstone |= EXPIRES_FLAG;
+        }
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<u32> {
+        self.expires_at
+    }
+
+    /// WithSequence sets the sequence bit of the tombstone byte (0010 0000) and the sequence number of the write, which orders the writes of a key for CompareAndSwap.
+    /// Entries written before sequence numbers existed have none and read as 0.
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        if sequence != 0 {
+            self.value.tombstone |= SEQUENCE_FLAG;
+        }
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// InBatch sets the batch bit of the tombstone byte (0000 1000), the entry only counts once the batch it belongs to is committed
+    pub fn in_batch(mut self) -> Self {
+        self.value.tombstone |= BATCH_OP_FLAG;
+        self
+    }
+
     /// encode performs the encode operation which converts the Entry to a byte slice which can be written to the disk
     /// Encoding scheme consists of the following structure:
     /// ```
-    /// ┌───────────┬──────────┬────────────┬─────┬───────┐
-    /// │ timestamp │ key_size │ value_size │ key │ value │
-    /// └───────────┴──────────┴────────────┴─────┴───────┘
+    /// ┌───────┬───────────┬──────────┬────────────┬─────┬───────┐
+    /// │ crc32 │ timestamp │ key_size │ value_size │ key │ value │
+    /// └───────┴───────────┴──────────┴────────────┴─────┴───────┘
     /// ```
-    /// timestamp, key_size, value_size consist of 32 bits each. The value ([]byte) consists of the value provided by the user and a byte for tombstone, that
+    /// crc32, timestamp, key_size, value_size consist of 32 bits each. The value ([]byte) consists of the value provided by the user and a byte for tombstone, that
     /// is used to signify if the key/value pair is deleted or not. Take a look at the NewDeletedEntry function.
+    /// An entry that expires extends the header with its expiry of 32 bits in front of the value, which the expiry bit of the tombstone byte announces.
+    /// The sequence number of 64 bits follows it the same way, announced by the sequence bit.
+    /// The crc32 covers everything after it, so a record torn by a crash or damaged on disk is detected when it is read back.
     /// A little-endian system, stores the least-significant byte at the smallest address. What is special about 4 bytes key size or 4 bytes value size?
     /// The maximum integer stored by 4 bytes is 4,294,967,295 (2 ** 32 - 1), roughly ~4.2GB. This means each key or value size can not be greater than 4.2GB.
     pub fn encode(&self) -> Vec<u8> {
-        let serialized_key = self.key.serialize();
-        let key_len_size = serialized_key.len() as u32;
-        let value_len_size = self.value.value.len() as u32 + TOMBSTONE_MARKER_SIZE;
+        let value = [self.value_prefix().as_slice(), &self.value.value].concat();
+        encode_record(self.timestamp(), &self.key.serialize(), &value, self.value.tombstone)
+    }
+
+    /// encode_sealed encodes the Entry the way `encode` does, with the HMAC of the key in place of the key and a value that seals the key and the value.
+    /// The hashed key, the timestamp, the delete bit, the expiry and the sequence number are authenticated along with them, so none of them can be changed without the record failing to open.
+    /// The expiry and the sequence number stay in front of the sealed value, so that expired entries are told apart and hints are written without opening them.
+    pub fn encode_sealed(&self, cipher: &Cipher) -> Vec<u8> {
+        let timestamp = self.timestamp();
+        let key = self.key.serialize();
+        let hashed_key = cipher.hash_key(&key);
+        let plaintext = [(key.len() as u32).to_le_bytes().as_slice(), &key, &self.value.value].concat();
+        let deleted = self.value.tombstone & TOMBSTONE_FLAG == TOMBSTONE_FLAG;
+        let sealed = cipher.seal(&plaintext, &sealed_record_aad(&hashed_key, timestamp, deleted, self.expires_at, self.sequence));
+        let value = [self.value_prefix().as_slice(), &sealed].concat();
+        encode_record(timestamp, &hashed_key, &value, self.value.tombstone)
+    }
 
-        let mut encoded = Vec::with_capacity(
-            (RESERVED_TIMESTAMP_SIZE + RESERVED_KEY_SIZE + RESERVED_VALUE_SIZE + key_len_size + value_len_size) as usize,
-        );
+    // value_prefix returns what is written in front of the value: the expiry and the sequence number, when the entry has them
+    fn value_prefix(&self) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(RESERVED_EXPIRY_SIZE + RESERVED_SEQUENCE_SIZE);
+        if let Some(expires_at) = self.expires_at {
+            prefix.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        if self.sequence != 0 {
+            prefix.extend_from_slice(&self.sequence.to_le_bytes());
+        }
+        prefix
+    }
 
-        let timestamp = if self.timestamp == 0 {
+    fn timestamp(&self) -> u32 {
+        if self.timestamp == 0 {
             self.clock.now() as u32
         } else {
             self.timestamp
+        }
+    }
+}
+
+// sealed_record_aad returns what is authenticated along with a sealed record, entries without an expiry or a sequence number leave them out as they did before those existed
+fn sealed_record_aad(hashed_key: &[u8], timestamp: u32, deleted: bool, expires_at: Option<u32>, sequence: u64) -> Vec<u8> {
+    let mut aad = [hashed_key, &timestamp.to_le_bytes(), &[deleted as u8]].concat();
+    if let Some(expires_at) = expires_at {
+        aad.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if sequence != 0 {
+        aad.extend_from_slice(&sequence.to_le_bytes());
+    }
+    aad
+}
+
+/// BatchMarker delimits the entries of a write batch in a segment, both markers carry the number of entries in the batch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchMarker {
+    Begin(u32),
+    Commit(u32),
+}
+
+impl BatchMarker {
+    /// encode converts the marker to a record without a key, whose value is the number of entries in the batch
+    pub fn encode(&self, timestamp: u32) -> Vec<u8> {
+        let (op_count, flag) = match self {
+            BatchMarker::Begin(op_count) => (op_count, BATCH_BEGIN_FLAG),
+            BatchMarker::Commit(op_count) => (op_count, BATCH_COMMIT_FLAG),
         };
+        encode_record(timestamp, &[], &op_count.to_le_bytes(), flag)
+    }
+}
 
-        // Write the header
-        encoded.extend_from_slice(&timestamp.to_le_bytes()); // Write timestamp as little-endian
-        encoded.extend_from_slice(&key_len_size.to_le_bytes());   // Write key length
-        encoded.extend_from_slice(&value_len_size.to_le_bytes()); // Write value length
-        encoded.extend_from_slice(&serialized_key);
-        encoded.extend_from_slice(&self.value.value);
-        encoded.push(self.value.tombstone);
+/// BatchOp is a put or a delete that is part of a write batch
+pub enum BatchOp<K> {
+    Put(K, Vec<u8>),
+    Delete(K),
+}
 
-        encoded
+impl<K: BitCaskKey> BatchOp<K> {
+    pub fn key(&self) -> &K {
+        match self {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) => key,
+        }
+    }
+
+    pub fn into_entry(self, sequence: u64, clock: Arc<dyn Clock>) -> Entry<K> {
+        match self {
+            BatchOp::Put(key, value) => Entry::new(key, value, clock),
+            BatchOp::Delete(key) => Entry::new_deleted_entry(key, clock),
+        }
+        .with_sequence(sequence)
+        .in_batch()
     }
 }
 
+fn encode_record(timestamp: u32, serialized_key: &[u8], value: &[u8], tombstone: u8) -> Vec<u8> {
+    let key_len_size = serialized_key.len() as u32;
+    let value_len_size = value.len() as u32 + TOMBSTONE_MARKER_SIZE;
+
+    let mut encoded = Vec::with_capacity(
+        (RESERVED_CHECKSUM_SIZE + RESERVED_TIMESTAMP_SIZE + RESERVED_KEY_SIZE + RESERVED_VALUE_SIZE + key_len_size + value_len_size) as usize,
+    );
+
+    // Write the header, leaving room for the checksum
+    encoded.extend_from_slice(&[0; RESERVED_CHECKSUM_SIZE as usize]);
+    encoded.extend_from_slice(&timestamp.to_le_bytes()); // Write timestamp as little-endian
+    encoded.extend_from_slice(&key_len_size.to_le_bytes());   // Write key length
+    encoded.extend_from_slice(&value_len_size.to_le_bytes()); // Write value length
+    encoded.extend_from_slice(serialized_key);
+    encoded.extend_from_slice(value);
+    encoded.push(tombstone);
+
+    let checksum = crc32fast::hash(&encoded[RESERVED_CHECKSUM_SIZE as usize..]);
+    LittleEndian::write_u32(&mut encoded, checksum);
+    encoded
+}
+
 pub struct StoredEntry {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
     pub deleted: bool,
     pub timestamp: u32,
+    pub batch_marker: Option<BatchMarker>,
+    pub in_batch: bool,
+    pub expires_at: Option<u32>,
+    pub sequence: u64,
+}
+
+impl StoredEntry {
+    /// open replaces the hashed key and the sealed value of an entry read from a sealed segment with the key and the value, failing with InvalidData if it does not open
+    pub fn open(self, cipher: &Cipher) -> Result<StoredEntry, Error> {
+        let (key, value) = open_record(cipher, &self.key, &self.value, self.timestamp, self.deleted, self.expires_at, self.sequence).ok_or(Error::InvalidData)?;
+        Ok(StoredEntry { key, value, ..self })
+    }
+}
+
+impl MappedStoredEntry<Vec<u8>> {
+    /// open does what StoredEntry::open does for an entry read with the rest of its segment, returning None if it does not open
+    pub fn open<K>(self, cipher: &Cipher, key_mapper: fn(&[u8]) -> K) -> Option<MappedStoredEntry<K>> {
+        let (key, value) = open_record(cipher, &self.key, &self.value, self.timestamp, self.deleted, self.expires_at, self.sequence)?;
+        Some(MappedStoredEntry {
+            key: key_mapper(&key),
+            value,
+            deleted: self.deleted,
+            timestamp: self.timestamp,
+            expires_at: self.expires_at,
+            sequence: self.sequence,
+            key_offset: self.key_offset,
+            entry_length: self.entry_length,
+        })
+    }
+}
+
+// open_record returns the key and the value sealed by Entry::encode_sealed
+fn open_record(cipher: &Cipher, hashed_key: &[u8], sealed: &[u8], timestamp: u32, deleted: bool, expires_at: Option<u32>, sequence: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+    let plaintext = cipher.open(sealed, &sealed_record_aad(hashed_key, timestamp, deleted, expires_at, sequence))?;
+    let key_size = LittleEndian::read_u32(plaintext.get(..RESERVED_KEY_SIZE as usize)?) as usize;
+    let key = plaintext.get(RESERVED_KEY_SIZE as usize..RESERVED_KEY_SIZE as usize + key_size)?.to_vec();
+    let value = plaintext[RESERVED_KEY_SIZE as usize + key_size..].to_vec();
+    Some((key, value))
+}
+
+/// DecodeError tells a record cut short by the end of the content from one that is complete but damaged
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// the content ends before the record does, which is what a write torn by a crash leaves behind
+    Truncated,
+    /// the record failed its checksum or is malformed, `length` is the number of bytes it claims to span
+    Corrupted { length: u32 },
 }
 
 /// decode performs the decode operation and returns an instance of StoredEntry
-pub fn decode(content: &[u8]) -> StoredEntry {
-    decode_from(content, 0).0
+pub fn decode(content: &[u8], format: RecordFormat) -> Result<StoredEntry, Error> {
+    decode_from(content, 0, format)
+        .map(|(entry, _)| entry)
+        .map_err(|_| Error::InvalidData)
 }
 
-/// decodeMulti performs multiple decode operations and returns an array of MappedStoredEntry
+/// DecodedSegment holds the entries of a segment along with the records that could not be decoded
+pub struct DecodedSegment<K> {
+    pub entries: Vec<MappedStoredEntry<K>>,
+    /// offsets of the records that were skipped because they are damaged
+    pub corrupted: Vec<u32>,
+    /// length of the segment up to the end of its last complete record, anything after it is a torn write
+    pub valid_length: u32,
+}
+
+/// decodeMulti performs multiple decode operations and returns a DecodedSegment
 /// This method is invoked when a segment file needs to be read completely. This happens during reload and merge operations.
-pub fn decode_multi<K: BitCaskKey>(
+/// A damaged record is skipped using the length in its header. A record cut short at the end of the segment marks the end of the valid content,
+/// unless a damaged record came before it, in which case its length cannot be trusted and the rest of the segment is reported as damaged instead.
+/// The entries of a write batch are only returned once its commit marker is read with all of them. A batch that never committed is dropped,
+/// and if it is the last thing in the segment, the valid content ends where it began so that recovery cuts it off.
+pub fn decode_multi<K>(
     content: &[u8],
+    format: RecordFormat,
     key_mapper: fn(&[u8]) -> K,
-) -> Vec<MappedStoredEntry<K>> {
+) -> DecodedSegment<K> {
     let content_length = content.len() as u32;
-    let mut offset = 0;
+    let mut offset = format.header_size().min(content_length);
     let mut entries = Vec::new();
+    let mut corrupted = Vec::new();
+    // offset of the begin marker, number of entries and entries read so far of the batch being read
+    let mut batch: Option<(u32, u32, Vec<MappedStoredEntry<K>>)> = None;
 
     while offset < content_length {
-        let (entry, traversed_offset) = decode_from(content, offset);
-        entries.push(MappedStoredEntry {
-            key: key_mapper(&entry.key),
-            value: entry.value.clone(),
-            deleted: entry.deleted,
-            timestamp: entry.timestamp,
-            key_offset: offset,
-            // TODO: verify if correct
-            entry_length: traversed_offset - offset,
-        });
-        offset = traversed_offset;
+        match decode_from(content, offset, format) {
+            Ok((entry, traversed_offset)) => {
+                match entry.batch_marker {
+                    Some(BatchMarker::Begin(op_count)) => batch = Some((offset, op_count, Vec::new())),
+                    Some(BatchMarker::Commit(op_count)) => {
+                        if let Some((_, expected_op_count, batch_entries)) = batch.take() {
+                            if expected_op_count == op_count && batch_entries.len() == op_count as usize {
+                                entries.extend(batch_entries);
+                            }
+                        }
+                    }
+                    None => {
+                        let in_batch = entry.in_batch;
+                        let mapped_entry = MappedStoredEntry {
+                            key: key_mapper(&entry.key),
+                            value: entry.value,
+                            deleted: entry.deleted,
+                            timestamp: entry.timestamp,
+                            expires_at: entry.expires_at,
+                            sequence: entry.sequence,
+                            key_offset: offset,
+                            entry_length: traversed_offset - offset,
+                        };
+                        match batch.as_mut() {
+                            Some((_, _, batch_entries)) if in_batch => batch_entries.push(mapped_entry),
+                            // an entry written outside of a batch means the batch before it was abandoned
+                            _ => {
+                                batch = None;
+                                entries.push(mapped_entry);
+                            }
+                        }
+                    }
+                }
+                offset = traversed_offset;
+            }
+            Err(DecodeError::Corrupted { length }) => {
+                corrupted.push(offset);
+                offset += length;
+            }
+            Err(DecodeError::Truncated) if corrupted.is_empty() => break,
+            Err(DecodeError::Truncated) => {
+                corrupted.push(offset);
+                offset = content_length;
+            }
+        }
     }
 
-    entries
+    if let Some((begin_offset, _, _)) = batch {
+        if corrupted.is_empty() {
+            offset = begin_offset;
+        }
+    }
+
+    DecodedSegment {
+        entries,
+        corrupted,
+        valid_length: offset,
+    }
+}
+
+/// latest_timestamp returns the newest timestamp among the records of a segment, up to the first one that cannot be decoded
+pub fn latest_timestamp(content: &[u8], format: RecordFormat) -> u32 {
+    let mut offset = format.header_size().min(content.len() as u32);
+    let mut latest = 0;
+    while let Ok((entry, traversed_offset)) = decode_from(content, offset, format) {
+        latest = latest.max(entry.timestamp);
+        offset = traversed_offset;
+    }
+    latest
 }
 
 /// decodeFrom performs the decode operation.
 /// Encoding scheme consists of the following structure:
 /// ```
-///	┌───────────┬──────────┬────────────┬─────┬───────┐
-///	│ timestamp │ key_size │ value_size │ key │ value │
-///	└───────────┴──────────┴────────────┴─────┴───────┘
+/// ┌───────┬───────────┬──────────┬────────────┬─────┬───────┐
+/// │ crc32 │ timestamp │ key_size │ value_size │ key │ value │
+/// └───────┴───────────┴──────────┴────────────┴─────┴───────┘
 /// ```
-/// In order to perform `decode`, the code reads the first 4 bytes to get the timestamp, next 4 bytes to get the key size, next 4 bytes to get the value size
+/// Legacy records have no crc32. In order to perform `decode`, the code reads the checksum, then the next 4 bytes to get the timestamp, next 4 bytes to get the key size, next 4 bytes to get the value size
 /// Note: the value size is the size including the length of the byte slice provided by the user and one byte for the tombstone marker
 /// Reading further from the offset to the offset+keySize return the actual key, followed by next read from offset to offset+valueSize which returns the actual value.
-/// DeletedFlag is determined by taking the last byte from the `value` byte slice and performing an AND operation with 0x01.
-fn decode_from(content: &[u8], mut offset: u32) -> (StoredEntry, u32) {
-    let timestamp = LittleEndian::read_u32(&content[offset as usize..]);
-    offset += RESERVED_TIMESTAMP_SIZE;
+/// DeletedFlag is determined by taking the last byte from the `value` byte slice and performing an AND operation with 0x01, the other bits of that byte mark the records of a write batch
+/// and the entries whose value starts with an expiry or a sequence number.
+/// Every size is checked against the content before slicing, so a torn or damaged record is reported as a DecodeError rather than a panic.
+fn decode_from(content: &[u8], offset: u32, format: RecordFormat) -> Result<(StoredEntry, u32), DecodeError> {
+    let start = offset as usize;
+    let checksum_size = match format {
+        RecordFormat::Legacy => 0,
+        RecordFormat::Checksummed | RecordFormat::Sealed { .. } => RESERVED_CHECKSUM_SIZE as usize,
+    };
+    let header_size = checksum_size + (RESERVED_TIMESTAMP_SIZE + RESERVED_KEY_SIZE + RESERVED_VALUE_SIZE) as usize;
+    let header = content.get(start..start + header_size).ok_or(DecodeError::Truncated)?;
 
-    let key_size = LittleEndian::read_u32(&content[offset as usize..]);
-    offset += RESERVED_KEY_SIZE;
+    let timestamp = LittleEndian::read_u32(&header[checksum_size..]);
+    let key_size = LittleEndian::read_u32(&header[checksum_size + RESERVED_TIMESTAMP_SIZE as usize..]);
+    let value_size = LittleEndian::read_u32(&header[checksum_size + (RESERVED_TIMESTAMP_SIZE + RESERVED_KEY_SIZE) as usize..]);
 
-    let value_size = LittleEndian::read_u32(&content[offset as usize..]);
-    offset += RESERVED_VALUE_SIZE;
+    let record_length = header_size as u64 + key_size as u64 + value_size as u64;
+    if start as u64 + record_length > content.len() as u64 {
+        return Err(DecodeError::Truncated);
+    }
+    let end = start + record_length as usize;
+    let corrupted = DecodeError::Corrupted { length: record_length as u32 };
 
-    let serialized_key = &content[offset as usize..(offset + key_size) as usize];
-    offset += key_size;
+    if format != RecordFormat::Legacy
+        && LittleEndian::read_u32(header) != crc32fast::hash(&content[start + checksum_size..end])
+    {
+        return Err(corrupted);
+    }
+    if value_size < TOMBSTONE_MARKER_SIZE {
+        return Err(corrupted);
+    }
 
-    let value = &content[offset as usize..(offset + value_size) as usize];
-    offset += value_size;
+    let key_start = start + header_size;
+    let value_start = key_start + key_size as usize;
+    let serialized_key = &content[key_start..value_start];
+    let value = &content[value_start..end];
 
     let value_length = value.len();
-    (
+    let tombstone = value[value_length - 1];
+    let value = &value[..value_length - 1];
+    let batch_marker = if tombstone & (BATCH_BEGIN_FLAG | BATCH_COMMIT_FLAG) != 0 {
+        if value.len() != BATCH_OP_COUNT_SIZE {
+            return Err(corrupted);
+        }
+        let op_count = LittleEndian::read_u32(value);
+        Some(if tombstone & BATCH_BEGIN_FLAG != 0 { BatchMarker::Begin(op_count) } else { BatchMarker::Commit(op_count) })
+    } else {
+        None
+    };
+    let (expires_at, value) = if tombstone & EXPIRES_FLAG != 0 {
+        if value.len() < RESERVED_EXPIRY_SIZE {
+            return Err(corrupted);
+        }
+        (Some(LittleEndian::read_u32(value)), &value[RESERVED_EXPIRY_SIZE..])
+    } else {
+        (None, value)
+    };
+    let (sequence, value) = if tombstone & SEQUENCE_FLAG != 0 {
+        if value.len() < RESERVED_SEQUENCE_SIZE {
+            return Err(corrupted);
+        }
+        (LittleEndian::read_u64(value), &value[RESERVED_SEQUENCE_SIZE..])
+    } else {
+        (0, value)
+    };
+
+    Ok((
         StoredEntry {
             key: serialized_key.to_vec(),
-            value: value[..value_length - 1].to_vec(),
-            deleted: (value[value_length - 1] & 0x01) == 0x01,
+            value: value.to_vec(),
+            deleted: (tombstone & TOMBSTONE_FLAG) == TOMBSTONE_FLAG,
             timestamp,
+            batch_marker,
+            in_batch: (tombstone & BATCH_OP_FLAG) == BATCH_OP_FLAG,
+            expires_at,
+            sequence,
         },
-        offset,
-    )
+        end as u32,
+    ))
 }
 
 #[derive(Clone, Debug)]
@@ -177,6 +568,140 @@ pub struct MappedStoredEntry<K> {
     pub value: Vec<u8>,
     pub deleted: bool,
     pub timestamp: u32,
+    pub expires_at: Option<u32>,
+    /// sequence number of the write, 0 for entries written before sequence numbers existed
+    pub sequence: u64,
     pub key_offset: u32,
     pub entry_length: u32,
 }
+
+impl<K> MappedStoredEntry<K> {
+    /// is_expired tells if the entry expired by `now`, in seconds since the epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at as u64 <= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_cask_key::{UUIDWasiKey, UUIDWasiKeyFrom};
+    use crate::clock::FakeClock;
+
+    fn segment(values: &[(&str, &[u8])]) -> (Vec<u8>, Vec<u32>) {
+        let clock: Arc<dyn Clock> = Arc::new(FakeClock::new(1_700_000_000));
+        let mut content = RecordFormat::Checksummed.segment_header();
+        let mut offsets = vec![];
+        for (key, value) in values {
+            offsets.push(content.len() as u32);
+            content.extend(Entry::new(UUIDWasiKey::from(key.to_string()), value.to_vec(), clock.clone()).encode());
+        }
+        (content, offsets)
+    }
+
+    fn keys(decoded: &DecodedSegment<UUIDWasiKey>) -> Vec<String> {
+        decoded.entries.iter().map(|entry| String::from(entry.key.clone())).collect()
+    }
+
+    #[test]
+    fn checksummed_records() {
+        let (content, offsets) = segment(&[("a", b"1"), ("b", b"22")]);
+        let decoded = decode_multi(&content, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a", "b"]);
+        assert_eq!(decoded.entries[1].value, b"22");
+        assert_eq!(decoded.entries[1].key_offset, offsets[1]);
+        assert_eq!(decoded.entries[1].timestamp, 1_700_000_000);
+        assert!(decoded.corrupted.is_empty());
+        assert_eq!(decoded.valid_length, content.len() as u32);
+        assert_eq!(decode(&content[offsets[1] as usize..], RecordFormat::Checksummed).unwrap().value, b"22");
+    }
+
+    #[test]
+    fn damaged_record() {
+        let (mut content, offsets) = segment(&[("a", b"1"), ("b", b"22"), ("c", b"3")]);
+        content[offsets[2] as usize - 2] ^= 0xff;
+        let decoded = decode_multi(&content, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a", "c"]);
+        assert_eq!(decoded.corrupted, [offsets[1]]);
+        assert_eq!(decoded.valid_length, content.len() as u32);
+        assert!(decode(&content[offsets[1] as usize..], RecordFormat::Checksummed).is_err());
+    }
+
+    #[test]
+    fn torn_tail() {
+        let (content, offsets) = segment(&[("a", b"1"), ("b", b"22")]);
+        let torn = &content[..content.len() - 3];
+        let decoded = decode_multi(torn, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a"]);
+        assert!(decoded.corrupted.is_empty());
+        assert_eq!(decoded.valid_length, offsets[1]);
+    }
+
+    #[test]
+    fn torn_tail_after_damage() {
+        let (mut content, offsets) = segment(&[("a", b"1"), ("b", b"22"), ("c", b"3")]);
+        content[offsets[1] as usize - 2] ^= 0xff;
+        content.truncate(content.len() - 3);
+        let decoded = decode_multi(&content, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        // the length of a record after a damaged one can not be trusted, so the rest of the segment is reported instead of cut off
+        assert_eq!(keys(&decoded), ["b"]);
+        assert_eq!(decoded.corrupted, [offsets[0], offsets[2]]);
+        assert_eq!(decoded.valid_length, content.len() as u32);
+    }
+
+    #[test]
+    fn format_detection() {
+        assert_eq!(RecordFormat::detect(&RecordFormat::Checksummed.segment_header()).unwrap(), RecordFormat::Checksummed);
+        assert_eq!(RecordFormat::detect(&1_600_000_000u32.to_le_bytes()).unwrap(), RecordFormat::Legacy);
+        assert!(RecordFormat::detect(b"BCSK\x09").is_err());
+    }
+
+    fn batch(content: &mut Vec<u8>, keys: &[&str]) {
+        let clock: Arc<dyn Clock> = Arc::new(FakeClock::new(1_700_000_000));
+        content.extend(BatchMarker::Begin(keys.len() as u32).encode(1_700_000_000));
+        for (sequence, key) in keys.iter().enumerate() {
+            content.extend(BatchOp::Put(UUIDWasiKey::from(key.to_string()), vec![1]).into_entry(sequence as u64 + 1, clock.clone()).encode());
+        }
+        content.extend(BatchMarker::Commit(keys.len() as u32).encode(1_700_000_000));
+    }
+
+    #[test]
+    fn committed_batch() {
+        let (mut content, _) = segment(&[("a", b"1")]);
+        batch(&mut content, &["b", "c"]);
+        let decoded = decode_multi(&content, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a", "b", "c"]);
+        assert_eq!(decoded.valid_length, content.len() as u32);
+    }
+
+    #[test]
+    fn uncommitted_batch() {
+        let (mut content, _) = segment(&[("a", b"1")]);
+        let begin = content.len();
+        batch(&mut content, &["b", "c"]);
+        // the commit marker did not make it to disk
+        let decoded = decode_multi(&content[..content.len() - 2], RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a"]);
+        assert!(decoded.corrupted.is_empty());
+        assert_eq!(decoded.valid_length, begin as u32);
+
+        // a batch followed by an entry written outside of it was abandoned
+        let commit_length = BatchMarker::Commit(2).encode(1_700_000_000).len();
+        let mut content = content[..content.len() - commit_length].to_vec();
+        content.extend(Entry::new(UUIDWasiKey::from("d".to_string()), vec![1], Arc::new(FakeClock::new(1_700_000_000))).encode());
+        let decoded = decode_multi(&content, RecordFormat::Checksummed, UUIDWasiKeyFrom);
+        assert_eq!(keys(&decoded), ["a", "d"]);
+        assert_eq!(decoded.valid_length, content.len() as u32);
+    }
+
+    #[test]
+    fn sequence() {
+        let clock: Arc<dyn Clock> = Arc::new(FakeClock::new(1_700_000_000));
+        let entry = Entry::new(UUIDWasiKey::from("a".to_string()), b"value".to_vec(), clock.clone()).with_expiry(Some(1_700_000_100)).with_sequence(7);
+        let decoded = decode(&entry.encode(), RecordFormat::Checksummed).unwrap();
+        assert_eq!((decoded.value.as_slice(), decoded.expires_at, decoded.sequence), (b"value".as_slice(), Some(1_700_000_100), 7));
+        let decoded = decode(&Entry::new_deleted_entry(UUIDWasiKey::from("a".to_string()), clock).with_sequence(8).encode(), RecordFormat::Checksummed).unwrap();
+        assert!(decoded.deleted);
+        assert_eq!(decoded.sequence, 8);
+    }
+}
@@ -8,7 +8,8 @@ pub enum Error {
     FileNotFound(u64),
     InvalidData,
     ParseError,
-    EntryNotFound
+    EntryNotFound,
+    InvalidConfig,
 }
 
 impl From<Error> for GuestError {
@@ -20,6 +21,7 @@ impl From<Error> for GuestError {
             Error::InvalidData => GuestError::InvalidData,
             Error::ParseError => GuestError::ParseError,
             Error::FileNotFound(error_code) => GuestError::FileNotFound(error_code),
+            Error::InvalidConfig => GuestError::InvalidConfig,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,163 @@
+use std::mem;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::entry::MappedStoredEntry;
+
+const HINT_MAGIC: &[u8; 4] = b"BCHT";
+const RESERVED_FILE_ID_SIZE: usize = mem::size_of::<u64>();
+const RESERVED_SEGMENT_LENGTH_SIZE: usize = mem::size_of::<u64>();
+const HINT_HEADER_SIZE: usize = HINT_MAGIC.len() + RESERVED_FILE_ID_SIZE + RESERVED_SEGMENT_LENGTH_SIZE;
+// timestamp, key_size, offset and entry_length of 32 bits each, and the flags
+const HINT_RECORD_HEADER_SIZE: usize = 4 * mem::size_of::<u32>() + mem::size_of::<u8>();
+const RESERVED_EXPIRY_SIZE: usize = mem::size_of::<u32>();
+const RESERVED_SEQUENCE_SIZE: usize = mem::size_of::<u64>();
+const DELETED_FLAG: u8 = 0x01;
+const EXPIRES_FLAG: u8 = 0x10;
+const SEQUENCE_FLAG: u8 = 0x20;
+const RESERVED_CHECKSUM_SIZE: usize = mem::size_of::<u32>();
+
+/// encode converts the entries of a segment to the content of its hint file, which holds everything the KeyDirectory needs without the values:
+/// ```
+/// ┌───────┬─────────┬────────────────┬─────────┬─────┬─────────┬───────┐
+/// │ magic │ file_id │ segment_length │ record  │ ... │ record  │ crc32 │
+/// └───────┴─────────┴────────────────┴─────────┴─────┴─────────┴───────┘
+/// ┌───────────┬──────────┬────────┬──────────────┬───────┬────────────┬──────────┬─────┐
+/// │ timestamp │ key_size │ offset │ entry_length │ flags │ expires_at │ sequence │ key │
+/// └───────────┴──────────┴────────┴──────────────┴───────┴────────────┴──────────┴─────┘
+/// ```
+/// The flags tell if the entry is deleted, if it expires and if it has a sequence number, `expires_at` and `sequence` are only there for the entries that have them.
+/// The length of the segment is kept so that a hint is not trusted for a segment that changed after it was written.
+/// Deleted entries are kept as well, a delete has to remove the key from the KeyDirectory when segments are replayed in order.
+pub fn encode(file_id: u64, segment_length: u64, entries: &[MappedStoredEntry<Vec<u8>>]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(
+        HINT_HEADER_SIZE
+            + entries.iter().map(|entry| HINT_RECORD_HEADER_SIZE + RESERVED_EXPIRY_SIZE + RESERVED_SEQUENCE_SIZE + entry.key.len()).sum::<usize>()
+            + RESERVED_CHECKSUM_SIZE,
+    );
+    encoded.extend_from_slice(HINT_MAGIC);
+    encoded.extend_from_slice(&file_id.to_le_bytes());
+    encoded.extend_from_slice(&segment_length.to_le_bytes());
+
+    for entry in entries {
+        encoded.extend_from_slice(&entry.timestamp.to_le_bytes());
+        encoded.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&entry.key_offset.to_le_bytes());
+        encoded.extend_from_slice(&entry.entry_length.to_le_bytes());
+        let mut flags = if entry.deleted { DELETED_FLAG } else { 0 };
+        if entry.expires_at.is_some() {
+            flags |= EXPIRES_FLAG;
+        }
+        if entry.sequence != 0 {
+            flags |= SEQUENCE_FLAG;
+        }
+        encoded.push(flags);
+        if let Some(expires_at) = entry.expires_at {
+            encoded.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        if entry.sequence != 0 {
+            encoded.extend_from_slice(&entry.sequence.to_le_bytes());
+        }
+        encoded.extend_from_slice(&entry.key);
+    }
+
+    let checksum = crc32fast::hash(&encoded);
+    encoded.extend_from_slice(&checksum.to_le_bytes());
+    encoded
+}
+
+/// decode returns the entries of a hint file, without their values.
+/// It returns None if the hint is torn, fails its checksum or does not describe the segment identified by `file_id` at its current length,
+/// in which case the segment has to be read in full.
+pub fn decode<K>(
+    content: &[u8],
+    file_id: u64,
+    segment_length: u64,
+    key_mapper: fn(&[u8]) -> K,
+) -> Option<Vec<MappedStoredEntry<K>>> {
+    let checksum_offset = content.len().checked_sub(RESERVED_CHECKSUM_SIZE)?;
+    if checksum_offset < HINT_HEADER_SIZE
+        || !content.starts_with(HINT_MAGIC)
+        || LittleEndian::read_u32(&content[checksum_offset..]) != crc32fast::hash(&content[..checksum_offset])
+    {
+        return None;
+    }
+    if LittleEndian::read_u64(&content[HINT_MAGIC.len()..]) != file_id
+        || LittleEndian::read_u64(&content[HINT_MAGIC.len() + RESERVED_FILE_ID_SIZE..]) != segment_length
+    {
+        return None;
+    }
+
+    let records = &content[HINT_HEADER_SIZE..checksum_offset];
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    while offset < records.len() {
+        let header = records.get(offset..offset + HINT_RECORD_HEADER_SIZE)?;
+        let key_size = LittleEndian::read_u32(&header[4..]) as usize;
+        let flags = header[16];
+        let mut key_start = offset + HINT_RECORD_HEADER_SIZE;
+        let mut expires_at = None;
+        if flags & EXPIRES_FLAG != 0 {
+            expires_at = Some(LittleEndian::read_u32(records.get(key_start..key_start + RESERVED_EXPIRY_SIZE)?));
+            key_start += RESERVED_EXPIRY_SIZE;
+        }
+        let mut sequence = 0;
+        if flags & SEQUENCE_FLAG != 0 {
+            sequence = LittleEndian::read_u64(records.get(key_start..key_start + RESERVED_SEQUENCE_SIZE)?);
+            key_start += RESERVED_SEQUENCE_SIZE;
+        }
+        let key = records.get(key_start..key_start + key_size)?;
+        entries.push(MappedStoredEntry {
+            key: key_mapper(key),
+            value: vec![],
+            deleted: flags & DELETED_FLAG != 0,
+            timestamp: LittleEndian::read_u32(header),
+            expires_at,
+            sequence,
+            key_offset: LittleEndian::read_u32(&header[8..]),
+            entry_length: LittleEndian::read_u32(&header[12..]),
+        });
+        offset = key_start + key_size;
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &[u8], deleted: bool, expires_at: Option<u32>, key_offset: u32) -> MappedStoredEntry<Vec<u8>> {
+        MappedStoredEntry { key: key.to_vec(), value: vec![], deleted, timestamp: 1_700_000_000, expires_at, sequence: 0, key_offset, entry_length: 30 }
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = vec![entry(b"a", false, None, 5), entry(b"b", true, None, 35), entry(b"c", false, Some(1_700_000_100), 65)];
+        let decoded = decode(&encode(3, 95, &entries), 3, 95, <[u8]>::to_vec).unwrap();
+        assert_eq!(decoded.len(), 3);
+        for (decoded, entry) in decoded.iter().zip(entries.iter()) {
+            assert_eq!(decoded.key, entry.key);
+            assert_eq!(decoded.deleted, entry.deleted);
+            assert_eq!(decoded.expires_at, entry.expires_at);
+            assert_eq!((decoded.key_offset, decoded.entry_length, decoded.timestamp), (entry.key_offset, entry.entry_length, entry.timestamp));
+        }
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut content = encode(3, 95, &[entry(b"a", false, None, 5)]);
+        assert!(decode(&content, 4, 95, <[u8]>::to_vec).is_none());
+        assert!(decode(&content, 3, 96, <[u8]>::to_vec).is_none());
+        assert!(decode(&content[..content.len() - 1], 3, 95, <[u8]>::to_vec).is_none());
+        content[HINT_HEADER_SIZE] ^= 0xff;
+        assert!(decode(&content, 3, 95, <[u8]>::to_vec).is_none());
+    }
+
+    #[test]
+    fn sequence() {
+        let entries = vec![MappedStoredEntry { sequence: 7, ..entry(b"a", false, Some(1_700_000_100), 5) }, MappedStoredEntry { sequence: 8, ..entry(b"b", true, None, 35) }];
+        let decoded = decode(&encode(3, 65, &entries), 3, 65, <[u8]>::to_vec).unwrap();
+        assert_eq!(decoded.iter().map(|entry| (entry.sequence, entry.expires_at)).collect::<Vec<_>>(), [(7, Some(1_700_000_100)), (8, None)]);
+        assert_eq!(decoded[1].key, b"b");
+    }
+}
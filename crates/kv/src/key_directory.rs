@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
 
 use crate::bit_cask_key::BitCaskKey;
 use crate::entry::MappedStoredEntry;
@@ -8,60 +9,142 @@ use crate::segments::WriteBackResponse;
 /// KeyDirectory is the in-memory storage which maintains a mapping between keys and the position of those keys in the datafiles called segment.
 /// Entry maintains `FileId` identifying the file containing the key, `Offset` identifying the position in the file where the key is stored and
 /// the `EntryLength` identifying the length of the entry
+/// Alongside the hashmap, an ordered index of the serialized keys serves prefix scans and paging through the keys.
+/// It also counts how many bytes of each segment hold live entries and tombstones, the rest of a segment is dead space that a merge reclaims.
 pub struct KeyDirectory<Key: BitCaskKey> {
     entry_by_key: HashMap<Key, Entry>,
+    ordered_keys: BTreeMap<Vec<u8>, Key>,
+    live_bytes: HashMap<u64, u64>,
+    tombstone_bytes: HashMap<u64, u64>,
 }
 
 impl<Key: BitCaskKey> KeyDirectory<Key> {
     pub fn new(initial_capacity: usize) -> Self {
         KeyDirectory {
             entry_by_key: HashMap::with_capacity(initial_capacity),
+            ordered_keys: BTreeMap::new(),
+            live_bytes: HashMap::new(),
+            tombstone_bytes: HashMap::new(),
         }
     }
 
     // Reload reloads the state of the KeyDirectory during start-up. As a part of reloading the state in bitcask model, all the inactive segments are read,
     // and the keys from all the inactive segments are stored in the KeyDirectory.
     // Riak's paper optimizes reloading by creating small sized hint files during merge and compaction.
-    // Hint files contain the keys and the metadata fields like fileId, fileOffset and entryLength, these hint files are referred during reload. This implementation does not create Hint file
+    // Hint files contain the keys and the metadata fields like fileId, fileOffset and entryLength, these hint files are referred during reload. This implementation writes a hint file for every segment that takes no more writes.
+    // Segments have to be reloaded oldest first, so that a later put or delete of a key replaces the earlier one.
     pub fn reload(&mut self, file_id: u64, entries: Vec<MappedStoredEntry<Key>>) {
         for entry in entries {
-            self.entry_by_key.insert(
-                entry.key.clone(),
-                Entry::new(file_id, entry.key_offset as i64, entry.entry_length),
-            );
+            if entry.deleted {
+                self.tombstone(&entry.key, Entry::new(file_id, entry.key_offset as i64, entry.entry_length));
+            } else {
+                let (expires_at, sequence) = (entry.expires_at, entry.sequence);
+                self.put(entry.key, Entry { expires_at, sequence, ..Entry::new(file_id, entry.key_offset as i64, entry.entry_length) });
+            }
         }
     }
 
     /// Put puts a key and its entry as the value in the KeyDirectory
     pub fn put(&mut self, key: Key, value: Entry) {
-        self.entry_by_key.insert(key, value);
+        *self.live_bytes.entry(value.file_id).or_default() += value.entry_length as u64;
+        self.ordered_keys.insert(key.serialize(), key.clone());
+        if let Some(previous) = self.entry_by_key.insert(key, value) {
+            self.release(&previous);
+        }
     }
 
     /// BulkUpdate performs bulk changes to the KeyDirectory state. This method is called during merge and compaction from KeyStore.
-    pub fn bulk_update(&mut self, changes: Vec<WriteBackResponse<Key>>) {
+    /// A key is only moved to its merged position if it still points into one of the merged segments identified by `merged_file_ids`,
+    /// a key written or deleted in a newer segment keeps its newer state. The merged segments are gone afterwards and so are their counts.
+    pub fn bulk_update(&mut self, changes: Vec<WriteBackResponse<Key>>, merged_file_ids: &[u64]) {
         for change in changes {
-            self.entry_by_key.insert(
-                change.key.clone(),
-                Entry::from(change.append_entry_response),
-            );
+            let merged_entry = Entry::from(change.append_entry_response);
+            if change.deleted {
+                *self.tombstone_bytes.entry(merged_entry.file_id).or_default() += merged_entry.entry_length as u64;
+                continue;
+            }
+            if let Some(entry) = self.entry_by_key.get_mut(&change.key) {
+                if merged_file_ids.contains(&entry.file_id) {
+                    *self.live_bytes.entry(merged_entry.file_id).or_default() += merged_entry.entry_length as u64;
+                    *entry = merged_entry;
+                }
+            }
+        }
+        for file_id in merged_file_ids {
+            self.live_bytes.remove(file_id);
+            self.tombstone_bytes.remove(file_id);
         }
     }
 
     /// Delete removes the key from the KeyDirectory
     pub fn delete(&mut self, key: &Key) {
-        self.entry_by_key.remove(key);
+        if let Some(previous) = self.entry_by_key.remove(key) {
+            self.ordered_keys.remove(&key.serialize());
+            self.release(&previous);
+        }
+    }
+
+    /// Tombstone removes the key from the KeyDirectory and counts the tombstone written for it at `tombstone`
+    pub fn tombstone(&mut self, key: &Key, tombstone: Entry) {
+        self.delete(key);
+        *self.tombstone_bytes.entry(tombstone.file_id).or_default() += tombstone.entry_length as u64;
+    }
+
+    /// PurgeExpired removes the keys whose value expired by `now`, in seconds since the epoch, so that the bytes of their values count as dead space
+    pub fn purge_expired(&mut self, now: u64) {
+        let expired_keys: Vec<Key> = self.entry_by_key.iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired_keys {
+            self.delete(&key);
+        }
+    }
+
+    /// LiveBytes returns the number of bytes of the segment identified by `file_id` that hold the current value of a key
+    pub fn live_bytes(&self, file_id: u64) -> u64 {
+        self.live_bytes.get(&file_id).copied().unwrap_or(0)
+    }
+
+    /// TombstoneBytes returns the number of bytes of the segment identified by `file_id` that hold tombstones
+    pub fn tombstone_bytes(&self, file_id: u64) -> u64 {
+        self.tombstone_bytes.get(&file_id).copied().unwrap_or(0)
     }
 
     // Get returns the Entry and a boolean to indicate if the value corresponding to the key is present in the KeyDirectory.
     pub fn get(&self, key: &Key) -> Option<&Entry> {
         self.entry_by_key.get(key)
     }
+
+    // release stops counting an entry that no longer holds the current value of its key as live
+    fn release(&mut self, entry: &Entry) {
+        if let Some(live_bytes) = self.live_bytes.get_mut(&entry.file_id) {
+            *live_bytes = live_bytes.saturating_sub(entry.entry_length as u64);
+        }
+    }
+
+    /// KeysWithPrefix returns the keys whose serialized form starts with `prefix`, in byte order, starting after the key serialized as `after` if provided.
+    /// Keys whose value expired by `now` are left out.
+    pub fn keys_with_prefix<'a>(&'a self, prefix: &'a [u8], after: Option<&[u8]>, now: u64) -> impl Iterator<Item = &'a Key> + 'a {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+        self.ordered_keys
+            .range((start, Bound::Unbounded))
+            .take_while(move |(serialized_key, _)| serialized_key.starts_with(prefix))
+            .map(|(_, key)| key)
+            .filter(move |key| !self.entry_by_key.get(*key).is_some_and(|entry| entry.is_expired(now)))
+    }
 }
 
 pub struct Entry {
     pub file_id: u64,
     pub offset: i64,
     pub entry_length: u32,
+    pub expires_at: Option<u32>,
+    // sequence number of the write, the version of the value for CompareAndSwap
+    pub sequence: u64,
 }
 
 impl Entry {
@@ -73,8 +156,15 @@ impl Entry {
             file_id,
             offset,
             entry_length,
+            expires_at: None,
+            sequence: 0,
         }
     }
+
+    /// is_expired tells if the value expired by `now`, in seconds since the epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at as u64 <= now)
+    }
 }
 
 impl From<AppendEntryResponse> for Entry {
@@ -83,6 +173,47 @@ impl From<AppendEntryResponse> for Entry {
             file_id: response.file_id,
             offset: response.offset,
             entry_length: response.entry_length,
+            expires_at: response.expires_at,
+            sequence: response.sequence,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_cask_key::UUIDWasiKey;
+
+    fn key_directory(keys: &[&str]) -> KeyDirectory<UUIDWasiKey> {
+        let mut key_directory = KeyDirectory::new(keys.len());
+        for (offset, key) in keys.iter().enumerate() {
+            key_directory.put(UUIDWasiKey::from(key.to_string()), Entry::new(1, offset as i64, 10));
         }
+        key_directory
     }
-}
\ No newline at end of file
+
+    fn keys_with_prefix(key_directory: &KeyDirectory<UUIDWasiKey>, prefix: &str, after: Option<&str>) -> Vec<String> {
+        key_directory.keys_with_prefix(prefix.as_bytes(), after.map(str::as_bytes), 0).cloned().map(String::from).collect()
+    }
+
+    #[test]
+    fn prefix() {
+        let mut key_directory = key_directory(&["utxo_2", "header_1", "utxo_1", "utxo", "utxp", "utxo_3"]);
+        key_directory.delete(&UUIDWasiKey::from("utxo_3".to_string()));
+        assert_eq!(keys_with_prefix(&key_directory, "utxo_", None), ["utxo_1", "utxo_2"]);
+        assert_eq!(keys_with_prefix(&key_directory, "utxo", None), ["utxo", "utxo_1", "utxo_2"]);
+        assert_eq!(keys_with_prefix(&key_directory, "", None).len(), 5);
+        assert!(keys_with_prefix(&key_directory, "block", None).is_empty());
+    }
+
+    #[test]
+    fn after() {
+        let key_directory = key_directory(&["utxo_1", "utxo_2", "utxo_3", "utxp"]);
+        assert_eq!(keys_with_prefix(&key_directory, "utxo_", Some("utxo_1")), ["utxo_2", "utxo_3"]);
+        // a cursor that is not a key starts after where it would be
+        assert_eq!(keys_with_prefix(&key_directory, "utxo_", Some("utxo_25")), ["utxo_3"]);
+        // a cursor before the prefix starts at the prefix
+        assert_eq!(keys_with_prefix(&key_directory, "utxo_", Some("a")), ["utxo_1", "utxo_2", "utxo_3"]);
+        assert!(keys_with_prefix(&key_directory, "utxo_", Some("utxo_3")).is_empty());
+    }
+}
@@ -1,13 +1,12 @@
-use std::collections::HashMap;
-use std::sync::{RwLock};
+use std::sync::{Arc, RwLock};
 
 use crate::bit_cask_key::BitCaskKey;
+use crate::clock::Clock;
 use crate::config::Config;
-use crate::entry::{MappedStoredEntry};
+use crate::entry::BatchOp;
 use crate::errors::Error;
 use crate::key_directory::{ KeyDirectory, Entry as KeyDirectoryEntry };
 use crate::merge_config::MergeConfig;
-use crate::merged_state::MergedState;
 use crate::segments::Segments;
 use crate::store::Store;
 
@@ -20,20 +19,52 @@ pub struct KVStore<Key: BitCaskKey, S: Store> {
     lock: RwLock<()>,
     merge_config: MergeConfig<Key>,
     counter: u64,
+    // sequence number of the last write, restored from the segments on start-up
+    sequence: u64,
+    recovery_report: RecoveryReport,
+    clock: Arc<dyn Clock>,
 }
 
+/// RecoveryReport describes what reload found wrong with the segments on disk.
+/// A torn tail is the expected result of the host being killed mid-write, damaged records point at a storage problem.
+#[derive(Default, Debug)]
+pub struct RecoveryReport {
+    /// file id of each segment whose torn tail record was cut off, with the number of bytes removed
+    pub truncated: Vec<(u64, u32)>,
+    /// file id and offset of each record that failed its checksum and was skipped, or of the torn tail of a segment that was no longer active
+    pub corrupted: Vec<(u64, u32)>,
+    /// tells if the manifest was damaged, the segments were then ordered by the timestamps of their entries and none of them was removed
+    pub manifest_damaged: bool,
+}
+
+/// CompactionReport describes what a merge did
+#[derive(Default, Debug)]
+pub struct CompactionReport {
+    pub merged_segments: u32,
+    /// bytes of the merged segments minus the bytes written in their place
+    pub reclaimed_bytes: u64,
+    /// tells if segments worth compacting were left for a later compaction
+    pub pending: bool,
+}
+
+/// COMPACTION_GARBAGE_RATIO is the share of dead bytes from which a segment is worth merging, below it a merge would mostly copy live entries
+const COMPACTION_GARBAGE_RATIO: f64 = 0.4;
+
 
 impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
     /// It creates a new instance of KVStore
     /// It also performs a reload operation `store.reload(config)` that is responsible for reloading the state of KeyDirectory from inactive segments
     pub fn new(config: &Config<Key>) -> Result<Self, Error> {
-        let segments = Segments::new::<Key>(config.directory().into(), config.max_segment_size_in_bytes(), config.clock())?;
+        let segments = Segments::new(config.directory().clone(), config.max_segment_size_in_bytes(), config.sync_policy(), config.keyring(), config.clock())?;
         let mut store = KVStore {
             segments,
             key_directory: KeyDirectory::new(config.key_directory_capacity() as usize),
             lock: RwLock::new(()),
             merge_config: config.merge_config().unwrap().clone(),
-            counter: 0
+            counter: 0,
+            sequence: 0,
+            recovery_report: RecoveryReport::default(),
+            clock: config.clock(),
         };
         store.reload()?;
         Ok(store)
@@ -44,30 +75,84 @@ impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
     /// 2.Append the key and the value in the append-only active segment using `kv.segments.Append(key, value)`.
     /// - Segments abstraction will append the key and the value to the active segment if the size of the active segment is less than the threshold, else it will perform a rollover of the active segment
     /// 3.Once the append operation is successful, it will write the key and the Entry to the KeyDirectory, which is an in-memory representation of the key and its position in an append-only segment
-    fn put(&mut self, key: Key, value: Vec<u8>) -> Result<(), Error> {
-        if self.counter >= self.merge_config.run_merge_every() {
-            self.begin_merge()?;
-            self.counter = 0
-        }
+    /// A value with `expires_at` set, in seconds since the epoch, is treated as missing once that time has passed.
+    fn put(&mut self, key: Key, value: Vec<u8>, expires_at: Option<u32>) -> Result<(), Error> {
+        self.maybe_merge()?;
 
         let _write_lock = self.lock.write().unwrap();
-        let append_entry_response = self.segments.append(key.clone(), value)?;
+        self.sequence += 1;
+        let append_entry_response = self.segments.append(key.clone(), value, expires_at, self.sequence)?;
         self.key_directory.put(key, KeyDirectoryEntry::from(append_entry_response));
         self.counter +=1;
-        Ok(())
+        self.segments.sync_active_segment()
+    }
+
+    /// WriteBatch appends a group of puts and deletes to the active segment between batch markers, and applies them to the KeyDirectory once all of them are written.
+    /// After a crash, reload only applies the batch if its commit marker made it to disk, so either all or none of the operations survive.
+    pub fn write_batch(&mut self, ops: Vec<BatchOp<Key>>) -> Result<(), Error> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.maybe_merge()?;
+
+        let _write_lock = self.lock.write().unwrap();
+        let keys: Vec<(Key, bool)> = ops.iter().map(|op| (op.key().clone(), matches!(op, BatchOp::Delete(_)))).collect();
+        let first_sequence = self.sequence + 1;
+        self.sequence += ops.len() as u64;
+        let append_entry_responses = self.segments.append_batch(ops, first_sequence)?;
+        for ((key, deleted), append_entry_response) in keys.into_iter().zip(append_entry_responses) {
+            if deleted {
+                self.key_directory.tombstone(&key, KeyDirectoryEntry::from(append_entry_response));
+            } else {
+                self.key_directory.put(key, KeyDirectoryEntry::from(append_entry_response));
+            }
+        }
+        self.counter += 1;
+        self.segments.sync_active_segment()
+    }
+
+    /// CompareAndSwap puts the value only if the version of the current value of the key is `expected_version`, or if the key has no value and `expected_version` is None.
+    /// It returns false without writing anything when the key changed since its version was read with GetVersioned.
+    /// The version of a value is the sequence number of the write that put it. Every write takes the next number of the store, so a key that is set back to an earlier value
+    /// still has a new version. Values written before sequence numbers existed all have version 0 until they are written again.
+    pub fn compare_and_swap(&mut self, key: Key, expected_version: Option<u64>, value: Vec<u8>) -> Result<bool, Error> {
+        let current_version = {
+            let _read_lock = self.lock.read().unwrap();
+            self.live_entry(&key).map(|entry| entry.sequence)
+        };
+        if current_version != expected_version {
+            return Ok(false);
+        }
+        self.put(key, value, None)?;
+        Ok(true)
+    }
+
+    /// GetVersioned gets the value corresponding to the key along with its version, to be passed to CompareAndSwap
+    pub fn get_versioned(&self, key: Key) -> Result<(Vec<u8>, u64), Error> {
+        let _read_lock = self.lock.read().unwrap();
+        let entry = self.live_entry(&key).ok_or(Error::EntryNotFound)?;
+        let stored_entry = self.segments.read(entry.file_id, entry.offset, entry.entry_length)?;
+        Ok((stored_entry.value, entry.sequence))
     }
 
     /// Update is very much similar to Put. It appends the key and the value to the log and performs an in-place update in the KeyDirectory
     pub fn update(&mut self, key: Key, value: Vec<u8>) -> Result<(), Error> {
-        self.put(key, value)
+        self.put(key, value, None)
+    }
+
+    /// UpdateWithTtl is Update for a value that expires `ttl_seconds` from now. Get treats it as missing afterwards and merges drop it
+    pub fn update_with_ttl(&mut self, key: Key, value: Vec<u8>, ttl_seconds: u64) -> Result<(), Error> {
+        let expires_at = self.clock.now().saturating_add(ttl_seconds).min(u32::MAX as u64) as u32;
+        self.put(key, value, Some(expires_at))
     }
 
     /// Delete appends the key and the value to the log and performs an in-place delete in the KeyDirectory
     pub fn delete(& mut self, key: Key) -> Result<(), Error> {
         let _write_lock = self.lock.write().unwrap();
-        self.segments.append_deleted(key.clone())?;
-        self.key_directory.delete(&key);
-        Ok(())
+        self.sequence += 1;
+        let append_entry_response = self.segments.append_deleted(key.clone(), self.sequence)?;
+        self.key_directory.tombstone(&key, KeyDirectoryEntry::from(append_entry_response));
+        self.segments.sync_active_segment()
     }
 
     /// Get gets the value corresponding to the key. Returns value and nil if the value is found, else returns nil and error
@@ -75,22 +160,54 @@ impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
     /// If an Entry corresponding to the key is found, a Read operation is performed in the Segments abstraction, which performs an in-memory lookup to identify the segment based on the fileId, and then a Read operation is performed in that Segment
     pub fn get(&self, key: Key) -> Result<Vec<u8>, Error> {
         let _read_lock = self.lock.read().unwrap();
-        if let Some(entry) = self.key_directory.get(&key) {
+        if let Some(entry) = self.live_entry(&key) {
             let stored_entry = self.segments.read(entry.file_id, entry.offset, entry.entry_length)?;
             return Ok(stored_entry.value);
         }
         Err(Error::EntryNotFound)
     }
 
-    /// WriteBack writes back the changes (merged changes) to new inactive segments. This operation is performed during merge.
-    /// It writes all the changes into M new inactive segments and once those changes are written to the new inactive segment(s), the state of the keys present in the `changes` parameter is updated in the KeyDirectory. More on this is mentioned in Worker.go inside merge/ package.
-    /// Once the state is updated in the KeyDirectory, the old segments identified by `fileIds` are removed from disk.
-    fn write_back(&mut self, file_ids: Vec<u64>, changes: HashMap<Key, MappedStoredEntry<Key>>) -> Result<(), Error> {
-        let _write_lock = self.lock.write().unwrap();
-        let write_back_responses = self.segments.write_back(changes)?;
-        self.key_directory.bulk_update(write_back_responses);
-        self.segments.remove(file_ids.as_slice());
-        Ok(())
+    /// Exists tells if the key has a value, without reading it from its segment
+    pub fn exists(&self, key: &Key) -> bool {
+        let _read_lock = self.lock.read().unwrap();
+        self.live_entry(key).is_some()
+    }
+
+    // live_entry returns the Entry of the key in the KeyDirectory, unless its value expired
+    fn live_entry(&self, key: &Key) -> Option<&KeyDirectoryEntry> {
+        self.key_directory.get(key).filter(|entry| !entry.is_expired(self.clock.now()))
+    }
+
+    /// Count returns the number of keys whose serialized form starts with `prefix`, an empty prefix counts all the keys
+    pub fn count(&self, prefix: &[u8]) -> u64 {
+        let _read_lock = self.lock.read().unwrap();
+        self.key_directory.keys_with_prefix(prefix, None, self.clock.now()).count() as u64
+    }
+
+    /// ListKeys returns up to `limit` keys starting with `prefix` in byte order, starting after the key serialized as `cursor`.
+    /// The last key of a page is the cursor of the next one, the returned flag tells if there are more keys after the page.
+    /// A `limit` of zero fails with InvalidConfig, since an empty page has no last key to continue from.
+    pub fn list_keys(&self, prefix: &[u8], cursor: Option<&[u8]>, limit: usize) -> Result<(Vec<Key>, bool), Error> {
+        if limit == 0 {
+            return Err(Error::InvalidConfig);
+        }
+        let _read_lock = self.lock.read().unwrap();
+        let mut keys: Vec<Key> = self.key_directory.keys_with_prefix(prefix, cursor, self.clock.now()).take(limit + 1).cloned().collect();
+        let more = keys.len() > limit;
+        keys.truncate(limit);
+        Ok((keys, more))
+    }
+
+    /// Scan returns the keys starting with `prefix` in byte order together with their values
+    pub fn scan(&self, prefix: &[u8]) -> Result<Vec<(Key, Vec<u8>)>, Error> {
+        let _read_lock = self.lock.read().unwrap();
+        self.key_directory.keys_with_prefix(prefix, None, self.clock.now())
+            .map(|key| {
+                let entry = self.key_directory.get(key).ok_or(Error::EntryNotFound)?;
+                let stored_entry = self.segments.read(entry.file_id, entry.offset, entry.entry_length)?;
+                Ok((key.clone(), stored_entry.value))
+            })
+            .collect()
     }
 
     // ClearLog removes all the log files
@@ -100,28 +217,124 @@ impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
         self.segments.remove_all_inactive();
     }
 
-    // Sync performs a sync of all the active and inactive segments. 
-    fn sync(&self) {
+    /// Flush syncs the writes the sync policy has not synced yet, so that they survive a power loss
+    pub fn flush(&mut self) -> Result<(), Error> {
         let _write_lock = self.lock.write().unwrap();
-        self.segments.sync();
+        self.segments.flush()
+    }
+
+    /// RecoveryReport returns what the reload during start-up found wrong with the segments
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
     }
 
-    // reload the entire state during start-up.
+    // reload the entire state during start-up. Segments are replayed oldest first in the order of the manifest, so the last write of a key wins.
+    // Writes go on from the highest sequence number found in the segments, or recorded in the manifest by a merge that dropped the record holding it.
+    // The KeyDirectory is loaded from the hint file of a segment when it has a valid one. Otherwise the segment is read in full and its hint is written for the next start-up.
+    // Damaged records are skipped and noted in the recovery report. The newest segment is the one that was active when the process stopped,
+    // a torn tail record is cut off from it and noted as truncated. An older segment was complete once, so it is left untouched and its tail is noted as damaged instead.
     fn reload(&mut self) -> Result<(), Error> {
         let _write_lock = self.lock.write().unwrap();
-        for (file_id, segment) in self.segments.all_inactive_segments() {
-            let entries = segment.read_full(self.merge_config.key_mapper())?;
-            self.key_directory.reload(*file_id, entries);
+        self.recovery_report.manifest_damaged = self.segments.manifest_damaged();
+        self.sequence = self.segments.last_sequence();
+        let mut segments = self.segments.inactive_segments_mut();
+        let segment_count = segments.len();
+        for (position, segment) in segments.iter_mut().enumerate() {
+            let file_id = segment.file_id;
+            if let Some(entries) = segment.read_hint(self.merge_config.key_mapper())? {
+                self.sequence = entries.iter().map(|entry| entry.sequence).fold(self.sequence, u64::max);
+                self.key_directory.reload(file_id, entries);
+                continue;
+            }
+
+            let newest = position + 1 == segment_count;
+            let (decoded, tail) = segment.recover(self.merge_config.key_mapper(), newest)?;
+            self.recovery_report.corrupted.extend(decoded.corrupted.iter().map(|offset| (file_id, *offset)));
+            if tail > 0 && !newest {
+                self.recovery_report.corrupted.push((file_id, decoded.valid_length));
+            }
+            if tail > 0 && newest {
+                self.recovery_report.truncated.push((file_id, tail));
+            }
+            self.sequence = decoded.entries.iter().map(|entry| entry.sequence).fold(self.sequence, u64::max);
+            self.key_directory.reload(file_id, decoded.entries);
+            // the hint of a segment whose tail is left in place would never match it
+            if tail == 0 || newest {
+                let _ = segment.write_hint();
+            }
+        }
+        self.key_directory.purge_expired(self.clock.now());
+        Ok(())
+    }
+
+    /// Compact merges the inactive segments with the most dead space, reading up to about `budget` bytes of segments: the last segment taken may go over it.
+    /// Only segments whose share of dead bytes reaches COMPACTION_GARBAGE_RATIO are worth it. It lets the host run merges at a time of its choosing,
+    /// in steps as small as it likes, rather than during a put.
+    pub fn compact(&mut self, budget: u64) -> Result<CompactionReport, Error> {
+        let candidates = self.merge_candidates(COMPACTION_GARBAGE_RATIO);
+        let mut file_ids = Vec::new();
+        let mut read_bytes = 0;
+        for (file_id, size) in candidates.iter() {
+            if read_bytes >= budget {
+                break;
+            }
+            file_ids.push(*file_id);
+            read_bytes += size;
+        }
+        let mut report = self.merge(&file_ids)?;
+        report.pending = candidates.len() > file_ids.len();
+        Ok(report)
+    }
+
+    // maybeMerge performs the merge operation once the counter of writes since the last merge is reached.
+    // When all the segments are to be read, every segment holding dead space is merged, else the `totalSegmentsToRead` segments with the most dead space.
+    // A merge that fails is left for the next one, the write that triggered it goes ahead.
+    fn maybe_merge(&mut self) -> Result<(), Error> {
+        if self.counter >= self.merge_config.run_merge_every() {
+            let file_ids: Vec<u64> = if self.merge_config.should_read_all_segments() {
+                self.merge_candidates(0.0).into_iter().map(|(file_id, _)| file_id).collect()
+            } else {
+                self.merge_candidates(COMPACTION_GARBAGE_RATIO).into_iter()
+                    .take(self.merge_config.total_segments_to_read())
+                    .map(|(file_id, _)| file_id)
+                    .collect()
+            };
+            let _ = self.merge(&file_ids);
+            self.counter = 0
         }
         Ok(())
     }
 
-    /// beginMerge performs the merge operation.
-    /// As a part of merge process, either all the inactive segments files are read or any of the K inactive segment files are read in memory.
-    /// Once those files are loaded in memory, an instance of MergedState is created that maintains a HashMap of Key and MappedStoredEntry.
-    /// MergedState is responsible for performing the merge operation. Merge operation is all about picking the latest value of a key
-    /// if it is present in 2 or more segment files.
-    /// Once the merge operation is done, the changes are written back to new inactive files and the in-memory state is updated in KeyDirectory.
+    // mergeCandidates returns the id and the number of bytes of the inactive segments whose share of dead bytes is above zero and at least `min_garbage_ratio`, most dead space first.
+    // Dead bytes are those that hold neither the current value of a key nor a tombstone. The tombstones of the oldest segment count as dead bytes too,
+    // since there is no older segment left for them to hide values in. A segment sealed with a previous key counts as all dead, it has to be rewritten to rotate the key.
+    // Keys whose value expired are purged from the KeyDirectory first, which turns their values into dead space and keeps the merge from writing them back.
+    fn merge_candidates(&mut self, min_garbage_ratio: f64) -> Vec<(u64, u64)> {
+        self.key_directory.purge_expired(self.clock.now());
+        let mut candidates: Vec<(u64, u64, f64)> = self.segments.inactive_segment_sizes().into_iter()
+            .enumerate()
+            .map(|(position, (file_id, size))| {
+                let mut kept_bytes = self.key_directory.live_bytes(file_id);
+                if position > 0 {
+                    kept_bytes += self.key_directory.tombstone_bytes(file_id);
+                }
+                // a segment without entries only takes up a file
+                let garbage_ratio = if size == 0 || self.segments.needs_rekey(file_id) {
+                    1.0
+                } else {
+                    size.saturating_sub(kept_bytes) as f64 / size as f64
+                };
+                (file_id, size, garbage_ratio)
+            })
+            .filter(|(_, _, garbage_ratio)| *garbage_ratio > 0.0 && *garbage_ratio >= min_garbage_ratio)
+            .collect();
+        candidates.sort_by(|first, second| second.2.total_cmp(&first.2));
+        candidates.into_iter().map(|(file_id, size, _)| (file_id, size)).collect()
+    }
+
+    /// merge performs the merge operation of the inactive segments identified by `file_ids`.
+    /// Merge operation is all about keeping only the latest value of a key, which is the one the KeyDirectory points to, and dropping the rest of the segments.
+    /// The segments are streamed one at a time into new inactive segments, and once the changes are written back the in-memory state is updated in KeyDirectory.
     ///
     /// Why do we need to update the in-memory state?
     /// Assume a Key K1 with Value V1 and Timestamp T1 is present in the segment file F1. This key gets updated with value V2 at a later timestamp T2
@@ -138,8 +351,8 @@ impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
     ///	└───────────┴──────────┴────────────┴─────┴───────┘
     /// ```
     /// KeyDirectory contains K1 pointing to the offset of K1 in the segment file F2.
-    /// With this background, let's consider that the merge process starts, and it reads the contents of F1 and F2 and performs a merge.
-    /// The merge writes the key K1 with its new value V2 and timestamp T2 in a new file F3, and deletes files F1 and F2.
+    /// With this background, let's consider that the merge process starts, and it reads the contents of F1 and F2.
+    /// The entry of K1 in F1 is dropped since the KeyDirectory points to F2, the merge writes the key K1 with its value V2 and timestamp T2 in a new file F3, and deletes files F1 and F2.
     ///
     ///	 Segment file F3 ```
     ///	┌───────────┬──────────┬────────────┬─────┬───────┐
@@ -147,28 +360,572 @@ impl<Key: BitCaskKey, S: Store > KVStore<Key, S> {
     ///	└───────────┴──────────┴────────────┴─────┴───────┘
     /// ```
     /// The moment merge process is done, the state of Key K1 needs to be updated in the KeyDirectory to point to the new offset in the new file.
-    fn begin_merge(&mut self) -> Result<(), Error> {
-        let (file_ids, segments)  = 
-            if self.merge_config.should_read_all_segments() {
-                self.segments.read_all_inactive_segments(self.merge_config.key_mapper())?
-            } else {
-                self.segments.read_inactive_segments(self.merge_config.total_segments_to_read(), self.merge_config.key_mapper())?
-            };
+    fn merge(&mut self, file_ids: &[u64]) -> Result<CompactionReport, Error> {
+        if file_ids.is_empty() {
+            return Ok(CompactionReport::default());
+        }
+        let _write_lock = self.lock.write().unwrap();
+        let read_bytes: u64 = self.segments.inactive_segment_sizes().into_iter()
+            .filter(|(file_id, _)| file_ids.contains(file_id))
+            .map(|(_, size)| size)
+            .sum();
 
-        if segments.len() >= 2 {
-            let mut merged_state = MergedState::new();
-            merged_state.take_all(segments[0].to_owned());
+        let key_directory = &self.key_directory;
+        let write_back_responses = self.segments.merge(file_ids, self.merge_config.key_mapper(), self.sequence, |key| {
+            key_directory.get(key).map(|entry| (entry.file_id, entry.offset))
+        })?;
+        let written_bytes: u64 = write_back_responses.iter().map(|response| response.append_entry_response.entry_length as u64).sum();
+        self.key_directory.bulk_update(write_back_responses, file_ids);
 
-            for segment in &segments[1..] {
-                merged_state.merge_with(segment.to_owned());
-            }
+        Ok(CompactionReport {
+            merged_segments: file_ids.len() as u32,
+            reclaimed_bytes: read_bytes.saturating_sub(written_bytes),
+            pending: false,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_cask_key::{Serializable, UUIDWasiKey, UUIDWasiKeyFrom};
+    use crate::cipher::{Keyring, ENCRYPTION_KEY_SIZE};
+    use crate::clock::FakeClock;
+    use crate::config::SyncPolicy;
+    use crate::entry::{BatchOp, Entry, RecordFormat};
+    use crate::manifest::MANIFEST_FILE_NAME;
+    use crate::segment::{hint_name, segment_name, Segment};
+    use crate::store::{Directory, MemoryStore, Store};
+
+    fn open(directory: &Directory, max_segment_size: u64) -> KVStore<UUIDWasiKey, MemoryStore> {
+        let config = Config::new(directory.clone(), max_segment_size, 16, Some(MergeConfig::new_manual(UUIDWasiKeyFrom)), Arc::new(FakeClock::new(1_700_000_000)));
+        KVStore::new(&config).unwrap()
+    }
+
+    fn key(key: &str) -> UUIDWasiKey {
+        UUIDWasiKey::from(key.to_string())
+    }
+
+    #[test]
+    fn torn_tail_of_active_segment() {
+        let directory = Directory::new(None, "torn".into());
+        let mut store = open(&directory, 1 << 20);
+        store.update(key("a"), vec![1; 10]).unwrap();
+        store.update(key("b"), vec![2; 10]).unwrap();
+        drop(store);
+        let segment = MemoryStore::file(&directory, &segment_name(1));
+        let length = segment.borrow().len();
+        segment.borrow_mut().truncate(length - 4);
 
-            // Ignoring the result for `write_back`, as in the Go code
-            let _ = self.write_back(file_ids, merged_state.value_by_key.clone());
+        let mut store = open(&directory, 1 << 20);
+        assert_eq!(store.get(key("a")).unwrap(), vec![1; 10]);
+        assert!(matches!(store.get(key("b")), Err(Error::EntryNotFound)));
+        let (file_id, truncated) = store.recovery_report().truncated[0];
+        assert_eq!(file_id, 1);
+        assert_eq!(segment.borrow().len(), length - 4 - truncated as usize);
+        assert!(store.recovery_report().corrupted.is_empty());
+
+        store.update(key("b"), vec![3; 10]).unwrap();
+        drop(store);
+        let store = open(&directory, 1 << 20);
+        assert_eq!(store.get(key("b")).unwrap(), vec![3; 10]);
+        assert!(store.recovery_report().truncated.is_empty());
+    }
+
+    #[test]
+    fn torn_tail_of_older_segment() {
+        let directory = Directory::new(None, "older".into());
+        // every record rolls the active segment over
+        let mut store = open(&directory, 40);
+        for name in ["a", "b", "c"] {
+            store.update(key(name), vec![1; 20]).unwrap();
         }
+        drop(store);
+        let segment = MemoryStore::file(&directory, &segment_name(1));
+        let length = segment.borrow().len();
+        segment.borrow_mut().truncate(length - 4);
 
-        self.sync();
+        let store = open(&directory, 40);
+        assert!(matches!(store.get(key("a")), Err(Error::EntryNotFound)));
+        assert_eq!(store.get(key("c")).unwrap(), vec![1; 20]);
+        assert!(store.recovery_report().truncated.is_empty());
+        assert_eq!(store.recovery_report().corrupted, [(1, RecordFormat::Checksummed.header_size())]);
+        assert_eq!(segment.borrow().len(), length - 4);
+    }
 
-        Ok(())
+    #[test]
+    fn damaged_record() {
+        let directory = Directory::new(None, "damaged".into());
+        let mut store = open(&directory, 1 << 20);
+        for (name, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            store.update(key(name), vec![value; 10]).unwrap();
+        }
+        let offset = store.key_directory.get(&key("b")).unwrap().offset as usize;
+        drop(store);
+        MemoryStore::file(&directory, &segment_name(1)).borrow_mut()[offset + 20] ^= 0xff;
+
+        let store = open(&directory, 1 << 20);
+        assert_eq!(store.get(key("a")).unwrap(), vec![1; 10]);
+        assert!(matches!(store.get(key("b")), Err(Error::EntryNotFound)));
+        assert_eq!(store.get(key("c")).unwrap(), vec![3; 10]);
+        assert_eq!(store.recovery_report().corrupted, [(1, offset as u32)]);
+        assert!(store.recovery_report().truncated.is_empty());
+    }
+
+    #[test]
+    fn unlisted_segment() {
+        let directory = Directory::new(None, "unlisted".into());
+        let mut store = open(&directory, 1 << 20);
+        store.update(key("a"), vec![1; 10]).unwrap();
+        drop(store);
+        // what a merge that did not commit leaves behind
+        let mut segment = Segment::<MemoryStore>::new(9, &directory, None).unwrap();
+        segment.append(&Entry::new(key("a"), vec![2; 10], Arc::new(FakeClock::new(1_700_000_000)))).unwrap();
+
+        let store = open(&directory, 1 << 20);
+        assert_eq!(store.get(key("a")).unwrap(), vec![1; 10]);
+        assert!(!MemoryStore::get_files(&directory).unwrap().contains(&segment_name(9)));
+        assert!(!store.recovery_report().manifest_damaged);
+    }
+
+    #[test]
+    fn damaged_manifest() {
+        let directory = Directory::new(None, "manifest".into());
+        let mut store = open(&directory, 40);
+        for name in ["a", "b", "c"] {
+            store.update(key(name), vec![1; 20]).unwrap();
+        }
+        drop(store);
+        // the id in the second edit, which added the segment of b
+        MemoryStore::file(&directory, MANIFEST_FILE_NAME).borrow_mut()[52] ^= 0xff;
+
+        let store = open(&directory, 40);
+        assert!(store.recovery_report().manifest_damaged);
+        for name in ["a", "b", "c"] {
+            assert_eq!(store.get(key(name)).unwrap(), vec![1; 20]);
+        }
+        drop(store);
+        let store = open(&directory, 40);
+        assert!(!store.recovery_report().manifest_damaged);
+        assert_eq!(store.get(key("c")).unwrap(), vec![1; 20]);
+    }
+
+    #[test]
+    fn hints() {
+        let directory = Directory::new(None, "hints".into());
+        let mut store = open(&directory, 40);
+        for (name, value) in [("a", 1), ("b", 2), ("a", 3)] {
+            store.update(key(name), vec![value; 20]).unwrap();
+        }
+        store.delete(key("b")).unwrap();
+        drop(store);
+        // segments that rolled over have a hint, the one that was active gets its hint on the next start-up
+        assert!(MemoryStore::get_files(&directory).unwrap().contains(&hint_name(1)));
+        assert!(!MemoryStore::get_files(&directory).unwrap().contains(&hint_name(4)));
+        drop(open(&directory, 40));
+        assert!(MemoryStore::get_files(&directory).unwrap().contains(&hint_name(4)));
+
+        // the hints alone rebuild the KeyDirectory, the values are only read on get
+        MemoryStore::file(&directory, &segment_name(1)).borrow_mut()[20] ^= 0xff;
+        let store = open(&directory, 40);
+        assert_eq!(store.get(key("a")).unwrap(), vec![3; 20]);
+        assert!(matches!(store.get(key("b")), Err(Error::EntryNotFound)));
+        assert!(store.recovery_report().corrupted.is_empty());
+    }
+
+    #[test]
+    fn hint_mismatch() {
+        let directory = Directory::new(None, "mismatch".into());
+        let mut store = open(&directory, 40);
+        for (name, value) in [("a", 1), ("b", 2), ("c", 1)] {
+            store.update(key(name), vec![value; 20]).unwrap();
+        }
+        drop(store);
+        // a damaged hint and the hint of a segment that changed after it was written are both ignored
+        MemoryStore::file(&directory, &hint_name(1)).borrow_mut()[10] ^= 0xff;
+        let mut segment = MemoryStore::open(&segment_name(2), &directory).unwrap();
+        segment.append(&Entry::new(key("b"), vec![3; 20], Arc::new(FakeClock::new(1_700_000_000))).encode()).unwrap();
+
+        let store = open(&directory, 40);
+        assert_eq!(store.get(key("a")).unwrap(), vec![1; 20]);
+        assert_eq!(store.get(key("b")).unwrap(), vec![3; 20]);
+        drop(store);
+        let store = open(&directory, 40);
+        assert_eq!(store.get(key("b")).unwrap(), vec![3; 20]);
+    }
+
+    #[test]
+    fn keys() {
+        let directory = Directory::new(None, "keys".into());
+        let mut store = open(&directory, 64);
+        for index in 0..25u8 {
+            store.update(key(&format!("utxo_{:02}", index)), vec![index]).unwrap();
+        }
+        store.update(key("header_1"), vec![1]).unwrap();
+        store.delete(key("utxo_05")).unwrap();
+        drop(store);
+
+        let store = open(&directory, 64);
+        assert_eq!(store.count(b"utxo_"), 24);
+        assert_eq!(store.count(b""), 25);
+        assert!(store.exists(&key("header_1")));
+        assert!(!store.exists(&key("utxo_05")));
+
+        let (page, more) = store.list_keys(b"utxo_", None, 10).unwrap();
+        assert!(more);
+        assert_eq!(page.first(), Some(&key("utxo_00")));
+        assert!(!page.contains(&key("utxo_05")));
+        let (page, more) = store.list_keys(b"utxo_", Some(&page[9].serialize()), 10).unwrap();
+        assert!(more);
+        assert_eq!(page.first(), Some(&key("utxo_11")));
+        let (page, more) = store.list_keys(b"utxo_", Some(&page[9].serialize()), 10).unwrap();
+        assert!(!more);
+        assert_eq!(page, [key("utxo_21"), key("utxo_22"), key("utxo_23"), key("utxo_24")]);
+        assert!(matches!(store.list_keys(b"utxo_", None, 0), Err(Error::InvalidConfig)));
+
+        let scanned = store.scan(b"utxo_1").unwrap();
+        assert_eq!(scanned.len(), 10);
+        assert_eq!(scanned[3], (key("utxo_13"), vec![13]));
+    }
+
+    #[test]
+    fn batches() {
+        let directory = Directory::new(None, "batches".into());
+        let mut store = open(&directory, 1 << 20);
+        store.update(key("a"), vec![1]).unwrap();
+        store.write_batch(vec![BatchOp::Put(key("b"), vec![2]), BatchOp::Delete(key("a"))]).unwrap();
+        assert!(matches!(store.get(key("a")), Err(Error::EntryNotFound)));
+        store.write_batch(vec![BatchOp::Put(key("c"), vec![3]), BatchOp::Put(key("b"), vec![4])]).unwrap();
+        drop(store);
+        // cut the commit marker of the last batch
+        let segment = MemoryStore::file(&directory, &segment_name(1));
+        let length = segment.borrow().len();
+        segment.borrow_mut().truncate(length - 2);
+
+        let store = open(&directory, 1 << 20);
+        assert!(matches!(store.get(key("a")), Err(Error::EntryNotFound)));
+        assert_eq!(store.get(key("b")).unwrap(), vec![2]);
+        assert!(matches!(store.get(key("c")), Err(Error::EntryNotFound)));
+        let (_, truncated) = store.recovery_report().truncated[0];
+        assert!(truncated as usize > length - segment.borrow().len() - 2 - 1);
+        assert_eq!(segment.borrow().len(), length - 2 - truncated as usize);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let directory = Directory::new(None, "cas".into());
+        let mut store = open(&directory, 1 << 20);
+        assert!(store.compare_and_swap(key("a"), None, b"A".to_vec()).unwrap());
+        assert!(!store.compare_and_swap(key("a"), None, b"B".to_vec()).unwrap());
+        let (value, version) = store.get_versioned(key("a")).unwrap();
+        assert_eq!(value, b"A");
+        assert!(store.compare_and_swap(key("a"), Some(version), b"B".to_vec()).unwrap());
+        assert!(!store.compare_and_swap(key("a"), Some(version), b"C".to_vec()).unwrap());
+
+        // a key set back to an earlier value still has a new version
+        let (_, b_version) = store.get_versioned(key("a")).unwrap();
+        store.update(key("a"), b"A".to_vec()).unwrap();
+        let (value, a_version) = store.get_versioned(key("a")).unwrap();
+        assert_eq!(value, b"A");
+        assert!(a_version > b_version && b_version > version);
+        assert!(!store.compare_and_swap(key("a"), Some(version), b"C".to_vec()).unwrap());
+
+        store.delete(key("a")).unwrap();
+        assert!(!store.compare_and_swap(key("a"), Some(a_version), b"C".to_vec()).unwrap());
+        assert!(store.compare_and_swap(key("a"), None, b"D".to_vec()).unwrap());
+        let (_, d_version) = store.get_versioned(key("a")).unwrap();
+        drop(store);
+
+        let mut store = open(&directory, 1 << 20);
+        assert_eq!(store.get_versioned(key("a")).unwrap(), (b"D".to_vec(), d_version));
+        store.update(key("b"), vec![1]).unwrap();
+        assert!(store.get_versioned(key("b")).unwrap().1 > d_version);
+    }
+
+    #[test]
+    fn versions_after_merge() {
+        let directory = Directory::new(None, "versions".into());
+        let mut store = open(&directory, 40);
+        for name in ["a", "b", "c"] {
+            store.update(key(name), vec![1; 20]).unwrap();
+        }
+        let (_, b_version) = store.get_versioned(key("b")).unwrap();
+        store.delete(key("c")).unwrap();
+        drop(store);
+
+        // the merge drops the tombstone of c, which holds the last sequence number of the store
+        let mut store = open(&directory, 40);
+        while store.compact(u64::MAX).unwrap().pending {}
+        let (_, version) = store.get_versioned(key("b")).unwrap();
+        assert_eq!(version, b_version);
+        drop(store);
+
+        let mut store = open(&directory, 40);
+        store.update(key("c"), vec![2; 20]).unwrap();
+        assert!(store.get_versioned(key("c")).unwrap().1 > b_version + 2);
+    }
+
+    fn open_encrypted(directory: &Directory, key: u8, previous_keys: &[u8]) -> Result<KVStore<UUIDWasiKey, MemoryStore>, Error> {
+        let previous_keys: Vec<Vec<u8>> = previous_keys.iter().map(|key| vec![*key; ENCRYPTION_KEY_SIZE]).collect();
+        let config = Config::new(directory.clone(), 100, 16, Some(MergeConfig::new_manual(UUIDWasiKeyFrom)), Arc::new(FakeClock::new(1_700_000_000)))
+            .with_keyring(Keyring::new(&[key; ENCRYPTION_KEY_SIZE], &previous_keys)?);
+        KVStore::new(&config)
+    }
+
+    fn contains(content: &[u8], part: &[u8]) -> bool {
+        content.windows(part.len()).any(|window| window == part)
+    }
+
+    #[test]
+    fn encryption() {
+        let directory = Directory::new(None, "encrypted".into());
+        let mut store = open_encrypted(&directory, 1, &[]).unwrap();
+        for name in ["alpha", "bravo", "charlie"] {
+            store.update(key(name), format!("secret-{name}").into_bytes()).unwrap();
+        }
+        drop(store);
+        for file_id in 1..=3 {
+            let segment = MemoryStore::file(&directory, &segment_name(file_id));
+            let content = segment.borrow();
+            assert!(!content.is_empty());
+            assert!(!contains(&content, b"secret") && !contains(&content, b"bravo"));
+        }
+
+        assert!(matches!(open_encrypted(&directory, 2, &[]), Err(Error::InvalidConfig)));
+        let store = open_encrypted(&directory, 1, &[]).unwrap();
+        assert_eq!(store.get(key("bravo")).unwrap(), b"secret-bravo");
+        let (keys, _) = store.list_keys(b"", None, 10).unwrap();
+        assert_eq!(keys.iter().map(|key| String::from(key.clone())).collect::<Vec<_>>(), ["alpha", "bravo", "charlie"]);
+    }
+
+    #[test]
+    fn key_rotation() {
+        let directory = Directory::new(None, "rotation".into());
+        let mut store = open_encrypted(&directory, 1, &[]).unwrap();
+        for name in ["alpha", "bravo", "charlie"] {
+            store.update(key(name), format!("secret-{name}").into_bytes()).unwrap();
+        }
+        drop(store);
+
+        // segments sealed with the previous key count as all dead, so that compaction rewrites every one of them
+        let mut store = open_encrypted(&directory, 2, &[1]).unwrap();
+        assert_eq!(store.merge_candidates(1.0).len(), store.segments.inactive_segment_sizes().len());
+        while store.compact(u64::MAX).unwrap().pending {}
+        assert!(store.merge_candidates(1.0).is_empty());
+        drop(store);
+
+        let store = open_encrypted(&directory, 2, &[]).unwrap();
+        for name in ["alpha", "bravo", "charlie"] {
+            assert_eq!(store.get(key(name)).unwrap(), format!("secret-{name}").into_bytes());
+        }
+    }
+
+    fn open_with_sync_policy(directory: &Directory, max_segment_size: u64, sync_policy: SyncPolicy, clock: Arc<FakeClock>) -> KVStore<UUIDWasiKey, MemoryStore> {
+        let config = Config::new(directory.clone(), max_segment_size, 16, Some(MergeConfig::new_manual(UUIDWasiKeyFrom)), clock)
+            .with_sync_policy(sync_policy);
+        KVStore::new(&config).unwrap()
+    }
+
+    // synced_writes returns how many of the writes were followed by a sync
+    fn synced_writes(store: &mut KVStore<UUIDWasiKey, MemoryStore>, writes: usize, clock: &FakeClock, seconds_between: u64) -> Vec<bool> {
+        (0..writes).map(|write| {
+            clock.advance(seconds_between);
+            let syncs = MemoryStore::syncs();
+            store.update(key(&write.to_string()), vec![1]).unwrap();
+            MemoryStore::syncs() > syncs
+        }).collect()
+    }
+
+    #[test]
+    fn sync_policies() {
+        let clock = Arc::new(FakeClock::new(1_700_000_000));
+        let mut store = open_with_sync_policy(&Directory::new(None, "os-default".into()), 1 << 20, SyncPolicy::OsDefault, clock.clone());
+        assert_eq!(synced_writes(&mut store, 3, &clock, 0), [false, false, false]);
+        let syncs = MemoryStore::syncs();
+        store.flush().unwrap();
+        assert_eq!(MemoryStore::syncs(), syncs + 1);
+
+        let mut store = open_with_sync_policy(&Directory::new(None, "always".into()), 1 << 20, SyncPolicy::Always, clock.clone());
+        assert_eq!(synced_writes(&mut store, 3, &clock, 0), [true, true, true]);
+
+        let mut store = open_with_sync_policy(&Directory::new(None, "every-writes".into()), 1 << 20, SyncPolicy::EveryWrites(2), clock.clone());
+        assert_eq!(synced_writes(&mut store, 4, &clock, 0), [false, true, false, true]);
+        // a batch counts as one write
+        let syncs = MemoryStore::syncs();
+        store.write_batch(vec![BatchOp::Put(key("a"), vec![1]), BatchOp::Put(key("b"), vec![1])]).unwrap();
+        assert_eq!(MemoryStore::syncs(), syncs);
+        store.delete(key("a")).unwrap();
+        assert_eq!(MemoryStore::syncs(), syncs + 1);
+
+        let mut store = open_with_sync_policy(&Directory::new(None, "every-millis".into()), 1 << 20, SyncPolicy::EveryMillis(1500), clock.clone());
+        assert_eq!(synced_writes(&mut store, 4, &clock, 1), [false, true, false, true]);
+
+        // segments that roll over are synced whatever the policy
+        let mut store = open_with_sync_policy(&Directory::new(None, "rollover".into()), 40, SyncPolicy::OsDefault, clock.clone());
+        assert_eq!(synced_writes(&mut store, 1, &clock, 0), [false]);
+        store.update(key("a"), vec![1; 40]).unwrap();
+        let syncs = MemoryStore::syncs();
+        store.update(key("b"), vec![1]).unwrap();
+        assert!(MemoryStore::syncs() > syncs);
+    }
+
+    #[test]
+    fn sync_errors() {
+        let clock = Arc::new(FakeClock::new(1_700_000_000));
+        let directory = Directory::new(None, "sync-errors".into());
+        // two writes fit in a segment
+        let mut store = open_with_sync_policy(&directory, 90, SyncPolicy::Always, clock);
+        store.update(key("a"), vec![1; 20]).unwrap();
+
+        // the write is applied, the error tells that it may not be on disk
+        MemoryStore::fail_syncs(true);
+        assert!(matches!(store.update(key("b"), vec![2; 20]), Err(Error::OpenFileError)));
+        assert_eq!(store.get(key("b")).unwrap(), vec![2; 20]);
+        assert!(matches!(store.flush(), Err(Error::OpenFileError)));
+
+        // the active segment is full, it does not roll over without being synced
+        let segment_count = store.segments.inactive_segment_sizes().len();
+        assert!(matches!(store.update(key("c"), vec![3; 20]), Err(Error::OpenFileError)));
+        assert_eq!(store.segments.inactive_segment_sizes().len(), segment_count);
+        assert!(matches!(store.get(key("c")), Err(Error::EntryNotFound)));
+
+        MemoryStore::fail_syncs(false);
+        store.update(key("c"), vec![3; 20]).unwrap();
+        store.delete(key("a")).unwrap();
+        store.update(key("d"), vec![4; 20]).unwrap();
+
+        // a merge whose segments are not synced leaves the merged segments in place
+        MemoryStore::fail_syncs(true);
+        assert!(matches!(store.compact(u64::MAX), Err(Error::OpenFileError)));
+        MemoryStore::fail_syncs(false);
+        assert!(store.compact(u64::MAX).unwrap().merged_segments > 0);
+        store.flush().unwrap();
+        drop(store);
+
+        let store = open(&directory, 90);
+        assert!(matches!(store.get(key("a")), Err(Error::EntryNotFound)));
+        for (name, value) in [("b", 2), ("c", 3), ("d", 4)] {
+            assert_eq!(store.get(key(name)).unwrap(), vec![value; 20]);
+        }
+    }
+
+    #[test]
+    fn merge_candidates() {
+        // two writes fit in a segment
+        let directory = Directory::new(None, "candidates".into());
+        let mut store = open(&directory, 90);
+        for write in 0..9 {
+            store.update(key(&format!("k{write}")), vec![1; 20]).unwrap();
+        }
+        let file_ids: Vec<u64> = store.segments.inactive_segment_sizes().into_iter().map(|(file_id, _)| file_id).collect();
+        assert_eq!(file_ids.len(), 4);
+        assert!(store.merge_candidates(0.0).is_empty());
+
+        // the first segment is all dead space, the second one half of it
+        for name in ["k0", "k1", "k2"] {
+            store.update(key(name), vec![2; 20]).unwrap();
+        }
+        let candidates = |store: &mut KVStore<UUIDWasiKey, MemoryStore>, min_garbage_ratio| -> Vec<u64> {
+            store.merge_candidates(min_garbage_ratio).into_iter().map(|(file_id, _)| file_id).collect()
+        };
+        assert_eq!(candidates(&mut store, 0.0), file_ids[..2]);
+        assert_eq!(candidates(&mut store, COMPACTION_GARBAGE_RATIO), file_ids[..2]);
+        assert_eq!(candidates(&mut store, 0.9), file_ids[..1]);
+
+        // a tombstone is dead space in the oldest segment only, where there is no older value left for it to hide
+        store.delete(key("k8")).unwrap();
+        for write in 9..12 {
+            store.update(key(&format!("k{write}")), vec![1; 20]).unwrap();
+        }
+        let tombstone_file_id = store.segments.inactive_segment_sizes().into_iter()
+            .map(|(file_id, _)| file_id)
+            .find(|file_id| store.key_directory.tombstone_bytes(*file_id) > 0)
+            .unwrap();
+        assert_ne!(tombstone_file_id, file_ids[0]);
+        assert!(!candidates(&mut store, 0.0).contains(&tombstone_file_id));
+    }
+
+    #[test]
+    fn compact_budget() {
+        let directory = Directory::new(None, "budget".into());
+        let mut store = open(&directory, 90);
+        for write in 0..7 {
+            store.update(key(&format!("k{write}")), vec![1; 20]).unwrap();
+        }
+        for name in ["k0", "k1", "k2"] {
+            store.update(key(name), vec![2; 20]).unwrap();
+        }
+        let sizes = store.segments.inactive_segment_sizes();
+
+        let report = store.compact(0).unwrap();
+        assert_eq!((report.merged_segments, report.reclaimed_bytes, report.pending), (0, 0, true));
+
+        // the first segment goes over the budget, the second one is left for the next call
+        let report = store.compact(1).unwrap();
+        assert_eq!((report.merged_segments, report.reclaimed_bytes, report.pending), (1, sizes[0].1, true));
+        assert!(!store.segments.inactive_segment_sizes().contains(&sizes[0]));
+
+        let report = store.compact(u64::MAX).unwrap();
+        assert_eq!((report.merged_segments, report.pending), (1, false));
+        assert!(report.reclaimed_bytes > 0 && report.reclaimed_bytes < sizes[1].1);
+        assert!(store.merge_candidates(COMPACTION_GARBAGE_RATIO).is_empty());
+        assert_eq!(store.compact(u64::MAX).unwrap().merged_segments, 0);
+
+        for write in 0..7 {
+            let value = if write < 3 { 2 } else { 1 };
+            assert_eq!(store.get(key(&format!("k{write}"))).unwrap(), vec![value; 20]);
+        }
+    }
+
+    #[test]
+    fn expiry() {
+        let clock = Arc::new(FakeClock::new(1_700_000_000));
+        let mut store = open_with_sync_policy(&Directory::new(None, "expiry".into()), 1 << 20, SyncPolicy::OsDefault, clock.clone());
+        store.update_with_ttl(key("a"), vec![1], 10).unwrap();
+        store.update(key("b"), vec![2]).unwrap();
+        clock.advance(9);
+        assert_eq!(store.get(key("a")).unwrap(), vec![1]);
+        clock.advance(1);
+        assert!(matches!(store.get(key("a")), Err(Error::EntryNotFound)));
+        assert!(!store.exists(&key("a")));
+        assert_eq!(store.count(b""), 1);
+        assert_eq!(store.list_keys(b"", None, 10).unwrap().0.len(), 1);
+        assert!(store.compare_and_swap(key("a"), None, vec![3]).unwrap());
+        assert_eq!(store.get(key("a")).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn expiry_in_merge() {
+        // two writes fill a segment
+        let clock = Arc::new(FakeClock::new(1_700_000_000));
+        let directory = Directory::new(None, "expiry-merge".into());
+        let mut store = open_with_sync_policy(&directory, 90, SyncPolicy::OsDefault, clock.clone());
+        store.update(key("x"), vec![1]).unwrap();
+        store.update(key("a"), vec![1; 40]).unwrap();
+        store.update_with_ttl(key("x"), vec![2; 20], 10).unwrap();
+        store.update(key("b"), vec![1; 20]).unwrap();
+        store.update(key("c"), vec![1]).unwrap();
+        let sizes = store.segments.inactive_segment_sizes();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(store.merge_candidates(0.0), sizes[..1]);
+
+        // the expired value turns into dead space, the segment holding it has the most of it
+        clock.advance(10);
+        assert_eq!(store.merge_candidates(0.0).first(), Some(&sizes[1]));
+        // the first segment has too little dead space to be compacted
+        let report = store.compact(u64::MAX).unwrap();
+        assert_eq!((report.merged_segments, report.pending), (1, false));
+        assert!(matches!(store.get(key("x")), Err(Error::EntryNotFound)));
+        drop(store);
+
+        // the merge left a tombstone behind, which keeps the older value of the first segment hidden
+        let store = open_with_sync_policy(&directory, 90, SyncPolicy::OsDefault, clock);
+        assert!(matches!(store.get(key("x")), Err(Error::EntryNotFound)));
+        assert_eq!(store.get(key("b")).unwrap(), vec![1; 20]);
+        let merged = store.segments.inactive_segment_sizes();
+        assert_eq!(merged[0], sizes[0]);
+        assert!(merged[1].1 < sizes[1].1);
     }
 }
+
@@ -2,29 +2,38 @@
 mod bindings;
 mod clock;
 mod bit_cask_key;
+mod cipher;
 mod config;
 mod merge_config;
-mod field_generator;
+mod manifest;
+mod hint;
 mod entry;
 mod segment;
 mod store;
 mod errors;
 mod segments;
 mod key_directory;
-mod merged_state;
 mod kvstore;
 
 use std::{cell::RefCell, sync::Arc};
 use bit_cask_key::{UUIDWasiKey, UUIDWasiKeyFrom};
+use cipher::Keyring;
 use clock::WasiClock;
-use config::Config;
+use config::{Config, SyncPolicy};
 use kvstore::KVStore as HashKVStore;
-use bindings::exports::component::kv::types::{Error, Guest, GuestKvstore};
+use bindings::exports::component::kv::types::{
+    BatchOp, Compaction, Error, Guest, GuestKvstore, KeyPage, Kvstore, MergePolicy, Recovery, StoreConfig, SyncPolicy as GuestSyncPolicy, VersionedValue,
+};
+use entry::BatchOp as StoreBatchOp;
 use merge_config::MergeConfig;
-use store::WasiStore;
+use store::{Directory, WasiStore};
 
 struct Component;
 
+const DEFAULT_STORE_NAME: &str = "bitcoin-wasm";
+const DEFAULT_SEGMENT_SIZE: u64 = 1048576;
+const DEFAULT_KEY_DIRECTORY_CAPACITY: u64 = 1024;
+
 struct KVStore {
     inner: RefCell<HashKVStore<UUIDWasiKey, WasiStore>>,
 }
@@ -34,6 +43,10 @@ impl GuestKvstore for KVStore {
         return  self.inner.borrow_mut().update(UUIDWasiKey::from(key), value).map_err(|err| err.into());
     }
 
+    fn insert_with_ttl(&self, key: String, value: Vec<u8>, seconds: u64) -> Result<(), Error> {
+        self.inner.borrow_mut().update_with_ttl(UUIDWasiKey::from(key), value, seconds).map_err(|err| err.into())
+    }
+
     fn get(&self, key: String) -> Result<Vec<u8>, Error> {
         return  self.inner.borrow_mut().get(UUIDWasiKey::from(key)).map_err(|err| err.into());
     }
@@ -41,13 +54,129 @@ impl GuestKvstore for KVStore {
     fn delete(&self, key: String) -> Result<(), Error> {
         return self.inner.borrow_mut().delete(UUIDWasiKey::from(key)).map_err(|err| err.into());
     }
+
+    fn exists(&self, key: String) -> bool {
+        self.inner.borrow().exists(&UUIDWasiKey::from(key))
+    }
+
+    fn count(&self, prefix: String) -> u64 {
+        self.inner.borrow().count(prefix.as_bytes())
+    }
+
+    fn list_keys(&self, prefix: String, cursor: Option<String>, limit: u32) -> Result<KeyPage, Error> {
+        let (keys, more) = self.inner.borrow().list_keys(prefix.as_bytes(), cursor.as_ref().map(|cursor| cursor.as_bytes()), limit as usize).map_err(Error::from)?;
+        let keys: Vec<String> = keys.into_iter().map(String::from).collect();
+        let cursor = if more { keys.last().cloned() } else { None };
+        Ok(KeyPage { keys, cursor })
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let entries = self.inner.borrow().scan(prefix.as_bytes()).map_err(Error::from)?;
+        Ok(entries.into_iter().map(|(key, value)| (String::from(key), value)).collect())
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+        let ops = ops.into_iter()
+            .map(|op| match op {
+                BatchOp::Put(pair) => StoreBatchOp::Put(UUIDWasiKey::from(pair.key), pair.value),
+                BatchOp::Delete(key) => StoreBatchOp::Delete(UUIDWasiKey::from(key)),
+            })
+            .collect();
+        self.inner.borrow_mut().write_batch(ops).map_err(|err| err.into())
+    }
+
+    fn get_versioned(&self, key: String) -> Result<VersionedValue, Error> {
+        let (value, version) = self.inner.borrow().get_versioned(UUIDWasiKey::from(key)).map_err(Error::from)?;
+        Ok(VersionedValue { value, version })
+    }
+
+    fn compare_and_swap(&self, key: String, expected_version: Option<u64>, value: Vec<u8>) -> Result<bool, Error> {
+        self.inner.borrow_mut().compare_and_swap(UUIDWasiKey::from(key), expected_version, value).map_err(|err| err.into())
+    }
     
-    fn new() -> Self {
-        let merge_config = MergeConfig::new_with_all_segments_to_read(UUIDWasiKeyFrom);
-        let config  = Config::new("bitcoin-wasm".to_string(), 1048576, 1024, Some(merge_config), Arc::new(WasiClock{}));
-        let hashtree = HashKVStore::new(&config).unwrap();
-        Self{ inner:  RefCell::new(hashtree)}
+    fn compact(&self, budget: u64) -> Result<Compaction, Error> {
+        let report = self.inner.borrow_mut().compact(budget).map_err(Error::from)?;
+        Ok(Compaction {
+            merged_segments: report.merged_segments,
+            reclaimed_bytes: report.reclaimed_bytes,
+            pending: report.pending,
+        })
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.inner.borrow_mut().flush().map_err(|err| err.into())
+    }
+
+    fn recovery_report(&self) -> Recovery {
+        let inner = self.inner.borrow();
+        let report = inner.recovery_report();
+        Recovery {
+            truncated: report.truncated.clone(),
+            corrupted: report.corrupted.clone(),
+            manifest_damaged: report.manifest_damaged,
+        }
+    }
+
+    fn open_default() -> Result<Kvstore, Error> {
+        Self::open(DEFAULT_STORE_NAME.to_string(), None, None)
     }
+
+    fn open(name: String, preopen: Option<String>, config: Option<StoreConfig>) -> Result<Kvstore, Error> {
+        if !is_valid_store_name(&name) {
+            return Err(Error::InvalidConfig);
+        }
+        let directory = Directory::new(preopen, name);
+        let config = match config {
+            Some(store_config) => store_config_to_config(directory, store_config)?,
+            None => {
+                let merge_config = MergeConfig::new_with_all_segments_to_read(UUIDWasiKeyFrom);
+                Config::new(directory, DEFAULT_SEGMENT_SIZE, DEFAULT_KEY_DIRECTORY_CAPACITY, Some(merge_config), Arc::new(WasiClock{}))
+            }
+        };
+        Ok(Kvstore::new(Self::open_with(&config)?))
+    }
+}
+
+impl KVStore {
+    fn open_with(config: &Config<UUIDWasiKey>) -> Result<Self, Error> {
+        let hashtree = HashKVStore::new(config)?;
+        Ok(Self{ inner:  RefCell::new(hashtree)})
+    }
+}
+
+// is_valid_store_name only accepts a single path component, so that a store can not reach the files of another one
+fn is_valid_store_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn store_config_to_config(directory: Directory, store_config: StoreConfig) -> Result<Config<UUIDWasiKey>, Error> {
+    let merge_config = match store_config.merge_policy {
+        MergePolicy::AllSegments(every) if every > 0 => MergeConfig::new_with_all_segments_to_read_every_fixed_duration(every, UUIDWasiKeyFrom),
+        MergePolicy::MostGarbage(partial) if partial.segments > 0 && partial.every > 0 => {
+            MergeConfig::new_with_duration(partial.segments as usize, partial.every, UUIDWasiKeyFrom)
+        }
+        MergePolicy::Manual => MergeConfig::new_manual(UUIDWasiKeyFrom),
+        _ => return Err(Error::InvalidConfig),
+    };
+    if store_config.segment_size == 0 {
+        return Err(Error::InvalidConfig);
+    }
+    let sync_policy = match store_config.sync_policy {
+        GuestSyncPolicy::OsDefault => SyncPolicy::OsDefault,
+        GuestSyncPolicy::Always => SyncPolicy::Always,
+        GuestSyncPolicy::EveryWrites(writes) if writes > 0 => SyncPolicy::EveryWrites(writes),
+        GuestSyncPolicy::EveryMillis(millis) if millis > 0 => SyncPolicy::EveryMillis(millis),
+        _ => return Err(Error::InvalidConfig),
+    };
+    let mut config = Config::new(directory, store_config.segment_size, DEFAULT_KEY_DIRECTORY_CAPACITY, Some(merge_config), Arc::new(WasiClock{}))
+        .with_sync_policy(sync_policy);
+    if let Some(encryption) = store_config.encryption {
+        config = config.with_keyring(Keyring::new(&encryption.key, &encryption.previous_keys)?);
+    }
+    Ok(config)
 }
 
 impl Guest for Component {
@@ -55,4 +184,56 @@ impl Guest for Component {
     type Kvstore = KVStore;
 }
 
-bindings::export!(Component with_types_in bindings);
\ No newline at end of file
+bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bindings::exports::component::kv::types::{Encryption, PartialMerge};
+
+    fn store_config(merge_policy: MergePolicy, sync_policy: GuestSyncPolicy) -> StoreConfig {
+        StoreConfig { segment_size: 4096, merge_policy, sync_policy, encryption: None }
+    }
+
+    #[test]
+    fn store_names() {
+        for name in ["bitcoin-wasm", "utxo_set", "headers.v2", "..chain"] {
+            assert!(is_valid_store_name(name), "{name}");
+        }
+        for name in ["", ".", "..", "../other", "a/b", "a\\b", "store name"] {
+            assert!(!is_valid_store_name(name), "{name}");
+        }
+        assert!(matches!(KVStore::open("../other".to_string(), None, None), Err(Error::InvalidConfig)));
+    }
+
+    #[test]
+    fn store_configs() {
+        let directory = Directory::new(None, "config".into());
+        let config = store_config_to_config(directory.clone(), store_config(MergePolicy::MostGarbage(PartialMerge { segments: 2, every: 60 }), GuestSyncPolicy::EveryWrites(10))).unwrap();
+        let merge_config = config.merge_config().unwrap();
+        assert_eq!((merge_config.total_segments_to_read(), merge_config.should_read_all_segments(), merge_config.run_merge_every()), (2, false, 60));
+        assert_eq!(config.sync_policy(), SyncPolicy::EveryWrites(10));
+        assert_eq!(config.max_segment_size_in_bytes(), 4096);
+        assert!(config.keyring().current().is_none());
+
+        let config = store_config_to_config(directory.clone(), store_config(MergePolicy::AllSegments(30), GuestSyncPolicy::Always)).unwrap();
+        assert!(config.merge_config().unwrap().should_read_all_segments());
+        assert_eq!(config.sync_policy(), SyncPolicy::Always);
+
+        let invalid = [
+            store_config(MergePolicy::AllSegments(0), GuestSyncPolicy::OsDefault),
+            store_config(MergePolicy::MostGarbage(PartialMerge { segments: 0, every: 60 }), GuestSyncPolicy::OsDefault),
+            store_config(MergePolicy::Manual, GuestSyncPolicy::EveryWrites(0)),
+            store_config(MergePolicy::Manual, GuestSyncPolicy::EveryMillis(0)),
+            StoreConfig { segment_size: 0, ..store_config(MergePolicy::Manual, GuestSyncPolicy::OsDefault) },
+            StoreConfig { encryption: Some(Encryption { key: vec![7; 16], previous_keys: vec![] }), ..store_config(MergePolicy::Manual, GuestSyncPolicy::OsDefault) },
+        ];
+        for store_config in invalid {
+            assert!(matches!(store_config_to_config(directory.clone(), store_config), Err(Error::InvalidConfig)));
+        }
+
+        let encryption = Encryption { key: vec![7; 32], previous_keys: vec![vec![8; 32]] };
+        let config = store_config_to_config(directory, StoreConfig { encryption: Some(encryption), ..store_config(MergePolicy::Manual, GuestSyncPolicy::OsDefault) }).unwrap();
+        assert!(config.keyring().current().is_some());
+    }
+}
@@ -0,0 +1,251 @@
+use std::mem;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::errors::Error;
+use crate::store::{Directory, Store};
+
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+const RESERVED_CHECKSUM_SIZE: usize = mem::size_of::<u32>();
+const RESERVED_COUNT_SIZE: usize = mem::size_of::<u32>();
+const RESERVED_SEQUENCE_SIZE: usize = mem::size_of::<u64>();
+const FILE_ID_SIZE: usize = mem::size_of::<u64>();
+const EDIT_HEADER_SIZE: usize = RESERVED_CHECKSUM_SIZE + 2 * RESERVED_COUNT_SIZE + RESERVED_SEQUENCE_SIZE;
+
+/// ManifestEdit is a change to the set of live segments.
+/// Segments in `added` take the place of the newest segment in `removed`, or become the newest segments if nothing is removed.
+/// A merge sets `last_sequence` to the sequence number of the last write of the store, which may be in a record the merge dropped, other edits leave it at 0.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ManifestEdit {
+    pub removed: Vec<u64>,
+    pub added: Vec<u64>,
+    pub last_sequence: u64,
+}
+
+impl ManifestEdit {
+    /// encode converts the edit to a record of the manifest log:
+    /// ```
+    /// ┌───────┬───────────────┬─────────────┬───────────────┬─────────────┬───────────┐
+    /// │ crc32 │ removed_count │ added_count │ last_sequence │ removed ids │ added ids │
+    /// └───────┴───────────────┴─────────────┴───────────────┴─────────────┴───────────┘
+    /// ```
+    /// The counts consist of 32 bits each, the sequence number and the file ids of 64 bits each, the crc32 covers everything after it.
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(EDIT_HEADER_SIZE + (self.removed.len() + self.added.len()) * FILE_ID_SIZE);
+        encoded.extend_from_slice(&[0; RESERVED_CHECKSUM_SIZE]);
+        encoded.extend_from_slice(&(self.removed.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&(self.added.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&self.last_sequence.to_le_bytes());
+        for file_id in self.removed.iter().chain(self.added.iter()) {
+            encoded.extend_from_slice(&file_id.to_le_bytes());
+        }
+        let checksum = crc32fast::hash(&encoded[RESERVED_CHECKSUM_SIZE..]);
+        LittleEndian::write_u32(&mut encoded, checksum);
+        encoded
+    }
+
+    /// decode_from decodes the edit at `offset`, returning None if it is torn or fails its checksum
+    fn decode_from(content: &[u8], offset: usize) -> Option<(Self, usize)> {
+        let header = content.get(offset..offset + EDIT_HEADER_SIZE)?;
+        let removed_count = LittleEndian::read_u32(&header[RESERVED_CHECKSUM_SIZE..]) as usize;
+        let added_count = LittleEndian::read_u32(&header[RESERVED_CHECKSUM_SIZE + RESERVED_COUNT_SIZE..]) as usize;
+        let end = offset.checked_add(EDIT_HEADER_SIZE + (removed_count + added_count).checked_mul(FILE_ID_SIZE)?)?;
+        let record = content.get(offset..end)?;
+        if LittleEndian::read_u32(record) != crc32fast::hash(&record[RESERVED_CHECKSUM_SIZE..]) {
+            return None;
+        }
+
+        let last_sequence = LittleEndian::read_u64(&record[RESERVED_CHECKSUM_SIZE + 2 * RESERVED_COUNT_SIZE..]);
+        let mut file_ids = record[EDIT_HEADER_SIZE..].chunks_exact(FILE_ID_SIZE).map(LittleEndian::read_u64);
+        let removed = file_ids.by_ref().take(removed_count).collect();
+        let added = file_ids.collect();
+        Some((ManifestEdit { removed, added, last_sequence }, end))
+    }
+
+    /// is_torn tells if the record at `offset` runs past the end of the content, which is what a crash in the middle of a commit leaves behind
+    fn is_torn(content: &[u8], offset: usize) -> bool {
+        let Some(header) = content.get(offset..offset + EDIT_HEADER_SIZE) else {
+            return true;
+        };
+        let removed_count = LittleEndian::read_u32(&header[RESERVED_CHECKSUM_SIZE..]) as u64;
+        let added_count = LittleEndian::read_u32(&header[RESERVED_CHECKSUM_SIZE + RESERVED_COUNT_SIZE..]) as u64;
+        offset as u64 + EDIT_HEADER_SIZE as u64 + (removed_count + added_count) * FILE_ID_SIZE as u64 > content.len() as u64
+    }
+}
+
+/// Manifest is an append-only log of ManifestEdit records that gives the segments a total order, oldest first.
+/// File ids come from a sequence above every id the manifest or the directory has seen, so they are unique across restarts,
+/// but it is the position in the manifest that decides which value of a key is the latest. Merged segments take the place of the segments they replace.
+pub struct Manifest<S: Store> {
+    store: S,
+    file_ids: Vec<u64>,
+    next_file_id: u64,
+    last_sequence: u64,
+    replayed_edits: usize,
+    damaged: bool,
+}
+
+impl<S: Store> Manifest<S> {
+    /// open replays the manifest of the directory. A record torn by a crash at the end of the log is cut off.
+    /// A record that is complete but fails its checksum stops the replay and leaves the log as it is, the edits after it are lost and `is_damaged` tells so.
+    /// `existing_file_ids` are the segment files found in the directory, new file ids are above all of them.
+    pub fn open(directory: &Directory, existing_file_ids: &[u64]) -> Result<Self, Error> {
+        let store = S::open(MANIFEST_FILE_NAME, directory)?;
+        let content = store.read_full()?;
+
+        let mut manifest = Manifest {
+            store,
+            file_ids: vec![],
+            next_file_id: 1,
+            last_sequence: 0,
+            replayed_edits: 0,
+            damaged: false,
+        };
+        let mut offset = 0;
+        while let Some((edit, end)) = ManifestEdit::decode_from(&content, offset) {
+            manifest.apply(&edit);
+            manifest.replayed_edits += 1;
+            offset = end;
+        }
+        if offset < content.len() {
+            if ManifestEdit::is_torn(&content, offset) {
+                manifest.store.truncate(offset as i64)?;
+            } else {
+                manifest.damaged = true;
+            }
+        }
+
+        if let Some(max_file_id) = existing_file_ids.iter().max() {
+            manifest.next_file_id = manifest.next_file_id.max(max_file_id + 1);
+        }
+        Ok(manifest)
+    }
+
+    /// is_new tells if the manifest had no edits yet, in which case segments found in the directory were written before the manifest existed
+    pub fn is_new(&self) -> bool {
+        self.replayed_edits == 0
+    }
+
+    /// is_damaged tells if the replay stopped at a damaged record, in which case the order of the segments is incomplete
+    pub fn is_damaged(&self) -> bool {
+        self.damaged
+    }
+
+    /// file_ids returns the live segments, oldest first
+    pub fn file_ids(&self) -> &[u64] {
+        &self.file_ids
+    }
+
+    /// last_sequence returns the highest sequence number recorded by an edit
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub fn contains(&self, file_id: u64) -> bool {
+        self.file_ids.contains(&file_id)
+    }
+
+    /// next_file_id reserves the id of a new segment file
+    pub fn next_file_id(&mut self) -> u64 {
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+        file_id
+    }
+
+    /// commit appends the edit to the log and syncs it before applying it, so that the order survives a crash right after
+    pub fn commit(&mut self, edit: ManifestEdit) -> Result<(), Error> {
+        self.store.append(&edit.encode())?;
+        self.store.sync()?;
+        self.apply(&edit);
+        Ok(())
+    }
+
+    /// rewrite replaces the log with a single edit listing `file_ids`, oldest first, which keeps the sequence number recorded by the edits that were replayed
+    pub fn rewrite(&mut self, file_ids: Vec<u64>) -> Result<(), Error> {
+        self.store.truncate(0)?;
+        self.file_ids.clear();
+        self.commit(ManifestEdit { removed: vec![], added: file_ids, last_sequence: self.last_sequence })
+    }
+
+    fn apply(&mut self, edit: &ManifestEdit) {
+        let position = self.file_ids.iter()
+            .rposition(|file_id| edit.removed.contains(file_id))
+            .map(|newest_removed| self.file_ids[..newest_removed].iter().filter(|file_id| !edit.removed.contains(file_id)).count());
+        self.file_ids.retain(|file_id| !edit.removed.contains(file_id));
+
+        let position = position.unwrap_or(self.file_ids.len());
+        self.file_ids.splice(position..position, edit.added.iter().copied());
+        if let Some(max_file_id) = edit.removed.iter().chain(edit.added.iter()).max() {
+            self.next_file_id = self.next_file_id.max(max_file_id + 1);
+        }
+        self.last_sequence = self.last_sequence.max(edit.last_sequence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn open(directory: &Directory) -> Manifest<MemoryStore> {
+        Manifest::open(directory, &[]).unwrap()
+    }
+
+    fn commit(manifest: &mut Manifest<MemoryStore>, removed: &[u64], added: &[u64]) {
+        manifest.commit(ManifestEdit { removed: removed.to_vec(), added: added.to_vec(), last_sequence: 0 }).unwrap();
+    }
+
+    #[test]
+    fn replay_order() {
+        let directory = Directory::new(None, "manifest".into());
+        let mut manifest = open(&directory);
+        assert!(manifest.is_new());
+        commit(&mut manifest, &[], &[1]);
+        commit(&mut manifest, &[], &[2]);
+        commit(&mut manifest, &[], &[3]);
+        // merged segments take the place of the newest segment they replace
+        commit(&mut manifest, &[1, 2], &[4, 5]);
+        assert_eq!(manifest.file_ids(), [4, 5, 3]);
+
+        let mut manifest = open(&directory);
+        assert!(!manifest.is_new() && !manifest.is_damaged());
+        assert_eq!(manifest.file_ids(), [4, 5, 3]);
+        assert_eq!(manifest.next_file_id(), 6);
+        assert_eq!(Manifest::<MemoryStore>::open(&directory, &[9]).unwrap().next_file_id(), 10);
+    }
+
+    #[test]
+    fn torn_tail() {
+        let directory = Directory::new(None, "torn".into());
+        let mut manifest = open(&directory);
+        commit(&mut manifest, &[], &[1]);
+        commit(&mut manifest, &[], &[2]);
+        let content = MemoryStore::file(&directory, MANIFEST_FILE_NAME);
+        let length = content.borrow().len();
+        content.borrow_mut().truncate(length - 3);
+
+        let mut manifest = open(&directory);
+        assert!(!manifest.is_damaged());
+        assert_eq!(manifest.file_ids(), [1]);
+        commit(&mut manifest, &[], &[3]);
+        assert_eq!(open(&directory).file_ids(), [1, 3]);
+    }
+
+    #[test]
+    fn damaged_record() {
+        let directory = Directory::new(None, "damaged".into());
+        let mut manifest = open(&directory);
+        commit(&mut manifest, &[], &[1]);
+        commit(&mut manifest, &[], &[2]);
+        MemoryStore::file(&directory, MANIFEST_FILE_NAME).borrow_mut()[EDIT_HEADER_SIZE] ^= 0xff;
+
+        let mut manifest = open(&directory);
+        assert!(manifest.is_damaged());
+        assert!(manifest.file_ids().is_empty());
+        manifest.rewrite(vec![2, 1]).unwrap();
+        let manifest = open(&directory);
+        assert!(!manifest.is_damaged());
+        assert_eq!(manifest.file_ids(), [2, 1]);
+    }
+}
@@ -53,6 +53,16 @@ impl<Key: BitCaskKey> MergeConfig<Key> {
         }
     }
 
+    /// new_manual never merges during writes, merges only run when the host compacts the store
+    pub fn new_manual(key_mapper: fn(&[u8]) -> Key) -> Self {
+        Self {
+            total_segments_to_read: 0,
+            should_read_all_segments: false,
+            key_mapper,
+            run_merge_every: u64::MAX,
+        }
+    }
+
     pub fn total_segments_to_read(&self) -> usize {
         self.total_segments_to_read
     }
@@ -1,32 +1,74 @@
+use std::sync::Arc;
+
 use crate::bit_cask_key::BitCaskKey;
-use crate::entry::{decode, decode_multi, Entry, MappedStoredEntry, StoredEntry};
+use crate::cipher::{Cipher, Keyring};
+use crate::entry::{decode, decode_multi, BatchMarker, DecodedSegment, Entry, MappedStoredEntry, RecordFormat, StoredEntry, SEGMENT_HEADER_MAX_SIZE};
 use crate::errors::Error;
-use crate::store::Store;
+use crate::hint;
+use crate::store::{Directory, Store};
 
 pub const SEGMENT_FILE_PREFIX: &str = "bitcask";
 pub const SEGMENT_FILE_SUFFIX: &str = "data";
+pub const HINT_FILE_SUFFIX: &str = "hint";
 
 pub struct AppendEntryResponse {
     pub file_id: u64,
     pub offset: i64,
     pub entry_length: u32,
+    pub expires_at: Option<u32>,
+    pub sequence: u64,
 }
 #[derive(Clone, Default)]
 pub struct Segment<S: Store> {
     pub file_id: u64,
     pub file_path: String,
     pub store: S,
+    pub format: RecordFormat,
+    pub directory: Directory,
+    // has_hint tells if a hint file was written for the segment
+    pub has_hint: bool,
+    // cipher seals the records and the hint of the segment if it is encrypted
+    pub cipher: Option<Arc<Cipher>>,
 }
 
 // NewSegment represents an append-only log
 impl<S: Store> Segment<S> {
-    pub fn new(file_id: u64, directory: &str) -> Result<Self, Error> {
+    pub fn new(file_id: u64, directory: &Directory, cipher: Option<Arc<Cipher>>) -> Result<Self, Error> {
+        let file_path = segment_name(file_id);
+        let mut store = S::open(&file_path, directory)?;
+        let format = cipher.as_ref()
+            .map(|cipher| RecordFormat::Sealed { key_id: cipher.key_id() })
+            .unwrap_or_default();
+        store.append(&format.segment_header())?;
+        Ok(Segment {
+            file_id,
+            file_path,
+            store,
+            format,
+            directory: directory.clone(),
+            has_hint: false,
+            cipher,
+        })
+    }
+
+    // reopen opens an existing segment file, detecting the format its records were written in.
+    // A sealed segment fails with InvalidConfig if the keyring lacks the key it was sealed with.
+    pub fn reopen(file_id: u64, directory: &Directory, has_hint: bool, keyring: &Keyring) -> Result<Self, Error> {
         let file_path = segment_name(file_id);
         let store = S::open(&file_path, directory)?;
+        let format = RecordFormat::detect(&store.read(0, SEGMENT_HEADER_MAX_SIZE)?)?;
+        let cipher = match format {
+            RecordFormat::Sealed { key_id } => Some(keyring.find(key_id).ok_or(Error::InvalidConfig)?),
+            _ => None,
+        };
         Ok(Segment {
             file_id,
             file_path,
             store,
+            format,
+            directory: directory.clone(),
+            has_hint,
+            cipher,
         })
     }
 
@@ -34,32 +76,142 @@ impl<S: Store> Segment<S> {
     /// 1. Encode the incoming entry, more on this in Entry.go
     /// 2. Write the encoded entry ([]byte) to the segment file using the Store abstraction
     pub fn append<K: BitCaskKey>(&mut self, entry: &Entry<K>) -> Result<AppendEntryResponse, Error> {
-        let encoded = entry.encode();
+        let encoded = self.encode(entry);
         let offset = self.store.append(&encoded)?;
         Ok(AppendEntryResponse {
             file_id: self.file_id,
             offset,
             entry_length: encoded.len() as u32,
+            expires_at: entry.expires_at(),
+            sequence: entry.sequence(),
         })
     }
 
+    /// append_batch appends the entries between a begin and a commit marker with a single write, and returns where each entry was written.
+    /// Until the commit marker is on disk, reload and merge ignore the entries.
+    pub fn append_batch<K: BitCaskKey>(&mut self, entries: &[Entry<K>], timestamp: u32) -> Result<Vec<AppendEntryResponse>, Error> {
+        let op_count = entries.len() as u32;
+        let mut encoded = BatchMarker::Begin(op_count).encode(timestamp);
+        let mut positions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let encoded_entry = self.encode(entry);
+            positions.push((encoded.len() as i64, encoded_entry.len() as u32, entry.expires_at(), entry.sequence()));
+            encoded.extend_from_slice(&encoded_entry);
+        }
+        encoded.extend_from_slice(&BatchMarker::Commit(op_count).encode(timestamp));
+
+        let offset = self.store.append(&encoded)?;
+        Ok(positions.into_iter()
+            .map(|(relative_offset, entry_length, expires_at, sequence)| AppendEntryResponse {
+                file_id: self.file_id,
+                offset: offset + relative_offset,
+                entry_length,
+                expires_at,
+                sequence,
+            })
+            .collect())
+    }
+
     // read performs a read operation from the offset in the segment file. This method is invoked in the Get operation
     pub fn read(&self, offset: i64, size: u32) -> Result<StoredEntry, Error> {
         let bytes = self.store.read(offset, size)?;
-        Ok(decode(&bytes))
+        let entry = decode(&bytes, self.format)?;
+        match &self.cipher {
+            Some(cipher) => entry.open(cipher),
+            None => Ok(entry),
+        }
     }
 
-    // ReadFull performs a full read of the segment file. This method is called by the reload operation that happens during DB start-up
+    // ReadFull performs a full read of the segment file. This method is called by the merge operation, damaged records were already reported by recover
     pub fn read_full<K: BitCaskKey>(&self, key_mapper: fn(&[u8]) -> K) -> Result<Vec<MappedStoredEntry<K>>, Error> {
         let bytes = self.store.read_full()?;
-        Ok(decode_multi(&bytes, key_mapper))
+        Ok(self.decode_all(&bytes, key_mapper).entries)
+    }
+
+    /// recover performs a full read of the segment file during DB start-up, and returns its entries with the number of bytes after its last complete record.
+    /// Only the segment that was active when the process stopped can end with a write torn by a crash, `truncate_tail` cuts it off so that the segment ends on a complete record again.
+    /// Any other segment is left as it is, reading stops at the damage and the caller reports it.
+    /// Damaged records are skipped and returned in the DecodedSegment for the caller to report.
+    pub fn recover<K: BitCaskKey>(&mut self, key_mapper: fn(&[u8]) -> K, truncate_tail: bool) -> Result<(DecodedSegment<K>, u32), Error> {
+        let bytes = self.store.read_full()?;
+        let decoded = self.decode_all(&bytes, key_mapper);
+        let tail = bytes.len() as u32 - decoded.valid_length;
+        if tail > 0 && truncate_tail {
+            self.store.truncate(decoded.valid_length as i64)?;
+        }
+        Ok((decoded, tail))
+    }
+
+    /// write_hint writes the hint file of the segment, holding the key, offset, length and timestamp of every entry so that start-up can skip the values.
+    /// It is written once the segment takes no more writes: when the active segment rolls over, when a merge writes the segment, or when start-up had to read it in full.
+    pub fn write_hint(&mut self) -> Result<(), Error> {
+        let bytes = self.store.read_full()?;
+        let decoded = self.decode_all(&bytes, <[u8]>::to_vec);
+        let mut content = hint::encode(self.file_id, decoded.valid_length as u64, &decoded.entries);
+        if let Some(cipher) = &self.cipher {
+            content = cipher.seal(&content, hint_name(self.file_id).as_bytes());
+        }
+        let mut hint_store = S::open(&hint_name(self.file_id), &self.directory)?;
+        hint_store.truncate(0)?;
+        hint_store.append(&content)?;
+        hint_store.sync()?;
+        self.has_hint = true;
+        Ok(())
+    }
+
+    /// read_hint returns the entries of the segment from its hint file, without their values.
+    /// It returns None if there is no hint, or if it is torn, damaged or out of date, in which case the segment has to be read in full.
+    pub fn read_hint<K: BitCaskKey>(&self, key_mapper: fn(&[u8]) -> K) -> Result<Option<Vec<MappedStoredEntry<K>>>, Error> {
+        if !self.has_hint {
+            return Ok(None);
+        }
+        let hint_store = S::open(&hint_name(self.file_id), &self.directory)?;
+        let mut content = hint_store.read_full()?;
+        if let Some(cipher) = &self.cipher {
+            match cipher.open(&content, hint_name(self.file_id).as_bytes()) {
+                Some(opened) => content = opened,
+                None => return Ok(None),
+            }
+        }
+        Ok(hint::decode(&content, self.file_id, self.size_in_bytes() as u64, key_mapper))
+    }
+
+    // encode encodes an entry for the segment, sealed if the segment is encrypted
+    fn encode<K: BitCaskKey>(&self, entry: &Entry<K>) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => entry.encode_sealed(cipher),
+            None => entry.encode(),
+        }
+    }
+
+    // decode_all decodes the entries of the segment, opening them if it is encrypted. An entry that does not open is reported as damaged.
+    fn decode_all<K>(&self, bytes: &[u8], key_mapper: fn(&[u8]) -> K) -> DecodedSegment<K> {
+        let Some(cipher) = &self.cipher else {
+            return decode_multi(bytes, self.format, key_mapper);
+        };
+        let decoded = decode_multi(bytes, self.format, <[u8]>::to_vec);
+        let mut corrupted = decoded.corrupted;
+        let mut entries = Vec::with_capacity(decoded.entries.len());
+        for entry in decoded.entries {
+            let offset = entry.key_offset;
+            match entry.open(cipher, key_mapper) {
+                Some(entry) => entries.push(entry),
+                None => corrupted.push(offset),
+            }
+        }
+        corrupted.sort_unstable();
+        DecodedSegment {
+            entries,
+            corrupted,
+            valid_length: decoded.valid_length,
+        }
     }
 
     pub fn size_in_bytes(&self) -> i64 {
         self.store.size_in_bytes()
     }
 
-    pub fn sync(&self) {
+    pub fn sync(&self) -> Result<(), Error> {
         self.store.sync()
     }
 
@@ -69,6 +221,11 @@ impl<S: Store> Segment<S> {
     // }
 
     pub fn remove(&mut self) {
+        if self.has_hint {
+            if let Ok(mut hint_store) = S::open(&hint_name(self.file_id), &self.directory) {
+                hint_store.remove();
+            }
+        }
         self.store.remove()
     }
 }
@@ -79,3 +236,7 @@ pub fn segment_name(file_id: u64) -> String {
     let file_name = format!("{}_{}.{}", file_id, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX);
     file_name
 }
+
+pub fn hint_name(file_id: u64) -> String {
+    format!("{}_{}.{}", file_id, SEGMENT_FILE_PREFIX, HINT_FILE_SUFFIX)
+}
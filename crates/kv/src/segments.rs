@@ -1,61 +1,109 @@
 use std::collections::HashMap;
-use std::io::Read;
+use std::mem;
 use std::sync::Arc;
 
+use crate::cipher::Keyring;
 use crate::clock::Clock;
+use crate::config::SyncPolicy;
 use crate::bit_cask_key::BitCaskKey;
-use crate::entry::{Entry, MappedStoredEntry, StoredEntry};
+use crate::entry::{latest_timestamp, BatchOp, Entry, MappedStoredEntry, StoredEntry};
 use crate::errors::Error;
-use crate::field_generator::TimestampBasedFileIdGenerator;
-use crate::segment::{segment_name, AppendEntryResponse, Segment, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX};
-use crate::store::Store;
+use crate::manifest::{Manifest, ManifestEdit};
+use crate::segment::{hint_name, AppendEntryResponse, Segment, HINT_FILE_SUFFIX, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX};
+use crate::store::{Directory, Store};
 
 
 pub struct Segments<S: Store> {
     active_segment: Segment<S>,
     inactive_segments: HashMap<u64, Segment<S>>,
-    file_id_generator: TimestampBasedFileIdGenerator,
+    manifest: Manifest<S>,
     clock: Arc<dyn Clock>,
     max_segment_size_bytes: u64,
-    directory: String,
+    sync_policy: SyncPolicy,
+    // writes to the active segment since it was last synced, and the monotonic time of that sync
+    unsynced_writes: u64,
+    last_sync: u64,
+    keyring: Keyring,
+    directory: Directory,
 }
 
 pub struct WriteBackResponse<K> {
     pub key: K,
     pub append_entry_response: AppendEntryResponse,
+    pub deleted: bool,
 }
 
 impl<S: Store > Segments<S> {
-    pub fn new<K: BitCaskKey>(directory: String, max_segment_size_bytes: u64, clock: Arc<dyn Clock>) -> Result<Self, Error> {
-        let file_id_generator = TimestampBasedFileIdGenerator{ clock: clock.clone()};
-        let file_id = file_id_generator.next();
-        let active_segment = Segment::new(file_id, &directory)?;
+    /// NewSegments opens the segments listed in the manifest as inactive segments and starts a new active segment after them.
+    /// Segment files the manifest does not list are what is left of a merge that did not commit, the segments they merged are still listed, so they are removed.
+    /// That only holds for a manifest that replayed in full. A damaged manifest may have lost the edits that listed them, so every segment file is kept
+    /// and the manifest is rewritten with the segments ordered the way segments written before the manifest existed are.
+    pub fn new(directory: Directory, max_segment_size_bytes: u64, sync_policy: SyncPolicy, keyring: Keyring, clock: Arc<dyn Clock>) -> Result<Self, Error> {
+        let existing_file_ids = Self::file_ids(&directory, SEGMENT_FILE_SUFFIX)?;
+        let hinted_file_ids = Self::file_ids(&directory, HINT_FILE_SUFFIX)?;
+        let mut manifest = Manifest::<S>::open(&directory, &existing_file_ids)?;
+        if manifest.is_damaged() || (manifest.is_new() && !existing_file_ids.is_empty()) {
+            let ordered_file_ids = Self::order_unmanaged_segments(&directory, &keyring, existing_file_ids.clone())?;
+            manifest.rewrite(ordered_file_ids)?;
+        }
+
+        let mut inactive_segments = HashMap::new();
+        for file_id in existing_file_ids.iter().copied() {
+            let mut segment = Segment::reopen(file_id, &directory, hinted_file_ids.contains(&file_id), &keyring)?;
+            if manifest.contains(file_id) {
+                inactive_segments.insert(file_id, segment);
+            } else {
+                segment.remove();
+            }
+        }
+        // hints of segments removed by a merge that stopped before removing the hint as well
+        for file_id in hinted_file_ids.iter().filter(|file_id| !existing_file_ids.contains(file_id)) {
+            S::open(&hint_name(*file_id), &directory)?.remove();
+        }
 
-        let mut segments = Segments {
+        let active_segment = Segment::new(manifest.next_file_id(), &directory, keyring.current())?;
+        manifest.commit(ManifestEdit { added: vec![active_segment.file_id], ..ManifestEdit::default() })?;
+
+        Ok(Segments {
             active_segment,
-            inactive_segments: HashMap::new(),
-            file_id_generator,
-            clock,
+            inactive_segments,
+            manifest,
             max_segment_size_bytes,
+            sync_policy,
+            unsynced_writes: 0,
+            last_sync: clock.monotonic_now(),
+            clock,
+            keyring,
             directory,
-        };
-        segments.reload::<K>()?;
-        Ok(segments)
+        })
     }
 
     /// Append performs an append operation in the active segment file.
     /// Before the append operation can be done, the size of the active segment is checked.
     /// If its size < the size of segment threshold, the key value pair is appended to the active segment, else the active segment is rolled-over
-    pub fn  append<K: BitCaskKey>(&mut self, key: K, value: Vec<u8>) -> Result<AppendEntryResponse, Error> {
+    /// An entry with `expires_at` set, in seconds since the epoch, is no longer read once that time has passed. `sequence` is the sequence number of the write.
+    pub fn  append<K: BitCaskKey>(&mut self, key: K, value: Vec<u8>, expires_at: Option<u32>, sequence: u64) -> Result<AppendEntryResponse, Error> {
         self.maybe_rollover_active_segment()?;
-        self.active_segment.append(&Entry::new(key, value, self.clock.clone()))
+        let entry = Entry::new(key, value, self.clock.clone()).with_expiry(expires_at).with_sequence(sequence);
+        self.active_segment.append(&entry)
     }
 
     /// AppendDeleted performs an append operation in the active segment file. Even the `delete` is an append operation in the log file.
     /// The key will eventually be removed during the merge operation
-    pub fn append_deleted<K: BitCaskKey>(&mut self, key: K) -> Result<AppendEntryResponse, Error> {
+    pub fn append_deleted<K: BitCaskKey>(&mut self, key: K, sequence: u64) -> Result<AppendEntryResponse, Error> {
+        self.maybe_rollover_active_segment()?;
+        self.active_segment.append(&Entry::new_deleted_entry(key, self.clock.clone()).with_sequence(sequence))
+    }
+
+    /// AppendBatch performs an append operation of all the operations of a batch in the active segment file.
+    /// The active segment is only rolled-over before the batch, so that a batch never spans two segments. The operations take the sequence numbers from `first_sequence` on.
+    pub fn append_batch<K: BitCaskKey>(&mut self, ops: Vec<BatchOp<K>>, first_sequence: u64) -> Result<Vec<AppendEntryResponse>, Error> {
         self.maybe_rollover_active_segment()?;
-        self.active_segment.append(&Entry::new_deleted_entry(key, self.clock.clone()))
+        let entries: Vec<Entry<K>> = ops.into_iter()
+            .zip(first_sequence..)
+            .map(|(op, sequence)| op.into_entry(sequence, self.clock.clone()))
+            .collect();
+        self.active_segment.append_batch(&entries, self.clock.now() as u32)
     }
 
     //Read performs a read operation from the offset in the segment file. This method is invoked in the Get operation
@@ -70,61 +118,103 @@ impl<S: Store > Segments<S> {
         }
     }
 
-    /// ReadInactiveSegments reads inactive segments identified by `totalSegments`. This operation is performed during merge.
-    /// keyMapper is used to map a byte slice Key to a generically typed Key. keyMapper is basically a means to perform deserialization of keys which is necessary to update the state in KeyDirectory after the merge operation is done, more on this is mentioned in KeyDirectory.go
-    pub fn read_inactive_segments<K: BitCaskKey>(
-        &self,
-        total_segments: usize,
-        key_mapper: fn(&[u8]) -> K,
-    ) -> Result<(Vec<u64>, Vec<Vec<MappedStoredEntry<K>>>), Error> {
-        let mut index = 0;
-        let mut contents = Vec::with_capacity(total_segments);
-        let mut file_ids = Vec::with_capacity(total_segments);
-
-        for (file_id, segment) in &self.inactive_segments {
-            if index >= total_segments {
-                break;
-            }
-            let entries = segment.read_full(key_mapper)?;
-            contents.push(entries);
-            file_ids.push(*file_id);
-            index += 1;
-        }
-        Ok((file_ids, contents))
+    /// InactiveSegmentSizes returns the id and the number of bytes of entries of every inactive segment, in the order of the manifest, oldest first
+    pub fn inactive_segment_sizes(&self) -> Vec<(u64, u64)> {
+        self.inactive_file_ids().into_iter()
+            .map(|file_id| {
+                let segment = &self.inactive_segments[&file_id];
+                (file_id, (segment.size_in_bytes() - segment.format.header_size() as i64).max(0) as u64)
+            })
+            .collect()
     }
 
-    pub fn read_all_inactive_segments<K: BitCaskKey>(
-        &self,
-        key_mapper: fn(&[u8]) -> K,
-    ) -> Result<(Vec<u64>, Vec<Vec<MappedStoredEntry<K>>>), Error> {
-        self.read_inactive_segments(self.inactive_segments.len(), key_mapper)
+    /// NeedsRekey tells if the inactive segment identified by `file_id` is not sealed with the current key of an encrypted store, so that a merge has to rewrite it to rotate the key
+    pub fn needs_rekey(&self, file_id: u64) -> bool {
+        let Some(current) = self.keyring.current() else {
+            return false;
+        };
+        self.inactive_segments.get(&file_id)
+            .is_some_and(|segment| segment.cipher.as_ref().is_none_or(|cipher| cipher.key_id() != current.key_id()))
     }
 
-    /// WriteBack writes back the changes (merged changes) to new inactive segments. This operation is performed during merge.
-    /// It writes all the changes into M new inactive segments and once those changes are written to the new inactive segment(s), the state of the keys present in the `changes` parameter is updated in the KeyDirectory. More on this is mentioned in Worker.go inside merge/ package.
-    pub fn write_back<K: BitCaskKey + Clone>(
+    /// Merge rewrites the inactive segments identified by `file_ids` into new inactive segments holding only what is still needed of them. This operation is performed during merge and compaction.
+    /// The segments are read one at a time and their entries are written out as they are read, so a merge holds at most one segment and the tombstones in memory.
+    /// `live_entry` returns the segment and the offset the KeyDirectory has for a key, an entry is only written back if it is the one the KeyDirectory points to.
+    /// That is what allows merging segments that are not next to each other: the merged segments take the place of the newest segment they replace in the manifest,
+    /// and no segment in between can hold a newer value of a key whose entry is still live.
+    /// Entries that are written back keep their timestamp, expiry and sequence number. The manifest edit of the merge records `last_sequence`, the sequence number
+    /// of the last write of the store, so that sequence numbers do not go back after a restart when the merge dropped the record of that write.
+    /// Expired entries are dropped like tombstones, and they are expected to be purged from the KeyDirectory before.
+    /// A tombstone is written back if its key is still deleted and a segment older than the tombstone is not part of the merge, which could hold a value the tombstone hides,
+    /// an expired entry leaves a tombstone behind for the same reason.
+    /// The new segments are sealed with the current key of an encrypted store, so that merges rotate keys, and synced before the manifest commits the replacement and the merged segments are removed from disk.
+    pub fn merge<K: BitCaskKey + Clone>(
         &mut self,
-        changes: HashMap<K, MappedStoredEntry<K>>,
+        file_ids: &[u64],
+        key_mapper: fn(&[u8]) -> K,
+        last_sequence: u64,
+        live_entry: impl Fn(&K) -> Option<(u64, i64)>,
     ) -> Result<Vec<WriteBackResponse<K>>, Error> {
-        let mut segment = Segment::<S>::new(self.file_id_generator.next(), &self.directory)?;
-        self.inactive_segments.insert(segment.file_id, segment.clone());
-
-        let mut write_back_responses = Vec::with_capacity(changes.len());
-        for (key, value) in changes {
-            let append_entry_response = segment.append(&Entry::new_preserving_timestamp(
-                key.clone(),
-                value.value,
-                value.timestamp,
-                self.clock.clone(),
-            ))?;
+        let now = self.clock.now();
+        let order = self.inactive_file_ids();
+        // tombstones of the segments before the first segment left out of the merge have no older segment to hide values in
+        let first_unmerged = order.iter().position(|file_id| !file_ids.contains(file_id)).unwrap_or(order.len());
+
+        let mut written_segments: Vec<Segment<S>> = Vec::new();
+        let mut write_back_responses = Vec::new();
+        let mut tombstones: HashMap<K, MappedStoredEntry<K>> = HashMap::new();
+        for (position, file_id) in order.iter().enumerate().filter(|(_, file_id)| file_ids.contains(file_id)) {
+            for entry in self.inactive_segments[file_id].read_full(key_mapper)? {
+                if entry.deleted || entry.is_expired(now) {
+                    if position >= first_unmerged {
+                        tombstones.insert(entry.key.clone(), entry);
+                    }
+                    continue;
+                }
+                if live_entry(&entry.key) != Some((*file_id, entry.key_offset as i64)) {
+                    continue;
+                }
+                let append_entry_response = self.writable_segment(&mut written_segments)?.append(&Entry::new_preserving_timestamp(
+                    entry.key.clone(),
+                    entry.value,
+                    entry.timestamp,
+                    self.clock.clone(),
+                ).with_expiry(entry.expires_at).with_sequence(entry.sequence))?;
+                write_back_responses.push(WriteBackResponse {
+                    key: entry.key,
+                    append_entry_response,
+                    deleted: false,
+                });
+            }
+        }
+        for (key, tombstone) in tombstones {
+            if live_entry(&key).is_some() {
+                continue;
+            }
+            let append_entry_response = self.writable_segment(&mut written_segments)?
+                .append(&Entry::new_deleted_preserving_timestamp(key.clone(), tombstone.timestamp, self.clock.clone()).with_sequence(tombstone.sequence))?;
             write_back_responses.push(WriteBackResponse {
                 key,
                 append_entry_response,
+                deleted: true,
             });
+        }
 
-            if let Some(new_segment) = self.maybe_rollover_segment(&mut segment)? {
-                self.inactive_segments.insert(new_segment.file_id, new_segment.clone());
-                segment = new_segment;
+        for segment in written_segments.iter_mut() {
+            segment.sync()?;
+            segment.write_hint()?;
+        }
+        self.manifest.commit(ManifestEdit {
+            removed: file_ids.to_vec(),
+            added: written_segments.iter().map(|segment| segment.file_id).collect(),
+            last_sequence,
+        })?;
+        for segment in written_segments {
+            self.inactive_segments.insert(segment.file_id, segment);
+        }
+        for file_id in file_ids {
+            if let Some(mut segment) = self.inactive_segments.remove(file_id) {
+                segment.remove();
             }
         }
         Ok(write_back_responses)
@@ -143,23 +233,30 @@ impl<S: Store > Segments<S> {
         self.inactive_segments.clear();
     }
 
-    pub fn remove(&mut self, file_ids: &[u64]) {
-        for file_id in file_ids {
-            if let Some(mut segment) = self.inactive_segments.remove(file_id) {
-                segment.remove();
-            }
-        }
+    /// LastSequence returns the highest sequence number recorded by the merges in the manifest
+    pub fn last_sequence(&self) -> u64 {
+        self.manifest.last_sequence()
+    }
+
+    /// ManifestDamaged tells if the manifest was damaged on start-up, in which case the segments were ordered by the timestamps of their entries
+    pub fn manifest_damaged(&self) -> bool {
+        self.manifest.is_damaged()
     }
 
-    pub fn all_inactive_segments(&self) -> &HashMap<u64, Segment<S>> {
-        &self.inactive_segments
+    /// InactiveSegmentsMut returns the inactive segments in the order of the manifest, oldest first
+    pub fn inactive_segments_mut(&mut self) -> Vec<&mut Segment<S>> {
+        let order = self.manifest.file_ids();
+        let mut segments: Vec<&mut Segment<S>> = self.inactive_segments.values_mut().collect();
+        segments.sort_by_key(|segment| order.iter().position(|file_id| *file_id == segment.file_id));
+        segments
     }
 
-    pub fn sync(&self) {
-        self.active_segment.sync();
+    pub fn sync(&self) -> Result<(), Error> {
+        self.active_segment.sync()?;
         for segment in self.inactive_segments.values() {
-            segment.sync();
+            segment.sync()?;
         }
+        Ok(())
     }
 
     // pub fn shutdown(&mut self) {
@@ -167,51 +264,95 @@ impl<S: Store > Segments<S> {
     //     self.inactive_segments.clear();
     // }
 
-    fn maybe_rollover_active_segment(&mut self) -> Result<(), Error> {
-        if let Some(new_segment) = self.maybe_rollover_segment(&mut self.active_segment.clone())? {
-            self.inactive_segments.insert(self.active_segment.file_id, self.active_segment.clone());
-            self.active_segment = new_segment;
+    /// Flush syncs the writes made to the active segment since it was last synced
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.active_segment.sync()?;
+        self.unsynced_writes = 0;
+        self.last_sync = self.clock.monotonic_now();
+        Ok(())
+    }
+
+    /// SyncActiveSegment counts a write that was appended and flushes it if the sync policy asks for it. It is called once the write is applied,
+    /// so that a write whose sync fails is still read: the error only tells that it may not survive a power loss.
+    pub fn sync_active_segment(&mut self) -> Result<(), Error> {
+        self.unsynced_writes += 1;
+        let due = match self.sync_policy {
+            SyncPolicy::OsDefault => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryWrites(writes) => self.unsynced_writes >= writes,
+            SyncPolicy::EveryMillis(millis) => self.clock.monotonic_now().saturating_sub(self.last_sync) >= millis.saturating_mul(1_000_000),
+        };
+        if due {
+            self.flush()?;
         }
         Ok(())
     }
 
-    fn maybe_rollover_segment(&self, segment: &mut Segment<S>) -> Result<Option<Segment<S>>, Error> {
-        if segment.size_in_bytes() >= self.max_segment_size_bytes as i64 {
+    fn maybe_rollover_active_segment(&mut self) -> Result<(), Error> {
+        if self.active_segment.size_in_bytes() >= self.max_segment_size_bytes as i64 {
             // TODO: Fix
             // segment.stop_writes();
-            let new_segment = Segment::new(self.file_id_generator.next(), &self.directory)?;
-            Ok(Some(new_segment))
-        } else {
-            Ok(None)
+            // the writes of the segment are synced before it rolls over, whatever the sync policy, a failed sync leaves it active
+            self.active_segment.sync()?;
+            let new_segment = self.create_segment()?;
+            self.manifest.commit(ManifestEdit { added: vec![new_segment.file_id], ..ManifestEdit::default() })?;
+            let mut segment = mem::replace(&mut self.active_segment, new_segment);
+            self.unsynced_writes = 0;
+            self.last_sync = self.clock.monotonic_now();
+            // the segment is complete, a missing hint only slows down the next start-up
+            let _ = segment.write_hint();
+            self.inactive_segments.insert(segment.file_id, segment);
         }
+        Ok(())
+    }
+
+    // writable_segment returns the segment a merge writes to, starting a new one when there is none yet or the last one is full
+    fn writable_segment<'a>(&mut self, written_segments: &'a mut Vec<Segment<S>>) -> Result<&'a mut Segment<S>, Error> {
+        let full = written_segments.last()
+            .is_none_or(|segment| segment.size_in_bytes() >= self.max_segment_size_bytes as i64);
+        if full {
+            written_segments.push(self.create_segment()?);
+        }
+        Ok(written_segments.last_mut().expect("a segment was just added"))
+    }
+
+    fn create_segment(&mut self) -> Result<Segment<S>, Error> {
+        Segment::new(self.manifest.next_file_id(), &self.directory, self.keyring.current())
+    }
+
+    // inactive_file_ids returns the ids of the inactive segments in the order of the manifest, oldest first
+    fn inactive_file_ids(&self) -> Vec<u64> {
+        self.manifest.file_ids().iter()
+            .filter(|file_id| self.inactive_segments.contains_key(file_id))
+            .copied()
+            .collect()
     }
 
-    fn reload<K: BitCaskKey>(&mut self) -> Result<(), Error> {
-        let suffix = format!("{}.{}", SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX);
- 
-        for entry in S::get_files(&self.directory)? {
+    // file_ids returns the ids of the segment files, or of the hint files, found in the directory
+    fn file_ids(directory: &Directory, file_suffix: &str) -> Result<Vec<u64>, Error> {
+        let suffix = format!("{}.{}", SEGMENT_FILE_PREFIX, file_suffix);
+        let mut file_ids = Vec::new();
+
+        for entry in S::get_files(directory)? {
             if entry.ends_with(&suffix) {
                 let file_id_str = entry.split('_').next().ok_or(Error::ParseError)?;
                 let file_id: u64 = file_id_str.parse().map_err(|_| Error::ParseError)?;
-
-                if file_id != self.active_segment.file_id {
-                    let segment = Self::reload_inactive_segment::<K>(file_id, &self.directory)?;
-                    self.inactive_segments.insert(file_id, segment);
-                }
+                file_ids.push(file_id);
             }
         }
-            
-        Ok(())
+        Ok(file_ids)
     }
 
-    fn reload_inactive_segment<K: BitCaskKey>(file_id: u64, directory: &str) -> Result<Segment<S>, Error> {
-        let file_path = segment_name(file_id);
-        let store = S::open(&file_path, directory)?;
-        
-        Ok(Segment {
-            file_id,
-            file_path,
-            store,
-        })
+    // order_unmanaged_segments orders segments written before the manifest existed. Their ids came from a monotonic clock that restarts with the process,
+    // so they are ordered by the newest timestamp of their entries instead, and by id within the same second.
+    fn order_unmanaged_segments(directory: &Directory, keyring: &Keyring, mut file_ids: Vec<u64>) -> Result<Vec<u64>, Error> {
+        let mut latest_timestamps = HashMap::with_capacity(file_ids.len());
+        for file_id in file_ids.iter() {
+            let segment = Segment::<S>::reopen(*file_id, directory, false, keyring)?;
+            let content = segment.store.read_full()?;
+            latest_timestamps.insert(*file_id, latest_timestamp(&content, segment.format));
+        }
+        file_ids.sort_by_key(|file_id| (latest_timestamps[file_id], *file_id));
+        Ok(file_ids)
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,7 @@
 use std::io::{Read, Write};
 use std::sync::Arc;
+#[cfg(test)]
+use std::{cell::{Cell, RefCell}, collections::HashMap, rc::Rc};
 
 use wasi::filesystem;
 use wasi::filesystem::types::{Descriptor, DescriptorFlags, OpenFlags, PathFlags};
@@ -11,43 +13,65 @@ pub trait Store: Clone   {
     fn read(&self, offset: i64, size: u32) -> Result<Vec<u8>, Error>;
     fn read_full(&self) -> Result<Vec<u8>, Error>;
     fn size_in_bytes(&self) -> i64;
-    fn sync(&self);
-    fn get_files(directory_path: &str )-> Result<Vec<String>, Error>;
-    fn open(file_path: &str, directory_path:  &str) -> Result<Self, Error> where Self: Sized ;
+    fn sync(&self) -> Result<(), Error>;
+    fn truncate(&mut self, size: i64) -> Result<(), Error>;
+    fn get_files(directory: &Directory)-> Result<Vec<String>, Error>;
+    fn open(file_path: &str, directory: &Directory) -> Result<Self, Error> where Self: Sized ;
     fn remove(&mut self);
 }
 
+/// Directory is where a store keeps its files: `path` inside the preopened directory named `preopen`, or inside the first preopened directory if it is None
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Directory {
+    pub preopen: Option<String>,
+    pub path: String,
+}
+
+impl Directory {
+    pub fn new(preopen: Option<String>, path: String) -> Self {
+        Self { preopen, path }
+    }
+}
+
 #[derive(Clone)]
 pub struct WasiStore {
     file_descriptor: Arc<Descriptor>,
     current_write_offset: i64,
-    directory_path: String,
+    directory: Directory,
     file_name: String,
 }
 
-
-
-
-impl Store for WasiStore {
-    fn open(file_path: &str, directory_path:  &str) -> Result<Self, Error> {
-        let (directory_descriptor, _) = &filesystem::preopens::get_directories()[0];
+impl WasiStore {
+    // open_directory opens the directory of the store inside its preopened directory, creating it if it does not exist yet
+    fn open_directory(directory: &Directory) -> Result<Descriptor, Error> {
+        let preopens = filesystem::preopens::get_directories();
+        let (directory_descriptor, _) = match &directory.preopen {
+            Some(preopen) => preopens.iter().find(|(_, name)| name == preopen).ok_or(Error::InvalidConfig)?,
+            None => preopens.first().ok_or(Error::OpenFileError)?,
+        };
         if let Err(err) =  directory_descriptor
             .create_directory_at(
-                directory_path
+                &directory.path
             ) {
                 if err != filesystem::types::ErrorCode::Exist {
-                    panic!("{}",err.name().to_string());
+                    return Err(Error::OpenFileError);
                 }
             }
             ;
-        
-        let opened_directory = directory_descriptor.open_at(PathFlags::empty(),
-            directory_path,
+
+        directory_descriptor.open_at(PathFlags::empty(),
+            &directory.path,
             OpenFlags::DIRECTORY,
             DescriptorFlags::MUTATE_DIRECTORY)
-            .map_err(|err| Error::OpenFileError)?;
+            .map_err(|_| Error::OpenFileError)
+    }
+}
 
 
+impl Store for WasiStore {
+    fn open(file_path: &str, directory: &Directory) -> Result<Self, Error> {
+        let opened_directory = Self::open_directory(directory)?;
+
         let file_descriptor = opened_directory
             .open_at(
                 PathFlags::empty(),
@@ -55,11 +79,16 @@ impl Store for WasiStore {
                 OpenFlags::CREATE,
                 DescriptorFlags::READ | DescriptorFlags::WRITE,
             ).map_err(|_| Error::OpenFileError)?;
+
+        // appends to an existing file continue after its content
+        let current_write_offset = file_descriptor.stat()
+            .map_err(|_| Error::OpenFileError)?
+            .size as i64;
            
         Ok(WasiStore {
             file_descriptor: Arc::new(file_descriptor),
-            current_write_offset: 0,
-            directory_path: directory_path.into(),
+            current_write_offset,
+            directory: directory.clone(),
             file_name: file_path.into()
         })
     }
@@ -94,22 +123,25 @@ impl Store for WasiStore {
         self.current_write_offset
     }
 
-    fn sync(&self) {
-        let _ = self.file_descriptor.sync();
+    fn sync(&self) -> Result<(), Error> {
+        self.file_descriptor.sync()
+            .map_err(|_| Error::OpenFileError)
+    }
+
+    fn truncate(&mut self, size: i64) -> Result<(), Error> {
+        self.file_descriptor.set_size(size as u64)
+            .map_err(|_| Error::OpenFileError)?;
+        self.current_write_offset = self.current_write_offset.min(size);
+        Ok(())
     }
     
-    fn get_files(directory_path: &str) -> Result<Vec<String>, Error> {
+    fn get_files(directory: &Directory) -> Result<Vec<String>, Error> {
         let mut store_files = Vec::new();
-        let (directory_descriptor, _) = &filesystem::preopens::get_directories()[0];
+        let opened_directory = Self::open_directory(directory)?;
 
-        let opened_directory = directory_descriptor.open_at(PathFlags::empty(),
-            directory_path,
-            OpenFlags::DIRECTORY,
-            DescriptorFlags::MUTATE_DIRECTORY)
+        let files = opened_directory.read_directory()
             .map_err(|_| Error::OpenFileError)?;
 
-        let files = opened_directory.read_directory().unwrap();
-
         while let Ok(entry_option) = files.read_directory_entry() {
             match entry_option {
                 Some(entry) => {
@@ -125,18 +157,120 @@ impl Store for WasiStore {
     }
 
     fn remove(&mut self) {
-        let (directory_descriptor, _) = &filesystem::preopens::get_directories()[0];
-        
-        let opened_directory = directory_descriptor.open_at(PathFlags::empty(),
-            self.directory_path.as_str(),
-            OpenFlags::DIRECTORY,
-            DescriptorFlags::MUTATE_DIRECTORY)
-            .map_err(|_| Error::OpenFileError);
-
-        if let Ok(dir) = opened_directory {
+        if let Ok(dir) = Self::open_directory(&self.directory) {
             let _ = dir.unlink_file_at(&self.file_name);
         }
     }
     
 
 }
+
+// content of the files of the MemoryStore by directory path and file name
+#[cfg(test)]
+type MemoryFiles = HashMap<(String, String), Rc<RefCell<Vec<u8>>>>;
+
+#[cfg(test)]
+thread_local! {
+    // every test runs on a thread of its own and so starts without files
+    static MEMORY_FILES: RefCell<MemoryFiles> = RefCell::new(HashMap::new());
+    // the number of syncs so far, and whether they fail
+    static SYNCS: Cell<u64> = const { Cell::new(0) };
+    static FAILING_SYNCS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// MemoryStore keeps its files in memory, so that tests can reopen a store and look at or damage what it wrote
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MemoryStore {
+    content: Rc<RefCell<Vec<u8>>>,
+    current_write_offset: i64,
+    directory: Directory,
+    file_name: String,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    /// file returns the content of the file named `file_name` in `directory`
+    pub fn file(directory: &Directory, file_name: &str) -> Rc<RefCell<Vec<u8>>> {
+        MEMORY_FILES.with(|files| files.borrow()[&(directory.path.clone(), file_name.to_string())].clone())
+    }
+
+    /// syncs returns the number of files synced so far
+    pub fn syncs() -> u64 {
+        SYNCS.with(Cell::get)
+    }
+
+    /// fail_syncs makes the syncs that follow fail, or succeed again
+    pub fn fail_syncs(failing: bool) {
+        FAILING_SYNCS.with(|failing_syncs| failing_syncs.set(failing));
+    }
+}
+
+#[cfg(test)]
+impl Store for MemoryStore {
+    fn open(file_path: &str, directory: &Directory) -> Result<Self, Error> {
+        let content = MEMORY_FILES.with(|files| {
+            files.borrow_mut().entry((directory.path.clone(), file_path.to_string())).or_default().clone()
+        });
+        let current_write_offset = content.borrow().len() as i64;
+        Ok(MemoryStore {
+            content,
+            current_write_offset,
+            directory: directory.clone(),
+            file_name: file_path.into(),
+        })
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<i64, Error> {
+        let mut content = self.content.borrow_mut();
+        let offset = self.current_write_offset as usize;
+        if content.len() < offset + bytes.len() {
+            content.resize(offset + bytes.len(), 0);
+        }
+        content[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.current_write_offset += bytes.len() as i64;
+        Ok(offset as i64)
+    }
+
+    fn read(&self, offset: i64, size: u32) -> Result<Vec<u8>, Error> {
+        let content = self.content.borrow();
+        let start = (offset as usize).min(content.len());
+        let end = (start + size as usize).min(content.len());
+        Ok(content[start..end].to_vec())
+    }
+
+    fn read_full(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.content.borrow().clone())
+    }
+
+    fn size_in_bytes(&self) -> i64 {
+        self.current_write_offset
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        if FAILING_SYNCS.with(Cell::get) {
+            return Err(Error::OpenFileError);
+        }
+        SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: i64) -> Result<(), Error> {
+        self.content.borrow_mut().truncate(size as usize);
+        self.current_write_offset = self.current_write_offset.min(size);
+        Ok(())
+    }
+
+    fn get_files(directory: &Directory) -> Result<Vec<String>, Error> {
+        Ok(MEMORY_FILES.with(|files| {
+            files.borrow().keys()
+                .filter(|(path, _)| *path == directory.path)
+                .map(|(_, file_name)| file_name.clone())
+                .collect()
+        }))
+    }
+
+    fn remove(&mut self) {
+        MEMORY_FILES.with(|files| files.borrow_mut().remove(&(self.directory.path.clone(), self.file_name.clone())));
+    }
+}
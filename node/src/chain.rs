@@ -1,16 +1,61 @@
-use std::{iter::zip, sync::Arc};
-use crate::{bindings, messages::{block_locator::NO_HASH_STOP, compact_filter::CompactFilter, tx_out::TxOut, Inv, InvVect}, util::{self, sha256d, Error}};
+use std::{collections::{BTreeMap, HashMap}, iter::zip, sync::Arc};
+use crate::{bindings, messages::{block::Block, compact_filter::CompactFilter, BlockHeader, Inv, InvVect, MerkleBlock, OutPoint, tx::Tx}, util::{self, sha256d, Error, Serializable}};
 
 use bitcoin::network as bitcoin_network;
+use wasi::{clocks::monotonic_clock, random::random};
 use bindings::component::kv::types::Error as StoreError ;
 use serde::Serialize;
 
-use crate::{db::KeyValueDb, node::CustomIPV4SocketAddress, p2p::{P2PControl, P2P}, util::Hash256};
+use crate::{db::{MemoryDb, Store}, fee::{FeeEstimator, MIN_FEE_SAMPLES, MIN_RELAY_FEE_RATE}, node::CustomIPV4SocketAddress, p2p::{BlockAnnouncement, P2PControl, PeerStatus, P2P}, pending::{PendingTx, PendingTxs}, privacy::{schedule_downloads, PrivacyConfig}, snapshot, util::Hash256, wallet::{OutpointIndex, SpendRecord, WalletState}};
 
 pub struct CompactChain {
     p2p: P2P,
-    db: Arc<KeyValueDb>,
+    db: Arc<dyn Store>,
     chain_state: ChainState,
+    wallets: BTreeMap<String, WalletState>,
+    outpoints: OutpointIndex,
+    pending: PendingTxs,
+    best_header_height: u64,
+    best_header_hash: Hash256,
+    privacy: PrivacyConfig,
+    fee_estimator: FeeEstimator,
+    // the first header of a fresh chain builds on it
+    genesis_blockhash: Hash256,
+    // easiest proof of work target that headers may claim
+    pow_limit: Hash256,
+    // fetched headers whose filters were not matched yet, in chain order after the filter tip
+    pending_headers: Vec<BlockHeader>,
+    // the last header request returned less than a full batch
+    tip_reached: bool,
+}
+
+/// Sync progress of the chain and its peers
+pub struct ChainStatus {
+    pub best_header_height: u64,
+    pub best_header_hash: Hash256,
+    pub filter_height: u64,
+    pub watched_scripts: u32,
+    pub utxo_count: u32,
+    pub peers: Vec<PeerStatus>,
+}
+
+/// How far filter sync got, see `CompactChain::sync_step`
+pub struct SyncProgress {
+    pub filter_height: u64,
+    pub best_header_height: u64,
+    /// Best height known from headers or announced by peers
+    pub target_height: u64,
+    /// No headers are left to match against filters
+    pub done: bool,
+}
+
+impl SyncProgress {
+    pub fn percent(&self) -> f32 {
+        match self.done || self.target_height == 0 {
+            true => 100.0,
+            false => (self.filter_height as f32 * 100.0 / self.target_height as f32).min(100.0),
+        }
+    }
 }
 
 
@@ -18,56 +63,266 @@ pub struct CompactChain {
 struct ChainState {
     last_block_hash: Hash256,
     last_block_height: u64,
-    filters: Vec<Vec<u8>>,
-    utxos: Vec<Utxo>
-}
-
-#[derive(serde::Deserialize, Serialize, Clone)]
-pub struct Utxo  {
-    pub tx_out: TxOut,
-    hash: Hash256,
-    index: usize,
-
+    // default wallet, kept inline so existing chain states still decode
+    wallet: WalletState,
 }
 
 const CHAIN_STATE_KEY: &str = "chain_state";
+const WALLET_IDS_KEY: &str = "wallet_ids";
+const WALLET_KEY_PREFIX: &str = "wallet_";
+const PROOF_KEY_PREFIX: &str = "proof_";
+const OUTPOINTS_KEY: &str = "outpoints";
+const PENDING_KEY: &str = "pending_txs";
 const MAX_HEADER_LEN: usize = 2000;
 const FILTER_SIZE: usize = 500;
 
 
 impl CompactChain {
 
-    pub fn new(socket: CustomIPV4SocketAddress, network: bitcoin_network::Network, db: Arc<KeyValueDb>  ) -> Self {
+    pub fn new(socket: CustomIPV4SocketAddress, decoy_peers: Vec<CustomIPV4SocketAddress>, privacy: PrivacyConfig, network: bitcoin_network::Network, genesis_blockhash: Hash256, db: Arc<dyn Store>  ) -> Self {
         let mut p2p = P2P::new();
         p2p.connect_peer(socket, network).expect("Failed to connect to peer");
+        for socket in decoy_peers {
+            // an unreachable decoy peer is left out, downloads are spread over the peers that connected
+            let _ = p2p.connect_peer(socket, network);
+        }
 
-        match db.get(CHAIN_STATE_KEY.to_string()) {
-             Ok(chain_state) =>  {
-                let deserialized_chain_state: ChainState = bincode::deserialize(&chain_state).unwrap();
-                Self{ p2p, db, chain_state: deserialized_chain_state }
-             }
+        let (chain_state, wallets, outpoints, pending) = Self::load(db.as_ref()).expect("Cannot load chain state");
+        let best_header_height = chain_state.last_block_height;
+        let best_header_hash = chain_state.last_block_hash;
+        Self{ p2p, db, chain_state, wallets, outpoints, pending, best_header_height, best_header_hash, privacy, fee_estimator: FeeEstimator::new(network), genesis_blockhash, pow_limit: util::pow_limit(network), pending_headers: vec![], tip_reached: false }
+    }
+
+    // state saved by earlier runs, a missing entry is a fresh chain
+    fn load(db: &dyn Store) -> Result<(ChainState, BTreeMap<String, WalletState>, OutpointIndex, PendingTxs), Error> {
+        let chain_state = match db.get(CHAIN_STATE_KEY.to_string()) {
+            Ok(chain_state) => deserialize(&chain_state)?,
             Err(Error::DBError(StoreError::EntryNotFound)) => {
-                let last_block_hash = Hash256::default();
-                let last_block_height = 0;
-                Self{ p2p, db, chain_state: ChainState{ last_block_hash, last_block_height, utxos: vec![], filters: vec![] } }
+                ChainState{ last_block_hash: Hash256::default(), last_block_height: 0, wallet: WalletState::default() }
             },
-            Err(_) => {
-                panic!("Cannot get chainstate")
+            Err(err) => return Err(err),
+        };
+
+        let wallet_ids: Vec<String> = match db.get(WALLET_IDS_KEY.to_string()) {
+            Ok(wallet_ids) => deserialize(&wallet_ids)?,
+            Err(Error::DBError(StoreError::EntryNotFound)) => vec![],
+            Err(err) => return Err(err),
+        };
+        let wallets = wallet_ids.into_iter().map(|id| {
+            let wallet = deserialize(&db.get(format!("{}{}", WALLET_KEY_PREFIX, id))?)?;
+            Ok((id, wallet))
+        }).collect::<Result<_, Error>>()?;
+
+        let outpoints = match db.get(OUTPOINTS_KEY.to_string()) {
+            Ok(outpoints) => deserialize(&outpoints)?,
+            Err(Error::DBError(StoreError::EntryNotFound)) => OutpointIndex::default(),
+            Err(err) => return Err(err),
+        };
+
+        let pending = match db.get(PENDING_KEY.to_string()) {
+            Ok(pending) => deserialize(&pending)?,
+            Err(Error::DBError(StoreError::EntryNotFound)) => PendingTxs::default(),
+            Err(err) => return Err(err),
+        };
+        Ok((chain_state, wallets, outpoints, pending))
+    }
+
+    /// Encrypts every stored entry into a snapshot the host can hold instead of a filesystem
+    pub fn export_state(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = random::get_random_bytes(snapshot::NONCE_LEN as u64).try_into().expect("random bytes of the requested length");
+        snapshot::seal(&self.db.entries()?, key, nonce)
+    }
+
+    /// Restores the entries of a snapshot and continues syncing from its state
+    pub fn import_state(&mut self, snapshot: &[u8], key: &[u8]) -> Result<(), Error> {
+        let entries = snapshot::open(snapshot, key)?;
+        // the snapshot is loaded on its own first, so that a bad one leaves the current state untouched
+        let staged = MemoryDb::default();
+        for (key, value) in entries.iter() {
+            staged.insert(key.clone(), value.clone())?;
+        }
+        let (chain_state, wallets, outpoints, pending) = Self::load(&staged)?;
+        // entries the snapshot does not know about would come back on the next load
+        self.db.replace_entries(entries)?;
+        self.best_header_height = chain_state.last_block_height;
+        self.best_header_hash = chain_state.last_block_hash;
+        self.chain_state = chain_state;
+        self.wallets = wallets;
+        self.outpoints = outpoints;
+        self.pending = pending;
+        self.pending_headers.clear();
+        self.tip_reached = false;
+        // fee samples belong to the chain that was synced before
+        self.fee_estimator.clear();
+        Ok(())
+    }
+
+    fn save_chain_state(&self) -> Result<(), Error> {
+        let binary_chain_state: Vec<u8> = bincode::serialize(&self.chain_state).map_err(|e| Error::SerializationError(e.to_string()))?;
+        self.db.insert(CHAIN_STATE_KEY.to_string(), binary_chain_state)?;
+        Ok(())
+    }
+
+    // the default wallet lives in the chain state, named wallets under their own key
+    fn save_wallet(&self, id: Option<&str>) -> Result<(), Error> {
+        match id {
+            None => self.save_chain_state(),
+            Some(id) => {
+                let wallet = self.wallet(Some(id))?;
+                let binary_wallet = bincode::serialize(wallet).map_err(|e| Error::SerializationError(e.to_string()))?;
+                self.db.insert(format!("{}{}", WALLET_KEY_PREFIX, id), binary_wallet)?;
+                Ok(())
             }
         }
     }
 
+    fn wallet(&self, id: Option<&str>) -> Result<&WalletState, Error> {
+        match id {
+            None => Ok(&self.chain_state.wallet),
+            Some(id) => self.wallets.get(id).ok_or_else(|| Error::WalletNotFound(id.to_string())),
+        }
+    }
+
+    fn wallet_mut(&mut self, id: Option<&str>) -> Result<&mut WalletState, Error> {
+        match id {
+            None => Ok(&mut self.chain_state.wallet),
+            Some(id) => self.wallets.get_mut(id).ok_or_else(|| Error::WalletNotFound(id.to_string())),
+        }
+    }
+
+    pub fn create_wallet(&mut self, id: &str) -> Result<(), Error> {
+        if id.is_empty() {
+            return Err(Error::BadArgument("Wallet id is empty".to_string()));
+        }
+        if self.wallets.contains_key(id) {
+            return Err(Error::InvalidOperation(format!("Wallet {} already exists", id)));
+        }
+        self.wallets.insert(id.to_string(), WalletState::default());
+        self.save_wallet(Some(id))?;
+
+        let wallet_ids: Vec<&String> = self.wallets.keys().collect();
+        let binary_wallet_ids = bincode::serialize(&wallet_ids).map_err(|e| Error::SerializationError(e.to_string()))?;
+        self.db.insert(WALLET_IDS_KEY.to_string(), binary_wallet_ids)?;
+        Ok(())
+    }
+
+    pub fn has_wallet(&self, id: &str) -> bool {
+        self.wallets.contains_key(id)
+    }
+
+    pub fn wallet_ids(&self) -> Vec<String> {
+        self.wallets.keys().cloned().collect()
+    }
+
     pub fn add_filter(& mut self, filter: Vec<u8>) -> Result<(), Error> {
 
-        self.chain_state.filters.push(filter);
-        let binary_chain_state: Vec<u8> = bincode::serialize(&self.chain_state).map_err(|e| Error::SerializationError(e.to_string()))?;
-        self.db.insert(CHAIN_STATE_KEY.to_string(), binary_chain_state)?;
+        self.chain_state.wallet.filters.push(filter);
+        self.save_chain_state()
+    }
+
+    /// Watches the given scripts in a wallet, skipping those already watched, and returns how many were added
+    pub fn add_filters(&mut self, wallet: Option<&str>, filters: Vec<Vec<u8>>) -> Result<u32, Error> {
+        let added = self.wallet_mut(wallet)?.add_filters(filters);
+        self.save_wallet(wallet)?;
+        Ok(added)
+    }
+
+    /// Stops watching the given scripts in a wallet and returns how many were removed
+    pub fn remove_filters(&mut self, wallet: Option<&str>, filters: &[Vec<u8>]) -> Result<u32, Error> {
+        let removed = self.wallet_mut(wallet)?.remove_filters(filters);
+        self.save_wallet(wallet)?;
+        Ok(removed)
+    }
+
+    fn save_outpoints(&self) -> Result<(), Error> {
+        let binary_outpoints = bincode::serialize(&self.outpoints).map_err(|e| Error::SerializationError(e.to_string()))?;
+        self.db.insert(OUTPOINTS_KEY.to_string(), binary_outpoints)?;
+        Ok(())
+    }
+
+    /// Watches an outpoint paying to `script` for spends in blocks after the current filter height
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint, script: Vec<u8>) -> Result<(), Error> {
+        if script.is_empty() {
+            return Err(Error::BadArgument("Script is empty".to_string()));
+        }
+        if self.outpoints.watch(outpoint, script) {
+            self.save_outpoints()?;
+        }
+        Ok(())
+    }
+
+    /// Returns how a watched outpoint was spent, `None` while it is unspent
+    pub fn get_spend(&self, outpoint: &OutPoint) -> Result<Option<SpendRecord>, Error> {
+        if !self.outpoints.contains(outpoint) {
+            return Err(Error::OutpointNotWatched(format!("{}:{}", outpoint.hash.encode(), outpoint.index)));
+        }
+        Ok(self.outpoints.spend(outpoint).cloned())
+    }
 
+    fn save_pending(&self) -> Result<(), Error> {
+        let binary_pending = bincode::serialize(&self.pending).map_err(|e| Error::SerializationError(e.to_string()))?;
+        self.db.insert(PENDING_KEY.to_string(), binary_pending)?;
         Ok(())
     }
 
-    pub fn get_utxos(& mut self) -> Result<Vec<Utxo>, Error> {
-        return Ok(self.chain_state.utxos.clone());
+    /// Counts a locally created transaction in the balances until a block confirms or conflicts with it.
+    ///
+    /// `owned_scripts` pay back to us, like PSBT change: the wallets whose UTXOs the transaction spends
+    /// start watching them, or the default wallet if it spends none.
+    pub fn add_local_tx(&mut self, txn: &Tx, owned_scripts: Vec<Vec<u8>>) -> Result<Hash256, Error> {
+        let pending = PendingTx::new(txn);
+        if !owned_scripts.is_empty() {
+            let mut owners: Vec<Option<String>> = self.wallets.iter()
+                .filter(|(_, wallet)| wallet.is_spent_by(&pending))
+                .map(|(id, _)| Some(id.clone()))
+                .collect();
+            if owners.is_empty() || self.chain_state.wallet.is_spent_by(&pending) {
+                owners.push(None);
+            }
+            for owner in owners {
+                self.add_filters(owner.as_deref(), owned_scripts.clone())?;
+            }
+        }
+        let spends_pending = pending.inputs().iter().any(|input| self.pending.contains(&input.hash));
+        if !spends_pending && !self.all_wallets().any(|wallet| wallet.is_touched_by(&pending)) {
+            return Err(Error::BadArgument("Transaction does not touch any wallet".to_string()));
+        }
+        let txid = pending.txid;
+        self.pending.add(pending);
+        self.save_pending()?;
+        Ok(txid)
+    }
+
+    /// Stops counting a local transaction that will not confirm, along with the ones spending its outputs
+    pub fn remove_local_tx(&mut self, txid: &Hash256) -> Result<Vec<Hash256>, Error> {
+        let removed = self.pending.remove(txid)?;
+        self.save_pending()?;
+        Ok(removed)
+    }
+
+    pub fn pending_txids(&self) -> Vec<Hash256> {
+        self.pending.txids()
+    }
+
+    /// Balance of a wallet with pending local transactions applied
+    pub fn balance(&self, wallet: Option<&str>) -> Result<i64, Error> {
+        Ok(self.wallet(wallet)?.balance(&self.pending))
+    }
+
+    // default wallet first, then named wallets in id order
+    fn all_wallets(&self) -> impl Iterator<Item = &WalletState> {
+        std::iter::once(&self.chain_state.wallet).chain(self.wallets.values())
+    }
+
+    pub fn status(&self) -> ChainStatus {
+        ChainStatus {
+            best_header_height: self.best_header_height,
+            best_header_hash: self.best_header_hash,
+            filter_height: self.chain_state.last_block_height,
+            watched_scripts: self.all_wallets().map(|wallet| wallet.filters.len() as u32).sum(),
+            utxo_count: self.all_wallets().map(|wallet| wallet.utxos.len() as u32).sum(),
+            peers: self.p2p.peers_status(),
+        }
     }
 
     fn get_and_verify_compact_filters(& mut self, start_height: u32, last_block_hash: Hash256) -> Result<Vec<CompactFilter>, Error> {
@@ -84,124 +339,354 @@ impl CompactChain {
         return Ok(filters);
     }
 
-    fn fetch_and_save_utxos(&mut self, filters: Vec<CompactFilter>) -> Result<(), Error> {
+    fn fetch_and_save_utxos(&mut self, start_height: u64, filters: Vec<CompactFilter>) -> Result<(), Error> {
 
-        let blockhash_present: Vec<_> = filters.into_iter().filter_map(|filter| {
+        // blocks are downloaded once and matched against the scripts of every wallet
+        // spends of watched outpoints match through the prevout scripts included in the filters
+        let filter_query: Vec<Vec<u8>> = self.all_wallets().flat_map(|wallet| wallet.filters.iter().cloned())
+            .chain(self.outpoints.scripts().cloned())
+            .collect();
+        let batch: Vec<Hash256> = filters.iter().map(|filter| filter.block_hash).collect();
+        let blockhash_present: Vec<_> = filters.iter().filter_map(|filter| {
             let filter_algo = util::block_filter::BlockFilter::new(&filter.filter_bytes);
-            let filter_query = &self.chain_state.filters;
-            let result = filter_algo.match_any(&filter.block_hash, filter_query.clone().into_iter()).unwrap();
-            match result {
-                true => Some(filter.block_hash),
-                false => None,
-            }
-        }).collect();
-
-        if blockhash_present.is_empty() {
+            // a truncated filter fails to decode, which is bad data from the peer
+            filter_algo.match_any(&filter.block_hash, filter_query.clone().into_iter())
+                .map(|matched| matched.then_some(filter.block_hash))
+                .map_err(|_| Error::FilterMatchEror)
+                .transpose()
+        }).collect::<Result<_, Error>>()?;
+
+        if blockhash_present.is_empty() && !self.privacy.enabled() {
             return Ok(());
         }
 
-        
+        // decoys are downloaded and checked exactly like real blocks before being dropped
+        let requests = schedule_downloads(&blockhash_present, &batch, self.privacy, self.p2p.peer_count(), random::get_random_u64);
+        let mut downloaded = HashMap::new();
+        for peer in 0..self.p2p.peer_count() {
+            let block_inv: Vec<_> = requests.iter().filter(|request| request.peer == peer).map(|request| {
+                InvVect{ obj_type: 2, hash: request.hash }
+            }).collect();
+            if block_inv.is_empty() {
+                continue;
+            }
 
-        let block_inv: Vec<_> = blockhash_present.into_iter().map(|hash| {
-            InvVect{ obj_type: 2, hash }
-        }).collect();
+            let blocks = self.p2p.get_block_from(peer, Inv{ objects: block_inv}).map_err(|err| Error::FetchBlock(err.to_error_code()))?;
+            for block in blocks {
+                let block_hash = block.header.hash();
+                if !requests.iter().any(|request| request.hash == block_hash) || block.merkle_root() != block.header.merkle_root {
+                    return Err(Error::BadData(format!("Block {:?} does not match its header", block_hash)));
+                }
 
-        let blocks = self.p2p.get_block(Inv{ objects: block_inv}).map_err(|err| Error::FetchBlock(err.to_error_code()))?;
+                // a filter missing any output script of its block was not built from that block
+                let served_filter = filters.iter().find(|filter| filter.block_hash == block_hash).expect("requested from this batch");
+                if !util::block_filter::BlockFilter::new(&served_filter.filter_bytes).match_block_outputs(&block)? {
+                    return Err(Error::BadData(format!("Filter of block {:?} does not match the block", block_hash)));
+                }
 
-        let mut new_utxos = self.chain_state.utxos.clone();
-        for block in blocks {
-             for txn in block.txns {
-                 for (index, output) in txn.outputs.iter().enumerate() {
-                    if self.chain_state.filters.contains(&output.lock_script) {
-                        new_utxos.push(Utxo { tx_out: output.to_owned(), hash: txn.hash(), index });
-                    }
+                // every downloaded block, decoys included, doubles as a fee sample
+                let position = batch.iter().position(|hash| *hash == block_hash).expect("requested from this batch");
+                self.fee_estimator.add_block(start_height + position as u64, &block);
+                downloaded.insert(block_hash, block);
+            }
+        }
+
+        // wallets are only updated once every block checked out
+        let mut default_wallet = self.chain_state.wallet.clone();
+        let mut wallets = self.wallets.clone();
+        let mut outpoints = self.outpoints.clone();
+        let mut pending = self.pending.clone();
+        // matching blocks are applied in chain order so spends within the batch are seen
+        for block_hash in blockhash_present.iter() {
+             let block = downloaded.remove(block_hash).ok_or(Error::BadData(format!("Block {:?} was not served", block_hash)))?;
+             let height = start_height + batch.iter().position(|hash| hash == block_hash).expect("matched in this batch") as u64;
+             let mut relevant_txids = default_wallet.apply_block(&block);
+             pending.apply_block(&block);
+             for txid in outpoints.apply_block(height, &block) {
+                 if !relevant_txids.contains(&txid) {
+                     relevant_txids.push(txid);
+                 }
+             }
+             for wallet in wallets.values_mut() {
+                 for txid in wallet.apply_block(&block) {
+                     if !relevant_txids.contains(&txid) {
+                         relevant_txids.push(txid);
+                     }
                  }
+             }
 
-                for input in txn.inputs {
-                   if input.prev_output.hash == NO_HASH_STOP {
-                       continue;
-                   } 
-
-                   //TODO: Ensure all inputs are included
-                   for (index,utxo) in  new_utxos.clone().iter().enumerate() {
-                        if utxo.hash == input.prev_output.hash && utxo.index as u32 == input.prev_output.index {
-                            new_utxos.remove(index);
-                        }       
-                   } 
-                }
+             if !relevant_txids.is_empty() {
+                 self.save_inclusion_proof(&block, &relevant_txids)?;
              }
         }
-        self.chain_state.utxos = new_utxos;
-        let binary_chain_state: Vec<u8> = bincode::serialize(&self.chain_state).map_err(|e| Error::SerializationError(e.to_string()))?;
-        self.db.insert(CHAIN_STATE_KEY.to_string(), binary_chain_state)?;
+        self.chain_state.wallet = default_wallet;
+        self.wallets = wallets;
+        self.outpoints = outpoints;
+        self.pending = pending;
+        self.save_chain_state()?;
+        self.save_outpoints()?;
+        self.save_pending()?;
+        for id in self.wallet_ids() {
+            self.save_wallet(Some(&id))?;
+        }
 
         Ok(())
 
     }
 
-    pub fn sync_state(& mut self) -> Result<(),Error> {
-        self.p2p.keep_alive().map_err(|_| Error::NetworkError)?;
+    /// Builds a merkle inclusion proof for the relevant transactions of a block and stores it per transaction
+    fn save_inclusion_proof(&self, block: &Block, txids: &[Hash256]) -> Result<(), Error> {
+        block.header.validate(&block.header.hash(), &[])?;
+        let merkle_block = MerkleBlock::from_block(block, txids)?;
+        let mut binary_proof = Vec::new();
+        merkle_block.write(&mut binary_proof)?;
+        for txid in txids {
+            self.db.insert(format!("{}{}", PROOF_KEY_PREFIX, txid.encode()), binary_proof.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the stored inclusion proof of a transaction found while syncing
+    pub fn get_inclusion_proof(&self, txid: Hash256) -> Result<MerkleBlock, Error> {
+        let binary_proof = self.db.get(format!("{}{}", PROOF_KEY_PREFIX, txid.encode()))?;
+        MerkleBlock::read(&mut binary_proof.as_slice())
+    }
 
-        let mut is_sync = true;
+    /// Fee rate in sat/vB expected to confirm within `target_blocks`.
+    ///
+    /// Samples come from the blocks downloaded while syncing. When fewer than `MIN_FEE_SAMPLES` recent
+    /// blocks were seen, the missing ones are downloaded from the tip down. The estimate never goes below
+    /// the highest fee filter of our peers, since they would not relay anything cheaper.
+    pub fn estimate_fee(&mut self, target_blocks: u32) -> Result<u64, Error> {
+        if target_blocks == 0 {
+            return Err(Error::BadArgument("Target must be at least one block".to_string()));
+        }
+        self.sync_state()?;
+
+        let tip_height = self.best_header_height;
+        let mut height = tip_height;
+        let mut hash = self.best_header_hash;
+        while height > 0 && self.fee_estimator.recent_samples(tip_height) < MIN_FEE_SAMPLES {
+            hash = match self.fee_estimator.sample(height) {
+                Some(sample) => sample.prev_hash,
+                None => {
+                    let blocks = self.p2p.get_block_from(0, Inv{ objects: vec![InvVect{ obj_type: 2, hash }]}).map_err(|err| Error::FetchBlock(err.to_error_code()))?;
+                    let block = blocks.into_iter().next().ok_or(Error::BadData(format!("Block {:?} was not served", hash)))?;
+                    if block.header.hash() != hash || block.merkle_root() != block.header.merkle_root {
+                        return Err(Error::BadData(format!("Block {:?} does not match its header", hash)));
+                    }
+                    self.fee_estimator.add_block(height, &block);
+                    block.header.prev_hash
+                },
+            };
+            height -= 1;
+        }
 
-        while is_sync {
+        // fee filters are in sat/kvB
+        let peer_fee_rate = self.p2p.peers_status().iter()
+            .filter_map(|peer| peer.features.fee_filter)
+            .max()
+            .map_or(0.0, |fee_filter| fee_filter as f64 / 1000.0);
+        let fee_rate = self.fee_estimator.estimate(target_blocks, tip_height, peer_fee_rate.max(MIN_RELAY_FEE_RATE));
+        Ok(fee_rate.ceil() as u64)
+    }
 
-            let fetched_block_headers = self.p2p.fetch_headers(self.chain_state.last_block_hash)
-            .map_err(|err| Error::FetchHeader(err.to_error_code()))?;
-            if fetched_block_headers.len() == 0 {
-                return Ok(());
-            }
+    /// Stays connected for `duration` seconds, processing new tips as the peer announces them
+    pub fn follow_tip(&mut self, duration: u64) -> Result<u64, Error> {
+        self.sync_state()?;
 
-            let last_block_hash = fetched_block_headers.last()
-                .expect("No block headers found")
-                .hash();
-
-            // Calculate the range for the for loop
-            let start_block = self.chain_state.last_block_height + 1;
-            let end_block = self.chain_state.last_block_height + fetched_block_headers.len() as u64;
-            // Generate ranges for block numbers and block headers counter
-            let block_numbers = (start_block..end_block).step_by(FILTER_SIZE as usize);
-            let block_headers_counters = (FILTER_SIZE..).step_by(FILTER_SIZE as usize);
-
-            // Use zip to iterate over both ranges simultaneously
-            for (current_block_num, block_headers_counter) in block_numbers.zip(block_headers_counters) {
-                // Get the last known block hash
-                let last_known_block_hash = fetched_block_headers
-                    .get(block_headers_counter as usize - 1)
-                    .map(|header| header.hash())
-                    .unwrap_or(last_block_hash);
-            
-                // Fetch and verify compact filters for the current range
-                let block_filters = self.get_and_verify_compact_filters(
-                    current_block_num as u32,
-                    last_known_block_hash,
-                )?;
-
-                if block_filters.is_empty() {
-                    continue;
-                }
-                        
-                // Fetch and save UTXOs for the verified block filters
-                self.fetch_and_save_utxos(block_filters)?;
+        let deadline = monotonic_clock::now() + duration * 1_000_000_000;
+        loop {
+            let now = monotonic_clock::now();
+            if now >= deadline {
+                break;
             }
-    
-            if fetched_block_headers.len() < MAX_HEADER_LEN {
-                is_sync = false;
+
+            let announcement = self.p2p.wait_for_announcement(deadline - now).map_err(|_| Error::NetworkError)?;
+            match announcement {
+                Some(BlockAnnouncement::Headers(headers)) if headers[0].prev_hash == self.tip_hash() => {
+                    self.connect_headers(&headers)?;
+                },
+                // unknown parent or hash-only announcement, catch up through getheaders
+                Some(_) => self.sync_state()?,
+                None => break,
             }
+        }
+
+        Ok(self.chain_state.last_block_height)
+    }
 
-            self.chain_state.last_block_height = end_block;
-            self.chain_state.last_block_hash = last_block_hash;
-        }  
+    /// Validates announced headers on top of the current tip and matches their filters right away
+    fn connect_headers(&mut self, headers: &[BlockHeader]) -> Result<(), Error> {
+        let prev_hash = check_headers(self.tip_hash(), headers, &self.pow_limit)?;
+        self.best_header_height = self.chain_state.last_block_height + headers.len() as u64;
+        self.best_header_hash = prev_hash;
 
-        
+        let start_height = self.chain_state.last_block_height + 1;
+        let block_filters = self.get_and_verify_compact_filters(start_height as u32, prev_hash)?;
+        if block_filters.len() != headers.len() {
+            return Err(Error::BadData("Missing filters for announced headers".to_string()));
+        }
+        self.fetch_and_save_utxos(start_height, block_filters)?;
 
-        let binary_chain_state: Vec<u8> = bincode::serialize(&self.chain_state).map_err(|e| Error::SerializationError(e.to_string()))?;
-        self.db.insert(CHAIN_STATE_KEY.to_string(), binary_chain_state)?;
+        self.chain_state.last_block_height += headers.len() as u64;
+        self.chain_state.last_block_hash = prev_hash;
+        self.save_chain_state()?;
 
         Ok(())
-        
+    }
+
+    // hash of the block the next header builds on, the genesis block on a fresh chain
+    fn tip_hash(&self) -> Hash256 {
+        if self.chain_state.last_block_hash == Hash256::default() {
+            return self.genesis_blockhash;
+        }
+        self.chain_state.last_block_hash
+    }
+
+    /// Syncs filters up to the tip of the peer
+    pub fn sync_state(& mut self) -> Result<(),Error> {
+        self.sync_step(u32::MAX).map(|_| ())
+    }
+
+    /// Matches at most `max_batches` filter batches of up to `FILTER_SIZE` blocks, fetching headers as needed.
+    ///
+    /// The filter tip is saved after every batch, so an interrupted or failed sync resumes at the first
+    /// batch that did not complete instead of starting over.
+    pub fn sync_step(&mut self, max_batches: u32) -> Result<SyncProgress, Error> {
+        self.p2p.keep_alive().map_err(|_| Error::NetworkError)?;
+
+        // headers left over from an earlier step are worked through before asking for more
+        if self.pending_headers.is_empty() {
+            self.tip_reached = false;
+        }
+
+        let mut batches = 0;
+        while batches < max_batches {
+            if self.pending_headers.is_empty() {
+                if self.tip_reached {
+                    break;
+                }
+                let fetched_block_headers = self.p2p.fetch_headers(self.chain_state.last_block_hash)
+                    .map_err(|err| Error::FetchHeader(err.to_error_code()))?;
+                self.tip_reached = fetched_block_headers.len() < MAX_HEADER_LEN;
+                if fetched_block_headers.is_empty() {
+                    break;
+                }
+                let last_hash = check_headers(self.tip_hash(), &fetched_block_headers, &self.pow_limit)?;
+                self.best_header_height = self.chain_state.last_block_height + fetched_block_headers.len() as u64;
+                self.best_header_hash = last_hash;
+                self.pending_headers = fetched_block_headers;
+            }
+
+            // the last batch can be a single header, which is matched like any other
+            let batch_len = self.pending_headers.len().min(FILTER_SIZE);
+            let start_height = self.chain_state.last_block_height + 1;
+            let stop_hash = self.pending_headers[batch_len - 1].hash();
+            let block_filters = self.get_and_verify_compact_filters(start_height as u32, stop_hash)?;
+            if block_filters.len() != batch_len {
+                return Err(Error::BadData("Missing filters for fetched headers".to_string()));
+            }
+            self.fetch_and_save_utxos(start_height, block_filters)?;
+
+            self.pending_headers.drain(..batch_len);
+            self.chain_state.last_block_height += batch_len as u64;
+            self.chain_state.last_block_hash = stop_hash;
+            self.save_chain_state()?;
+            batches += 1;
+        }
+
+        Ok(self.sync_progress())
+    }
+
+    fn sync_progress(&self) -> SyncProgress {
+        // peers report their height at connection time, before any header was fetched
+        let peer_height = self.p2p.peers_status().iter()
+            .map(|peer| peer.features.start_height.max(0) as u64)
+            .max()
+            .unwrap_or(0);
+        SyncProgress {
+            filter_height: self.chain_state.last_block_height,
+            best_header_height: self.best_header_height,
+            target_height: self.best_header_height.max(peer_height),
+            done: self.pending_headers.is_empty() && self.tip_reached,
+        }
     }
 
 
     
 }
+
+// Checks that headers follow each other from `prev_hash` and meet their proof of work target, returning the hash of the last one.
+// The target of a header must not be easier than `pow_limit`, otherwise anyone could mine a chain of them at no cost.
+fn check_headers(mut prev_hash: Hash256, headers: &[BlockHeader], pow_limit: &Hash256) -> Result<Hash256, Error> {
+    for header in headers {
+        if header.prev_hash != prev_hash {
+            return Err(Error::BadData("Headers do not connect".to_string()));
+        }
+        header.validate_target(pow_limit)?;
+        prev_hash = header.hash();
+        header.validate(&prev_hash, &[])?;
+    }
+    Ok(prev_hash)
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    bincode::deserialize(bytes).map_err(|e| Error::SerializationError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // regtest difficulty, met by about every other nonce
+    const REGTEST_BITS: u32 = 0x207fffff;
+
+    fn mine(prev_hash: Hash256) -> BlockHeader {
+        let mut header = BlockHeader { version: 0x20000000, prev_hash, merkle_root: Hash256([1; 32]), timestamp: 1_700_000_000, bits: REGTEST_BITS, nonce: 0 };
+        while header.validate(&header.hash(), &[]).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn headers_after(prev_hash: Hash256, count: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for _ in 0..count {
+            let prev_hash = headers.last().map_or(prev_hash, |header| header.hash());
+            headers.push(mine(prev_hash));
+        }
+        headers
+    }
+
+    fn regtest_pow_limit() -> Hash256 {
+        util::pow_limit(bitcoin_network::Network::Regtest)
+    }
+
+    #[test]
+    fn connected_headers() {
+        let tip = Hash256([7; 32]);
+        let headers = headers_after(tip, 3);
+        assert_eq!(check_headers(tip, &headers, &regtest_pow_limit()).unwrap(), headers[2].hash());
+        assert!(check_headers(Hash256([8; 32]), &headers, &regtest_pow_limit()).is_err());
+        // a fresh chain builds on the genesis block, not on whatever the first header points at
+        assert!(check_headers(Hash256::default(), &headers, &regtest_pow_limit()).is_err());
+    }
+
+    #[test]
+    fn broken_headers() {
+        let tip = Hash256([7; 32]);
+        let mut headers = headers_after(tip, 3);
+        headers.remove(1);
+        assert!(check_headers(tip, &headers, &regtest_pow_limit()).is_err());
+
+        // mainnet difficulty is not met by chance
+        let mut headers = headers_after(tip, 2);
+        headers[1].bits = 0x1d00ffff;
+        assert!(check_headers(tip, &headers, &regtest_pow_limit()).is_err());
+
+        // regtest headers meet their own target, which is far easier than the mainnet limit
+        let headers = headers_after(tip, 2);
+        assert!(check_headers(tip, &headers, &util::pow_limit(bitcoin_network::Network::Bitcoin)).is_err());
+    }
+}
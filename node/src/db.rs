@@ -1,8 +1,25 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{cell::RefCell, collections::{BTreeMap, HashSet}, sync::Arc};
 use crate::{bindings, util::Error};
 
-use bindings::component::kv::types::Kvstore ;
+use bindings::component::kv::types::{BatchOp, Error as StoreError, KeyValue, Kvstore};
 
+/// Where the chain keeps its state, so that hosts without filesystem access can keep it in memory
+pub trait Store {
+    fn insert(&self, key: String, value: Vec<u8>) -> Result<(), Error>;
+
+    /// Retrieve a value by its key, a missing key is `Error::DBError(StoreError::EntryNotFound)`
+    fn get(&self, key: String) -> Result<Vec<u8>, Error>;
+
+    fn delete(&self, key: String) -> Result<(), Error>;
+
+    /// Every key-value pair in the store, used for state snapshots
+    fn entries(&self) -> Result<Vec<(String, Vec<u8>)>, Error>;
+
+    /// Replace every key-value pair at once, used to restore snapshots
+    fn replace_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error>;
+}
+
+/// State persisted through the kv component
 pub struct KeyValueDb {
     conn: Arc<Kvstore>
 }
@@ -12,16 +29,63 @@ impl KeyValueDb {
     pub fn new(store: RefCell<Kvstore>) -> Self {
         Self{ conn: Arc::new(store.into_inner()) }
     }
-    pub fn insert(&self, key: String, value: Vec<u8>) -> Result<(), Error> {
-        self.conn.insert(&key, &value).map_err(|err| Error::DBError(err))        
+}
+
+impl Store for KeyValueDb {
+    fn insert(&self, key: String, value: Vec<u8>) -> Result<(), Error> {
+        self.conn.insert(&key, &value).map_err(Error::DBError)
     }
-     /// Retrieve a value by its key.
-    pub fn get(&self, key: String) -> Result<Vec<u8>, Error> { 
-            self.conn.get(&key).map_err(|err| Error::DBError(err))
-      }
 
-     /// Delete a key-value pair by its key.
-     fn delete(&self, key: String) -> Result<(), Error> {
-        self.conn.delete(&key).map_err(|err| Error::DBError(err))
-     }
-}
\ No newline at end of file
+    fn get(&self, key: String) -> Result<Vec<u8>, Error> {
+        self.conn.get(&key).map_err(Error::DBError)
+    }
+
+    fn delete(&self, key: String) -> Result<(), Error> {
+        self.conn.delete(&key).map_err(Error::DBError)
+    }
+
+    fn entries(&self) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.conn.scan("").map_err(Error::DBError)
+    }
+
+    fn replace_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error> {
+        let kept: HashSet<&String> = entries.iter().map(|(key, _)| key).collect();
+        let mut ops: Vec<BatchOp> = self.entries()?.into_iter()
+            .filter(|(key, _)| !kept.contains(key))
+            .map(|(key, _)| BatchOp::Delete(key))
+            .collect();
+        ops.extend(entries.iter().map(|(key, value)| BatchOp::Put(KeyValue { key: key.clone(), value: value.clone() })));
+        // a single batch, so that a failing write leaves the previous state in place
+        self.conn.write_batch(&ops).map_err(Error::DBError)
+    }
+}
+
+/// State kept in memory only, lost when the component is dropped unless exported as a snapshot
+#[derive(Default)]
+pub struct MemoryDb {
+    entries: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Store for MemoryDb {
+    fn insert(&self, key: String, value: Vec<u8>) -> Result<(), Error> {
+        self.entries.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Vec<u8>, Error> {
+        self.entries.borrow().get(&key).cloned().ok_or(Error::DBError(StoreError::EntryNotFound))
+    }
+
+    fn delete(&self, key: String) -> Result<(), Error> {
+        self.entries.borrow_mut().remove(&key).map(|_| ()).ok_or(Error::DBError(StoreError::EntryNotFound))
+    }
+
+    fn entries(&self) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        Ok(self.entries.borrow().iter().map(|(key, value)| (key.clone(), value.clone())).collect())
+    }
+
+    fn replace_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), Error> {
+        *self.entries.borrow_mut() = entries.into_iter().collect();
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::network as bitcoin_network;
+
+use crate::messages::block::Block;
+use crate::util::Hash256;
+
+/// Most recent blocks kept for estimates
+pub const MAX_FEE_SAMPLES: usize = 144;
+/// Blocks below the tip considered recent enough to estimate from
+pub const FEE_SAMPLE_WINDOW: u64 = 144;
+/// Recent blocks wanted before estimating, missing ones are downloaded from the tip down
+pub const MIN_FEE_SAMPLES: usize = 6;
+/// Lowest fee rate nodes relay by default, in sat/vB
+pub const MIN_RELAY_FEE_RATE: f64 = 1.0;
+
+const INITIAL_SUBSIDY: i64 = 50 * 100_000_000;
+const SUBSIDY_HALVING_INTERVAL: u64 = 210_000;
+const REGTEST_SUBSIDY_HALVING_INTERVAL: u64 = 150;
+
+/// Fee rates paid in one block
+#[derive(Debug, Clone)]
+pub struct FeeSample {
+    pub height: u64,
+    /// Parent of the sampled block, so that the tip can be walked back past samples
+    pub prev_hash: Hash256,
+    /// Fee rate in sat/vB and virtual size of the transactions
+    rates: Vec<(f64, u64)>,
+}
+
+/// Estimates fee rates from the blocks the light client downloads anyway.
+///
+/// Spent output values are unknown without a transaction index, so the fees of a block come from its
+/// coinbase: everything claimed above the subsidy. Transactions spending only outputs of the same block
+/// get their exact fee rate, the remaining fees are spread over the other transactions by virtual size.
+pub struct FeeEstimator {
+    samples: VecDeque<FeeSample>,
+    halving_interval: u64,
+}
+
+impl FeeEstimator {
+
+    pub fn new(network: bitcoin_network::Network) -> Self {
+        let halving_interval = match network {
+            bitcoin_network::Network::Regtest => REGTEST_SUBSIDY_HALVING_INTERVAL,
+            _ => SUBSIDY_HALVING_INTERVAL,
+        };
+        Self { samples: VecDeque::new(), halving_interval }
+    }
+
+    /// Forgets every sample, e.g. when the synced chain is replaced
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn sample(&self, height: u64) -> Option<&FeeSample> {
+        self.samples.iter().find(|sample| sample.height == height)
+    }
+
+    /// Number of samples within the window below `tip_height`
+    pub fn recent_samples(&self, tip_height: u64) -> usize {
+        self.samples.iter().filter(|sample| sample.height + FEE_SAMPLE_WINDOW > tip_height).count()
+    }
+
+    /// Records the fee rates of a downloaded block, keeping the most recent samples.
+    /// Blocks whose amounts do not add up without overflowing are skipped.
+    pub fn add_block(&mut self, height: u64, block: &Block) {
+        if self.sample(height).is_some() {
+            return;
+        }
+        let Some(rates) = self.block_fee_rates(height, block) else {
+            return;
+        };
+        let sample = FeeSample { height, prev_hash: block.header.prev_hash, rates };
+        let position = self.samples.iter().position(|sample| sample.height > height).unwrap_or(self.samples.len());
+        self.samples.insert(position, sample);
+        while self.samples.len() > MAX_FEE_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Fee rate in sat/vB to confirm within `target_blocks`, never below `min_fee_rate`.
+    ///
+    /// The estimate is the fee rate under which `50% / target_blocks` (at least 5%) of the recent block space
+    /// was paid, so the next block gets the median and longer targets settle for cheaper space.
+    pub fn estimate(&self, target_blocks: u32, tip_height: u64, min_fee_rate: f64) -> f64 {
+        let mut rates: Vec<(f64, u64)> = self.samples.iter()
+            .filter(|sample| sample.height + FEE_SAMPLE_WINDOW > tip_height)
+            .flat_map(|sample| sample.rates.iter().copied())
+            .collect();
+        if rates.is_empty() {
+            return min_fee_rate;
+        }
+        rates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let quantile = (0.5 / target_blocks.max(1) as f64).max(0.05);
+        let total_vsize: u64 = rates.iter().map(|(_, vsize)| vsize).sum();
+        let wanted = (total_vsize as f64 * quantile).ceil() as u64;
+        let mut vsize = 0;
+        for (rate, tx_vsize) in rates.iter() {
+            vsize += tx_vsize;
+            if vsize >= wanted {
+                return rate.max(min_fee_rate);
+            }
+        }
+        min_fee_rate
+    }
+
+    fn subsidy(&self, height: u64) -> i64 {
+        match height / self.halving_interval {
+            halvings if halvings >= 64 => 0,
+            halvings => INITIAL_SUBSIDY >> halvings,
+        }
+    }
+
+    // None if the amounts served by the peer overflow when added up
+    fn block_fee_rates(&self, height: u64, block: &Block) -> Option<Vec<(f64, u64)>> {
+        let Some((coinbase, txns)) = block.txns.split_first() else {
+            return Some(vec![]);
+        };
+        let claimed = checked_sum(coinbase.outputs.iter().map(|output| output.satoshis))?;
+        let block_fees = claimed.checked_sub(self.subsidy(height))?.max(0);
+
+        let created: HashMap<(Hash256, u32), i64> = txns.iter()
+            .flat_map(|txn| {
+                let txid = txn.hash();
+                txn.outputs.iter().enumerate().map(move |(index, output)| ((txid, index as u32), output.satoshis))
+            })
+            .collect();
+
+        let mut rates = Vec::with_capacity(txns.len());
+        let mut estimated = Vec::new();
+        let mut exact_fees: i64 = 0;
+        for txn in txns {
+            let vsize = txn.vsize() as u64;
+            let spent: Option<Vec<i64>> = txn.inputs.iter()
+                .map(|input| created.get(&(input.prev_output.hash, input.prev_output.index)).copied())
+                .collect();
+            match spent {
+                Some(spent) => {
+                    let fee = checked_sum(spent)?.checked_sub(checked_sum(txn.outputs.iter().map(|output| output.satoshis))?)?.max(0);
+                    exact_fees = exact_fees.checked_add(fee)?;
+                    rates.push((fee as f64 / vsize as f64, vsize));
+                },
+                None => estimated.push(vsize),
+            }
+        }
+
+        let estimated_vsize: u64 = estimated.iter().sum();
+        if estimated_vsize > 0 {
+            let average_rate = (block_fees - exact_fees).max(0) as f64 / estimated_vsize as f64;
+            rates.extend(estimated.into_iter().map(|vsize| (average_rate, vsize)));
+        }
+        Some(rates)
+    }
+}
+
+fn checked_sum(amounts: impl IntoIterator<Item = i64>) -> Option<i64> {
+    amounts.into_iter().try_fold(0i64, |sum, amount| sum.checked_add(amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{tx::Tx, tx_in::TxIn, tx_out::TxOut, BlockHeader, OutPoint, COINBASE_OUTPOINT_HASH, COINBASE_OUTPOINT_INDEX};
+
+    fn tx(prev_output: OutPoint, satoshis: i64) -> Tx {
+        Tx {
+            version: 2,
+            flag: None,
+            inputs: vec![TxIn { prev_output, unlock_script: vec![0; 100], sequence: 0xffffffff }],
+            outputs: vec![TxOut { satoshis, lock_script: vec![0x51] }],
+            witnesses: None,
+            lock_time: 0,
+        }
+    }
+
+    fn block(claimed: i64, txns: Vec<Tx>) -> Block {
+        let coinbase = tx(OutPoint { hash: COINBASE_OUTPOINT_HASH, index: COINBASE_OUTPOINT_INDEX }, claimed);
+        Block { header: BlockHeader::default(), txns: [vec![coinbase], txns].concat() }
+    }
+
+    #[test]
+    fn coinbase_fees() {
+        let mut estimator = FeeEstimator::new(bitcoin_network::Network::Bitcoin);
+        let spend = tx(OutPoint { hash: Hash256([1; 32]), index: 0 }, 1000);
+        let vsize = spend.vsize() as f64;
+        estimator.add_block(1, &block(INITIAL_SUBSIDY + 10 * vsize as i64, vec![spend]));
+        assert_eq!(estimator.estimate(1, 1, MIN_RELAY_FEE_RATE), 10.0);
+        // the floor wins over cheaper blocks
+        assert_eq!(estimator.estimate(1, 1, 20.0), 20.0);
+        // samples outside the window are ignored
+        assert_eq!(estimator.estimate(1, 1 + FEE_SAMPLE_WINDOW, MIN_RELAY_FEE_RATE), MIN_RELAY_FEE_RATE);
+    }
+
+    #[test]
+    fn halvings() {
+        let mut estimator = FeeEstimator::new(bitcoin_network::Network::Regtest);
+        let spend = tx(OutPoint { hash: Hash256([1; 32]), index: 0 }, 1000);
+        let vsize = spend.vsize() as i64;
+        estimator.add_block(300, &block(INITIAL_SUBSIDY / 4 + 5 * vsize, vec![spend]));
+        assert_eq!(estimator.estimate(1, 300, MIN_RELAY_FEE_RATE), 5.0);
+    }
+
+    #[test]
+    fn exact_fees_in_block() {
+        let mut estimator = FeeEstimator::new(bitcoin_network::Network::Bitcoin);
+        let parent = tx(OutPoint { hash: Hash256([1; 32]), index: 0 }, 100_000);
+        let child = tx(OutPoint { hash: parent.hash(), index: 0 }, 100_000 - 50 * parent.vsize() as i64);
+        let vsize = parent.vsize() as i64;
+        // the child pays 50 sat/vB, which leaves 2 sat/vB for its parent
+        estimator.add_block(1, &block(INITIAL_SUBSIDY + 52 * vsize, vec![parent, child]));
+        assert_eq!(estimator.estimate(1, 1, MIN_RELAY_FEE_RATE), 2.0);
+        assert_eq!(estimator.sample(1).unwrap().rates.iter().map(|(rate, _)| *rate).fold(0.0, f64::max), 50.0);
+    }
+
+    #[test]
+    fn overflowing_amounts() {
+        let mut estimator = FeeEstimator::new(bitcoin_network::Network::Bitcoin);
+        let parent = tx(OutPoint { hash: Hash256([1; 32]), index: 0 }, i64::MAX);
+        let child = tx(OutPoint { hash: parent.hash(), index: 0 }, i64::MIN);
+        estimator.add_block(1, &block(INITIAL_SUBSIDY, vec![parent, child]));
+        let mut coinbase = block(i64::MAX, vec![]);
+        coinbase.txns[0].outputs.push(TxOut { satoshis: 1, lock_script: vec![0x51] });
+        estimator.add_block(2, &coinbase);
+        assert!(estimator.sample(1).is_none());
+        assert!(estimator.sample(2).is_none());
+    }
+
+    #[test]
+    fn targets() {
+        let mut estimator = FeeEstimator::new(bitcoin_network::Network::Bitcoin);
+        for (height, rate) in [(1, 40), (2, 10), (3, 30), (4, 20)] {
+            let spend = tx(OutPoint { hash: Hash256([height as u8; 32]), index: 0 }, 1000);
+            let vsize = spend.vsize() as i64;
+            estimator.add_block(height, &block(INITIAL_SUBSIDY + rate * vsize, vec![spend]));
+        }
+        assert_eq!(estimator.estimate(1, 4, MIN_RELAY_FEE_RATE), 20.0);
+        assert_eq!(estimator.estimate(2, 4, MIN_RELAY_FEE_RATE), 10.0);
+        assert!(estimator.estimate(1, 4, MIN_RELAY_FEE_RATE) >= estimator.estimate(6, 4, MIN_RELAY_FEE_RATE));
+    }
+}
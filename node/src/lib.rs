@@ -1,41 +1,161 @@
 #[allow(warnings)]
 mod bindings;
-use std::{cell::RefCell};
+use std::{cell::RefCell, rc::Rc, sync::Arc};
 
+use db::{KeyValueDb, MemoryDb, Store};
 use node::Node;
-use bindings::exports::component::node::types::{Guest, GuestClientNode, NodeConfig};
+use bindings::exports::component::node::types::{Guest, GuestClientNode, GuestWallet, NodeConfig, NodeStatus, SpendRecord, SyncProgress, Wallet};
 use bindings::component::kv::types::{Kvstore };
 
 mod node;
 mod p2p;
 mod tcpsocket;
-mod util;
-mod messages;
+// protocol types are public so the test runner's fake peer speaks the same wire format
+pub mod util;
+pub mod messages;
 mod chain;
 mod db;
+mod wallet;
+mod privacy;
+mod fee;
+mod snapshot;
+mod pending;
 struct Component;
 
 struct BitcoinNode {
-    inner: RefCell<Node>,
+    inner: Rc<RefCell<Node>>,
+}
+
+/// A wallet namespace sharing the header and filter sync of its node
+struct BitcoinWallet {
+    node: Rc<RefCell<Node>>,
+    id: String,
+}
+
+impl GuestWallet for BitcoinWallet {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_balance(&self) -> Result<i64, u32> {
+        return  self.node.borrow_mut().balance(Some(&self.id)).map_err(|err| err.to_error_code());
+    }
+
+    fn watch_address(&self, address: String) -> Result<(), u32> {
+        return  self.node.borrow_mut().watch_address(Some(&self.id), address).map_err(|err| err.to_error_code());
+    }
+
+    fn watch_descriptor(&self, descriptor: String, range: u32) -> Result<u32, u32> {
+        return  self.node.borrow_mut().watch_descriptor(Some(&self.id), descriptor, range).map_err(|err| err.to_error_code());
+    }
+
+    fn remove_watch(&self, target: String, range: u32) -> Result<u32, u32> {
+        return  self.node.borrow_mut().remove_watch(Some(&self.id), target, range).map_err(|err| err.to_error_code());
+    }
 }
 
 impl GuestClientNode for BitcoinNode {
     fn get_balance(&self) -> Result<i64, u32> {
-        return  self.inner.borrow_mut().balance().map_err(|err| err.to_error_code());
+        return  self.inner.borrow_mut().balance(None).map_err(|err| err.to_error_code());
+    }
+
+    fn create_wallet(&self, id: String) -> Result<Wallet, u32> {
+        self.inner.borrow_mut().create_wallet(&id).map_err(|err| err.to_error_code())?;
+        Ok(Wallet::new(BitcoinWallet { node: self.inner.clone(), id }))
+    }
+
+    fn open_wallet(&self, id: String) -> Result<Wallet, u32> {
+        self.inner.borrow().open_wallet(&id).map_err(|err| err.to_error_code())?;
+        Ok(Wallet::new(BitcoinWallet { node: self.inner.clone(), id }))
+    }
+
+    fn list_wallets(&self) -> Vec<String> {
+        self.inner.borrow().list_wallets()
     }
 
     fn add_filter(&self, filter: String) -> Result<(), u32> {
         return  self.inner.borrow_mut().add_filter(filter).map_err(|err| err.to_error_code());
     }
 
+    fn watch_address(&self, address: String) -> Result<(), u32> {
+        return  self.inner.borrow_mut().watch_address(None, address).map_err(|err| err.to_error_code());
+    }
+
+    fn watch_descriptor(&self, descriptor: String, range: u32) -> Result<u32, u32> {
+        return  self.inner.borrow_mut().watch_descriptor(None, descriptor, range).map_err(|err| err.to_error_code());
+    }
+
+    fn remove_watch(&self, target: String, range: u32) -> Result<u32, u32> {
+        return  self.inner.borrow_mut().remove_watch(None, target, range).map_err(|err| err.to_error_code());
+    }
+
+    fn follow_tip(&self, duration_secs: u32) -> Result<u64, u32> {
+        return  self.inner.borrow_mut().follow_tip(duration_secs.into()).map_err(|err| err.to_error_code());
+    }
+
+    fn sync_step(&self, max_work: u32) -> Result<SyncProgress, u32> {
+        return  self.inner.borrow_mut().sync_step(max_work).map(|progress| progress.into()).map_err(|err| err.to_error_code());
+    }
+
+    fn estimate_fee(&self, target_blocks: u32) -> Result<u64, u32> {
+        return  self.inner.borrow_mut().estimate_fee(target_blocks).map_err(|err| err.to_error_code());
+    }
+
+    fn watch_outpoint(&self, txid: String, vout: u32, script: String) -> Result<(), u32> {
+        return  self.inner.borrow_mut().watch_outpoint(txid, vout, script).map_err(|err| err.to_error_code());
+    }
+
+    fn get_spend(&self, txid: String, vout: u32) -> Result<Option<SpendRecord>, u32> {
+        return  self.inner.borrow_mut().get_spend(txid, vout).map(|spend| spend.map(|spend| spend.into())).map_err(|err| err.to_error_code());
+    }
+
+    fn get_status(&self) -> Result<NodeStatus, u32> {
+        return  Ok(self.inner.borrow().status().into());
+    }
+
+    fn add_local_tx(&self, tx: String) -> Result<String, u32> {
+        return  self.inner.borrow_mut().add_local_tx(tx).map_err(|err| err.to_error_code());
+    }
+
+    fn remove_local_tx(&self, txid: String) -> Result<Vec<String>, u32> {
+        return  self.inner.borrow_mut().remove_local_tx(txid).map_err(|err| err.to_error_code());
+    }
+
+    fn list_pending(&self) -> Vec<String> {
+        self.inner.borrow().list_pending()
+    }
+
+    fn export_state(&self, key: Vec<u8>) -> Result<Vec<u8>, u32> {
+        return  self.inner.borrow_mut().export_state(key).map_err(|err| err.to_error_code());
+    }
+
+    fn import_state(&self, snapshot: Vec<u8>, key: Vec<u8>) -> Result<(), u32> {
+        return  self.inner.borrow_mut().import_state(snapshot, key).map_err(|err| err.to_error_code());
+    }
+
+    fn get_inclusion_proof(&self, txid: String) -> Result<String, u32> {
+        return  self.inner.borrow_mut().get_inclusion_proof(txid).map_err(|err| err.to_error_code());
+    }
+
+    fn verify_inclusion_proof(&self, proof: String) -> Result<Vec<String>, u32> {
+        return  self.inner.borrow_mut().verify_inclusion_proof(proof).map_err(|err| err.to_error_code());
+    }
+
     fn new(config: NodeConfig) -> Self {
-        Self{ inner:  Node::new(config.into(), Kvstore::new().into()).into()}
+        let config: node::NodeConfig = config.into();
+        // ephemeral nodes never open the kv store, some hosts give no filesystem access
+        let store: Arc<dyn Store> = match config.ephemeral {
+            true => Arc::new(MemoryDb::default()),
+            false => Arc::new(KeyValueDb::new(Kvstore::open_default().expect("Failed to open the kv store").into())),
+        };
+        Self{ inner:  Rc::new(Node::new(config, store).into())}
     }
 }
 
 impl Guest for Component {
     
     type ClientNode  = BitcoinNode;
+    type Wallet = BitcoinWallet;
    
 }
 
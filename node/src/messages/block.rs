@@ -1,12 +1,16 @@
 use crate::messages::{BlockHeader, Payload};
 use crate::util::{
-    var_int, Result, Serializable,
+    sha256d, var_int, Error, Hash256, Result, Serializable,
 };
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 
-use super::tx::Tx;
+use super::tx::{Tx, MIN_TX_SIZE};
+
+/// Max serialized block size including witness data (4MB)
+pub const MAX_BLOCK_SIZE: usize = 4_000_000;
 
 /// Block of transactions
 #[derive(Default, PartialEq, Eq, Hash, Clone)]
@@ -17,6 +21,34 @@ pub struct Block {
     pub txns: Vec<Tx>,
 }
 
+impl Block {
+    /// Calculates the merkle root from the transactions
+    pub fn merkle_root(&self) -> Hash256 {
+        let mut row = VecDeque::new();
+        for tx in self.txns.iter() {
+            row.push_back(tx.hash());
+        }
+        while row.len() > 1 {
+            let mut n = row.len();
+            while n > 0 {
+                n -= 1;
+                let h1 = row.pop_front().unwrap();
+                let h2 = if n == 0 {
+                    h1
+                } else {
+                    n -= 1;
+                    row.pop_front().unwrap()
+                };
+                let mut h = Vec::with_capacity(64);
+                h1.write(&mut h).unwrap();
+                h2.write(&mut h).unwrap();
+                row.push_back(sha256d(&h));
+            }
+        }
+        row.pop_front().unwrap_or_default()
+    }
+}
+
 // impl Block {
 //     /// Returns a set of the inputs spent in this block
 //     pub fn inputs(&self) -> Result<HashSet<OutPoint>> {
@@ -97,38 +129,16 @@ pub struct Block {
 
     //     Ok(())
     // }
-
-    // // Calculates the merkle root from the transactions
-    // fn merkle_root(&self) -> Hash256 {
-    //     let mut row = VecDeque::new();
-    //     for tx in self.txns.iter() {
-    //         row.push_back(tx.hash());
-    //     }
-    //     while row.len() > 1 {
-    //         let mut n = row.len();
-    //         while n > 0 {
-    //             n -= 1;
-    //             let h1 = row.pop_front().unwrap();
-    //             let h2 = if n == 0 {
-    //                 h1.clone()
-    //             } else {
-    //                 n -= 1;
-    //                 row.pop_front().unwrap()
-    //             };
-    //             let mut h = Vec::with_capacity(64);
-    //             h1.write(&mut h).unwrap();
-    //             h2.write(&mut h).unwrap();
-    //             row.push_back(sha256d(&h));
-    //         }
-    //     }
-    //     return row.pop_front().unwrap();
-    // }
 // }
 
 impl Serializable<Block> for Block {
     fn read(reader: &mut dyn Read) -> Result<Block> {
         let header = BlockHeader::read(reader)?;
         let txn_count = var_int::read(reader)?;
+        if txn_count > (MAX_BLOCK_SIZE / MIN_TX_SIZE) as u64 {
+            let msg = format!("Txn count exceeded maximum: {}", txn_count);
+            return Err(Error::BadData(msg));
+        }
         let mut txns = Vec::with_capacity(txn_count as usize);
         for _i in 0..txn_count {
             txns.push(Tx::read(reader)?);
@@ -64,6 +64,15 @@ impl BlockHeader {
         Ok(())
     }
 
+    /// Checks that the target difficulty is not easier than `pow_limit`, the easiest target of the network.
+    /// `validate` only checks the hash against the target the header claims, which anyone can set low enough to mine at no cost.
+    pub fn validate_target(&self, pow_limit: &Hash256) -> Result<()> {
+        if &self.difficulty_target()? > pow_limit {
+            return Err(Error::BadData("Target above the POW limit".to_string()));
+        }
+        Ok(())
+    }
+
     /// Calculates the target difficulty hash
     fn difficulty_target(&self) -> Result<Hash256> {
         let exp = (self.bits >> 24) as usize;
@@ -195,4 +204,14 @@ mod tests {
         h.nonce = 0;
         assert!(h.validate(&h.hash(), &headers).is_err());
     }
+    #[test]
+    fn validate_target() {
+        let pow_limit = Hash256::decode("00000000ffff0000000000000000000000000000000000000000000000000000").unwrap();
+        let header = BlockHeader { bits: 0x1d00ffff, ..Default::default() };
+        assert!(header.validate_target(&pow_limit).is_ok());
+
+        // the regtest target is far easier than the mainnet limit
+        let header = BlockHeader { bits: 0x207fffff, ..Default::default() };
+        assert!(header.validate_target(&pow_limit).is_err());
+    }
 }
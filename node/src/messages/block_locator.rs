@@ -8,6 +8,9 @@ use std::io::{Read, Write};
 /// Return results until either there are 2000 for getheaders or 500 or getblocks, or no more left
 pub const NO_HASH_STOP: Hash256 = Hash256([0; 32]);
 
+/// Maximum number of hashes in a block locator
+pub const MAX_LOCATOR_HASHES: u64 = 101;
+
 /// Specifies which blocks to return
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct BlockLocator {
@@ -34,7 +37,11 @@ impl Serializable<BlockLocator> for BlockLocator {
     fn read(reader: &mut dyn Read) -> Result<BlockLocator> {
         let version = reader.read_u32::<LittleEndian>()?;
         let num_hashes = var_int::read(reader)?;
-        let mut block_locator_hashes = Vec::new();
+        if num_hashes > MAX_LOCATOR_HASHES {
+            let msg = format!("Num locator hashes exceeded maximum: {}", num_hashes);
+            return Err(Error::BadData(msg));
+        }
+        let mut block_locator_hashes = Vec::with_capacity(num_hashes as usize);
         for _i in 0..num_hashes {
             block_locator_hashes.push(Hash256::read(reader)?);
         }
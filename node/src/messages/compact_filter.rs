@@ -1,8 +1,8 @@
-use crate::util::{var_int, Hash256, Result, Serializable};
+use crate::util::{var_int, Error, Hash256, Result, Serializable};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use std::io;
 use std::io::{Read, Write};
-use crate::messages::message::Payload;
+use crate::messages::message::{Payload, MAX_PAYLOAD_SIZE};
 
 
 /// Block header
@@ -28,10 +28,12 @@ impl Serializable<CompactFilter> for CompactFilter {
         let filter_type = reader.read_u8()?;
         let block_hash = Hash256::read(reader)?;
         let filter_len = var_int::read(reader)?;
-        let mut filter_bytes = Vec::new();
-        for _i in 0..filter_len {
-            filter_bytes.push(reader.read_u8()?);
+        if filter_len > MAX_PAYLOAD_SIZE as u64 {
+            let msg = format!("Filter size exceeded maximum: {}", filter_len);
+            return Err(Error::BadData(msg));
         }
+        let mut filter_bytes = vec![0; filter_len as usize];
+        reader.read_exact(&mut filter_bytes)?;
         Ok(CompactFilter {
             filter_type,
             filter_bytes,
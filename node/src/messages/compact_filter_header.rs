@@ -1,9 +1,11 @@
-use crate::util::{var_int, Hash256, Result, Serializable};
+use crate::util::{var_int, Error, Hash256, Result, Serializable};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use std::io;
 use std::io::{Read, Write};
 use crate::messages::message::Payload;
 
+/// Maximum number of filter hashes in a cfheaders message
+pub const MAX_CFHEADERS: u64 = 1000;
 
 /// Block header
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
@@ -31,7 +33,11 @@ impl Serializable<CompactFilterHeader> for CompactFilterHeader {
         let stop_hash = Hash256::read(reader)?;
         let previous_filter_header = Hash256::read(reader)?;
         let filter_len = var_int::read(reader)?;
-        let mut filter_hashes = Vec::new();
+        if filter_len > MAX_CFHEADERS {
+            let msg = format!("Num filter hashes exceeded maximum: {}", filter_len);
+            return Err(Error::BadData(msg));
+        }
+        let mut filter_hashes = Vec::with_capacity(filter_len as usize);
         for _i in 0..filter_len {
             filter_hashes.push(Hash256::read(reader)?);
         }
@@ -6,6 +6,9 @@ use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 
+/// Maximum number of headers in a headers message
+pub const MAX_HEADERS: u64 = 2000;
+
 /// Collection of block headers
 #[derive(Default, PartialEq, Eq, Hash, Clone)]
 pub struct Headers {
@@ -16,10 +19,14 @@ pub struct Headers {
 impl Serializable<Headers> for Headers {
     fn read(reader: &mut dyn Read) -> Result<Headers> {
         let n = var_int::read(reader)?;
-        let mut headers = Vec::new();
+        if n > MAX_HEADERS {
+            let msg = format!("Num headers exceeded maximum: {}", n);
+            return Err(Error::BadData(msg));
+        }
+        let mut headers = Vec::with_capacity(n as usize);
         for _i in 0..n {
             headers.push(BlockHeader::read(reader)?);
-            let _txn_count = reader.read_u8();
+            let _txn_count = reader.read_u8()?;
         }
         Ok(Headers { inner: headers })
     }
@@ -144,4 +151,11 @@ mod tests {
         assert!(header_hash(1, &headers).unwrap() == header2.hash());
         assert!(header_hash(2, &headers).is_err());
     }
+
+    #[test]
+    fn too_many_headers() {
+        let mut v = Vec::new();
+        var_int::write(MAX_HEADERS + 1, &mut v).unwrap();
+        assert!(Headers::read(&mut Cursor::new(&v)).is_err());
+    }
 }
@@ -0,0 +1,381 @@
+use crate::messages::block::{Block, MAX_BLOCK_SIZE};
+use crate::messages::block_header::BlockHeader;
+use crate::messages::message::Payload;
+use crate::messages::tx::MIN_TX_SIZE;
+use crate::util::{sha256d, var_int, Error, Hash256, Result, Serializable};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+/// A block header and partial merkle tree proving that transactions are included in a block
+///
+/// This is the payload of the `merkleblock` message and the same encoding returned by
+/// `gettxoutproof`.
+#[derive(Default, PartialEq, Eq, Hash, Clone)]
+pub struct MerkleBlock {
+    /// Block header
+    pub header: BlockHeader,
+    /// Number of transactions in the block
+    pub total_transactions: u32,
+    /// Hashes in depth-first order
+    pub hashes: Vec<Hash256>,
+    /// Bit vector used to assign hashes to nodes in the partial merkle tree, least significant bit first
+    pub flags: Vec<u8>,
+}
+
+impl MerkleBlock {
+    /// Builds a proof that the transactions identified by `txids` are included in `block`
+    pub fn from_block(block: &Block, txids: &[Hash256]) -> Result<MerkleBlock> {
+        if block.txns.is_empty() {
+            return Err(Error::BadArgument("Block has no transactions".to_string()));
+        }
+        let block_txids: Vec<Hash256> = block.txns.iter().map(|tx| tx.hash()).collect();
+        let matches: Vec<bool> = block_txids.iter().map(|txid| txids.contains(txid)).collect();
+        if !matches.iter().any(|m| *m) {
+            return Err(Error::BadArgument("No transaction found in block".to_string()));
+        }
+
+        let mut tree = PartialMerkleTree {
+            total_transactions: block_txids.len() as u32,
+            hashes: Vec::new(),
+            bits: Vec::new(),
+        };
+        let height = tree.height();
+        tree.traverse_and_build(height, 0, &block_txids, &matches);
+
+        let mut flags = vec![0; tree.bits.len().div_ceil(8)];
+        for (i, bit) in tree.bits.iter().enumerate() {
+            if *bit {
+                flags[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        Ok(MerkleBlock {
+            header: block.header.clone(),
+            total_transactions: tree.total_transactions,
+            hashes: tree.hashes,
+            flags,
+        })
+    }
+
+    /// Validates the partial merkle tree against the header and returns the matched transaction ids
+    pub fn validate(&self) -> Result<Vec<Hash256>> {
+        if self.total_transactions == 0 {
+            return Err(Error::BadData("No transactions".to_string()));
+        }
+        if self.total_transactions as usize > MAX_BLOCK_SIZE / MIN_TX_SIZE {
+            return Err(Error::BadData("Too many transactions".to_string()));
+        }
+        if self.hashes.len() as u32 > self.total_transactions {
+            return Err(Error::BadData("More hashes than transactions".to_string()));
+        }
+        if self.flags.len() * 8 < self.hashes.len() {
+            return Err(Error::BadData("Not enough flag bits".to_string()));
+        }
+
+        let bits: Vec<bool> = (0..self.flags.len() * 8)
+            .map(|i| (self.flags[i / 8] >> (i % 8)) & 1 == 1)
+            .collect();
+        let tree = PartialMerkleTree {
+            total_transactions: self.total_transactions,
+            hashes: self.hashes.clone(),
+            bits,
+        };
+
+        let mut bits_used = 0;
+        let mut hashes_used = 0;
+        let mut matches = Vec::new();
+        let height = tree.height();
+        let merkle_root =
+            tree.traverse_and_extract(height, 0, &mut bits_used, &mut hashes_used, &mut matches)?;
+
+        if bits_used.div_ceil(8) != self.flags.len() {
+            return Err(Error::BadData("Not all flag bits consumed".to_string()));
+        }
+        if hashes_used != self.hashes.len() {
+            return Err(Error::BadData("Not all hashes consumed".to_string()));
+        }
+        if merkle_root != self.header.merkle_root {
+            return Err(Error::BadData("Merkle root doesn't match".to_string()));
+        }
+        Ok(matches)
+    }
+}
+
+/// Partial merkle tree as described in BIP 37
+struct PartialMerkleTree {
+    total_transactions: u32,
+    hashes: Vec<Hash256>,
+    bits: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Returns the height of the tree needed for the transaction count
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    /// Returns the number of nodes at a given height
+    fn width(&self, height: u32) -> u32 {
+        (self.total_transactions + (1 << height) - 1) >> height
+    }
+
+    /// Calculates the hash of the node at a given height and position
+    fn calc_hash(&self, height: u32, pos: u32, txids: &[Hash256]) -> Hash256 {
+        if height == 0 {
+            return txids[pos as usize];
+        }
+        let left = self.calc_hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.calc_hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn traverse_and_build(&mut self, height: u32, pos: u32, txids: &[Hash256], matches: &[bool]) {
+        let start = (pos << height) as usize;
+        let end = std::cmp::min(((pos + 1) << height) as usize, txids.len());
+        let parent_of_match = matches[start..end].iter().any(|m| *m);
+        self.bits.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    fn traverse_and_extract(
+        &self,
+        height: u32,
+        pos: u32,
+        bits_used: &mut usize,
+        hashes_used: &mut usize,
+        matches: &mut Vec<Hash256>,
+    ) -> Result<Hash256> {
+        if *bits_used >= self.bits.len() {
+            return Err(Error::BadData("Overflowed the bits array".to_string()));
+        }
+        let parent_of_match = self.bits[*bits_used];
+        *bits_used += 1;
+        if height == 0 || !parent_of_match {
+            if *hashes_used >= self.hashes.len() {
+                return Err(Error::BadData("Overflowed the hash array".to_string()));
+            }
+            let hash = self.hashes[*hashes_used];
+            *hashes_used += 1;
+            if height == 0 && parent_of_match {
+                matches.push(hash);
+            }
+            return Ok(hash);
+        }
+        let left = self.traverse_and_extract(height - 1, pos * 2, bits_used, hashes_used, matches)?;
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right =
+                self.traverse_and_extract(height - 1, pos * 2 + 1, bits_used, hashes_used, matches)?;
+            // Identical siblings allow a second tree with the same root (CVE-2012-2459)
+            if right == left {
+                return Err(Error::BadData("Duplicate transactions".to_string()));
+            }
+            right
+        } else {
+            left
+        };
+        Ok(hash_pair(&left, &right))
+    }
+}
+
+fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut concat = Vec::with_capacity(64);
+    concat.extend_from_slice(&left.0);
+    concat.extend_from_slice(&right.0);
+    sha256d(&concat)
+}
+
+impl Serializable<MerkleBlock> for MerkleBlock {
+    fn read(reader: &mut dyn Read) -> Result<MerkleBlock> {
+        let header = BlockHeader::read(reader)?;
+        let total_transactions = reader.read_u32::<LittleEndian>()?;
+        let num_hashes = var_int::read(reader)?;
+        if num_hashes > total_transactions as u64 {
+            return Err(Error::BadData("More hashes than transactions".to_string()));
+        }
+        let mut hashes = Vec::with_capacity(num_hashes as usize);
+        for _i in 0..num_hashes {
+            hashes.push(Hash256::read(reader)?);
+        }
+        let num_flags = var_int::read(reader)?;
+        if num_flags > (MAX_BLOCK_SIZE / MIN_TX_SIZE) as u64 {
+            return Err(Error::BadData("Too many flag bytes".to_string()));
+        }
+        let mut flags = vec![0; num_flags as usize];
+        reader.read_exact(&mut flags)?;
+        Ok(MerkleBlock {
+            header,
+            total_transactions,
+            hashes,
+            flags,
+        })
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.header.write(writer)?;
+        writer.write_u32::<LittleEndian>(self.total_transactions)?;
+        var_int::write(self.hashes.len() as u64, writer)?;
+        for hash in self.hashes.iter() {
+            hash.write(writer)?;
+        }
+        var_int::write(self.flags.len() as u64, writer)?;
+        writer.write_all(&self.flags)?;
+        Ok(())
+    }
+}
+
+impl Payload<MerkleBlock> for MerkleBlock {
+    fn size(&self) -> usize {
+        BlockHeader::SIZE
+            + 4
+            + var_int::size(self.hashes.len() as u64)
+            + self.hashes.len() * 32
+            + var_int::size(self.flags.len() as u64)
+            + self.flags.len()
+    }
+}
+
+impl fmt::Debug for MerkleBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MerkleBlock")
+            .field("header", &self.header)
+            .field("total_transactions", &self.total_transactions)
+            .field("hashes", &self.hashes)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::tx::Tx;
+    use crate::messages::tx_in::TxIn;
+    use crate::messages::tx_out::TxOut;
+    use crate::messages::OutPoint;
+    use std::io::Cursor;
+
+    fn block_with_txns(n: u32) -> Block {
+        let txns: Vec<Tx> = (0..n)
+            .map(|i| Tx {
+                version: 2,
+                flag: None,
+                inputs: vec![TxIn {
+                    prev_output: OutPoint {
+                        hash: Hash256([i as u8; 32]),
+                        index: i,
+                    },
+                    unlock_script: vec![1, 2, 3],
+                    sequence: 0xffffffff,
+                }],
+                outputs: vec![TxOut {
+                    satoshis: 1000 + i as i64,
+                    lock_script: vec![0, 20, i as u8],
+                }],
+                witnesses: None,
+                lock_time: 0,
+            })
+            .collect();
+        let mut block = Block {
+            header: BlockHeader::default(),
+            txns,
+        };
+        block.header.merkle_root = block.merkle_root();
+        block
+    }
+
+    #[test]
+    fn write_read() {
+        let block = block_with_txns(7);
+        let txid = block.txns[3].hash();
+        let merkle_block = MerkleBlock::from_block(&block, &[txid]).unwrap();
+        let mut v = Vec::new();
+        merkle_block.write(&mut v).unwrap();
+        assert!(v.len() == merkle_block.size());
+        assert!(MerkleBlock::read(&mut Cursor::new(&v)).unwrap() == merkle_block);
+    }
+
+    #[test]
+    fn build_and_validate() {
+        for n in 1..12 {
+            let block = block_with_txns(n);
+            for i in 0..n {
+                let txid = block.txns[i as usize].hash();
+                let merkle_block = MerkleBlock::from_block(&block, &[txid]).unwrap();
+                assert!(merkle_block.validate().unwrap() == vec![txid]);
+            }
+        }
+
+        let block = block_with_txns(9);
+        let txids = vec![block.txns[1].hash(), block.txns[8].hash()];
+        let merkle_block = MerkleBlock::from_block(&block, &txids).unwrap();
+        assert!(merkle_block.validate().unwrap() == txids);
+    }
+
+    #[test]
+    fn validate_real_block() {
+        // Block 2 only contains its coinbase, so the merkle root is the coinbase txid
+        let b = hex::decode("010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd610101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d010bffffffff0100f2052a010000004341047211a824f55b505228e4c3d5194c1fcfaa15a456abdf37f9b9d97a4040afc073dee6c89064984f03385237d92167c13e236446b417ab79a0fcae412ae3316b77ac00000000").unwrap();
+        let block = Block::read(&mut Cursor::new(&b)).unwrap();
+        assert!(block.merkle_root() == block.header.merkle_root);
+        let txid = block.txns[0].hash();
+        let merkle_block = MerkleBlock::from_block(&block, &[txid]).unwrap();
+        assert!(merkle_block.validate().unwrap() == vec![txid]);
+        assert!(merkle_block
+            .header
+            .validate(&merkle_block.header.hash(), &[])
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        let block = block_with_txns(5);
+        let txid = block.txns[2].hash();
+        let merkle_block = MerkleBlock::from_block(&block, &[txid]).unwrap();
+
+        // Unknown transaction
+        assert!(MerkleBlock::from_block(&block, &[Hash256([9; 32])]).is_err());
+
+        // Wrong merkle root
+        let mut m = merkle_block.clone();
+        m.header.merkle_root = Hash256([1; 32]);
+        assert!(m.validate().is_err());
+
+        // Tampered hash
+        let mut m = merkle_block.clone();
+        m.hashes[0] = Hash256([2; 32]);
+        assert!(m.validate().is_err());
+
+        // Missing hash
+        let mut m = merkle_block.clone();
+        m.hashes.pop();
+        assert!(m.validate().is_err());
+
+        // Extra flag byte
+        let mut m = merkle_block.clone();
+        m.flags.push(0);
+        assert!(m.validate().is_err());
+
+        // No transactions
+        let mut m = merkle_block.clone();
+        m.total_transactions = 0;
+        assert!(m.validate().is_err());
+    }
+}
@@ -1,12 +1,14 @@
 // use crate::messages::addr::Addr;
 use crate::messages::block::Block;
 use crate::messages::block_locator::BlockLocator;
+use crate::messages::fee_filter::FeeFilter;
 use crate::messages::headers::Headers;
 use crate::messages::inv::Inv;
+use crate::messages::merkle_block::MerkleBlock;
 use crate::messages::message_header::MessageHeader;
 use crate::messages::ping::Ping;
 use crate::messages::reject::Reject;
-// use crate::messages::send_cmpct::SendCmpct;
+use crate::messages::send_cmpct::SendCmpct;
 use crate::messages::tx::Tx;
 use crate::messages::version::Version;
 use crate::util::{Error, Result, Serializable};
@@ -22,8 +24,8 @@ use super::filter_locator::FilterLocator;
 /// Checksum to use when there is an empty payload
 pub const NO_CHECKSUM: [u8; 4] = [0x5d, 0xf6, 0xe0, 0xe2];
 
-/// Max message payload size (32MB)
-pub const MAX_PAYLOAD_SIZE: u32 = 0x02000000;
+/// Max message payload size (4MB)
+pub const MAX_PAYLOAD_SIZE: u32 = 4_000_000;
 
 /// Message commands for the header
 pub mod commands {
@@ -108,6 +110,12 @@ pub mod commands {
     /// [Version acknowledgement command](https://en.bitcoin.it/wiki/Protocol_documentation#verack)
     pub const VERACK: [u8; 12] = *b"verack\0\0\0\0\0\0";
 
+    /// [Wtxid relay command](https://github.com/bitcoin/bips/blob/master/bip-0339.mediawiki)
+    pub const WTXIDRELAY: [u8; 12] = *b"wtxidrelay\0\0";
+
+    /// [Send addrv2 command](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki)
+    pub const SENDADDRV2: [u8; 12] = *b"sendaddrv2\0\0";
+
     /// [GetCfilters command](https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki#getcfilters)
     pub const GETCFILTERS: [u8; 12] = *b"getcfilters\0";
 
@@ -129,6 +137,7 @@ pub mod commands {
 pub enum Message {
     // Addr(Addr),
     Block(Block),
+    FeeFilter(FeeFilter),
     GetAddr,
     GetBlocks(BlockLocator),
     GetData(Inv),
@@ -136,6 +145,7 @@ pub enum Message {
     Headers(Headers),
     Inv(Inv),
     Mempool,
+    MerkleBlock(MerkleBlock),
     NotFound(Inv),
     Other(String),
     Partial(MessageHeader),
@@ -146,11 +156,13 @@ pub enum Message {
     CFilters(CompactFilter),
     CFHeaders(CompactFilterHeader),
     Reject(Reject),
+    SendAddrV2,
     SendHeaders,
-    //SendCmpct(SendCmpct),
+    SendCmpct(SendCmpct),
     Tx(Tx),
     Verack,
     Version(Version),
+    WtxidRelay,
 }
 
 impl Message {
@@ -159,9 +171,9 @@ impl Message {
     /// It's possible for a message's header to be read but not its payload. In this case, the
     /// return value is not an Error but a Partial message, and the complete message may be read
     /// later using read_partial.
-    pub fn read(reader: &mut dyn Read) -> Result<(Self, MessageHeader)> {
+    pub fn read(reader: &mut dyn Read, magic: [u8; 4]) -> Result<(Self, MessageHeader)> {
         let header = MessageHeader::read(reader)?;
-        //header.validate(magic, MAX_PAYLOAD_SIZE)?;
+        header.validate(magic, MAX_PAYLOAD_SIZE)?;
         match Message::read_partial(reader, &header) {
             Ok(msg) => Ok((msg, header)),
             Err(e) => {
@@ -196,6 +208,13 @@ impl Message {
             return Ok(Message::Block(block));
         }
 
+        // Feefilter
+        if header.command == commands::FEEFILTER {
+            let payload = header.payload(reader)?;
+            let feefilter = FeeFilter::read(&mut Cursor::new(payload))?;
+            return Ok(Message::FeeFilter(feefilter));
+        }
+
 
         // Getaddr
         if header.command == commands::GETADDR {
@@ -213,12 +232,26 @@ impl Message {
             return Ok(Message::GetData(inv));
         }
 
-        // // Getheaders
-        // if header.command == commands::GETHEADERS {
-        //     let payload = header.payload(reader)?;
-        //     let block_locator = BlockLocator::read(&mut Cursor::new(payload))?;
-        //     return Ok(Message::GetHeaders(block_locator));
-        // }
+        // Getheaders
+        if header.command == commands::GETHEADERS {
+            let payload = header.payload(reader)?;
+            let block_locator = BlockLocator::read(&mut Cursor::new(payload))?;
+            return Ok(Message::GetHeaders(block_locator));
+        }
+
+        // Getcfilters
+        if header.command == commands::GETCFILTERS {
+            let payload = header.payload(reader)?;
+            let filter_locator = FilterLocator::read(&mut Cursor::new(payload))?;
+            return Ok(Message::GetCFilters(filter_locator));
+        }
+
+        // Getcfheaders
+        if header.command == commands::GETCFHEADERS {
+            let payload = header.payload(reader)?;
+            let filter_locator = FilterLocator::read(&mut Cursor::new(payload))?;
+            return Ok(Message::GetCFHeaders(filter_locator));
+        }
 
         // Headers
         if header.command == commands::HEADERS {
@@ -256,6 +289,13 @@ impl Message {
             return Ok(Message::Mempool);
         }
 
+        // Merkle block
+        if header.command == commands::MERKLEBLOCK {
+            let payload = header.payload(reader)?;
+            let merkle_block = MerkleBlock::read(&mut Cursor::new(payload))?;
+            return Ok(Message::MerkleBlock(merkle_block));
+        }
+
 
         // Notfound
         if header.command == commands::NOTFOUND {
@@ -285,12 +325,20 @@ impl Message {
             return Ok(Message::Reject(reject));
         }
 
-        // // Sendcmpct
-        // if header.command == commands::SENDCMPCT {
-        //     let payload = header.payload(reader)?;
-        //     let sendcmpct = SendCmpct::read(&mut Cursor::new(payload))?;
-        //     return Ok(Message::SendCmpct(sendcmpct));
-        // }
+        // Sendcmpct
+        if header.command == commands::SENDCMPCT {
+            let payload = header.payload(reader)?;
+            let sendcmpct = SendCmpct::read(&mut Cursor::new(payload))?;
+            return Ok(Message::SendCmpct(sendcmpct));
+        }
+
+        // Sendaddrv2
+        if header.command == commands::SENDADDRV2 {
+            if header.payload_size != 0 {
+                return Err(Error::BadData("Bad payload".to_string()));
+            }
+            return Ok(Message::SendAddrV2);
+        }
 
         // Sendheaders
         if header.command == commands::SENDHEADERS {
@@ -323,6 +371,14 @@ impl Message {
             return Ok(Message::Verack);
         }
 
+        // Wtxidrelay
+        if header.command == commands::WTXIDRELAY {
+            if header.payload_size != 0 {
+                return Err(Error::BadData("Bad payload".to_string()));
+            }
+            return Ok(Message::WtxidRelay);
+        }
+
         // Unknown message
         if header.payload_size > 0 {
             header.payload(reader)?;
@@ -338,6 +394,7 @@ impl Message {
             // Message::Addr(p) => write_with_payload(writer, ADDR, p, magic),
             Message::Block(p) => write_with_payload(writer, BLOCK, p, magic),
             Message::Tx(p) => write_with_payload(writer, TX, p, magic),
+            Message::FeeFilter(p) => write_with_payload(writer, FEEFILTER, p, magic),
             Message::GetAddr => write_without_payload(writer, GETADDR, magic),
             Message::GetBlocks(p) => write_with_payload(writer, GETBLOCKS, p, magic),
             Message::GetData(p) => write_with_payload(writer, GETDATA, p, magic),
@@ -348,6 +405,7 @@ impl Message {
             Message::CFHeaders(p) => write_with_payload(writer, CFHEADERS, p, magic),
             Message::CFilters(p) => write_with_payload(writer, CFILTERS, p, magic),
             Message::Mempool => write_without_payload(writer, MEMPOOL, magic),
+            Message::MerkleBlock(p) => write_with_payload(writer, MERKLEBLOCK, p, magic),
             Message::NotFound(p) => write_with_payload(writer, NOTFOUND, p, magic),
             Message::Inv(p) => write_with_payload(writer, INV, p, magic),
             Message::Other(s) => Err(io::Error::new(io::ErrorKind::InvalidData, s.as_str())),
@@ -358,11 +416,13 @@ impl Message {
             Message::Ping(p) => write_with_payload(writer, PING, p, magic),
             Message::Pong(p) => write_with_payload(writer, PONG, p, magic),
            Message::Reject(p) => write_with_payload(writer, REJECT, p, magic),
+            Message::SendAddrV2 => write_without_payload(writer, SENDADDRV2, magic),
             Message::SendHeaders => write_without_payload(writer, SENDHEADERS, magic),
-        //    Message::SendCmpct(p) => write_with_payload(writer, SENDCMPCT, p, magic),
+            Message::SendCmpct(p) => write_with_payload(writer, SENDCMPCT, p, magic),
         //    Message::Tx(p) => write_with_payload(writer, TX, p, magic),
             Message::Verack => write_without_payload(writer, VERACK, magic),
             Message::Version(v) => write_with_payload(writer, VERSION, v, magic),
+            Message::WtxidRelay => write_without_payload(writer, WTXIDRELAY, magic),
         }
     }
 }
@@ -373,6 +433,7 @@ impl fmt::Debug for Message {
             // Message::Addr(p) => f.write_str(&format!("{:#?}", p)),
             Message::Block(p) => f.write_str(&format!("{:#?}", p)),
             Message::Tx(p) => f.write_str(&format!("{:#?}", p)),
+            Message::FeeFilter(p) => f.write_str(&format!("{:#?}", p)),
             Message::GetAddr => f.write_str("GetAddr"),
             Message::GetBlocks(p) => f
                 .debug_struct("GetBlocks")
@@ -400,17 +461,20 @@ impl fmt::Debug for Message {
            Message::CFHeaders(p) => f.write_str(&format!("{:#?}", p)),
             Message::Inv(p) => f.write_str(&format!("{:#?}", p)),
             Message::Mempool => f.write_str("Mempool"),
+            Message::MerkleBlock(p) => f.write_str(&format!("{:#?}", p)),
             Message::NotFound(p) => f.debug_struct("NotFound").field("inv", &p).finish(),
             Message::Other(p) => f.write_str(&format!("{:#?}", p)),
             Message::Partial(h) => f.write_str(&format!("Partial {:#?}", h)),
             Message::Ping(p) => f.write_str(&format!("{:#?}", p)),
             Message::Pong(p) => f.debug_struct("Pong").field("nonce", &p.nonce).finish(),
             Message::Reject(p) => f.write_str(&format!("{:#?}", p)),
+            Message::SendAddrV2 => f.write_str("SendAddrV2"),
             Message::SendHeaders => f.write_str("SendHeaders"),
-         //   Message::SendCmpct(p) => f.write_str(&format!("{:#?}", p)),
+            Message::SendCmpct(p) => f.write_str(&format!("{:#?}", p)),
           //  Message::Tx(p) => f.write_str(&format!("{:#?}", p)),
             Message::Verack => f.write_str("Verack"),
             Message::Version(p) => f.write_str(&format!("{:#?}", p)),
+            Message::WtxidRelay => f.write_str("WtxidRelay"),
         }
     }
 }
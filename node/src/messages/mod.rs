@@ -2,11 +2,13 @@
 pub mod block;
 mod block_header;
 pub mod block_locator;
+pub mod fee_filter;
 pub mod headers;
 pub mod filter_locator;
 pub mod compact_filter;
 pub mod inv;
 pub mod inv_vect;
+pub mod merkle_block;
 mod witness;
 mod message;
 mod message_header;
@@ -19,29 +21,35 @@ pub mod tx_in;
 pub mod tx_out;
 mod version;
 pub mod reject;
+pub mod send_cmpct;
 pub mod compact_filter_header;
 
 
 // pub use self::addr::Addr;
 // pub use self::block::Block;
 pub use self::block_header::BlockHeader;
+pub use self::fee_filter::FeeFilter;
 // pub use self::block_locator::{BlockLocator, NO_HASH_STOP};
 // pub use self::headers::{header_hash, Headers};
 pub use self::inv::{Inv};
 pub use self::inv_vect::{
     InvVect,
 };
-//pub use self::merkle_block::MerkleBlock;
+pub use self::merkle_block::MerkleBlock;
 pub use self::message::{commands, Message, Payload};
 pub use self::node_addr::NodeAddr;
 pub use self::out_point::{OutPoint, COINBASE_OUTPOINT_HASH, COINBASE_OUTPOINT_INDEX};
 // pub use self::ping::Ping;
 
-// pub use self::send_cmpct::SendCmpct;
+pub use self::send_cmpct::SendCmpct;
 // pub use self::tx::{Tx, MAX_SATOSHIS};
 // pub use self::tx_in::TxIn;
 // pub use self::tx_out::TxOut;
 pub use self::version::{
     Version,
+    NODE_COMPACT_FILTERS,
+    NODE_NETWORK,
+    NODE_WITNESS,
     PROTOCOL_VERSION,
+    WTXID_RELAY_VERSION,
 };
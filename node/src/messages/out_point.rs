@@ -1,5 +1,6 @@
 use crate::util::{Hash256, Result, Serializable};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 use std::io;
 use std::io::{Read, Write};
 
@@ -9,7 +10,7 @@ pub const COINBASE_OUTPOINT_HASH: Hash256 = Hash256([0; 32]);
 pub const COINBASE_OUTPOINT_INDEX: u32 = 0xffffffff;
 
 /// Reference to a transaction output
-#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct OutPoint {
     /// Hash of the referenced transaction
     pub hash: Hash256,
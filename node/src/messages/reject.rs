@@ -15,6 +15,12 @@ pub const REJECT_DUST: u8 = 0x41;
 pub const REJECT_INSUFFICIENT_FEE: u8 = 0x42;
 pub const REJECT_CHECKPOINT: u8 = 0x43;
 
+/// Maximum length of the rejected message command
+pub const MAX_REJECT_MESSAGE_LEN: usize = 12;
+
+/// Maximum length of the rejection reason
+pub const MAX_REJECT_REASON_LEN: usize = 111;
+
 /// Rejected message
 #[derive(Default, PartialEq, Eq, Hash, Clone)]
 pub struct Reject {
@@ -45,18 +51,26 @@ impl Reject {
 impl Serializable<Reject> for Reject {
     fn read(reader: &mut dyn Read) -> Result<Reject> {
         let message_size = var_int::read(reader)? as usize;
+        if message_size > MAX_REJECT_MESSAGE_LEN {
+            let msg = format!("Reject message too long: {}", message_size);
+            return Err(Error::BadData(msg));
+        }
         let mut message_bytes = vec![0; message_size];
-        reader.read(&mut message_bytes)?;
+        reader.read_exact(&mut message_bytes)?;
         let message = String::from_utf8(message_bytes)?;
         let code = reader.read_u8()?;
         let reason_size = var_int::read(reader)? as usize;
+        if reason_size > MAX_REJECT_REASON_LEN {
+            let msg = format!("Reject reason too long: {}", reason_size);
+            return Err(Error::BadData(msg));
+        }
         let mut reason_bytes = vec![0; reason_size];
-        reader.read(&mut reason_bytes)?;
+        reader.read_exact(&mut reason_bytes)?;
         let reason = String::from_utf8(reason_bytes)?;
         let mut data = vec![];
         if message == "block".to_string() || message == "tx".to_string() {
             data = vec![0_u8; 32];
-            reader.read(&mut data)?;
+            reader.read_exact(&mut data)?;
         }
         Ok(Reject {
             message,
@@ -92,8 +106,9 @@ impl fmt::Debug for Reject {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut data_str = "".to_string();
         if self.message == "block".to_string() || self.message == "tx".to_string() {
-            let mut data = Cursor::new(&self.data);
-            data_str = Hash256::read(&mut data).unwrap().encode();
+            if let Ok(hash) = Hash256::read(&mut Cursor::new(&self.data)) {
+                data_str = hash.encode();
+            }
         }
         f.debug_struct("Reject")
             .field("message", &self.message)
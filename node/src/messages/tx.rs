@@ -1,10 +1,11 @@
 use crate::messages::{COINBASE_OUTPOINT_HASH, COINBASE_OUTPOINT_INDEX};
-use crate::util::{sha256d, var_int, Hash256, Result, Serializable};
+use crate::util::{sha256d, var_int, Error, Hash256, Result, Serializable};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 
+use super::block::MAX_BLOCK_SIZE;
 use super::tx_in::TxIn;
 use super::tx_out::TxOut;
 use super::witness::TxWitness;
@@ -13,6 +14,22 @@ use super::Payload;
 /// Maximum number of satoshis possible
 pub const MAX_SATOSHIS: i64 = 21_000_000 * 100_000_000;
 
+/// Smallest possible serialized transaction
+pub const MIN_TX_SIZE: usize = 60;
+
+/// Upper bound on inputs or outputs that fit in a block, each taking at least 9 bytes
+const MAX_TX_IO: u64 = (MAX_BLOCK_SIZE / 9) as u64;
+
+/// Reads an input or output count and checks it against the protocol limit
+fn read_io_count(reader: &mut dyn Read) -> Result<u64> {
+    let n = var_int::read(reader)?;
+    if n > MAX_TX_IO {
+        let msg = format!("Num inputs or outputs exceeded maximum: {}", n);
+        return Err(Error::BadData(msg));
+    }
+    Ok(n)
+}
+
 /// Bitcoin transaction
 #[derive(Default, PartialEq, Eq, Hash, Clone)]
 pub struct Tx {
@@ -38,6 +55,21 @@ impl Tx {
         sha256d(&b)
     }
 
+    /// Weight units of the transaction, witness data counting a quarter of the other bytes
+    pub fn weight(&self) -> usize {
+        let witness_size = match &self.witnesses {
+            // segwit marker and flag followed by one witness per input
+            Some(witnesses) => 2 + witnesses.iter().map(|witness| witness.size()).sum::<usize>(),
+            None => 0,
+        };
+        self.size() * 4 + witness_size
+    }
+
+    /// Virtual size of the transaction in vbytes, used for fee rates
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+
     // /// Validates a non-coinbase transaction
     // pub fn validate(
     //     &self,
@@ -157,17 +189,17 @@ impl Serializable<Tx> for Tx {
     fn read(reader: &mut dyn Read) -> Result<Tx> {
         let version = reader.read_i32::<LittleEndian>()?;
         let version = version as u32;
-        let n_inputs = var_int::read(reader)?;
+        let n_inputs = read_io_count(reader)?;
         if n_inputs == 0 {
             let segwit_flag = reader.read_u8()?;
             match segwit_flag {
                 1 => {
+                    let n_inputs = read_io_count(reader)?;
                     let mut inputs = Vec::with_capacity(n_inputs as usize);
-                    let n_inputs = var_int::read(reader)?;
                     for _i in 0..n_inputs {
                         inputs.push(TxIn::read(reader)?);
                     }
-                    let n_outputs = var_int::read(reader)?;
+                    let n_outputs = read_io_count(reader)?;
                     let mut outputs = Vec::with_capacity(n_outputs as usize);
                     for _i in 0..n_outputs {
                         outputs.push(TxOut::read(reader)?);
@@ -188,7 +220,8 @@ impl Serializable<Tx> for Tx {
 
                 }
                 _ => {
-                    panic!("cant flag this")
+                    let msg = format!("Unknown segwit flag: {}", segwit_flag);
+                    return Err(Error::BadData(msg));
                 }
             }
         }
@@ -196,7 +229,7 @@ impl Serializable<Tx> for Tx {
         for _i in 0..n_inputs {
             inputs.push(TxIn::read(reader)?);
         }
-        let n_outputs = var_int::read(reader)?;
+        let n_outputs = read_io_count(reader)?;
         let mut outputs = Vec::with_capacity(n_outputs as usize);
         for _i in 0..n_outputs {
             outputs.push(TxOut::read(reader)?);
@@ -274,7 +307,7 @@ impl fmt::Debug for Tx {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::messages::OutPoint;
+    use crate::messages::{witness::TxWitnessData, OutPoint};
     use crate::util::Hash256;
     use std::io::Cursor;
 
@@ -349,6 +382,41 @@ mod tests {
         assert!(tx.coinbase());
     }
 
+    #[test]
+    fn read_bad_segwit_flag() {
+        // version, empty input marker, then an unknown flag
+        let b = hex::decode("010000000002".as_bytes()).unwrap();
+        assert!(Tx::read(&mut Cursor::new(&b)).is_err());
+    }
+
+    #[test]
+    fn read_too_many_outputs() {
+        let mut v = Vec::new();
+        v.write_u32::<LittleEndian>(1).unwrap();
+        var_int::write(1, &mut v).unwrap();
+        TxIn::default().write(&mut v).unwrap();
+        var_int::write(u64::MAX, &mut v).unwrap();
+        assert!(Tx::read(&mut Cursor::new(&v)).is_err());
+    }
+
+    #[test]
+    fn weight() {
+        let mut tx = Tx {
+            version: 2,
+            inputs: vec![TxIn::default()],
+            outputs: vec![TxOut { satoshis: 1000, lock_script: vec![0x51] }],
+            ..Default::default()
+        };
+        assert_eq!(tx.weight(), tx.size() * 4);
+        assert_eq!(tx.vsize(), tx.size());
+
+        // a 72 byte signature and a 33 byte public key
+        tx.flag = Some(1);
+        tx.witnesses = Some(vec![TxWitness { witness: vec![TxWitnessData { witness_data: vec![0; 72] }, TxWitnessData { witness_data: vec![0; 33] }] }]);
+        assert_eq!(tx.weight(), tx.size() * 4 + 2 + 108);
+        assert_eq!(tx.vsize(), tx.size() + 28);
+    }
+
     // #[test]
     // fn validate() {
     //     let utxo = (
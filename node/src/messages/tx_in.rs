@@ -1,5 +1,6 @@
+use crate::messages::block::MAX_BLOCK_SIZE;
 use crate::messages::OutPoint;
-use crate::util::{var_int, Result, Serializable};
+use crate::util::{var_int, Error, Result, Serializable};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::{io};
 use std::io::{Read, Write};
@@ -31,10 +32,12 @@ impl Serializable<TxIn> for TxIn {
     fn read(reader: &mut dyn Read) -> Result<TxIn> {
         let prev_output = OutPoint::read(reader)?;
         let script_len = var_int::read(reader)?;
-        let mut unlock_script = Vec::new();
-        for _i in 0..script_len {
-            unlock_script.push(reader.read_u8()?)
+        if script_len > MAX_BLOCK_SIZE as u64 {
+            let msg = format!("Script size exceeded maximum: {}", script_len);
+            return Err(Error::BadData(msg));
         }
+        let mut unlock_script = vec![0; script_len as usize];
+        reader.read_exact(&mut unlock_script)?;
         let sequence = reader.read_u32::<LittleEndian>()?;
         Ok(TxIn {
             prev_output,
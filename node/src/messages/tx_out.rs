@@ -1,4 +1,5 @@
-use crate::util::{var_int, Result, Serializable};
+use crate::messages::block::MAX_BLOCK_SIZE;
+use crate::util::{var_int, Error, Result, Serializable};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 use std::io;
@@ -24,10 +25,12 @@ impl Serializable<TxOut> for TxOut {
     fn read(reader: &mut dyn Read) -> Result<TxOut> {
         let satoshis = reader.read_i64::<LittleEndian>()?;
         let script_len = var_int::read(reader)?;
-        let mut lock_script = Vec::new();
-        for _i in 0..script_len {
-            lock_script.push(reader.read_u8()?)
+        if script_len > MAX_BLOCK_SIZE as u64 {
+            let msg = format!("Script size exceeded maximum: {}", script_len);
+            return Err(Error::BadData(msg));
         }
+        let mut lock_script = vec![0; script_len as usize];
+        reader.read_exact(&mut lock_script)?;
         Ok(TxOut {
             satoshis,
             lock_script,
@@ -12,6 +12,9 @@ pub const PROTOCOL_VERSION: u32 = 70015;
 /// Minimum protocol version supported by this library
 pub const MIN_SUPPORTED_PROTOCOL_VERSION: u32 = 70001;
 
+/// Maximum length of the user agent string
+pub const MAX_USER_AGENT_LEN: usize = 256;
+
 /// Unknown IP address to use as a default
 pub const UNKNOWN_IP: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 127, 0, 0, 1];
 
@@ -24,6 +27,15 @@ pub const NODE_NETWORK: u64 = 1;
 /// Service flag that node is a full node and implements all protocol features
 pub const NODE_BITCOIN_CASH: u64 = 1 << 5;
 
+/// Service flag that node can serve witness data (BIP 144)
+pub const NODE_WITNESS: u64 = 1 << 3;
+
+/// Service flag that node can serve compact block filters (BIP 157)
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+
+/// Minimum protocol version that negotiates wtxidrelay and sendaddrv2 before verack
+pub const WTXID_RELAY_VERSION: u32 = 70016;
+
 /// Version payload defining a node's capabilities
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct Version {
@@ -61,6 +73,15 @@ impl Version {
         }
         Ok(())
     }
+
+    /// Checks that the node offers all of the `required` service bits
+    pub fn validate_services(&self, required: u64) -> Result<()> {
+        if self.services & required != required {
+            let msg = format!("Missing services: {:#x}", required & !self.services);
+            return Err(Error::Unsupported(msg));
+        }
+        Ok(())
+    }
 }
 
 impl Serializable<Version> for Version {
@@ -75,8 +96,12 @@ impl Serializable<Version> for Version {
         ret.tx_addr = NodeAddr::read(reader)?;
         ret.nonce = reader.read_u64::<LittleEndian>()?;
         let user_agent_size = var_int::read(reader)? as usize;
+        if user_agent_size > MAX_USER_AGENT_LEN {
+            let msg = format!("User agent too long: {}", user_agent_size);
+            return Err(Error::BadData(msg));
+        }
         let mut user_agent_bytes = vec![0; user_agent_size];
-        reader.read(&mut user_agent_bytes)?;
+        reader.read_exact(&mut user_agent_bytes)?;
         ret.user_agent = String::from_utf8(user_agent_bytes)?;
         ret.start_height = reader.read_i32::<LittleEndian>()?;
         ret.relay = reader.read_u8()? == 0x01;
@@ -189,4 +214,30 @@ mod tests {
         };
         assert!(m3.validate().is_err());
     }
+
+    #[test]
+    fn read_long_user_agent() {
+        let m = Version {
+            user_agent: "a".repeat(MAX_USER_AGENT_LEN + 1),
+            ..Default::default()
+        };
+        let mut v = Vec::new();
+        m.write(&mut v).unwrap();
+        assert!(Version::read(&mut Cursor::new(&v)).is_err());
+    }
+
+    #[test]
+    fn validate_services() {
+        let m = Version {
+            services: NODE_NETWORK | NODE_WITNESS | NODE_COMPACT_FILTERS,
+            ..Default::default()
+        };
+        assert!(m.validate_services(NODE_WITNESS | NODE_COMPACT_FILTERS).is_ok());
+        assert!(m.validate_services(NODE_NONE).is_ok());
+        let m2 = Version {
+            services: NODE_NETWORK | NODE_WITNESS,
+            ..m.clone()
+        };
+        assert!(m2.validate_services(NODE_WITNESS | NODE_COMPACT_FILTERS).is_err());
+    }
 }
@@ -1,5 +1,6 @@
-use crate::util::{var_int, Result, Serializable};
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use crate::messages::block::MAX_BLOCK_SIZE;
+use crate::util::{var_int, Error, Result, Serializable};
+use byteorder::WriteBytesExt;
 use std::{io};
 use std::io::{Read, Write};
 
@@ -14,14 +15,24 @@ pub struct TxWitnessData {
     pub witness_data: Vec<u8>
 }
 
+impl TxWitness {
+    /// Serialized size of the witness stack
+    pub fn size(&self) -> usize {
+        var_int::size(self.witness.len() as u64) + self.witness.iter()
+            .map(|data| var_int::size(data.witness_data.len() as u64) + data.witness_data.len())
+            .sum::<usize>()
+    }
+}
 
 impl Serializable<TxWitnessData> for TxWitnessData {
     fn read(reader: &mut dyn Read) -> Result<TxWitnessData> {
         let witness_len = var_int::read(reader)?;
-        let mut witness_data = Vec::new();
-        for _i in 0..witness_len {
-            witness_data.push(reader.read_u8()?);
-        };
+        if witness_len > MAX_BLOCK_SIZE as u64 {
+            let msg = format!("Witness size exceeded maximum: {}", witness_len);
+            return Err(Error::BadData(msg));
+        }
+        let mut witness_data = vec![0; witness_len as usize];
+        reader.read_exact(&mut witness_data)?;
         Ok(TxWitnessData {
             witness_data
         })
@@ -40,6 +51,10 @@ impl Serializable<TxWitnessData> for TxWitnessData {
 impl Serializable<TxWitness> for TxWitness {
     fn read(reader: &mut dyn Read) -> Result<TxWitness> {
         let witness_len = var_int::read(reader)?;
+        if witness_len > MAX_BLOCK_SIZE as u64 {
+            let msg = format!("Num witness items exceeded maximum: {}", witness_len);
+            return Err(Error::BadData(msg));
+        }
         let mut witness = Vec::new();
         for _i in 0..witness_len {
             witness.push(TxWitnessData::read(reader)?);
@@ -5,13 +5,17 @@ use std::{hash::Hash, iter::zip, vec};
 use bitcoin::{
     block, network as bitcoin_network,
 };
-use bindings::exports::component::node::types::{BitcoinNetwork as WasiBitcoinNetwork,NodeConfig as WasiNodeConfig };
+use bindings::exports::component::node::types::{BitcoinNetwork as WasiBitcoinNetwork, NodeConfig as WasiNodeConfig, NodeStatus as WasiNodeStatus, PeerStatus as WasiPeerStatus, SocketAddress as WasiSocketAddress, SpendRecord as WasiSpendRecord, StorageMode as WasiStorageMode, SyncProgress as WasiSyncProgress};
 use bindings::component::kv::types::{Kvstore, Error as StoreError };
 
-use crate::chain::CompactChain;
-use crate::db::KeyValueDb;
-use crate::util::Error;
-use crate::{bindings, messages::{block::Block, compact_filter::{self, CompactFilter}, filter_locator::NO_HASH_STOP, headers, BlockHeader, Inv, InvVect}, p2p::{P2PControl, P2P}, util::{self, sha256d, Hash256}};
+use crate::chain::{ChainStatus, CompactChain, SyncProgress};
+use crate::p2p::PeerStatus;
+use crate::pending::read_local_tx;
+use crate::privacy::PrivacyConfig;
+use crate::wallet::SpendRecord;
+use crate::db::Store;
+use crate::util::{descriptor, Error, Serializable};
+use crate::{bindings, messages::{block::Block, compact_filter::{self, CompactFilter}, filter_locator::NO_HASH_STOP, headers, BlockHeader, Inv, InvVect, MerkleBlock, OutPoint}, p2p::{P2PControl, P2P}, util::{self, sha256d, Hash256}};
 
 
 
@@ -28,6 +32,7 @@ impl From<WasiBitcoinNetwork> for bitcoin_network::Network {
             WasiBitcoinNetwork::Mainnet => bitcoin_network::Network::Bitcoin,
             WasiBitcoinNetwork::Testnet => bitcoin_network::Network::Testnet,
             WasiBitcoinNetwork::Regtest => bitcoin_network::Network::Regtest,
+            WasiBitcoinNetwork::Signet => bitcoin_network::Network::Signet,
         }
     }
 }
@@ -35,38 +40,117 @@ impl From<WasiBitcoinNetwork> for bitcoin_network::Network {
 
 
 
-impl From<WasiNodeConfig> for NodeConfig {
-    fn from(val: WasiNodeConfig) -> Self {
-        let WasiNodeConfig { network, socket_address, genesis_blockhash, wallet_address } = val;
+impl From<bitcoin_network::Network> for WasiBitcoinNetwork {
+    fn from(val: bitcoin_network::Network) -> Self {
+        match val {
+            bitcoin_network::Network::Bitcoin => WasiBitcoinNetwork::Mainnet,
+            bitcoin_network::Network::Regtest => WasiBitcoinNetwork::Regtest,
+            bitcoin_network::Network::Signet => WasiBitcoinNetwork::Signet,
+            // other test networks are never configured through the component interface
+            _ => WasiBitcoinNetwork::Testnet,
+        }
+    }
+}
 
-        // Convert the network type
-        let network: bitcoin_network::Network = network.into();
+impl From<PeerStatus> for WasiPeerStatus {
+    fn from(val: PeerStatus) -> Self {
+        WasiPeerStatus {
+            address: val.address,
+            user_agent: val.features.user_agent,
+            services: val.features.services,
+            version: val.features.version,
+            ping_latency_ms: val.stats.ping_latency,
+            bytes_sent: val.stats.bytes_sent,
+            bytes_received: val.stats.bytes_received,
+        }
+    }
+}
 
+impl From<NodeStatus> for WasiNodeStatus {
+    fn from(val: NodeStatus) -> Self {
+        let NodeStatus { network, chain, last_error } = val;
+        WasiNodeStatus {
+            network: network.into(),
+            best_header_height: chain.best_header_height,
+            best_header_hash: chain.best_header_hash.encode(),
+            filter_height: chain.filter_height,
+            watched_scripts: chain.watched_scripts,
+            utxo_count: chain.utxo_count,
+            peers: chain.peers.into_iter().map(|peer| peer.into()).collect(),
+            last_error,
+        }
+    }
+}
+
+impl From<SyncProgress> for WasiSyncProgress {
+    fn from(val: SyncProgress) -> Self {
+        WasiSyncProgress {
+            filter_height: val.filter_height,
+            best_header_height: val.best_header_height,
+            percent: val.percent(),
+            done: val.done,
+        }
+    }
+}
+
+impl From<SpendRecord> for WasiSpendRecord {
+    fn from(val: SpendRecord) -> Self {
+        WasiSpendRecord {
+            txid: val.txid.encode(),
+            input_index: val.input_index,
+            height: val.height,
+        }
+    }
+}
+
+impl From<WasiSocketAddress> for CustomIPV4SocketAddress {
+    fn from(val: WasiSocketAddress) -> Self {
         // Parse the socket address IP
-        let ip_segments: Vec<u8> = socket_address.ip
+        let ip_segments: Vec<u8> = val.ip
             .split('.')
             .filter_map(|segment| u8::from_str_radix(segment, 10).ok())
             .collect();
 
         if ip_segments.len() != 4 {
-            panic!("Invalid IP address: {}", socket_address.ip);
+            panic!("Invalid IP address: {}", val.ip);
         }
 
-        // Construct the CustomIPV4SocketAddress
-        let socket_address = CustomIPV4SocketAddress {
+        CustomIPV4SocketAddress {
             ip: (ip_segments[0], ip_segments[1], ip_segments[2], ip_segments[3]),
-            port: socket_address.port,
-        };
+            port: val.port,
+        }
+    }
+}
+
+impl From<WasiNodeConfig> for NodeConfig {
+    fn from(val: WasiNodeConfig) -> Self {
+        let WasiNodeConfig { network, socket_address, genesis_blockhash, wallet_address, privacy, storage } = val;
+
+        // Convert the network type
+        let network: bitcoin_network::Network = network.into();
+
+        let socket_address = socket_address.into();
 
         // Decode the  genesis blockhash with error handling
         let genesis_blockhash = Hash256::decode(&genesis_blockhash).expect("Failed to decode genesis blockhash");
 
+        let (privacy, decoy_peers) = match privacy {
+            Some(privacy) => (
+                PrivacyConfig { decoy_ratio: privacy.decoy_ratio },
+                privacy.peers.into_iter().map(|peer| peer.into()).collect(),
+            ),
+            None => (PrivacyConfig::default(), vec![]),
+        };
+
         // Construct and return the NodeConfig
         NodeConfig {
             wallet_address,
             network,
             socket_address,
             genesis_blockhash,
+            privacy,
+            decoy_peers,
+            ephemeral: storage == WasiStorageMode::Ephemeral,
         }
     }
 }
@@ -78,36 +162,197 @@ pub struct NodeConfig {
     pub network: bitcoin_network::Network,
     pub wallet_address: String,
     pub genesis_blockhash: Hash256,
+    pub privacy: PrivacyConfig,
+    // extra peers that only serve (real and decoy) block downloads
+    pub decoy_peers: Vec<CustomIPV4SocketAddress>,
+    // keep all state in memory instead of the kv component
+    pub ephemeral: bool,
 }
 
 
 
+pub struct NodeStatus {
+    pub network: bitcoin_network::Network,
+    pub chain: ChainStatus,
+    pub last_error: Option<String>,
+}
+
 pub struct Node {
     chain: CompactChain,
+    network: bitcoin_network::Network,
+    last_error: Option<String>,
 }
 
 
 impl Node {
 
-    pub fn new(node_config: NodeConfig, store: RefCell<Kvstore>) -> Self {
-        let store = Arc::new(KeyValueDb::new(store)); 
-        let chain = CompactChain::new(node_config.socket_address, node_config.network, store.clone());
+    pub fn new(node_config: NodeConfig, store: Arc<dyn Store>) -> Self {
+        let chain = CompactChain::new(node_config.socket_address, node_config.decoy_peers, node_config.privacy, node_config.network, node_config.genesis_blockhash, store);
 
-        Self { chain }
+        let mut node = Self { chain, network: node_config.network, last_error: None };
+        if !node_config.wallet_address.is_empty() {
+            // an invalid address must not trap the constructor, watch_address keeps the error for get-status
+            let _ = node.watch_address(None, node_config.wallet_address);
+        }
+        node
 
     }
 
-    pub fn balance(&mut self) -> Result<i64, Error> {
-        self.chain.sync_state()?;
-        let utxos = self.chain.get_utxos()?;
+    // keeps the last failure around for get-status
+    fn record<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(err) = &result {
+            self.last_error = Some(err.to_string());
+        }
+        result
+    }
 
-        return Ok(utxos.into_iter().fold(0, |acc, e| acc + e.tx_out.satoshis));  
+    pub fn status(&self) -> NodeStatus {
+        NodeStatus {
+            network: self.network,
+            chain: self.chain.status(),
+            last_error: self.last_error.clone(),
+        }
+    }
+
+    /// `wallet` selects a named wallet, `None` is the default wallet of the node
+    pub fn balance(&mut self, wallet: Option<&str>) -> Result<i64, Error> {
+        let synced = self.chain.sync_state();
+        self.record(synced)?;
+        self.chain.balance(wallet)
+    }
+
+    pub fn create_wallet(&mut self, id: &str) -> Result<(), Error> {
+        let created = self.chain.create_wallet(id);
+        self.record(created)
+    }
+
+    pub fn open_wallet(&self, id: &str) -> Result<(), Error> {
+        if !self.chain.has_wallet(id) {
+            return Err(Error::WalletNotFound(id.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn list_wallets(&self) -> Vec<String> {
+        self.chain.wallet_ids()
+    }
+
+    pub fn follow_tip(&mut self, duration: u64) -> Result<u64, Error> {
+        let height = self.chain.follow_tip(duration);
+        self.record(height)
+    }
+
+    /// Syncs at most `max_work` filter batches, so that hosts can drive sync in bounded time slices
+    pub fn sync_step(&mut self, max_work: u32) -> Result<SyncProgress, Error> {
+        let progress = self.chain.sync_step(max_work);
+        self.record(progress)
+    }
+
+    /// Fee rate in sat/vB expected to confirm within `target_blocks`
+    pub fn estimate_fee(&mut self, target_blocks: u32) -> Result<u64, Error> {
+        let fee_rate = self.chain.estimate_fee(target_blocks);
+        self.record(fee_rate)
     }
 
     pub fn add_filter(& mut self, filter: String) -> Result<(), Error> {
         let decoded_filter = hex::decode(filter).map_err(|e| Error::FromHexError(e))?;
-        return self.chain.add_filter(decoded_filter);
+        let added = self.chain.add_filter(decoded_filter);
+        self.record(added)
+
+    }
+
+    pub fn watch_address(&mut self, wallet: Option<&str>, address: String) -> Result<(), Error> {
+        let added = descriptor::address_script(&address, self.network)
+            .and_then(|script| self.chain.add_filters(wallet, vec![script]));
+        self.record(added).map(|_| ())
+    }
+
+    pub fn watch_descriptor(&mut self, wallet: Option<&str>, descriptor: String, range: u32) -> Result<u32, Error> {
+        let added = descriptor::expand(&descriptor, self.network, range)
+            .and_then(|scripts| self.chain.add_filters(wallet, scripts));
+        self.record(added)
+    }
+
+    /// Stops watching an address, or every script of a descriptor up to `range`
+    pub fn remove_watch(&mut self, wallet: Option<&str>, target: String, range: u32) -> Result<u32, Error> {
+        let scripts = if target.contains('(') {
+            descriptor::expand(&target, self.network, range)
+        } else {
+            descriptor::address_script(&target, self.network).map(|script| vec![script])
+        };
+        let removed = scripts.and_then(|scripts| self.chain.remove_filters(wallet, &scripts));
+        self.record(removed)
+    }
+
+    /// Watches output `vout` of `txid`, paying to the hex encoded `script`, for its spend
+    pub fn watch_outpoint(&mut self, txid: String, vout: u32, script: String) -> Result<(), Error> {
+        let script = hex::decode(script).map_err(Error::FromHexError);
+        let watched = Hash256::decode(&txid)
+            .and_then(|hash| self.chain.watch_outpoint(OutPoint { hash, index: vout }, script?));
+        self.record(watched)
+    }
+
+    /// Syncs and returns the spend of a watched outpoint, `None` while it is unspent
+    pub fn get_spend(&mut self, txid: String, vout: u32) -> Result<Option<SpendRecord>, Error> {
+        let spend = self.chain.sync_state()
+            .and_then(|_| Hash256::decode(&txid))
+            .and_then(|hash| self.chain.get_spend(&OutPoint { hash, index: vout }));
+        self.record(spend)
+    }
+
+    /// Registers a raw transaction or finalized PSBT in hex that we created, returning its txid
+    pub fn add_local_tx(&mut self, tx: String) -> Result<String, Error> {
+        let added = hex::decode(tx).map_err(Error::FromHexError)
+            .and_then(|bytes| read_local_tx(&bytes))
+            .and_then(|(txn, owned_scripts)| self.chain.add_local_tx(&txn, owned_scripts));
+        self.record(added).map(|txid| txid.encode())
+    }
+
+    /// Drops a local transaction that never confirms, returning it and its pending descendants
+    pub fn remove_local_tx(&mut self, txid: String) -> Result<Vec<String>, Error> {
+        let removed = Hash256::decode(&txid).and_then(|txid| self.chain.remove_local_tx(&txid));
+        self.record(removed).map(|txids| txids.iter().map(|txid| txid.encode()).collect())
+    }
+
+    pub fn list_pending(&self) -> Vec<String> {
+        self.chain.pending_txids().iter().map(|txid| txid.encode()).collect()
+    }
+
+    /// Encrypted snapshot of the node state under a 32 byte key, for hosts holding the state themselves
+    pub fn export_state(&mut self, key: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let snapshot = self.chain.export_state(&key);
+        self.record(snapshot)
+    }
+
+    pub fn import_state(&mut self, snapshot: Vec<u8>, key: Vec<u8>) -> Result<(), Error> {
+        let imported = self.chain.import_state(&snapshot, &key);
+        self.record(imported)
+    }
+
+    pub fn get_inclusion_proof(&mut self, txid: String) -> Result<String, Error> {
+        let proof = Hash256::decode(&txid)
+            .and_then(|txid| self.chain.get_inclusion_proof(txid))
+            .and_then(|merkle_block| {
+                let mut binary_proof = Vec::new();
+                merkle_block.write(&mut binary_proof)?;
+                Ok(hex::encode(binary_proof))
+            });
+        self.record(proof)
+    }
+
+    pub fn verify_inclusion_proof(&mut self, proof: String) -> Result<Vec<String>, Error> {
+        let txids = self.read_inclusion_proof(proof);
+        self.record(txids)
+    }
 
+    fn read_inclusion_proof(&self, proof: String) -> Result<Vec<String>, Error> {
+        let decoded_proof = hex::decode(proof).map_err(Error::FromHexError)?;
+        let merkle_block = MerkleBlock::read(&mut decoded_proof.as_slice())?;
+        // a header with a made up target would prove anything, it must at least meet the proof of work of the network
+        merkle_block.header.validate_target(&util::pow_limit(self.network))?;
+        merkle_block.header.validate(&merkle_block.header.hash(), &[])?;
+        let txids = merkle_block.validate()?;
+        Ok(txids.iter().map(|txid| txid.encode()).collect())
     }
 
 
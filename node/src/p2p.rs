@@ -1,29 +1,93 @@
-use std::{io::{Read, Write}, net::{Ipv4Addr}, str::FromStr, sync::atomic::AtomicUsize};
+use std::{collections::VecDeque, io::{Read, Write}, net::{IpAddr, Ipv4Addr, SocketAddr}, str::FromStr, sync::atomic::AtomicUsize};
 
-use wasi::{clocks::{monotonic_clock, wall_clock}, random::random, sockets::{instance_network, network::{self, Ipv4SocketAddress}, tcp::{InputStream, IpSocketAddress, OutputStream}, tcp_create_socket::create_tcp_socket}};
+use wasi::{clocks::{monotonic_clock, wall_clock}, io::poll, random::random, sockets::{instance_network, network::{self, Ipv4SocketAddress}, tcp::{InputStream, IpSocketAddress, OutputStream}, tcp_create_socket::create_tcp_socket}};
 use bitcoin::{
     network as bitcoin_network, Network
 };
-use crate::{messages::{self, block::Block, block_locator::{BlockLocator, NO_HASH_STOP }, commands::{self, PONG}, compact_filter::CompactFilter, compact_filter_header::CompactFilterHeader, filter_locator::FilterLocator, tx::Tx, BlockHeader, Inv, Message, NodeAddr, Version, PROTOCOL_VERSION}, util::Hash256};
+use crate::{messages::{self, block::Block, block_locator::{BlockLocator, NO_HASH_STOP }, commands::{self, PONG}, compact_filter::CompactFilter, compact_filter_header::CompactFilterHeader, filter_locator::FilterLocator, inv_vect::INV_VECT_BLOCK, tx::Tx, BlockHeader, FeeFilter, Inv, Message, SendCmpct, NodeAddr, Version, NODE_COMPACT_FILTERS, NODE_WITNESS, PROTOCOL_VERSION, WTXID_RELAY_VERSION}, util::Hash256};
 use crate::node::CustomIPV4SocketAddress;
 use crate::tcpsocket::WasiTcpSocket;
 use core::sync::atomic::Ordering;
 use crate::messages::Message::Ping;
 use crate::util::{Error, Result};
 
-const MAX_PROTOCOL_VERSION: u32 = 70015;
+const MAX_PROTOCOL_VERSION: u32 = 70016;
 const USER_AGENT: &str = concat!("/BITCOINWASM:", env!("CARGO_PKG_VERSION"), '/');
+// services we advertise to peers
+const LOCAL_SERVICES: u64 = NODE_WITNESS;
+// services a peer must offer for us to sync from it
+const REQUIRED_SERVICES: u64 = NODE_COMPACT_FILTERS | NODE_WITNESS;
+// we never relay transactions, so ask peers not to announce any (21M BTC in sats/kvB)
+const MAX_FEE_FILTER: u64 = 21_000_000 * 100_000_000;
+// BIP 152 compact block version for segwit nodes
+const CMPCT_VERSION: u64 = 2;
+
+/// Features negotiated with a peer during the handshake
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerFeatures {
+    /// Protocol version used on this connection
+    pub version: u32,
+    /// Service bits advertised by the peer
+    pub services: u64,
+    /// User agent advertised by the peer
+    pub user_agent: String,
+    /// Best height reported by the peer in its version message
+    pub start_height: i32,
+    /// Peer asked for new blocks to be announced with headers
+    pub send_headers: bool,
+    /// Peer announces transactions by wtxid
+    pub wtxid_relay: bool,
+    /// Peer accepts addrv2 messages
+    pub addr_v2: bool,
+    /// Minimum fee rate the peer wants transactions announced for, in sats/kvB
+    pub fee_filter: Option<u64>,
+    /// Compact block version announced by the peer, if any
+    pub cmpct_version: Option<u64>,
+    /// Peer asked for high-bandwidth compact block relay
+    pub cmpct_high_bandwidth: bool,
+}
+
+/// Traffic and latency counters of a peer connection
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Round trip of the last ping in milliseconds
+    pub ping_latency: Option<u64>,
+    /// Bytes written to the peer including message headers
+    pub bytes_sent: u64,
+    /// Bytes read from the peer including message headers
+    pub bytes_received: u64,
+}
+
+/// Snapshot of a connected peer for diagnostics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub address: String,
+    pub features: PeerFeatures,
+    pub stats: PeerStats,
+}
+
+/// New blocks announced by a peer outside of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockAnnouncement {
+    /// Headers announced after `sendheaders`
+    Headers(Vec<BlockHeader>),
+    /// Block hashes announced through `inv`
+    Hashes(Vec<Hash256>),
+}
 
 pub struct Peer {
     input_stream: InputStream,
     output_stream: OutputStream,
     remote_address: NodeAddr,
     bitcoin_config: BitcoinP2PConfig,
+    features: PeerFeatures,
+    stats: PeerStats,
+    announcements: VecDeque<BlockAnnouncement>,
 }
 
 impl Peer {
       
-    pub fn new(network: bitcoin_network::Network, input_stream: InputStream, output_stream: OutputStream, remote_address: NodeAddr) -> Self {
+    pub fn new(network: bitcoin_network::Network, input_stream: InputStream, output_stream: OutputStream, remote_address: NodeAddr) -> Result<Self> {
       let bitcoin_config = BitcoinP2PConfig {
          network,
          nonce: random::get_random_u64(),
@@ -31,15 +95,31 @@ impl Peer {
          user_agent: USER_AGENT.to_owned(),
          height: AtomicUsize::new(0),
       };
-      let mut peer =  Self { input_stream, output_stream, remote_address, bitcoin_config};
-      peer.handshake().unwrap();
-      peer
+      let mut peer =  Self { input_stream, output_stream, remote_address, bitcoin_config, features: PeerFeatures::default(), stats: PeerStats::default(), announcements: VecDeque::new()};
+      peer.handshake()?;
+      Ok(peer)
+    }
+
+    pub fn status(&self) -> PeerStatus {
+      let ip = match self.remote_address.ip.to_ipv4_mapped() {
+          Some(ipv4) => IpAddr::V4(ipv4),
+          None => IpAddr::V6(self.remote_address.ip),
+      };
+      PeerStatus {
+          address: SocketAddr::new(ip, self.remote_address.port).to_string(),
+          features: self.features.clone(),
+          stats: self.stats.clone(),
+      }
+    }
+
+    fn magic(&self) -> [u8; 4] {
+      self.bitcoin_config.network.magic().to_bytes()
     }
 
     fn version (&self) -> Message {
       // now in unix time
       let timestamp =  wall_clock::now().seconds;
-      let services = 0;
+      let services = LOCAL_SERVICES;
       // build message
       Message::Version(Version {
           version:  self.bitcoin_config.max_protocol_version,
@@ -57,21 +137,36 @@ impl Peer {
     }
 
       fn handshake(&mut self) -> Result<()> {
+        self.features = PeerFeatures::default();
         let version_message = self.version();
         self.send(version_message)?;
         let res = self.receive(commands::VERSION)?;
 
-        if let Message::Version(_) = res {
+        if let Message::Version(remote_version) = res {
+            remote_version.validate()?;
+            // fail fast instead of timing out on filter requests later
+            remote_version.validate_services(REQUIRED_SERVICES)?;
+            self.features.version = remote_version.version.min(self.bitcoin_config.max_protocol_version);
+            self.features.services = remote_version.services;
+            self.features.user_agent = remote_version.user_agent;
+            self.features.start_height = remote_version.start_height;
+
+            // BIP 339 and BIP 155 feature messages must be sent between version and verack
+            if self.features.version >= WTXID_RELAY_VERSION {
+                self.send(Message::WtxidRelay)?;
+                self.send(Message::SendAddrV2)?;
+            }
+            self.send(Message::Verack)?;
+
             let res = self.receive(commands::VERACK)?;
 
             if let Message::Verack = res {
-                let message = Message::Verack;
-                self.send(message)?;
-                let nonce = random::get_random_u64();
-                
-                let ping_message = Ping(messages::ping::Ping { nonce });
-                self.send(ping_message)?;
-                self.receive(PONG)?;
+                self.send(Message::SendHeaders)?;
+                // low-bandwidth mode: new blocks are announced with headers instead of cmpctblock
+                self.send(Message::SendCmpct(SendCmpct { enable: 0, version: CMPCT_VERSION }))?;
+                self.send(Message::FeeFilter(FeeFilter { minfee: MAX_FEE_FILTER }))?;
+
+                self.ping()?;
 
                 println!("handshake complete");
 
@@ -81,6 +176,60 @@ impl Peer {
         Err(Error::WrongP2PMessage)
       }
 
+      // answers pings and records feature messages that arrive while we wait for something else
+      fn handle_unsolicited(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Ping(ping) => self.send(Message::Pong(ping))?,
+            Message::SendHeaders => self.features.send_headers = true,
+            Message::WtxidRelay => self.features.wtxid_relay = true,
+            Message::SendAddrV2 => self.features.addr_v2 = true,
+            Message::FeeFilter(fee_filter) => self.features.fee_filter = Some(fee_filter.minfee),
+            Message::SendCmpct(send_cmpct) => {
+                self.features.cmpct_version = Some(send_cmpct.version);
+                self.features.cmpct_high_bandwidth = send_cmpct.enable == 1;
+            },
+            Message::Headers(headers) if !headers.inner.is_empty() => {
+                self.announcements.push_back(BlockAnnouncement::Headers(headers.inner));
+            },
+            Message::Inv(inv) => {
+                let hashes: Vec<_> = inv.objects.iter()
+                    .filter(|object| object.obj_type == INV_VECT_BLOCK)
+                    .map(|object| object.hash)
+                    .collect();
+                if !hashes.is_empty() {
+                    self.announcements.push_back(BlockAnnouncement::Hashes(hashes));
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+      }
+
+      /// Waits up to `timeout` nanoseconds for the peer to announce a new block
+      pub fn wait_for_announcement(&mut self, timeout: u64) -> Result<Option<BlockAnnouncement>> {
+        let deadline = monotonic_clock::now() + timeout;
+        loop {
+            if let Some(announcement) = self.announcements.pop_front() {
+                return Ok(Some(announcement));
+            }
+            let now = monotonic_clock::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // sleep until the peer sends something or the time is up
+            let readable = self.input_stream.subscribe();
+            let timer = monotonic_clock::subscribe_duration(deadline - now);
+            if !poll::poll(&[&readable, &timer]).contains(&0) {
+                return Ok(None);
+            }
+            drop(readable);
+            let magic = self.magic();
+            let (message, header) = Message::read(&mut self.input_stream, magic)?;
+            self.stats.bytes_received += (header.size() + header.payload_size as usize) as u64;
+            self.handle_unsolicited(message)?;
+        }
+      }
+
       pub fn fetch_headers(& mut self, last_known_blockhash: Hash256) -> Result<Vec<BlockHeader>> {
             let block_locator = BlockLocator{ version: PROTOCOL_VERSION, block_locator_hashes: vec![last_known_blockhash], hash_stop:  NO_HASH_STOP};
             self.send(Message::GetHeaders(block_locator))?;
@@ -119,12 +268,20 @@ impl Peer {
         Err(Error::WrongP2PMessage)
   }
 
-      pub fn keep_alive(& mut self) -> Result<()> {
+      // sends a ping and records the round trip once the pong arrives
+      fn ping(&mut self) -> Result<()> {
             let nonce = random::get_random_u64();
             let ping_message = Ping(messages::ping::Ping { nonce });
             self.send(ping_message)?;
+            let sent_at = monotonic_clock::now();
 
-            match self.receive(PONG) {
+            self.receive(PONG)?;
+            self.stats.ping_latency = Some((monotonic_clock::now() - sent_at) / 1_000_000);
+            Ok(())
+      }
+
+      pub fn keep_alive(& mut self) -> Result<()> {
+            match self.ping() {
                 Ok(_) => {
                     println!("initialted already");
 
@@ -142,14 +299,23 @@ impl Peer {
         self.send(Message::GetData(inv))?;
 
         loop {
-            if let Message::Block(block) =  self.receive(commands::BLOCK)?{
-                blocks.push(block.clone());
-                if blocks.len() == data_len {
-                    return Ok(blocks);
-                } 
-                continue;
+            match self.receive(commands::BLOCK)? {
+                Message::Block(block) => {
+                    blocks.push(block.clone());
+                    if blocks.len() == data_len {
+                        return Ok(blocks);
+                    }
+                    continue;
+                },
+                // sent after the blocks the peer has, so nothing is left to read
+                Message::NotFound(inv) => {
+                    let hashes: Vec<_> = inv.objects.iter().map(|object| object.hash).collect();
+                    return Err(Error::BlockNotFound(format!("{:?}", hashes)));
+                },
+                _ => {
+                    return Err(Error::WrongP2PMessage);
+                }
             }
-            return Err(Error::WrongP2PMessage);
         }
     }
 
@@ -180,7 +346,11 @@ impl Peer {
     }
     
         fn send(&mut self, message: Message) -> Result<()> {
-            message.write(&mut self.output_stream, [0xfa, 0xbf, 0xb5, 0xda]).map_err(Error::IOError)?;
+            let magic = self.magic();
+            let mut bytes = Vec::new();
+            message.write(&mut bytes, magic).map_err(Error::IOError)?;
+            self.output_stream.write_all(&bytes).map_err(Error::IOError)?;
+            self.stats.bytes_sent += bytes.len() as u64;
             self.output_stream.blocking_flush().map_err(Error::StreamingError)?;
             Ok(())
       }
@@ -190,15 +360,25 @@ impl Peer {
     fn receive(& mut self, message_type: [u8; 12]) -> Result<Message>{
          let duration = monotonic_clock::now() + 1_000_000_000;
          while monotonic_clock::now() < duration {
-             let decoded_message = Message::read(&mut self.input_stream);
+             // the read blocks until a whole message arrives, so wait for the peer to send something first: a stalled peer times out
+             let readable = self.input_stream.subscribe();
+             let timer = monotonic_clock::subscribe_duration(duration.saturating_sub(monotonic_clock::now()));
+             if !poll::poll(&[&readable, &timer]).contains(&0) {
+                 break;
+             }
+             drop(readable);
+             let magic = self.magic();
+             let decoded_message = Message::read(&mut self.input_stream, magic);
              match decoded_message{
                  Ok(message) => {
+                    self.stats.bytes_received += (message.1.size() + message.1.payload_size as usize) as u64;
                     if message.1.command == commands::NOTFOUND {
                         return Ok(message.0)
                     }
                      if message.1.command == message_type {
                          return Ok(message.0)
                      }
+                     self.handle_unsolicited(message.0)?;
                  },
                  Err(Error::IOError(_)) => continue,
                  Err(err) => {
@@ -223,8 +403,10 @@ pub struct BitcoinP2PConfig {
 }
 
 pub struct P2P {
-    peer: Option<Peer>,
-    socket: WasiTcpSocket, 
+    // the first peer drives header and filter sync, the others only serve blocks.
+    // peers are declared first so their streams drop before the sockets
+    peers: Vec<Peer>,
+    sockets: Vec<WasiTcpSocket>,
 }
 pub trait  P2PControl {
     fn connect_peer(&mut self, address: CustomIPV4SocketAddress, network: bitcoin_network::Network) -> Result<()>;
@@ -233,15 +415,18 @@ pub trait  P2PControl {
 impl P2PControl for P2P {
     fn connect_peer(&mut self, remote_address: CustomIPV4SocketAddress, network: bitcoin_network::Network) -> Result<()> {
         let wasi_socket_address = IpSocketAddress::Ipv4(Ipv4SocketAddress{ port: remote_address.port, address: remote_address.ip });
-        let connect_res = self.socket.blocking_connect(wasi_socket_address);
+        let raw_socket = create_tcp_socket(network::IpAddressFamily::Ipv4).map_err(Error::TCPError)?;
+        let socket = WasiTcpSocket::new(raw_socket, instance_network::instance_network());
+        let connect_res = socket.blocking_connect(wasi_socket_address);
 
         match connect_res {
             Ok((input_stream, output_stream)) => {
                 let (a, b,c, d) = remote_address.ip;
                 let socket_address = std::net::IpAddr::V4(Ipv4Addr::new(a, b, c, d));
                 let remote_address = NodeAddr::new(socket_address, remote_address.port); 
-                let peer = Peer::new(network, input_stream, output_stream, remote_address);
-                self.peer = Some(peer);
+                let peer = Peer::new(network, input_stream, output_stream, remote_address)?;
+                self.peers.push(peer);
+                self.sockets.push(socket);
                 Ok(())
             },
             Err(e) => {
@@ -256,52 +441,78 @@ impl P2PControl for P2P {
     impl P2P {
 
         pub fn new() -> Self {
-            let raw_socket = create_tcp_socket(network::IpAddressFamily::Ipv4).expect("cant create socket");
-            let network =  instance_network::instance_network();
-            let wasi_socket = WasiTcpSocket::new(raw_socket, network);
-            P2P{ socket: wasi_socket, peer: None}
+            P2P{ peers: Vec::new(), sockets: Vec::new() }
+        }
+
+        pub fn peer_count(&self) -> usize {
+            self.peers.len()
+        }
+
+        fn primary(&mut self) -> Result<&mut Peer> {
+            self.peers
+                .first_mut()
+                .ok_or(Error::PeerNotFound)
         }
 
         pub fn fetch_headers(&mut self, last_known_blockhash: Hash256) -> Result<Vec<BlockHeader>> {
-            self.peer
-                .as_mut()
-                .ok_or(Error::PeerNotFound)?
+            self.primary()?
                 .fetch_headers(last_known_blockhash)
         }
     
         pub fn get_compact_filters(&mut self, start_height: u32, hash_stop: Hash256) -> Result<Vec<CompactFilter>> { 
-            self.peer
-                .as_mut()
-                .ok_or(Error::PeerNotFound)?
+            self.primary()?
                 .fetch_compact_filters(start_height, hash_stop)
         }
 
         pub fn get_compact_filter_headers(&mut self, start_height: u32, hash_stop: Hash256) -> Result<CompactFilterHeader> { 
-            self.peer
-                .as_mut()
-                .ok_or(Error::PeerNotFound)?
+            self.primary()?
                 .fetch_compact_filter_headers(start_height, hash_stop)
         }
     
-        pub fn get_block(&mut self, inv: Inv) -> Result<Vec<Block>> {
-            self.peer
-                .as_mut()
+        /// Fetches blocks from the peer at `peer`. Blocks that a decoy peer does not have are fetched
+        /// from the primary peer instead, which served the filters and so knows every block of the batch.
+        pub fn get_block_from(&mut self, peer: usize, inv: Inv) -> Result<Vec<Block>> {
+            let fetched = self.peers
+                .get_mut(peer)
                 .ok_or(Error::PeerNotFound)?
-                .fetch_blocks(inv)
+                .fetch_blocks(inv.clone());
+            match fetched {
+                Err(Error::BlockNotFound(_)) if peer != 0 => self.primary()?.fetch_blocks(inv),
+                fetched => fetched,
+            }
         }
 
         pub fn get_transaction(&mut self, inv: Inv) -> Result<Vec<Tx>> {
-            self.peer
-                .as_mut()
-                .ok_or(Error::PeerNotFound)?
+            self.primary()?
                 .fetch_transactions(inv)
         }
     
+        pub fn wait_for_announcement(&mut self, timeout: u64) -> Result<Option<BlockAnnouncement>> {
+            self.primary()?
+                .wait_for_announcement(timeout)
+        }
+
+        pub fn peers_status(&self) -> Vec<PeerStatus> {
+            self.peers
+                .iter()
+                .map(|peer| peer.status())
+                .collect()
+        }
+
+        /// Keeps the primary peer connected, decoy peers that stop answering are dropped and their downloads go to the peers left
         pub fn keep_alive(&mut self) -> Result<()> {
-            self.peer
-                .as_mut()
-                .ok_or(Error::PeerNotFound)?
-                .keep_alive()
+            self.primary()?.keep_alive()?;
+            let mut peer = 1;
+            while peer < self.peers.len() {
+                if self.peers[peer].keep_alive().is_ok() {
+                    peer += 1;
+                    continue;
+                }
+                // the streams go before their socket
+                self.peers.remove(peer);
+                self.sockets.remove(peer);
+            }
+            Ok(())
         }
         
     }
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use bitcoin::{consensus, Psbt};
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{block::Block, tx::Tx, tx_out::TxOut, OutPoint};
+use crate::util::{Error, Hash256, Serializable};
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// Reads a locally created transaction, either serialized or as a finalized PSBT.
+///
+/// Also returns the scripts of PSBT outputs carrying a key origin, which the signer derived itself, such as change.
+pub fn read_local_tx(bytes: &[u8]) -> Result<(Tx, Vec<Vec<u8>>), Error> {
+    if !bytes.starts_with(PSBT_MAGIC) {
+        return Ok((Tx::read(&mut &bytes[..])?, vec![]));
+    }
+
+    let psbt = Psbt::deserialize(bytes).map_err(|e| Error::BadData(format!("Invalid PSBT: {}", e)))?;
+    if let Some(index) = psbt.inputs.iter().position(|input| input.final_script_sig.is_none() && input.final_script_witness.is_none()) {
+        return Err(Error::BadData(format!("PSBT input {} is not finalized", index)));
+    }
+    let owned_scripts = psbt.outputs.iter().zip(psbt.unsigned_tx.output.iter())
+        .filter(|(output, _)| !output.bip32_derivation.is_empty() || !output.tap_key_origins.is_empty())
+        .map(|(_, tx_out)| tx_out.script_pubkey.to_bytes())
+        .collect();
+    let txn = psbt.extract_tx_unchecked_fee_rate();
+    Ok((Tx::read(&mut consensus::encode::serialize(&txn).as_slice())?, owned_scripts))
+}
+
+/// A transaction created locally that no block confirmed yet
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PendingTx {
+    pub txid: Hash256,
+    inputs: Vec<OutPoint>,
+    outputs: Vec<TxOut>,
+}
+
+impl PendingTx {
+    pub fn new(txn: &Tx) -> Self {
+        PendingTx {
+            txid: txn.hash(),
+            inputs: txn.inputs.iter().map(|input| input.prev_output.clone()).collect(),
+            outputs: txn.outputs.clone(),
+        }
+    }
+
+    pub fn inputs(&self) -> &[OutPoint] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = (OutPoint, &TxOut)> {
+        self.outputs.iter().enumerate().map(|(index, output)| (OutPoint { hash: self.txid, index: index as u32 }, output))
+    }
+}
+
+/// Local transactions whose inputs count as spent and whose outputs count as received until they confirm or conflict
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct PendingTxs {
+    txns: Vec<PendingTx>,
+}
+
+impl PendingTxs {
+
+    pub fn txids(&self) -> Vec<Hash256> {
+        self.txns.iter().map(|txn| txn.txid).collect()
+    }
+
+    pub fn contains(&self, txid: &Hash256) -> bool {
+        self.txns.iter().any(|txn| txn.txid == *txid)
+    }
+
+    /// Outpoints spent by pending transactions
+    pub fn spent(&self) -> HashSet<&OutPoint> {
+        self.txns.iter().flat_map(|txn| txn.inputs.iter()).collect()
+    }
+
+    /// Outputs created by pending transactions
+    pub fn outputs(&self) -> impl Iterator<Item = (OutPoint, &TxOut)> {
+        self.txns.iter().flat_map(|txn| txn.outputs())
+    }
+
+    /// Adds a transaction, replacing pending ones spending the same outpoints, and returns the replaced txids
+    pub fn add(&mut self, pending: PendingTx) -> Vec<Hash256> {
+        if self.contains(&pending.txid) {
+            return vec![];
+        }
+        let spent: HashSet<&OutPoint> = pending.inputs.iter().collect();
+        let conflicts = self.txns.iter().filter(|txn| txn.inputs.iter().any(|input| spent.contains(input))).map(|txn| txn.txid).collect();
+        let replaced = self.remove_with_descendants(conflicts);
+        self.txns.push(pending);
+        replaced
+    }
+
+    /// Drops a transaction that will not confirm along with its descendants, returning their txids
+    pub fn remove(&mut self, txid: &Hash256) -> Result<Vec<Hash256>, Error> {
+        if !self.contains(txid) {
+            return Err(Error::BadArgument(format!("Transaction {} is not pending", txid.encode())));
+        }
+        Ok(self.remove_with_descendants(vec![*txid]))
+    }
+
+    /// Drops the transactions a block confirms or conflicts with, returning the conflicting txids.
+    ///
+    /// Descendants of a conflicting transaction spend outputs that will never exist, so they go too.
+    pub fn apply_block(&mut self, block: &Block) -> Vec<Hash256> {
+        let mut conflicts = Vec::new();
+        for txn in block.txns.iter() {
+            let txid = txn.hash();
+            let spent: HashSet<&OutPoint> = txn.inputs.iter().map(|input| &input.prev_output).collect();
+            self.txns.retain(|pending| {
+                if pending.txid == txid {
+                    return false;
+                }
+                if pending.inputs.iter().any(|input| spent.contains(input)) {
+                    conflicts.push(pending.txid);
+                    return false;
+                }
+                true
+            });
+        }
+        self.remove_with_descendants(conflicts)
+    }
+
+    fn remove_with_descendants(&mut self, mut removed: Vec<Hash256>) -> Vec<Hash256> {
+        let mut checked = 0;
+        while checked < removed.len() {
+            let parent = removed[checked];
+            checked += 1;
+            self.txns.retain(|txn| txn.txid != parent);
+            for txn in self.txns.iter() {
+                if txn.inputs.iter().any(|input| input.hash == parent) && !removed.contains(&txn.txid) {
+                    removed.push(txn.txid);
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{tx_in::TxIn, BlockHeader};
+
+    fn tx(prev_outputs: Vec<OutPoint>, satoshis: i64) -> Tx {
+        Tx {
+            version: 2,
+            flag: None,
+            inputs: prev_outputs.into_iter().map(|prev_output| TxIn { prev_output, unlock_script: vec![], sequence: 0xffffffff }).collect(),
+            outputs: vec![TxOut { satoshis, lock_script: vec![0x51] }],
+            witnesses: None,
+            lock_time: 0,
+        }
+    }
+
+    fn outpoint(byte: u8) -> OutPoint {
+        OutPoint { hash: Hash256([byte; 32]), index: 0 }
+    }
+
+    #[test]
+    fn replace_and_descendants() {
+        let mut pending = PendingTxs::default();
+        let parent = tx(vec![outpoint(1)], 1000);
+        let child = tx(vec![OutPoint { hash: parent.hash(), index: 0 }], 900);
+        assert!(pending.add(PendingTx::new(&parent)).is_empty());
+        assert!(pending.add(PendingTx::new(&child)).is_empty());
+        assert!(pending.add(PendingTx::new(&child)).is_empty());
+        assert_eq!(pending.txids(), vec![parent.hash(), child.hash()]);
+
+        // a fee bump of the parent replaces it and orphans the child
+        let bump = tx(vec![outpoint(1)], 800);
+        assert_eq!(pending.add(PendingTx::new(&bump)), vec![parent.hash(), child.hash()]);
+        assert_eq!(pending.txids(), vec![bump.hash()]);
+    }
+
+    #[test]
+    fn confirm_and_conflict() {
+        let mut pending = PendingTxs::default();
+        let confirmed = tx(vec![outpoint(1)], 1000);
+        let conflicted = tx(vec![outpoint(2)], 1000);
+        let child = tx(vec![OutPoint { hash: conflicted.hash(), index: 0 }], 900);
+        for txn in [&confirmed, &conflicted, &child] {
+            pending.add(PendingTx::new(txn));
+        }
+
+        let double_spend = tx(vec![outpoint(3), outpoint(2)], 500);
+        let block = Block { header: BlockHeader::default(), txns: vec![confirmed, double_spend] };
+        assert_eq!(pending.apply_block(&block), vec![conflicted.hash(), child.hash()]);
+        assert!(pending.txids().is_empty());
+    }
+
+    #[test]
+    fn read_raw_and_psbt() {
+        let txn = tx(vec![outpoint(1)], 1000);
+        let mut raw = Vec::new();
+        txn.write(&mut raw).unwrap();
+        assert_eq!(read_local_tx(&raw).unwrap(), (txn.clone(), vec![]));
+
+        // the unsigned transaction in the global map, then a finalized input and an empty output map
+        let mut psbt = PSBT_MAGIC.to_vec();
+        psbt.extend_from_slice(&[1, 0, raw.len() as u8]);
+        psbt.extend_from_slice(&raw);
+        psbt.push(0);
+        let unfinalized = [psbt.clone(), vec![0, 0]].concat();
+        assert!(read_local_tx(&unfinalized).is_err());
+
+        psbt.extend_from_slice(&[1, 7, 2, 0x01, 0xaa, 0]);
+        let (extracted, owned_scripts) = read_local_tx(&[psbt.clone(), vec![0]].concat()).unwrap();
+        assert_eq!(extracted.inputs[0].unlock_script, vec![0x01, 0xaa]);
+        assert_eq!(extracted.hash(), tx_with_script(txn, vec![0x01, 0xaa]).hash());
+        assert!(owned_scripts.is_empty());
+
+        // the output map carries the derivation of its key, so the output pays back to us
+        let mut derivation = vec![34, 0x02];
+        derivation.extend_from_slice(&hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap());
+        derivation.extend_from_slice(&[8, 0xde, 0xad, 0xbe, 0xef, 1, 0, 0, 0, 0]);
+        let (_, owned_scripts) = read_local_tx(&[psbt, derivation].concat()).unwrap();
+        assert_eq!(owned_scripts, vec![vec![0x51]]);
+    }
+
+    #[test]
+    fn remove_stuck() {
+        let mut pending = PendingTxs::default();
+        let parent = tx(vec![outpoint(1)], 1000);
+        let child = tx(vec![OutPoint { hash: parent.hash(), index: 0 }], 900);
+        let unrelated = tx(vec![outpoint(2)], 1000);
+        for txn in [&parent, &child, &unrelated] {
+            pending.add(PendingTx::new(txn));
+        }
+
+        assert_eq!(pending.remove(&parent.hash()).unwrap(), vec![parent.hash(), child.hash()]);
+        assert_eq!(pending.txids(), vec![unrelated.hash()]);
+        assert!(pending.remove(&parent.hash()).is_err());
+    }
+
+    fn tx_with_script(mut txn: Tx, unlock_script: Vec<u8>) -> Tx {
+        txn.inputs[0].unlock_script = unlock_script;
+        txn
+    }
+}
@@ -0,0 +1,128 @@
+use crate::util::Hash256;
+
+/// Controls the decoy block downloads that hide which compact filters matched our scripts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrivacyConfig {
+    /// Decoy blocks requested for every matching block, 0 disables decoys
+    pub decoy_ratio: u32,
+}
+
+impl PrivacyConfig {
+    pub fn enabled(&self) -> bool {
+        self.decoy_ratio > 0
+    }
+}
+
+/// A block to download and the peer it is requested from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub hash: Hash256,
+    /// Index of the connected peer serving the request
+    pub peer: usize,
+    /// Downloaded only to look like a false positive and discarded afterwards
+    pub decoy: bool,
+}
+
+/// Plans the block downloads of one filter batch.
+///
+/// `matched` are the blocks whose filters matched and `candidates` every block of the batch.
+/// With privacy enabled, `decoy_ratio` random non-matching blocks are added per matching block, and
+/// batches without a match still fetch `decoy_ratio` decoys so that downloading at all reveals nothing.
+/// Real and decoy requests are shuffled together and dealt round-robin over `peers` from a random
+/// starting peer, so neither the order nor the serving peer tells them apart.
+pub fn schedule_downloads(matched: &[Hash256], candidates: &[Hash256], config: PrivacyConfig, peers: usize, mut random: impl FnMut() -> u64) -> Vec<BlockRequest> {
+    let mut pool: Vec<Hash256> = candidates.iter().filter(|hash| !matched.contains(hash)).copied().collect();
+    let wanted = matched.len().max(1) * config.decoy_ratio as usize;
+    let decoys = wanted.min(pool.len());
+
+    // partial Fisher-Yates: the first `decoys` entries of the pool become a uniform sample
+    for i in 0..decoys {
+        let j = i + (random() % (pool.len() - i) as u64) as usize;
+        pool.swap(i, j);
+    }
+    pool.truncate(decoys);
+
+    let mut requests: Vec<BlockRequest> = matched.iter()
+        .map(|hash| BlockRequest { hash: *hash, peer: 0, decoy: false })
+        .chain(pool.into_iter().map(|hash| BlockRequest { hash, peer: 0, decoy: true }))
+        .collect();
+
+    if config.enabled() {
+        for i in (1..requests.len()).rev() {
+            let j = (random() % (i as u64 + 1)) as usize;
+            requests.swap(i, j);
+        }
+    }
+
+    let peers = peers.max(1);
+    let first_peer = if config.enabled() { (random() % peers as u64) as usize } else { 0 };
+    for (i, request) in requests.iter_mut().enumerate() {
+        request.peer = if config.enabled() { (first_peer + i) % peers } else { 0 };
+    }
+    requests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(count: u8) -> Vec<Hash256> {
+        (0..count).map(|i| Hash256([i; 32])).collect()
+    }
+
+    // xorshift, good enough to exercise the shuffles deterministically
+    fn rng() -> impl FnMut() -> u64 {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let candidates = hashes(10);
+        let matched = vec![candidates[2], candidates[7]];
+        let requests = schedule_downloads(&matched, &candidates, PrivacyConfig::default(), 3, rng());
+        let expected: Vec<_> = matched.iter().map(|hash| BlockRequest { hash: *hash, peer: 0, decoy: false }).collect();
+        assert_eq!(requests, expected);
+    }
+
+    #[test]
+    fn decoys() {
+        let candidates = hashes(50);
+        let matched = vec![candidates[3], candidates[40]];
+        let requests = schedule_downloads(&matched, &candidates, PrivacyConfig { decoy_ratio: 3 }, 3, rng());
+        assert_eq!(requests.len(), 8);
+        for hash in matched.iter() {
+            assert!(requests.iter().any(|request| request.hash == *hash && !request.decoy));
+        }
+        let decoys: Vec<_> = requests.iter().filter(|request| request.decoy).collect();
+        assert_eq!(decoys.len(), 6);
+        for (i, decoy) in decoys.iter().enumerate() {
+            assert!(candidates.contains(&decoy.hash) && !matched.contains(&decoy.hash));
+            assert!(!decoys[i + 1..].iter().any(|other| other.hash == decoy.hash));
+        }
+        for peer in 0..3 {
+            assert!(requests.iter().filter(|request| request.peer == peer).count() >= 2);
+        }
+    }
+
+    #[test]
+    fn decoys_without_match() {
+        let candidates = hashes(50);
+        let requests = schedule_downloads(&[], &candidates, PrivacyConfig { decoy_ratio: 2 }, 1, rng());
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.decoy && request.peer == 0));
+    }
+
+    #[test]
+    fn small_batch() {
+        let candidates = hashes(3);
+        let matched = vec![candidates[0]];
+        let requests = schedule_downloads(&matched, &candidates, PrivacyConfig { decoy_ratio: 5 }, 2, rng());
+        assert_eq!(requests.len(), 3);
+    }
+}
@@ -0,0 +1,90 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+pub use ring::aead::NONCE_LEN;
+
+use crate::util::Error;
+
+/// Length of the key the host encrypts snapshots with
+pub const SNAPSHOT_KEY_LEN: usize = 32;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"NSNP";
+const SNAPSHOT_VERSION: u8 = 1;
+// magic and version
+const HEADER_LEN: usize = 5;
+
+/// Encrypts the entries of a store with ChaCha20-Poly1305.
+///
+/// A snapshot is the magic bytes and a version, followed by the nonce and the sealed bincode encoded entries.
+/// The header is authenticated as well, so a snapshot cannot be replayed as another version.
+pub fn seal(entries: &[(String, Vec<u8>)], key: &[u8], nonce: [u8; NONCE_LEN]) -> Result<Vec<u8>, Error> {
+    let key = snapshot_key(key)?;
+    let mut sealed = bincode::serialize(entries).map_err(|e| Error::SerializationError(e.to_string()))?;
+
+    let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+    snapshot.push(SNAPSHOT_VERSION);
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(&snapshot), &mut sealed)?;
+    snapshot.extend_from_slice(&nonce);
+    snapshot.extend_from_slice(&sealed);
+    Ok(snapshot)
+}
+
+/// Decrypts a snapshot made by `seal`, failing if it was made with another key or tampered with
+pub fn open(snapshot: &[u8], key: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let key = snapshot_key(key)?;
+    if snapshot.len() < HEADER_LEN + NONCE_LEN || &snapshot[..4] != SNAPSHOT_MAGIC {
+        return Err(Error::BadData("Not a state snapshot".to_string()));
+    }
+    if snapshot[4] != SNAPSHOT_VERSION {
+        return Err(Error::Unsupported(format!("Snapshot version {}", snapshot[4])));
+    }
+
+    let (header, rest) = snapshot.split_at(HEADER_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let mut sealed = sealed.to_vec();
+    let plaintext = key.open_in_place(nonce, Aad::from(header), &mut sealed)?;
+    bincode::deserialize(plaintext).map_err(|e| Error::SerializationError(e.to_string()))
+}
+
+fn snapshot_key(key: &[u8]) -> Result<LessSafeKey, Error> {
+    if key.len() != SNAPSHOT_KEY_LEN {
+        return Err(Error::BadArgument(format!("Snapshot key must be {} bytes", SNAPSHOT_KEY_LEN)));
+    }
+    Ok(LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(String, Vec<u8>)> {
+        vec![("chain_state".to_string(), vec![1, 2, 3]), ("wallet_alice".to_string(), vec![])]
+    }
+
+    #[test]
+    fn seal_open() {
+        let key = [7; SNAPSHOT_KEY_LEN];
+        let snapshot = seal(&entries(), &key, [1; NONCE_LEN]).unwrap();
+        assert_eq!(&snapshot[..4], SNAPSHOT_MAGIC);
+        assert_eq!(open(&snapshot, &key).unwrap(), entries());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let snapshot = seal(&entries(), &[7; SNAPSHOT_KEY_LEN], [1; NONCE_LEN]).unwrap();
+        assert!(open(&snapshot, &[8; SNAPSHOT_KEY_LEN]).is_err());
+        assert!(open(&snapshot, &[7; 16]).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let key = [7; SNAPSHOT_KEY_LEN];
+        let snapshot = seal(&entries(), &key, [1; NONCE_LEN]).unwrap();
+        for position in [4, HEADER_LEN, snapshot.len() - 1] {
+            let mut tampered = snapshot.clone();
+            tampered[position] ^= 1;
+            assert!(open(&tampered, &key).is_err());
+        }
+        assert!(open(&snapshot[..HEADER_LEN + NONCE_LEN - 1], &key).is_err());
+    }
+}
@@ -8,12 +8,17 @@
 
 use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::io::Read;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
 use std::{cmp, io};
 use bitcoin::hashes::siphash24;
 
+use crate::messages::{block::Block, OutPoint};
 use super::{var_int, Hash256};
 
+// scripts starting with OP_RETURN are provably unspendable and left out of the filter
+const OP_RETURN: u8 = 0x6a;
+
 const P: u8 = 19;
 const M: u64 = 784931;
 
@@ -28,6 +33,27 @@ impl BlockFilter {
     /// Creates a new filter from pre-computed data.
     pub fn new(content: &[u8]) -> BlockFilter { BlockFilter { content: content.to_vec() } }
 
+    /// Computes the basic filter of a block.
+    ///
+    /// `script_for_coin` returns the script of every output spent by the block.
+    pub fn from_block<M>(block: &Block, script_for_coin: M) -> Result<BlockFilter, io::Error>
+    where
+        M: FnMut(&OutPoint) -> Result<Vec<u8>, io::Error>,
+    {
+        let mut out = Vec::new();
+        let mut writer = BlockFilterWriter::new(&mut out, block);
+        writer.add_output_scripts();
+        writer.add_input_scripts(script_for_coin)?;
+        writer.finish()?;
+        Ok(BlockFilter { content: out })
+    }
+
+    /// Returns true if every output script of the block is in this [`BlockFilter`].
+    ///
+    /// Light clients don't know the spent scripts, so this is the part of a served filter they can check.
+    pub fn match_block_outputs(&self, block: &Block) -> Result<bool, io::Error> {
+        self.match_all(&block.header.hash(), output_scripts(block))
+    }
 
     /// Returns true if any query matches against this [`BlockFilter`].
     pub fn match_any<I>(&self, block_hash: &Hash256, query: I) -> Result<bool, io::Error>
@@ -51,6 +77,65 @@ impl BlockFilter {
 }
 
 
+// output scripts of a block that go into its basic filter
+fn output_scripts(block: &Block) -> impl Iterator<Item = &[u8]> {
+    block.txns.iter()
+        .flat_map(|txn| txn.outputs.iter())
+        .map(|output| output.lock_script.as_slice())
+        .filter(|script| !script.is_empty() && script[0] != OP_RETURN)
+}
+
+// siphash keys of a block filter are the first 16 bytes of the block hash
+fn filter_keys(block_hash: &Hash256) -> (u64, u64) {
+    let k0 = u64::from_le_bytes(block_hash.0[0..8].try_into().expect("8 byte slice"));
+    let k1 = u64::from_le_bytes(block_hash.0[8..16].try_into().expect("8 byte slice"));
+    (k0, k1)
+}
+
+/// Compiles and writes a block filter
+pub struct BlockFilterWriter<'a, W> {
+    block: &'a Block,
+    writer: GcsFilterWriter<'a, W>,
+}
+
+impl<'a, W: Write> BlockFilterWriter<'a, W> {
+    /// Creates a new [`BlockFilterWriter`] from `block`.
+    pub fn new(writer: &'a mut W, block: &'a Block) -> BlockFilterWriter<'a, W> {
+        let (k0, k1) = filter_keys(&block.header.hash());
+        let writer = GcsFilterWriter::new(writer, k0, k1, M, P);
+        BlockFilterWriter { block, writer }
+    }
+
+    /// Adds output scripts of the block to the filter, excluding OP_RETURN scripts.
+    pub fn add_output_scripts(&mut self) {
+        for script in output_scripts(self.block) {
+            self.add_element(script);
+        }
+    }
+
+    /// Adds the scripts of the outputs spent by the block, `script_for_coin` looks them up.
+    pub fn add_input_scripts<M>(&mut self, mut script_for_coin: M) -> Result<(), io::Error>
+    where
+        M: FnMut(&OutPoint) -> Result<Vec<u8>, io::Error>,
+    {
+        for txn in self.block.txns.iter().filter(|txn| !txn.coinbase()) {
+            for input in txn.inputs.iter() {
+                let script = script_for_coin(&input.prev_output)?;
+                if !script.is_empty() {
+                    self.add_element(&script);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an arbitrary element to filter.
+    pub fn add_element(&mut self, data: &[u8]) { self.writer.add_element(data); }
+
+    /// Writes the block filter.
+    pub fn finish(&mut self) -> Result<usize, io::Error> { self.writer.finish() }
+}
+
 /// Reads and interpret a block filter
 pub struct BlockFilterReader {
     reader: GcsFilterReader
@@ -60,9 +145,7 @@ impl BlockFilterReader {
     /// Create a block filter reader
 
     pub fn new(block_hash: &Hash256) -> BlockFilterReader {
-        let block_hash_as_int = block_hash.0;
-        let k0 = u64::from_le_bytes(block_hash_as_int[0..8].try_into().expect("8 byte slice"));
-        let k1 = u64::from_le_bytes(block_hash_as_int[8..16].try_into().expect("8 byte slice"));
+        let (k0, k1) = filter_keys(block_hash);
         BlockFilterReader { reader: GcsFilterReader::new(k0, k1,M,P) }
     }
 
@@ -187,6 +270,55 @@ impl GcsFilterReader {
     }
 }
 
+/// Golomb-Rice encoded filter writer
+pub struct GcsFilterWriter<'a, W> {
+    filter: GcsFilter,
+    writer: &'a mut W,
+    elements: BTreeSet<Vec<u8>>,
+    m: u64,
+}
+
+impl<'a, W: Write> GcsFilterWriter<'a, W> {
+    /// Creates a new [`GcsFilterWriter`] wrapping a generic writer, with specific seed to siphash.
+    pub fn new(writer: &'a mut W, k0: u64, k1: u64, m: u64, p: u8) -> GcsFilterWriter<'a, W> {
+        GcsFilterWriter { filter: GcsFilter::new(k0, k1, p), writer, elements: BTreeSet::new(), m }
+    }
+
+    /// Adds data to the filter, duplicates are only counted once.
+    pub fn add_element(&mut self, element: &[u8]) {
+        if !element.is_empty() {
+            self.elements.insert(element.to_vec());
+        }
+    }
+
+    /// Writes the filter to the wrapped writer and returns the number of bytes written.
+    pub fn finish(&mut self) -> Result<usize, io::Error> {
+        let nm = self.elements.len() as u64 * self.m;
+
+        // map hashes to [0, n_elements * M)
+        let mut mapped: Vec<_> = self
+            .elements
+            .iter()
+            .map(|e| map_to_range(self.filter.hash(e.as_slice()), nm))
+            .collect();
+        mapped.sort_unstable();
+
+        // write number of elements as varint
+        var_int::write(mapped.len() as u64, self.writer)?;
+        let mut wrote = var_int::size(mapped.len() as u64);
+
+        // write out deltas of sorted values into a Golomb-Rice coded bit stream
+        let mut writer = BitStreamWriter::new(self.writer);
+        let mut last = 0;
+        for data in mapped {
+            wrote += self.filter.golomb_rice_encode(&mut writer, data - last)?;
+            last = data;
+        }
+        wrote += writer.flush()?;
+        Ok(wrote)
+    }
+}
+
 // fast reduction of hash to [0, nm) range
 fn map_to_range (hash: u64, nm: u64) -> u64 {
     ((hash as u128 * nm as u128) >> 64) as u64
@@ -205,6 +337,23 @@ impl GcsFilter {
     fn new(k0: u64, k1: u64, p: u8) -> GcsFilter { GcsFilter { k0, k1, p } }
 
 
+    /// Golomb-Rice encodes a number `n` to a bit stream (parameter 2^k), returns the number of bytes written.
+    fn golomb_rice_encode<W>(&self, writer: &mut BitStreamWriter<'_, W>, n: u64) -> Result<usize, io::Error>
+    where
+        W: Write + ?Sized,
+    {
+        let mut wrote = 0;
+        let mut q = n >> self.p;
+        while q > 0 {
+            let nbits = cmp::min(q, 64) as u8;
+            wrote += writer.write(!0u64, nbits)?;
+            q -= nbits as u64;
+        }
+        wrote += writer.write(0, 1)?;
+        wrote += writer.write(n, self.p)?;
+        Ok(wrote)
+    }
+
     /// Golomb-Rice decodes a number from a bit stream (parameter 2^k).
     fn golomb_rice_decode<R>(&self, reader: &mut BitStreamReader<R>) -> Result<u64, io::Error>
     where
@@ -225,7 +374,6 @@ impl GcsFilter {
 }
 
 
-/// Bitwise stream reader
 /// Bitwise stream reader.
 pub struct BitStreamReader<'a, R: ?Sized> {
     buffer: [u8; 1],
@@ -273,170 +421,151 @@ impl<'a, R: Read + ?Sized> BitStreamReader<'a, R> {
     }
 }
 
+/// Bitwise stream writer.
+pub struct BitStreamWriter<'a, W: ?Sized> {
+    buffer: [u8; 1],
+    offset: u8,
+    writer: &'a mut W,
+}
 
+impl<'a, W: Write + ?Sized> BitStreamWriter<'a, W> {
+    /// Creates a new [`BitStreamWriter`] that writes bitwise to a given `writer`.
+    pub fn new(writer: &'a mut W) -> BitStreamWriter<'a, W> {
+        BitStreamWriter { buffer: [0u8], writer, offset: 0 }
+    }
 
-// #[cfg(test)]
-// mod test {
-//     use blockfilter::test::rustc_serialize::json::Json;
-//     use rand;
-//     use rand::Rng;
-//     use rand::RngCore;
-//     use std::fs::File;
-//     use std::io::Cursor;
-//     use std::io::Read;
-//     use std::path::PathBuf;
-//     use std::collections::HashMap;
-//     use super::*;
-
-//     extern crate rustc_serialize;
-
-//     extern crate hex;
-
-//     fn decode<T: ? Sized>(data: Vec<u8>) -> Result<T, io::Error>
-//         where T: Decodable<Cursor<Vec<u8>>> {
-//         let mut decoder = Cursor::new(data);
-//         Ok(Decodable::consensus_decode(&mut decoder)
-//             .map_err(|_| { io::Error::new(io::ErrorKind::InvalidData, "serialization error") })?)
-//     }
-
-//     impl UTXOAccessor for HashMap<(Sha256dHash, u32), (Script, u64)> {
-//         fn get_utxo(&mut self, txid: &Sha256dHash, ix: u32) -> Result<(Script, u64), io::Error> {
-//             if let Some (ux) = self.get(&(*txid, ix)) {
-//                 Ok(ux.clone())
-//             }
-//             else {
-//                 println!("missing {}", txid);
-//                 Err(io::Error::from(io::ErrorKind::NotFound))
-//             }
-//         }
-//     }
-
-//     #[test]
-//     fn test_blockfilters () {
-//         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-//         d.push("tests/blockfilters.json");
-//         let mut file = File::open(d).unwrap();
-//         let mut data = String::new();
-//         file.read_to_string(&mut data).unwrap();
-
-//         let json = Json::from_str(&data).unwrap();
-//         let blocks = json[0].as_array().unwrap();
-//         let txs = json[1].as_array().unwrap();
-//         for t in 1..8 {
-//             let mut txmap = HashMap::new();
-//             let test_case = blocks [t].as_array().unwrap();
-//             let block_hash = Sha256dHash::from_hex(test_case [1].as_string().unwrap()).unwrap();
-//             let previous_header_hash = Sha256dHash::from_hex(test_case [3].as_string().unwrap()).unwrap();
-//             let header_hash = Sha256dHash::from_hex(test_case[5].as_string().unwrap()).unwrap();
-//             let block :Block = decode (hex::decode(test_case[2].as_string().unwrap()).unwrap()).unwrap();
-//             assert_eq!(block.bitcoin_hash(), block_hash);
-
-//             for tx in &block.txdata {
-//                 for (ix, out) in tx.output.iter().enumerate() {
-//                     txmap.insert((tx.txid(), ix as u32), (out.script_pubkey.clone(), out.value));
-//                 }
-//             }
-//             for i in 1 .. 9 {
-//                 let line = txs[i].as_array().unwrap();
-//                 let tx: bitcoin::blockdata::transaction::Transaction = decode(hex::decode(line[1].as_string().unwrap()).unwrap()).unwrap();
-//                 assert_eq!(tx.txid().to_string(), line[0].as_string().unwrap());
-//                 for (ix, out) in tx.output.iter().enumerate() {
-//                     txmap.insert((tx.txid(), ix as u32), (out.script_pubkey.clone(), out.value));
-//                 }
-//             }
-
-//             let test_filter = hex::decode(test_case[4].as_string().unwrap()).unwrap();
-//             let mut constructed_filter = Cursor::new(Vec::new());
-//             {
-//                 let mut writer = BlockFilterWriter::new(&mut constructed_filter, &block);
-//                 writer.wallet_filter(txmap).unwrap();
-//                 writer.finish().unwrap();
-//             }
-
-//             let filter = constructed_filter.into_inner();
-//             assert_eq!(test_filter, filter);
-//             let filter_hash = Sha256dHash::from_data(filter.as_slice());
-//             let mut header_data = [0u8; 64];
-//             header_data[0..32].copy_from_slice(&filter_hash.as_bytes()[..]);
-//             header_data[32..64].copy_from_slice(&previous_header_hash.as_bytes()[..]);
-//             let filter_header_hash = Sha256dHash::from_data(&header_data);
-//             assert_eq!(filter_header_hash, header_hash);
-//         }
-//     }
-
-//     #[test]
-//     fn test_filter () {
-//         let mut bytes = Vec::new();
-//         let mut rng = rand::thread_rng();
-//         let mut patterns = HashSet::new();
-//         for _ in 0..1000 {
-//             let mut bytes = [0u8; 8];
-//             rng.fill_bytes(&mut bytes);
-//             patterns.insert(bytes);
-//         }
-//         {
-//             let mut out = Cursor::new(&mut bytes);
-//             let mut writer = GCSFilterWriter::new(&mut out, 0, 0);
-//             for p in &patterns {
-//                 writer.add_element(p);
-//             }
-//             writer.finish().unwrap();
-//         }
-//         {
-//             let ref mut reader = GCSFilterReader::new(0, 0).unwrap();
-//             let mut it = patterns.iter();
-//             for _ in 0..5 {
-//                 reader.add_query_pattern(it.next().unwrap());
-//             }
-//             for _ in 0..100 {
-//                 let mut p = it.next().unwrap().to_vec();
-//                 p [0] = !p[0];
-//                 reader.add_query_pattern(p.as_slice());
-//             }
-//             let mut input = Cursor::new(&bytes);
-//             assert!(reader.match_any(&mut input).unwrap());
-//         }
-//         {
-//             let mut reader = GCSFilterReader::new(0, 0).unwrap();
-//             let mut it = patterns.iter();
-//             for _ in 0..100 {
-//                 let mut p = it.next().unwrap().to_vec();
-//                 p [0] = !p[0];
-//                 reader.add_query_pattern(p.as_slice());
-//             }
-//             let mut input = Cursor::new(&bytes);
-//             assert!(!reader.match_any(&mut input).unwrap());
-//         }
-//     }
-
-//     #[test]
-//     fn test_bit_stream () {
-//         let mut bytes = Vec::new();
-//         {
-//             let mut out = Cursor::new(&mut bytes);
-//             let mut writer = BitStreamWriter::new(&mut out);
-//             writer.write(0, 1).unwrap(); // 0
-//             writer.write(2, 2).unwrap(); // 10
-//             writer.write(6, 3).unwrap(); // 110
-//             writer.write(11, 4).unwrap(); // 1011
-//             writer.write(1, 5).unwrap(); // 00001
-//             writer.write(32, 6).unwrap(); // 100000
-//             writer.write(7, 7).unwrap(); // 0000111
-//             writer.flush().unwrap();
-//         }
-//         assert_eq!("01011010110000110000000001110000", format!("{:08b}{:08b}{:08b}{:08b}",bytes[0],bytes[1],bytes[2],bytes[3]));
-//         {
-//             let mut input = Cursor::new(&mut bytes);
-//             let mut reader = BitStreamReader::new(&mut input);
-//             assert_eq!(reader.read(1).unwrap(), 0);
-//             assert_eq!(reader.read(2).unwrap(), 2);
-//             assert_eq!(reader.read(3).unwrap(), 6);
-//             assert_eq!(reader.read(4).unwrap(), 11);
-//             assert_eq!(reader.read(5).unwrap(), 1);
-//             assert_eq!(reader.read(6).unwrap(), 32);
-//             assert_eq!(reader.read(7).unwrap(), 7);
-//             // 4 bits remained
-//             assert!(reader.read(5).is_err());
-//         }
-//     }
-// }
\ No newline at end of file
+    /// Writes nbits bits from data, returns the number of whole bytes written.
+    pub fn write(&mut self, data: u64, mut nbits: u8) -> Result<usize, io::Error> {
+        if nbits > 64 {
+            return Err(io::Error::other("can not write more than 64 bits at once"));
+        }
+        let mut wrote = 0;
+        while nbits > 0 {
+            let bits = cmp::min(8 - self.offset, nbits);
+            self.buffer[0] |= ((data << (64 - nbits)) >> (64 - 8 + self.offset)) as u8;
+            self.offset += bits;
+            nbits -= bits;
+            if self.offset == 8 {
+                wrote += self.flush()?;
+            }
+        }
+        Ok(wrote)
+    }
+
+    /// flush bits not yet written.
+    pub fn flush(&mut self) -> Result<usize, io::Error> {
+        if self.offset > 0 {
+            self.writer.write_all(&self.buffer)?;
+            self.buffer[0] = 0u8;
+            self.offset = 0;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{tx::Tx, tx_in::TxIn, tx_out::TxOut, BlockHeader};
+    use crate::util::Serializable;
+
+    fn patterns() -> Vec<Vec<u8>> {
+        (0u64..1000).map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn bit_stream() {
+        let mut bytes = Vec::new();
+        {
+            let mut writer = BitStreamWriter::new(&mut bytes);
+            writer.write(0, 1).unwrap(); // 0
+            writer.write(2, 2).unwrap(); // 10
+            writer.write(6, 3).unwrap(); // 110
+            writer.write(11, 4).unwrap(); // 1011
+            writer.write(1, 5).unwrap(); // 00001
+            writer.write(32, 6).unwrap(); // 100000
+            writer.write(7, 7).unwrap(); // 0000111
+            writer.flush().unwrap();
+        }
+        assert_eq!("01011010110000110000000001110000", format!("{:08b}{:08b}{:08b}{:08b}", bytes[0], bytes[1], bytes[2], bytes[3]));
+
+        let mut input = bytes.as_slice();
+        let mut reader = BitStreamReader::new(&mut input);
+        assert_eq!(reader.read(1).unwrap(), 0);
+        assert_eq!(reader.read(2).unwrap(), 2);
+        assert_eq!(reader.read(3).unwrap(), 6);
+        assert_eq!(reader.read(4).unwrap(), 11);
+        assert_eq!(reader.read(5).unwrap(), 1);
+        assert_eq!(reader.read(6).unwrap(), 32);
+        assert_eq!(reader.read(7).unwrap(), 7);
+    }
+
+    #[test]
+    fn gcs_filter() {
+        let patterns = patterns();
+        let mut bytes = Vec::new();
+        {
+            let mut writer = GcsFilterWriter::new(&mut bytes, 0, 0, M, P);
+            for pattern in patterns.iter() {
+                writer.add_element(pattern);
+            }
+            assert_eq!(writer.finish().unwrap(), bytes.len());
+        }
+
+        let reader = GcsFilterReader::new(0, 0, M, P);
+        let flipped: Vec<Vec<u8>> = patterns[..100].iter().map(|p| { let mut p = p.clone(); p[0] = !p[0]; p }).collect();
+        assert!(reader.match_any(&mut bytes.as_slice(), patterns[..5].iter().chain(flipped.iter()).map(|p| p.as_slice())).unwrap());
+        assert!(!reader.match_any(&mut bytes.as_slice(), flipped.iter().map(|p| p.as_slice())).unwrap());
+        assert!(reader.match_all(&mut bytes.as_slice(), patterns.iter().map(|p| p.as_slice())).unwrap());
+        assert!(!reader.match_all(&mut bytes.as_slice(), patterns[..5].iter().chain(flipped[..1].iter()).map(|p| p.as_slice())).unwrap());
+    }
+
+    #[test]
+    fn testnet_genesis() {
+        // BIP 158 test vector for testnet block 0
+        let genesis = hex::decode("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae180101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000").unwrap();
+        let block = Block::read(&mut genesis.as_slice()).unwrap();
+        assert_eq!(block.header.hash(), Hash256::decode("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap());
+
+        let filter = BlockFilter::from_block(&block, |_| Err(io::Error::from(io::ErrorKind::NotFound))).unwrap();
+        assert_eq!(hex::encode(&filter.content), "019dfca8");
+        assert!(filter.match_block_outputs(&block).unwrap());
+    }
+
+    #[test]
+    fn input_scripts() {
+        let spent_script = vec![0x00, 0x14, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7];
+        let coinbase = Tx {
+            version: 1,
+            flag: None,
+            inputs: vec![TxIn { prev_output: OutPoint { hash: Hash256([0; 32]), index: 0xffffffff }, unlock_script: vec![1, 1], sequence: 0xffffffff }],
+            outputs: vec![TxOut { satoshis: 50, lock_script: vec![0x51] }, TxOut { satoshis: 0, lock_script: vec![OP_RETURN, 1, 2] }],
+            witnesses: None,
+            lock_time: 0,
+        };
+        let spend = Tx {
+            version: 1,
+            flag: None,
+            inputs: vec![TxIn { prev_output: OutPoint { hash: Hash256([9; 32]), index: 1 }, unlock_script: vec![], sequence: 0xffffffff }],
+            outputs: vec![TxOut { satoshis: 40, lock_script: vec![0x52] }],
+            witnesses: None,
+            lock_time: 0,
+        };
+        let block = Block { header: BlockHeader::default(), txns: vec![coinbase, spend] };
+
+        let mut lookups = Vec::new();
+        let filter = BlockFilter::from_block(&block, |outpoint| {
+            lookups.push(outpoint.clone());
+            Ok(spent_script.clone())
+        }).unwrap();
+        assert_eq!(lookups, vec![OutPoint { hash: Hash256([9; 32]), index: 1 }]);
+
+        let block_hash = block.header.hash();
+        let all: Vec<&[u8]> = vec![&[0x51], &[0x52], &spent_script];
+        assert!(filter.match_all(&block_hash, all.into_iter()).unwrap());
+        assert!(!filter.match_any(&block_hash, vec![&[OP_RETURN, 1, 2][..]].into_iter()).unwrap());
+        assert!(filter.match_block_outputs(&block).unwrap());
+    }
+}
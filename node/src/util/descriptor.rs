@@ -0,0 +1,318 @@
+//! Turns addresses and output descriptors into the scriptPubKeys we match filters against
+//!
+//! Only the single-key descriptors a light wallet needs are supported: `addr`, `raw`, `pk`,
+//! `pkh`, `wpkh`, `sh(wpkh)` and key-path only `tr`. Keys are hex public keys or extended
+//! public keys with an optional unhardened derivation path ending in `*`.
+
+use std::str::FromStr;
+
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::bip32::{ChildNumber, Xpub};
+use bitcoin::key::{CompressedPublicKey, PublicKey};
+use bitcoin::network as bitcoin_network;
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::{Address, NetworkKind, ScriptBuf};
+
+use crate::util::{Error, Result};
+
+/// Largest number of scripts a ranged descriptor may be expanded into at once
+pub const MAX_DESCRIPTOR_RANGE: u32 = 10_000;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Returns the scriptPubKey of an address after checking it belongs to `network`
+pub fn address_script(address: &str, network: bitcoin_network::Network) -> Result<Vec<u8>> {
+    let address = Address::<NetworkUnchecked>::from_str(address)
+        .map_err(|e| Error::InvalidAddress(e.to_string()))?
+        .require_network(network)
+        .map_err(|e| Error::InvalidAddress(e.to_string()))?;
+    Ok(address.script_pubkey().into_bytes())
+}
+
+/// Expands a descriptor into its scriptPubKeys
+///
+/// Ranged descriptors are derived for the indexes `0..range`, others ignore `range`.
+pub fn expand(descriptor: &str, network: bitcoin_network::Network, range: u32) -> Result<Vec<Vec<u8>>> {
+    let descriptor = strip_checksum(descriptor.trim())?;
+    let secp = Secp256k1::verification_only();
+    let parsed = Descriptor::parse(descriptor, network)?;
+
+    if !parsed.is_ranged() {
+        return Ok(vec![parsed.script(&secp, 0)?.into_bytes()]);
+    }
+    if range == 0 || range > MAX_DESCRIPTOR_RANGE {
+        return Err(Error::BadArgument(format!("Descriptor range out of bounds: {}", range)));
+    }
+    (0..range)
+        .map(|index| parsed.script(&secp, index).map(|script| script.into_bytes()))
+        .collect()
+}
+
+/// Computes the BIP 380 checksum of a descriptor without its `#` suffix
+pub fn checksum(descriptor: &str) -> Result<String> {
+    let mut c = 1_u64;
+    let mut cls = 0_u64;
+    let mut cls_count = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .ok_or_else(|| Error::InvalidDescriptor(format!("Invalid character: {}", ch)))? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    if c0 & 1 != 0 {
+        c ^= 0xf5dee51989;
+    }
+    if c0 & 2 != 0 {
+        c ^= 0xa9fdca3312;
+    }
+    if c0 & 4 != 0 {
+        c ^= 0x1bab10e32d;
+    }
+    if c0 & 8 != 0 {
+        c ^= 0x3706b1677a;
+    }
+    if c0 & 16 != 0 {
+        c ^= 0x644d626ffd;
+    }
+    c
+}
+
+// verifies and removes the optional checksum
+fn strip_checksum(descriptor: &str) -> Result<&str> {
+    match descriptor.split_once('#') {
+        Some((body, expected)) => {
+            if checksum(body)? != expected {
+                return Err(Error::InvalidDescriptor("Checksum mismatch".to_string()));
+            }
+            Ok(body)
+        }
+        None => Ok(descriptor),
+    }
+}
+
+// splits `name(inner)` into its parts
+fn function<'a>(descriptor: &'a str, name: &str) -> Option<&'a str> {
+    descriptor
+        .strip_prefix(name)?
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+enum Descriptor {
+    Addr(ScriptBuf),
+    Raw(ScriptBuf),
+    Pk(DescriptorKey),
+    Pkh(DescriptorKey),
+    Wpkh(DescriptorKey),
+    ShWpkh(DescriptorKey),
+    Tr(DescriptorKey),
+}
+
+impl Descriptor {
+    fn parse(descriptor: &str, network: bitcoin_network::Network) -> Result<Descriptor> {
+        if let Some(address) = function(descriptor, "addr") {
+            return Ok(Descriptor::Addr(ScriptBuf::from_bytes(address_script(address, network)?)));
+        }
+        if let Some(script) = function(descriptor, "raw") {
+            return Ok(Descriptor::Raw(ScriptBuf::from_bytes(hex::decode(script)?)));
+        }
+        if let Some(inner) = function(descriptor, "sh") {
+            return match function(inner, "wpkh") {
+                Some(key) => Ok(Descriptor::ShWpkh(DescriptorKey::parse(key, network)?)),
+                None => Err(Error::Unsupported(format!("Descriptor: {}", descriptor))),
+            };
+        }
+        if let Some(key) = function(descriptor, "pk") {
+            return Ok(Descriptor::Pk(DescriptorKey::parse(key, network)?));
+        }
+        if let Some(key) = function(descriptor, "pkh") {
+            return Ok(Descriptor::Pkh(DescriptorKey::parse(key, network)?));
+        }
+        if let Some(key) = function(descriptor, "wpkh") {
+            return Ok(Descriptor::Wpkh(DescriptorKey::parse(key, network)?));
+        }
+        if let Some(key) = function(descriptor, "tr") {
+            if key.contains(',') {
+                return Err(Error::Unsupported("Taproot script paths".to_string()));
+            }
+            return Ok(Descriptor::Tr(DescriptorKey::parse(key, network)?));
+        }
+        Err(Error::Unsupported(format!("Descriptor: {}", descriptor)))
+    }
+
+    fn is_ranged(&self) -> bool {
+        match self {
+            Descriptor::Addr(_) | Descriptor::Raw(_) => false,
+            Descriptor::Pk(key)
+            | Descriptor::Pkh(key)
+            | Descriptor::Wpkh(key)
+            | Descriptor::ShWpkh(key)
+            | Descriptor::Tr(key) => key.wildcard,
+        }
+    }
+
+    fn script(&self, secp: &Secp256k1<VerifyOnly>, index: u32) -> Result<ScriptBuf> {
+        Ok(match self {
+            Descriptor::Addr(script) | Descriptor::Raw(script) => script.clone(),
+            Descriptor::Pk(key) => ScriptBuf::new_p2pk(&key.derive(secp, index)?),
+            Descriptor::Pkh(key) => ScriptBuf::new_p2pkh(&key.derive(secp, index)?.pubkey_hash()),
+            Descriptor::Wpkh(key) => ScriptBuf::new_p2wpkh(&key.derive_compressed(secp, index)?.wpubkey_hash()),
+            Descriptor::ShWpkh(key) => {
+                let witness_script = ScriptBuf::new_p2wpkh(&key.derive_compressed(secp, index)?.wpubkey_hash());
+                ScriptBuf::new_p2sh(&witness_script.script_hash())
+            }
+            Descriptor::Tr(key) => {
+                let internal_key = key.derive(secp, index)?.inner.x_only_public_key().0;
+                ScriptBuf::new_p2tr(secp, internal_key, None)
+            }
+        })
+    }
+}
+
+enum KeySource {
+    Single(PublicKey),
+    Extended(Xpub),
+}
+
+struct DescriptorKey {
+    source: KeySource,
+    path: Vec<ChildNumber>,
+    wildcard: bool,
+}
+
+impl DescriptorKey {
+    fn parse(key: &str, network: bitcoin_network::Network) -> Result<DescriptorKey> {
+        // key origin information is not needed to derive scripts
+        let key = match key.strip_prefix('[') {
+            Some(rest) => rest
+                .split_once(']')
+                .ok_or_else(|| Error::InvalidDescriptor("Unterminated key origin".to_string()))?
+                .1,
+            None => key,
+        };
+
+        let mut parts = key.split('/');
+        let encoded_key = parts.next().unwrap_or_default();
+        if encoded_key.len() == 66 || encoded_key.len() == 130 {
+            if key.contains('/') {
+                return Err(Error::InvalidDescriptor("Derivation from a single key".to_string()));
+            }
+            let public_key = PublicKey::from_str(encoded_key)
+                .map_err(|e| Error::InvalidDescriptor(e.to_string()))?;
+            return Ok(DescriptorKey { source: KeySource::Single(public_key), path: vec![], wildcard: false });
+        }
+
+        let xpub = Xpub::from_str(encoded_key).map_err(|e| Error::InvalidDescriptor(e.to_string()))?;
+        if xpub.network != NetworkKind::from(network) {
+            return Err(Error::InvalidDescriptor("Extended key is for another network".to_string()));
+        }
+
+        let mut path = Vec::new();
+        let mut wildcard = false;
+        for part in parts {
+            if wildcard {
+                return Err(Error::InvalidDescriptor("Wildcard must be the last step".to_string()));
+            }
+            if part == "*" {
+                wildcard = true;
+                continue;
+            }
+            if part.ends_with('\'') || part.ends_with('h') || part.ends_with('*') {
+                return Err(Error::Unsupported("Hardened derivation from an extended public key".to_string()));
+            }
+            let index = part.parse::<u32>()?;
+            let child = ChildNumber::from_normal_idx(index).map_err(|e| Error::InvalidDescriptor(e.to_string()))?;
+            path.push(child);
+        }
+        Ok(DescriptorKey { source: KeySource::Extended(xpub), path, wildcard })
+    }
+
+    fn derive(&self, secp: &Secp256k1<VerifyOnly>, index: u32) -> Result<PublicKey> {
+        match &self.source {
+            KeySource::Single(public_key) => Ok(*public_key),
+            KeySource::Extended(xpub) => {
+                let mut path = self.path.clone();
+                if self.wildcard {
+                    path.push(ChildNumber::from_normal_idx(index).map_err(|e| Error::InvalidDescriptor(e.to_string()))?);
+                }
+                let derived = xpub.derive_pub(secp, &path).map_err(|e| Error::InvalidDescriptor(e.to_string()))?;
+                Ok(PublicKey::new(derived.public_key))
+            }
+        }
+    }
+
+    fn derive_compressed(&self, secp: &Secp256k1<VerifyOnly>, index: u32) -> Result<CompressedPublicKey> {
+        CompressedPublicKey::try_from(self.derive(secp, index)?)
+            .map_err(|_| Error::InvalidDescriptor("Segwit requires compressed keys".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::network::Network;
+
+    #[test]
+    fn checksum_vector() {
+        assert!(checksum("raw(deadbeef)").unwrap() == "89f8spxm");
+        assert!(expand("raw(deadbeef)#89f8spxm", Network::Bitcoin, 0).unwrap() == vec![vec![0xde, 0xad, 0xbe, 0xef]]);
+        assert!(expand("raw(deadbeef)#89f8spxx", Network::Bitcoin, 0).is_err());
+    }
+
+    #[test]
+    fn address() {
+        let script = address_script("bcrt1qlhwg8036lga3c2t4pmmc6wf49f8t0m5gshjzpj", Network::Regtest).unwrap();
+        assert!(hex::encode(script) == "0014fddc83be3afa3b1c29750ef78d39352a4eb7ee88");
+        assert!(address_script("bcrt1qlhwg8036lga3c2t4pmmc6wf49f8t0m5gshjzpj", Network::Bitcoin).is_err());
+        assert!(address_script("not an address", Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn single_key_descriptors() {
+        let key = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let wpkh = expand(&format!("wpkh({})", key), Network::Bitcoin, 0).unwrap();
+        assert!(hex::encode(&wpkh[0]) == "0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let pkh = expand(&format!("pkh({})", key), Network::Bitcoin, 0).unwrap();
+        assert!(hex::encode(&pkh[0]) == "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac");
+        let sh_wpkh = expand(&format!("sh(wpkh({}))", key), Network::Bitcoin, 0).unwrap();
+        assert!(sh_wpkh[0].len() == 23 && sh_wpkh[0][0] == 0xa9);
+        let tr = expand(&format!("tr({})", key), Network::Bitcoin, 0).unwrap();
+        assert!(tr[0].len() == 34 && tr[0][0] == 0x51);
+    }
+
+    #[test]
+    fn ranged_descriptor() {
+        // BIP 32 test vector 1 master key
+        let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let scripts = expand(&format!("wpkh({}/0/*)", xpub), Network::Bitcoin, 3).unwrap();
+        assert!(scripts.len() == 3);
+        assert!(scripts[0] != scripts[1]);
+        assert!(expand(&format!("wpkh({}/0/*)", xpub), Network::Bitcoin, 0).is_err());
+        assert!(expand(&format!("wpkh({}/0'/*)", xpub), Network::Bitcoin, 3).is_err());
+        assert!(expand(&format!("wpkh({}/0/*)", xpub), Network::Regtest, 3).is_err());
+    }
+}
@@ -2,6 +2,8 @@
 
 use std::time::SystemTime;
 
+use bitcoin::network::Network;
+
 #[allow(dead_code)]
 mod bits;
 
@@ -13,6 +15,7 @@ mod latch;
 mod result;
 mod serdes;
 pub mod block_filter;
+pub mod descriptor;
 pub(crate) mod var_int;
 
 
@@ -37,3 +40,8 @@ pub const GENESIS_UPGRADE_HEIGHT_MAINNET: i32 = 620538;
 
 /// Block height that activated the genesis upgrade on testnet
 pub const GENESIS_UPGRADE_HEIGHT_TESTNET: i32 = 1344302;
+
+/// Easiest proof of work target of `network`, as a hash that block hashes and targets compare against
+pub fn pow_limit(network: Network) -> Hash256 {
+    Hash256(network.params().max_attainable_target.to_le_bytes())
+}
@@ -55,7 +55,17 @@ pub enum Error {
     /// Slice Error
     SliceError(String),
     /// Peer Not Found Error
-    PeerNotFound
+    PeerNotFound,
+    /// Address could not be parsed or is for another network
+    InvalidAddress(String),
+    /// Output descriptor could not be parsed
+    InvalidDescriptor(String),
+    /// No wallet with the given id exists
+    WalletNotFound(String),
+    /// The outpoint is not watched
+    OutpointNotWatched(String),
+    /// The peer does not have the requested blocks
+    BlockNotFound(String),
 }
 
 impl Error {
@@ -88,6 +98,11 @@ impl Error {
             Error::FilterMatchEror => 25,
             Error::NetworkError => 26,
             Error::FetchHeader(_) => 27,
+            Error::InvalidAddress(_) => 28,
+            Error::InvalidDescriptor(_) => 29,
+            Error::WalletNotFound(_) => 30,
+            Error::OutpointNotWatched(_) => 31,
+            Error::BlockNotFound(_) => 32,
         }
     }
 }
@@ -122,6 +137,11 @@ impl std::fmt::Display for Error {
             Error::FilterMatchEror => f.write_str(&format!("Filter Match Error")),
             Error::NetworkError => f.write_str(&format!("Network Error")),
             Error::FetchHeader(e) => f.write_str(&format!("Fetching Header Error: {}", e)),
+            Error::InvalidAddress(s) => f.write_str(&format!("Invalid address: {}", s)),
+            Error::InvalidDescriptor(s) => f.write_str(&format!("Invalid descriptor: {}", s)),
+            Error::WalletNotFound(s) => f.write_str(&format!("Wallet not found: {}", s)),
+            Error::OutpointNotWatched(s) => f.write_str(&format!("Outpoint not watched: {}", s)),
+            Error::BlockNotFound(s) => f.write_str(&format!("Block not found: {}", s)),
 
         }
     }
@@ -157,6 +177,11 @@ impl std::error::Error for Error {
             Error::FilterMatchEror => "Filter Match Error",
             Error::NetworkError => "Network Error",
             Error::FetchHeader(_) => "Fetch Header Error",
+            Error::InvalidAddress(_) => "Invalid address",
+            Error::InvalidDescriptor(_) => "Invalid descriptor",
+            Error::WalletNotFound(_) => "Wallet not found",
+            Error::OutpointNotWatched(_) => "Outpoint not watched",
+            Error::BlockNotFound(_) => "Block not found",
         }
     }
 
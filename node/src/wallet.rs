@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{block::Block, tx_out::TxOut, OutPoint};
+use crate::pending::{PendingTx, PendingTxs};
+use crate::util::Hash256;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Utxo  {
+    pub tx_out: TxOut,
+    hash: Hash256,
+    index: usize,
+
+}
+
+impl Utxo {
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint { hash: self.hash, index: self.index as u32 }
+    }
+}
+
+/// Watched scripts and unspent outputs of one wallet
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct WalletState {
+    pub filters: Vec<Vec<u8>>,
+    pub utxos: Vec<Utxo>,
+}
+
+impl WalletState {
+
+    /// Watches the given scripts, skipping those already watched, and returns how many were added
+    pub fn add_filters(&mut self, filters: Vec<Vec<u8>>) -> u32 {
+        let mut added = 0;
+        for filter in filters {
+            if !self.filters.contains(&filter) {
+                self.filters.push(filter);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Stops watching the given scripts and returns how many were removed
+    pub fn remove_filters(&mut self, filters: &[Vec<u8>]) -> u32 {
+        let watched = self.filters.len();
+        self.filters.retain(|filter| !filters.contains(filter));
+        (watched - self.filters.len()) as u32
+    }
+
+    /// Whether a local transaction spends one of our UTXOs
+    pub fn is_spent_by(&self, pending: &PendingTx) -> bool {
+        pending.inputs().iter().any(|input| self.utxos.iter().any(|utxo| utxo.outpoint() == *input))
+    }
+
+    /// Whether a local transaction spends one of our UTXOs or pays to a watched script
+    pub fn is_touched_by(&self, pending: &PendingTx) -> bool {
+        self.is_spent_by(pending)
+            || pending.outputs().any(|(_, output)| self.filters.contains(&output.lock_script))
+    }
+
+    /// Confirmed UTXOs less those spent by pending local transactions, plus their outputs paying to us
+    pub fn balance(&self, pending: &PendingTxs) -> i64 {
+        let spent = pending.spent();
+        let confirmed: i64 = self.utxos.iter()
+            .filter(|utxo| !spent.contains(&utxo.outpoint()))
+            .map(|utxo| utxo.tx_out.satoshis)
+            .sum();
+        let received: i64 = pending.outputs()
+            .filter(|(outpoint, output)| !spent.contains(outpoint) && self.filters.contains(&output.lock_script))
+            .map(|(_, output)| output.satoshis)
+            .sum();
+        confirmed + received
+    }
+
+    /// Adds outputs paying to watched scripts, removes spent ones and returns the txids that touched the wallet
+    pub fn apply_block(&mut self, block: &Block) -> Vec<Hash256> {
+        let mut relevant_txids = Vec::new();
+        for txn in block.txns.iter() {
+            let txid = txn.hash();
+            let mut is_relevant = false;
+            for (index, output) in txn.outputs.iter().enumerate() {
+                if self.filters.contains(&output.lock_script) {
+                    self.utxos.push(Utxo { tx_out: output.to_owned(), hash: txid, index });
+                    is_relevant = true;
+                }
+            }
+
+            let spent: HashSet<&OutPoint> = txn.inputs.iter().map(|input| &input.prev_output).collect();
+            let unspent = self.utxos.len();
+            self.utxos.retain(|utxo| !spent.contains(&utxo.outpoint()));
+            if self.utxos.len() != unspent {
+                is_relevant = true;
+            }
+
+            if is_relevant {
+                relevant_txids.push(txid);
+            }
+        }
+        relevant_txids
+    }
+}
+
+/// The input that spent a watched outpoint
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpendRecord {
+    pub txid: Hash256,
+    pub input_index: u32,
+    pub height: u64,
+}
+
+/// Outpoints watched for their spend, independently of any wallet
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct OutpointIndex {
+    // unspent outpoints and the script they pay to, which the filter of the spending block contains
+    watched: HashMap<OutPoint, Vec<u8>>,
+    spends: HashMap<OutPoint, SpendRecord>,
+}
+
+impl OutpointIndex {
+
+    /// Watches an outpoint paying to `script`, returns false if it is already watched or spent
+    pub fn watch(&mut self, outpoint: OutPoint, script: Vec<u8>) -> bool {
+        if self.contains(&outpoint) {
+            return false;
+        }
+        self.watched.insert(outpoint, script);
+        true
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.watched.contains_key(outpoint) || self.spends.contains_key(outpoint)
+    }
+
+    /// Scripts to match against block filters, one per unspent outpoint
+    pub fn scripts(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.watched.values()
+    }
+
+    pub fn spend(&self, outpoint: &OutPoint) -> Option<&SpendRecord> {
+        self.spends.get(outpoint)
+    }
+
+    /// Records the spends of watched outpoints in a block at `height` and returns the spending txids
+    pub fn apply_block(&mut self, height: u64, block: &Block) -> Vec<Hash256> {
+        let mut spending_txids = Vec::new();
+        if self.watched.is_empty() {
+            return spending_txids;
+        }
+        for txn in block.txns.iter() {
+            let txid = txn.hash();
+            for (input_index, input) in txn.inputs.iter().enumerate() {
+                if self.watched.remove(&input.prev_output).is_some() {
+                    self.spends.insert(input.prev_output.clone(), SpendRecord { txid, input_index: input_index as u32, height });
+                    if !spending_txids.contains(&txid) {
+                        spending_txids.push(txid);
+                    }
+                }
+            }
+        }
+        spending_txids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{tx::Tx, tx_in::TxIn, BlockHeader};
+
+    fn tx(prev_outputs: Vec<OutPoint>, scripts: Vec<Vec<u8>>) -> Tx {
+        Tx {
+            version: 2,
+            flag: None,
+            inputs: prev_outputs.into_iter().map(|prev_output| TxIn { prev_output, unlock_script: vec![], sequence: 0xffffffff }).collect(),
+            outputs: scripts.into_iter().map(|lock_script| TxOut { satoshis: 1000, lock_script }).collect(),
+            witnesses: None,
+            lock_time: 0,
+        }
+    }
+
+    fn block(txns: Vec<Tx>) -> Block {
+        Block { header: BlockHeader::default(), txns }
+    }
+
+    #[test]
+    fn wallet_spends_within_block() {
+        let mut wallet = WalletState::default();
+        wallet.add_filters(vec![vec![1], vec![2]]);
+        let funding = tx(vec![OutPoint { hash: Hash256([9; 32]), index: 0 }], vec![vec![1], vec![2], vec![3]]);
+        let spending = tx(vec![OutPoint { hash: funding.hash(), index: 1 }], vec![vec![3]]);
+        let txids = wallet.apply_block(&block(vec![funding.clone(), spending.clone()]));
+        assert_eq!(txids, vec![funding.hash(), spending.hash()]);
+        assert_eq!(wallet.utxos.len(), 1);
+        assert_eq!(wallet.utxos[0].outpoint(), OutPoint { hash: funding.hash(), index: 0 });
+    }
+
+    #[test]
+    fn pending_balance() {
+        let mut wallet = WalletState::default();
+        wallet.add_filters(vec![vec![1]]);
+        let funding = tx(vec![OutPoint { hash: Hash256([9; 32]), index: 0 }], vec![vec![1], vec![1]]);
+        wallet.apply_block(&block(vec![funding.clone()]));
+        let mut pending = PendingTxs::default();
+        assert_eq!(wallet.balance(&pending), 2000);
+
+        // spends one coin, pays elsewhere and returns change
+        let send = tx(vec![OutPoint { hash: funding.hash(), index: 0 }], vec![vec![2], vec![1]]);
+        assert!(wallet.is_touched_by(&PendingTx::new(&send)));
+        pending.add(PendingTx::new(&send));
+        assert_eq!(wallet.balance(&pending), 2000);
+
+        // the change is spent again before confirming
+        let sweep = tx(vec![OutPoint { hash: send.hash(), index: 1 }], vec![vec![2]]);
+        pending.add(PendingTx::new(&sweep));
+        assert_eq!(wallet.balance(&pending), 1000);
+        // confirmed coins stay in place until a block spends them
+        assert_eq!(wallet.utxos.len(), 2);
+        let unrelated = tx(vec![OutPoint { hash: Hash256([8; 32]), index: 0 }], vec![vec![2]]);
+        assert!(!wallet.is_touched_by(&PendingTx::new(&unrelated)));
+    }
+
+    #[test]
+    fn outpoint_spend() {
+        let mut index = OutpointIndex::default();
+        let watched = OutPoint { hash: Hash256([7; 32]), index: 1 };
+        assert!(index.watch(watched.clone(), vec![1]));
+        assert!(!index.watch(watched.clone(), vec![1]));
+        assert_eq!(index.scripts().collect::<Vec<_>>(), vec![&vec![1]]);
+
+        let unrelated = tx(vec![OutPoint { hash: Hash256([7; 32]), index: 0 }], vec![vec![2]]);
+        assert!(index.apply_block(5, &block(vec![unrelated])).is_empty());
+        assert!(index.spend(&watched).is_none());
+
+        let spending = tx(vec![OutPoint { hash: Hash256([8; 32]), index: 0 }, watched.clone()], vec![vec![2]]);
+        assert_eq!(index.apply_block(6, &block(vec![spending.clone()])), vec![spending.hash()]);
+        assert_eq!(index.spend(&watched), Some(&SpendRecord { txid: spending.hash(), input_index: 1, height: 6 }));
+        assert_eq!(index.scripts().count(), 0);
+        // spent outpoints stay known
+        assert!(index.contains(&watched));
+        assert!(!index.watch(watched, vec![1]));
+    }
+}
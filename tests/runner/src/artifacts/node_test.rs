@@ -1,6 +1,13 @@
-use exports::component::node::types::{BitcoinNetwork, NodeConfig, SocketAddress};
+use exports::component::node::types::{BitcoinNetwork, NodeConfig, PrivacyConfig, SocketAddress, StorageMode};
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use node::messages::{commands, tx::Tx, tx_out::TxOut, OutPoint, NODE_NETWORK};
+use node::util::{Hash256, Serializable};
+use crate::fake_peer::{spend, FakePeer, Fault, SyntheticChain};
 use wasmtime::component::*;
 use wasmtime::{Config, Engine, Store};
 use wasmtime_wasi::{ DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiView};
@@ -9,18 +16,448 @@ use wasmtime_wasi_http::{self, WasiHttpCtx, WasiHttpView};
 include!(concat!(env!("OUT_DIR"), "/node_WIT.rs"));
 
 
+const FUNDED_ADDRESS: &str = "bcrt1qlhwg8036lga3c2t4pmmc6wf49f8t0m5gshjzpj";
+const FUNDED_SCRIPT: &str = "0014fddc83be3afa3b1c29750ef78d39352a4eb7ee88";
+
 pub fn test_node(){
     
-    let (nodeworld, mut store ,node) = create_node().unwrap();
-    let wallet_filter = "0014c894cacf116202a9e85047a906eb215f26ab03d3".to_string();
-    nodeworld.component_node_types().client_node().call_add_filter(&mut store, node.clone(), &wallet_filter).unwrap().unwrap();
+    let ip_config = SocketAddress{ ip: "127.0.0.1".to_string(), port: 19444 };
+    let (nodeworld, mut store ,node) = create_node(ip_config, Path::new("./testfolder")).unwrap();
+    nodeworld.component_node_types().client_node().call_watch_address(&mut store, node.clone(), FUNDED_ADDRESS).unwrap().unwrap();
     let balance = nodeworld.component_node_types().client_node().call_get_balance(&mut store, node.clone()).unwrap().unwrap();
     assert_eq!(balance, 10_0000_0000);
 
 
 }
 
-fn create_node() -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, ResourceAny)> {
+/// Node tests served by the fake peer, they need neither docker nor bitcoind
+pub fn test_node_offline() {
+    sync_from_fake_peer();
+    reject_wrong_filter();
+    reject_malformed_frame();
+    reject_peer_without_filters();
+    estimate_fee_from_recent_blocks();
+    spend_of_watched_outpoint();
+    ephemeral_state_snapshot();
+    persistent_state_snapshot();
+    resume_sync_in_steps();
+    pending_local_transactions();
+    follow_announced_tip();
+    sync_single_new_block();
+    time_out_stalled_filters();
+    refuse_announced_reorg();
+    fall_back_on_primary_peer();
+}
+
+fn sync_from_fake_peer() {
+    let funded_script = hex::decode(FUNDED_SCRIPT).unwrap();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(100);
+    let funding = chain.mine_to(funded_script.clone(), 10_0000_0000);
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("sync");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+
+    // new blocks are picked up on the next sync
+    peer.with_chain(|chain| chain.mine_to(funded_script.clone(), 5_0000_0000));
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 15_0000_0000);
+
+    // spending the first coin only matches through the input scripts of the filter
+    peer.with_chain(|chain| {
+        let prev_output = OutPoint { hash: chain.block(&funding).unwrap().txns[0].hash(), index: 0 };
+        let txn = spend(prev_output, vec![TxOut { satoshis: 9_0000_0000, lock_script: vec![0x51] }]);
+        chain.mine_block(vec![], vec![txn]);
+    });
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 5_0000_0000);
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn reject_wrong_filter() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(5);
+    let peer = FakePeer::spawn(chain).unwrap();
+    // claims block 3 pays our script, which the downloaded block then disproves
+    peer.inject(Fault::WrongFilter { height: 3, elements: vec![hex::decode(FUNDED_SCRIPT).unwrap()] });
+
+    let store_dir = offline_store("wrong-filter");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert!(client_node.call_get_balance(&mut store, node).unwrap().is_err());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn reject_malformed_frame() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(5);
+    let peer = FakePeer::spawn(chain).unwrap();
+    peer.inject(Fault::MalformedFrame(commands::GETCFILTERS));
+
+    let store_dir = offline_store("malformed-frame");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    assert!(client_node.call_get_balance(&mut store, node).unwrap().is_err());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn reject_peer_without_filters() {
+    let peer = FakePeer::spawn(SyntheticChain::regtest()).unwrap();
+    peer.inject(Fault::Services(NODE_NETWORK));
+
+    let store_dir = offline_store("no-filters");
+    // the handshake fails, which traps the constructor
+    assert!(create_node(fake_peer_address(&peer), &store_dir).is_err());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn estimate_fee_from_recent_blocks() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(100);
+    // six blocks each holding one transaction, paying 10 to 60 sat/vB through the coinbase
+    for (height, fee_rate) in (1..=6).zip((10..=60).step_by(10)) {
+        let prev_output = OutPoint { hash: chain.block_at(height).unwrap().txns[0].hash(), index: 0 };
+        let txn = spend(prev_output, vec![TxOut { satoshis: 49_0000_0000, lock_script: vec![0x51] }]);
+        let fee = fee_rate * txn.vsize() as i64;
+        chain.mine_block(vec![TxOut { satoshis: 50_0000_0000 + fee, lock_script: vec![0x51] }], vec![txn]);
+    }
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("estimate-fee");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    // nothing was downloaded while syncing, so the recent blocks are fetched for the estimate
+    assert_eq!(client_node.call_estimate_fee(&mut store, node, 1).unwrap().unwrap(), 30);
+    assert_eq!(client_node.call_estimate_fee(&mut store, node, 10).unwrap().unwrap(), 10);
+    assert!(client_node.call_estimate_fee(&mut store, node, 0).unwrap().is_err());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn spend_of_watched_outpoint() {
+    // an escrow output nobody's wallet watches
+    let escrow_script = [vec![0x00, 0x14], vec![0x11; 20]].concat();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    let funding = chain.mine_to(escrow_script.clone(), 2_0000_0000);
+    let escrow = OutPoint { hash: chain.block(&funding).unwrap().txns[0].hash(), index: 0 };
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("outpoint");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    let txid = escrow.hash.encode();
+    client_node.call_watch_outpoint(&mut store, node, &txid, 0, &hex::encode(&escrow_script)).unwrap().unwrap();
+    assert!(client_node.call_get_spend(&mut store, node, &txid, 0).unwrap().unwrap().is_none());
+    // only watched outpoints have a spend to report
+    assert!(client_node.call_get_spend(&mut store, node, &txid, 1).unwrap().is_err());
+
+    let spending_txid = peer.with_chain(|chain| {
+        let txn = spend(escrow.clone(), vec![TxOut { satoshis: 1_0000_0000, lock_script: vec![0x51] }]);
+        let txid = txn.hash();
+        chain.mine_empty(2);
+        chain.mine_block(vec![], vec![txn]);
+        txid
+    });
+    let spend = client_node.call_get_spend(&mut store, node, &txid, 0).unwrap().unwrap().unwrap();
+    assert_eq!(spend.txid, spending_txid.encode());
+    assert_eq!(spend.input_index, 0);
+    assert_eq!(spend.height, 7);
+    // the spending transaction comes with an inclusion proof
+    assert!(client_node.call_get_inclusion_proof(&mut store, node, &spend.txid).unwrap().is_ok());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn ephemeral_state_snapshot() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    chain.mine_to(hex::decode(FUNDED_SCRIPT).unwrap(), 10_0000_0000);
+    let peer = FakePeer::spawn(chain).unwrap();
+    let key = vec![0x42; 32];
+
+    let store_dir = offline_store("ephemeral");
+    let (nodeworld, mut store, node) = create_node_with_storage(fake_peer_address(&peer), &store_dir, StorageMode::Ephemeral).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+    let snapshot = client_node.call_export_state(&mut store, node, &key).unwrap().unwrap();
+    // nothing touched the filesystem
+    assert!(fs::read_dir(&store_dir).unwrap().next().is_none());
+
+    // a new node only knows the funded address through the snapshot
+    let (nodeworld, mut store, node) = create_node_with_storage(fake_peer_address(&peer), &store_dir, StorageMode::Ephemeral).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    assert!(client_node.call_import_state(&mut store, node, &snapshot, &[0x43; 32]).unwrap().is_err());
+    client_node.call_import_state(&mut store, node, &snapshot, &key).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn persistent_state_snapshot() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    chain.mine_to(hex::decode(FUNDED_SCRIPT).unwrap(), 10_0000_0000);
+    let peer = FakePeer::spawn(chain).unwrap();
+    let key = vec![0x42; 32];
+
+    let exported_dir = offline_store("persistent-export");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &exported_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+    let snapshot = client_node.call_export_state(&mut store, node, &key).unwrap().unwrap();
+
+    // state the snapshot does not have is dropped by the import, in memory and on disk
+    let imported_dir = offline_store("persistent-import");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &imported_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_create_wallet(&mut store, node, "savings").unwrap().unwrap();
+    client_node.call_import_state(&mut store, node, &snapshot, &key).unwrap().unwrap();
+    assert!(client_node.call_list_wallets(&mut store, node).unwrap().is_empty());
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+    drop(store);
+
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &imported_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    assert!(client_node.call_list_wallets(&mut store, node).unwrap().is_empty());
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+
+    fs::remove_dir_all(exported_dir).unwrap();
+    fs::remove_dir_all(imported_dir).unwrap();
+}
+
+fn resume_sync_in_steps() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(1200);
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("sync-step");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    let progress = client_node.call_sync_step(&mut store, node, 1).unwrap().unwrap();
+    assert_eq!((progress.filter_height, progress.best_header_height, progress.done), (500, 1200, false));
+    assert!(progress.percent > 41.0 && progress.percent < 42.0);
+    drop(store);
+
+    // a new node on the same store picks up after the saved batch
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    let progress = client_node.call_sync_step(&mut store, node, 1).unwrap().unwrap();
+    assert_eq!((progress.filter_height, progress.done), (1000, false));
+    let progress = client_node.call_sync_step(&mut store, node, 10).unwrap().unwrap();
+    assert_eq!((progress.filter_height, progress.done, progress.percent), (1200, true, 100.0));
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn pending_local_transactions() {
+    let funded_script = hex::decode(FUNDED_SCRIPT).unwrap();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    let funding = chain.mine_block(vec![
+        TxOut { satoshis: 10_0000_0000, lock_script: funded_script.clone() },
+        TxOut { satoshis: 5_0000_0000, lock_script: funded_script.clone() },
+    ], vec![]);
+    let coinbase = chain.block(&funding).unwrap().txns[0].hash();
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("pending");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 15_0000_0000);
+
+    // sends 3 BTC away from the first coin with the change coming back right away
+    let send = spend(OutPoint { hash: coinbase, index: 0 }, vec![
+        TxOut { satoshis: 3_0000_0000, lock_script: vec![0x51] },
+        TxOut { satoshis: 6_9999_0000, lock_script: funded_script.clone() },
+    ]);
+    let txid = client_node.call_add_local_tx(&mut store, node, &raw_tx(&send)).unwrap().unwrap();
+    assert_eq!(txid, send.hash().encode());
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 11_9999_0000);
+    assert_eq!(client_node.call_list_pending(&mut store, node).unwrap(), vec![txid]);
+
+    // transactions touching no wallet are refused
+    let unrelated = spend(OutPoint { hash: Hash256([5; 32]), index: 0 }, vec![TxOut { satoshis: 1000, lock_script: vec![0x51] }]);
+    assert!(client_node.call_add_local_tx(&mut store, node, &raw_tx(&unrelated)).unwrap().is_err());
+
+    // confirming leaves the balance where it was
+    peer.with_chain(|chain| chain.mine_block(vec![], vec![send.clone()]));
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 11_9999_0000);
+    assert!(client_node.call_list_pending(&mut store, node).unwrap().is_empty());
+
+    // a conflicting spend of the second coin confirms instead of ours
+    let ours = spend(OutPoint { hash: coinbase, index: 1 }, vec![TxOut { satoshis: 4_9999_0000, lock_script: vec![0x51] }]);
+    client_node.call_add_local_tx(&mut store, node, &raw_tx(&ours)).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 6_9999_0000);
+    let conflict = spend(OutPoint { hash: coinbase, index: 1 }, vec![TxOut { satoshis: 4_9998_0000, lock_script: funded_script.clone() }]);
+    peer.with_chain(|chain| chain.mine_block(vec![], vec![conflict]));
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 11_9997_0000);
+    assert!(client_node.call_list_pending(&mut store, node).unwrap().is_empty());
+
+    // a spend of the change that never makes it into a block is dropped by hand
+    let stuck = spend(OutPoint { hash: send.hash(), index: 1 }, vec![TxOut { satoshis: 6_9998_0000, lock_script: vec![0x51] }]);
+    let stuck_txid = client_node.call_add_local_tx(&mut store, node, &raw_tx(&stuck)).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 4_9998_0000);
+    assert_eq!(client_node.call_remove_local_tx(&mut store, node, &stuck_txid).unwrap().unwrap(), vec![stuck_txid.clone()]);
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 11_9997_0000);
+    assert!(client_node.call_list_pending(&mut store, node).unwrap().is_empty());
+    assert!(client_node.call_remove_local_tx(&mut store, node, &stuck_txid).unwrap().is_err());
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn follow_announced_tip() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(5);
+    let peer = Arc::new(FakePeer::spawn(chain).unwrap());
+
+    let store_dir = offline_store("follow-tip");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    // the new blocks are announced with headers while the node waits for them
+    let announcing = peer.clone();
+    let announcer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        announcing.extend(2).unwrap();
+    });
+    assert_eq!(client_node.call_follow_tip(&mut store, node, 2).unwrap().unwrap(), 7);
+    announcer.join().unwrap();
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn sync_single_new_block() {
+    let funded_script = hex::decode(FUNDED_SCRIPT).unwrap();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    let peer = FakePeer::spawn(chain).unwrap();
+
+    let store_dir = offline_store("single-block");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    let progress = client_node.call_sync_step(&mut store, node, 10).unwrap().unwrap();
+    assert_eq!((progress.filter_height, progress.done), (3, true));
+
+    // one new header is a batch of its own whose filter still has to be matched
+    peer.with_chain(|chain| chain.mine_to(funded_script.clone(), 10_0000_0000));
+    let progress = client_node.call_sync_step(&mut store, node, 10).unwrap().unwrap();
+    assert_eq!((progress.filter_height, progress.done), (4, true));
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn time_out_stalled_filters() {
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(5);
+    let peer = FakePeer::spawn(chain).unwrap();
+    peer.inject(Fault::Stall(commands::GETCFILTERS));
+
+    let store_dir = offline_store("stall");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    // the filter request times out instead of blocking the node
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap(), Err(22));
+    let status = client_node.call_get_status(&mut store, node).unwrap().unwrap();
+    assert_eq!(status.last_error.as_deref(), Some("Fetching Compact Filter Error: 12"));
+    assert_eq!(status.filter_height, 0);
+
+    // the next sync picks up where the stalled one stopped
+    peer.clear_faults();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap(), Ok(0));
+    assert_eq!(client_node.call_get_status(&mut store, node).unwrap().unwrap().filter_height, 5);
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn refuse_announced_reorg() {
+    let funded_script = hex::decode(FUNDED_SCRIPT).unwrap();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(3);
+    chain.mine_to(funded_script, 10_0000_0000);
+    chain.mine_empty(1);
+    let peer = Arc::new(FakePeer::spawn(chain).unwrap());
+
+    let store_dir = offline_store("reorg");
+    let (nodeworld, mut store, node) = create_node(fake_peer_address(&peer), &store_dir).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+
+    // the branch replacing the funding block does not build on the tip of the node, which keeps its synced state
+    let announcing = peer.clone();
+    let announcer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        announcing.reorg(2, 3).unwrap();
+    });
+    assert_eq!(client_node.call_follow_tip(&mut store, node, 2).unwrap(), Err(2));
+    announcer.join().unwrap();
+    let status = client_node.call_get_status(&mut store, node).unwrap().unwrap();
+    assert_eq!(status.last_error.as_deref(), Some("Bad data: Headers do not connect"));
+    assert_eq!((status.filter_height, status.utxo_count), (5, 1));
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn fall_back_on_primary_peer() {
+    let funded_script = hex::decode(FUNDED_SCRIPT).unwrap();
+    let mut chain = SyntheticChain::regtest();
+    chain.mine_empty(5);
+    chain.mine_to(funded_script, 10_0000_0000);
+    chain.mine_empty(5);
+    let peer = FakePeer::spawn(chain).unwrap();
+    // the decoy peer has none of the blocks, every download dealt to it answers with notfound
+    let decoy = FakePeer::spawn(SyntheticChain::regtest()).unwrap();
+
+    let store_dir = offline_store("decoy-notfound");
+    let privacy = PrivacyConfig { decoy_ratio: 3, peers: vec![fake_peer_address(&decoy)] };
+    let (nodeworld, mut store, node) = create_node_with(fake_peer_address(&peer), &store_dir, StorageMode::Persistent, Some(privacy)).unwrap();
+    let client_node = nodeworld.component_node_types().client_node();
+    client_node.call_watch_address(&mut store, node, FUNDED_ADDRESS).unwrap().unwrap();
+    assert_eq!(client_node.call_get_balance(&mut store, node).unwrap().unwrap(), 10_0000_0000);
+    assert_eq!(client_node.call_get_status(&mut store, node).unwrap().unwrap().peers.len(), 2);
+
+    fs::remove_dir_all(store_dir).unwrap();
+}
+
+fn raw_tx(txn: &Tx) -> String {
+    let mut raw = Vec::new();
+    txn.write(&mut raw).unwrap();
+    hex::encode(raw)
+}
+
+fn fake_peer_address(peer: &FakePeer) -> SocketAddress {
+    SocketAddress{ ip: "127.0.0.1".to_string(), port: peer.address().port() }
+}
+
+// every offline test starts from an empty store
+fn offline_store(name: &str) -> PathBuf {
+    let store_dir = env::temp_dir().join(format!("node-offline-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&store_dir);
+    fs::create_dir_all(&store_dir).unwrap();
+    store_dir
+}
+
+fn create_node(ip_config: SocketAddress, store_dir: &Path) -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, ResourceAny)> {
+    create_node_with_storage(ip_config, store_dir, StorageMode::Persistent)
+}
+
+fn create_node_with_storage(ip_config: SocketAddress, store_dir: &Path, storage: StorageMode) -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, ResourceAny)> {
+    create_node_with(ip_config, store_dir, storage, None)
+}
+
+fn create_node_with(ip_config: SocketAddress, store_dir: &Path, storage: StorageMode, privacy: Option<PrivacyConfig>) -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, ResourceAny)> {
     let mut config = Config::default();
     config.wasm_component_model(true);
     config.async_support(false);
@@ -33,7 +470,7 @@ fn create_node() -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, Resource
     wasmtime_wasi::add_to_linker_sync(&mut linker).unwrap();
     wasmtime_wasi_http::add_only_http_to_linker_sync(&mut linker).unwrap();
     
-    let wasi_view = ServerWasiView::new();
+    let wasi_view = ServerWasiView::new(store_dir);
     let mut store = Store::new(&engine, wasi_view);
     
     let component = Component::from_file(&engine, pathtowasm).unwrap();
@@ -41,14 +478,12 @@ fn create_node() -> wasmtime::Result<(Nodeworld, Store<ServerWasiView>, Resource
     let instance =  Nodeworld::instantiate(&mut store, &component, &linker)
         .unwrap();
     
-    let ip_config = SocketAddress{ ip: "127.0.0.1".to_string(), port: 19444 };
     let network_config = BitcoinNetwork::Regtest;
     let wallet_address = "bcrt1qvgksuwmvc7h5y0xzjl7exx549r59fq5jgcdm93".to_string();
-    let wallet_filter = "0014622d0e3b6cc7af423cc297fd931a9528e8548292".to_string();
     let genesis_blockhash = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206".to_string();
 
-    let node_config = NodeConfig{ socket_address: ip_config, network: network_config, wallet_address, genesis_blockhash};
-    let resource = instance.component_node_types().client_node().call_constructor(&mut store, &node_config).unwrap();
+    let node_config = NodeConfig{ socket_address: ip_config, network: network_config, wallet_address, genesis_blockhash, privacy, storage };
+    let resource = instance.component_node_types().client_node().call_constructor(&mut store, &node_config)?;
     
     wasmtime::Result::Ok((instance, store, resource))
 }
@@ -61,12 +496,12 @@ struct ServerWasiView {
 }
 
 impl ServerWasiView {
-    fn new() -> Self {
+    fn new(store_dir: &Path) -> Self {
         let table = ResourceTable::new();
         let http_ctx = WasiHttpCtx::new();
         let ctx = WasiCtxBuilder::new()
             .inherit_stdio()
-            .preopened_dir("./testfolder", ".", DirPerms::all(), FilePerms::all()).unwrap()
+            .preopened_dir(store_dir, ".", DirPerms::all(), FilePerms::all()).unwrap()
             .inherit_network()
             .allow_ip_name_lookup(true)
             .allow_tcp(true)
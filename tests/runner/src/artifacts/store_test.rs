@@ -1,5 +1,5 @@
 
-use exports::component::kv::types::{Kvstore, Error};
+use exports::component::kv::types::{Kvstore, Error, MergePolicy, StoreConfig, SyncPolicy};
 use std::env;
 use std::path::PathBuf;
 use wasmtime::component::*;
@@ -29,6 +29,30 @@ pub fn test_store(){
         println!("working motherfucker")
     }
 
+    let page = storeworld.component_kv_types().kvstore().call_list_keys(&mut store, kvstore.clone(), "1", None, 5).unwrap().unwrap();
+    assert_eq!(page.keys, ["10", "11", "12", "13", "14"]);
+    let page = storeworld.component_kv_types().kvstore().call_list_keys(&mut store, kvstore.clone(), "1", page.cursor.as_deref(), 5).unwrap().unwrap();
+    assert_eq!(page.keys, ["15", "16", "17", "18", "19"]);
+    assert!(page.cursor.is_none());
+    assert!(matches!(storeworld.component_kv_types().kvstore().call_list_keys(&mut store, kvstore.clone(), "1", None, 0).unwrap(), Err(Error::InvalidConfig)));
+    assert_eq!(storeworld.component_kv_types().kvstore().call_count(&mut store, kvstore.clone(), "2").unwrap(), 10);
+    let scanned = storeworld.component_kv_types().kvstore().call_scan(&mut store, kvstore.clone(), "2").unwrap().unwrap();
+    assert_eq!(scanned.len(), 10);
+    assert!(storeworld.component_kv_types().kvstore().call_exists(&mut store, kvstore.clone(), "29").unwrap());
+
+    let recovery = storeworld.component_kv_types().kvstore().call_recovery_report(&mut store, kvstore.clone()).unwrap();
+    assert!(recovery.truncated.is_empty() && recovery.corrupted.is_empty() && !recovery.manifest_damaged);
+
+    // a named store keeps its keys apart from the default store
+    let config = StoreConfig { segment_size: 4096, merge_policy: MergePolicy::Manual, sync_policy: SyncPolicy::Always, encryption: None };
+    let named = storeworld.component_kv_types().kvstore().call_open(&mut store, "store-test", None, Some(config)).unwrap().unwrap();
+    storeworld.component_kv_types().kvstore().call_insert(&mut store, named.clone(), "headers", b"1".as_ref()).unwrap().unwrap();
+    assert_eq!(storeworld.component_kv_types().kvstore().call_get(&mut store, named.clone(), "headers").unwrap().unwrap(), b"1");
+    storeworld.component_kv_types().kvstore().call_flush(&mut store, named.clone()).unwrap().unwrap();
+    assert!(!storeworld.component_kv_types().kvstore().call_exists(&mut store, named.clone(), "29").unwrap());
+    assert!(!storeworld.component_kv_types().kvstore().call_exists(&mut store, kvstore.clone(), "headers").unwrap());
+    assert!(matches!(storeworld.component_kv_types().kvstore().call_open(&mut store, "../store-test", None, None).unwrap(), Err(Error::InvalidConfig)));
+
 
 }
 
@@ -54,7 +78,7 @@ fn create_kvstore() -> wasmtime::Result<(Kvworld, Store<ServerWasiView>, Resourc
         .unwrap();
     
 
-    let resource = instance.component_kv_types().kvstore().call_constructor(&mut store).unwrap();
+    let resource = instance.component_kv_types().kvstore().call_open_default(&mut store)?.expect("the default store opens");
     
     wasmtime::Result::Ok((instance, store, resource))
 }
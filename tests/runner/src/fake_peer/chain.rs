@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::io;
+
+use node::messages::{block::Block, tx::Tx, tx_in::TxIn, tx_out::TxOut, BlockHeader, OutPoint, COINBASE_OUTPOINT_HASH, COINBASE_OUTPOINT_INDEX};
+use node::util::{block_filter::BlockFilter, sha256d, Hash256, Serializable};
+
+// regtest genesis block, the chain every synthetic chain starts from
+const REGTEST_GENESIS: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f20020000000101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+const REGTEST_BITS: u32 = 0x207fffff;
+const BLOCK_VERSION: u32 = 0x20000000;
+const BLOCK_INTERVAL: u32 = 600;
+const BLOCK_REWARD: i64 = 50 * 100_000_000;
+// anyone-can-spend script used for coinbase rewards nobody watches
+const OP_TRUE: u8 = 0x51;
+
+/// An in-memory regtest chain with the blocks, basic filters and filter headers a peer serves
+pub struct SyntheticChain {
+    blocks: Vec<Block>,
+    filters: Vec<Vec<u8>>,
+    filter_headers: Vec<Hash256>,
+    // scripts of every output ever mined, needed to build the filters of spending blocks
+    coins: HashMap<OutPoint, Vec<u8>>,
+    // makes coinbases unique so replacement blocks of a reorg get new hashes
+    extra_nonce: u32,
+}
+
+impl SyntheticChain {
+    /// Creates a chain holding only the regtest genesis block
+    pub fn regtest() -> SyntheticChain {
+        let genesis = Block::read(&mut hex::decode(REGTEST_GENESIS).unwrap().as_slice()).unwrap();
+        let mut chain = SyntheticChain { blocks: vec![], filters: vec![], filter_headers: vec![], coins: HashMap::new(), extra_nonce: 0 };
+        chain.connect(genesis);
+        chain
+    }
+
+    /// Height of the tip, the genesis block being at height 0
+    pub fn height(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().unwrap()
+    }
+
+    pub fn block_at(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height)
+    }
+
+    pub fn block(&self, hash: &Hash256) -> Option<&Block> {
+        self.height_of(hash).map(|height| &self.blocks[height])
+    }
+
+    pub fn height_of(&self, hash: &Hash256) -> Option<usize> {
+        self.blocks.iter().position(|block| block.header.hash() == *hash)
+    }
+
+    /// Basic filter of the block at `height`
+    pub fn filter(&self, height: usize) -> Option<&Vec<u8>> {
+        self.filters.get(height)
+    }
+
+    /// Filter header committing to the filters up to `height`
+    pub fn filter_header(&self, height: usize) -> Option<Hash256> {
+        self.filter_headers.get(height).copied()
+    }
+
+    /// Headers following the first locator hash found on this chain, or the genesis block if none is
+    pub fn headers_after(&self, locator: &[Hash256], hash_stop: &Hash256, max: usize) -> Vec<BlockHeader> {
+        let start = locator.iter().find_map(|hash| self.height_of(hash)).unwrap_or(0) + 1;
+        let mut headers = Vec::new();
+        for block in self.blocks.iter().skip(start).take(max) {
+            headers.push(block.header.clone());
+            if block.header.hash() == *hash_stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    /// Mines a block whose coinbase pays `outputs`, or an unwatched script if empty, followed by `txns`
+    pub fn mine_block(&mut self, outputs: Vec<TxOut>, txns: Vec<Tx>) -> Hash256 {
+        let height = self.blocks.len() as u32;
+        self.extra_nonce += 1;
+
+        // BIP 34 height push followed by the extra nonce
+        let mut unlock_script = vec![4];
+        unlock_script.extend_from_slice(&height.to_le_bytes());
+        unlock_script.push(4);
+        unlock_script.extend_from_slice(&self.extra_nonce.to_le_bytes());
+
+        let outputs = match outputs.is_empty() {
+            true => vec![TxOut { satoshis: BLOCK_REWARD, lock_script: vec![OP_TRUE] }],
+            false => outputs,
+        };
+        let coinbase = Tx {
+            version: 1,
+            flag: None,
+            inputs: vec![TxIn { prev_output: OutPoint { hash: COINBASE_OUTPOINT_HASH, index: COINBASE_OUTPOINT_INDEX }, unlock_script, sequence: 0xffffffff }],
+            outputs,
+            witnesses: None,
+            lock_time: 0,
+        };
+
+        let prev = &self.tip().header;
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: prev.hash(),
+                merkle_root: Hash256::default(),
+                timestamp: prev.timestamp + BLOCK_INTERVAL,
+                bits: REGTEST_BITS,
+                nonce: 0,
+            },
+            txns: [vec![coinbase], txns].concat(),
+        };
+        block.header.merkle_root = block.merkle_root();
+        while block.header.validate(&block.header.hash(), &[]).is_err() {
+            block.header.nonce += 1;
+        }
+
+        let hash = block.header.hash();
+        self.connect(block);
+        hash
+    }
+
+    /// Mines a block paying `satoshis` to `script` in its coinbase
+    pub fn mine_to(&mut self, script: Vec<u8>, satoshis: i64) -> Hash256 {
+        self.mine_block(vec![TxOut { satoshis, lock_script: script }], vec![])
+    }
+
+    /// Mines `count` blocks paying nobody we watch
+    pub fn mine_empty(&mut self, count: usize) {
+        for _ in 0..count {
+            self.mine_block(vec![], vec![]);
+        }
+    }
+
+    /// Drops the `depth` most recent blocks so that a competing branch can be mined on top
+    pub fn disconnect(&mut self, depth: usize) -> Vec<Block> {
+        let fork_height = self.blocks.len() - depth.min(self.height());
+        self.filters.truncate(fork_height);
+        self.filter_headers.truncate(fork_height);
+        self.blocks.split_off(fork_height)
+    }
+
+    fn connect(&mut self, block: Block) {
+        for txn in block.txns.iter() {
+            let txid = txn.hash();
+            for (index, output) in txn.outputs.iter().enumerate() {
+                self.coins.insert(OutPoint { hash: txid, index: index as u32 }, output.lock_script.clone());
+            }
+        }
+
+        let coins = &self.coins;
+        let filter = BlockFilter::from_block(&block, |outpoint| {
+            coins.get(outpoint).cloned().ok_or(io::Error::from(io::ErrorKind::NotFound))
+        }).unwrap();
+        let prev_header = self.filter_headers.last().copied().unwrap_or_default();
+        self.filter_headers.push(filter_header(&filter.content, &prev_header));
+        self.filters.push(filter.content);
+        self.blocks.push(block);
+    }
+}
+
+/// BIP 157 filter header, the hash of a filter chained to the previous filter header
+pub fn filter_header(filter: &[u8], prev_header: &Hash256) -> Hash256 {
+    let mut preimage = sha256d(filter).0.to_vec();
+    preimage.extend_from_slice(&prev_header.0);
+    sha256d(&preimage)
+}
+
+/// Spends `prev_output` into `outputs`, scripts are not checked by light clients so it is left unsigned
+pub fn spend(prev_output: OutPoint, outputs: Vec<TxOut>) -> Tx {
+    Tx {
+        version: 2,
+        flag: None,
+        inputs: vec![TxIn { prev_output, unlock_script: vec![], sequence: 0xffffffff }],
+        outputs,
+        witnesses: None,
+        lock_time: 0,
+    }
+}
@@ -0,0 +1,279 @@
+//! A scriptable in-process Bitcoin peer serving a synthetic regtest chain, so node components can be
+//! tested without bitcoind. It speaks the wire protocol through the node's own `messages` types.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use node::messages::{
+    block_locator::NO_HASH_STOP, compact_filter::CompactFilter, compact_filter_header::CompactFilterHeader,
+    filter_locator::FilterLocator, headers::Headers, inv_vect::INV_VECT_BLOCK, FeeFilter, Inv, Message,
+    SendCmpct, Version, NODE_COMPACT_FILTERS, NODE_NETWORK, NODE_WITNESS, WTXID_RELAY_VERSION,
+};
+use node::util::{block_filter::BlockFilterWriter, Hash256};
+
+mod chain;
+
+pub use self::chain::{filter_header, spend, SyntheticChain};
+
+const REGTEST_MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+const USER_AGENT: &str = "/fakepeer:0.1.0/";
+const MAX_HEADERS: usize = 2000;
+// offset of the checksum in a message header
+const CHECKSUM_OFFSET: usize = 20;
+
+/// Scripted misbehaviour, applied to every connection until cleared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Serves a filter of `elements` instead of the real one for the block at `height`, with filter headers
+    /// committing to it, like a peer lying about the block contents
+    WrongFilter { height: usize, elements: Vec<Vec<u8>> },
+    /// Never answers requests with this command
+    Stall([u8; 12]),
+    /// Answers requests with this command using frames whose checksum does not match the payload
+    MalformedFrame([u8; 12]),
+    /// Advertises only these service bits in the version message
+    Services(u64),
+}
+
+struct Shared {
+    chain: SyntheticChain,
+    faults: Vec<Fault>,
+    connections: Vec<Arc<Mutex<TcpStream>>>,
+}
+
+/// Handle to a fake peer listening on a local port, shared by the tests driving it
+pub struct FakePeer {
+    address: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl FakePeer {
+    /// Starts serving `chain` on an ephemeral local port
+    pub fn spawn(chain: SyntheticChain) -> io::Result<FakePeer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared { chain, faults: vec![], connections: vec![] }));
+
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = accepting.clone();
+                thread::spawn(move || serve(stream, shared));
+            }
+        });
+        Ok(FakePeer { address, shared })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.shared.lock().unwrap().faults.push(fault);
+    }
+
+    pub fn clear_faults(&self) {
+        self.shared.lock().unwrap().faults.clear();
+    }
+
+    /// Runs `f` on the served chain, e.g. to mine blocks between node calls
+    pub fn with_chain<T>(&self, f: impl FnOnce(&mut SyntheticChain) -> T) -> T {
+        f(&mut self.shared.lock().unwrap().chain)
+    }
+
+    /// Announces the blocks above `fork_height` to every connected node with a headers message
+    pub fn announce_from(&self, fork_height: usize) -> io::Result<()> {
+        let shared = self.shared.lock().unwrap();
+        let fork_hash = shared.chain.block_at(fork_height).map(|block| block.header.hash()).unwrap_or_default();
+        let headers = shared.chain.headers_after(&[fork_hash], &NO_HASH_STOP, MAX_HEADERS);
+        let frame = frame(&Message::Headers(Headers { inner: headers }))?;
+        for connection in shared.connections.iter() {
+            // nodes that went away are skipped
+            let _ = connection.lock().unwrap().write_all(&frame);
+        }
+        Ok(())
+    }
+
+    /// Mines `count` empty blocks and announces them
+    pub fn extend(&self, count: usize) -> io::Result<()> {
+        let fork_height = self.with_chain(|chain| {
+            let height = chain.height();
+            chain.mine_empty(count);
+            height
+        });
+        self.announce_from(fork_height)
+    }
+
+    /// Replaces the `depth` most recent blocks with `length` new ones and announces the new branch
+    pub fn reorg(&self, depth: usize, length: usize) -> io::Result<()> {
+        let fork_height = self.with_chain(|chain| {
+            chain.disconnect(depth);
+            let height = chain.height();
+            chain.mine_empty(length);
+            height
+        });
+        self.announce_from(fork_height)
+    }
+}
+
+// answers one node connection until it closes or sends something unparseable
+fn serve(stream: TcpStream, shared: Arc<Mutex<Shared>>) {
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+    let writer = Arc::new(Mutex::new(stream));
+    shared.lock().unwrap().connections.push(writer.clone());
+
+    while let Ok((message, header)) = Message::read(&mut reader, REGTEST_MAGIC) {
+        let frames = {
+            let shared = shared.lock().unwrap();
+            respond(&shared, message, header.command)
+        };
+        let frames = match frames {
+            Ok(frames) => frames,
+            Err(_) => break,
+        };
+        let mut writer = writer.lock().unwrap();
+        if frames.iter().try_for_each(|frame| writer.write_all(frame)).is_err() {
+            break;
+        }
+    }
+    shared.lock().unwrap().connections.retain(|connection| !Arc::ptr_eq(connection, &writer));
+}
+
+// frames answering `message`, with the scripted faults applied
+fn respond(shared: &Shared, message: Message, command: [u8; 12]) -> io::Result<Vec<Vec<u8>>> {
+    if shared.faults.contains(&Fault::Stall(command)) {
+        return Ok(vec![]);
+    }
+
+    let mut frames = reply(shared, message)?.iter().map(frame).collect::<io::Result<Vec<_>>>()?;
+    if shared.faults.contains(&Fault::MalformedFrame(command)) {
+        for frame in frames.iter_mut() {
+            frame[CHECKSUM_OFFSET] ^= 0xff;
+        }
+    }
+    Ok(frames)
+}
+
+// what a bitcoind with blockfilterindex and peerblockfilters would answer
+fn reply(shared: &Shared, message: Message) -> io::Result<Vec<Message>> {
+    let chain = &shared.chain;
+    let replies = match message {
+        Message::Version(version) => {
+            let mut replies = vec![Message::Version(local_version(shared, &version))];
+            if version.version >= WTXID_RELAY_VERSION {
+                replies.push(Message::WtxidRelay);
+                replies.push(Message::SendAddrV2);
+            }
+            replies.push(Message::Verack);
+            replies
+        },
+        Message::Verack => vec![
+            Message::SendHeaders,
+            Message::SendCmpct(SendCmpct { enable: 0, version: 2 }),
+            Message::FeeFilter(FeeFilter { minfee: 1000 }),
+        ],
+        Message::Ping(ping) => vec![Message::Pong(ping)],
+        Message::GetHeaders(locator) => {
+            let headers = chain.headers_after(&locator.block_locator_hashes, &locator.hash_stop, MAX_HEADERS);
+            vec![Message::Headers(Headers { inner: headers })]
+        },
+        Message::GetCFHeaders(locator) => match filter_range(chain, &locator) {
+            Some((start, stop)) => {
+                let filters = served_filters(shared, start, stop);
+                let previous_filter_header = match start {
+                    0 => Hash256::default(),
+                    _ => served_filter_header(shared, start - 1),
+                };
+                let filter_hashes = filters.iter().map(|filter| node::util::sha256d(filter)).collect();
+                vec![Message::CFHeaders(CompactFilterHeader { filter_type: 0, stop_hash: locator.hash_stop, previous_filter_header, filter_hashes })]
+            },
+            None => vec![],
+        },
+        Message::GetCFilters(locator) => match filter_range(chain, &locator) {
+            Some((start, stop)) => served_filters(shared, start, stop).into_iter().zip(start..=stop).map(|(filter_bytes, height)| {
+                Message::CFilters(CompactFilter { filter_type: 0, block_hash: chain.block_at(height).unwrap().header.hash(), filter_bytes })
+            }).collect(),
+            None => vec![],
+        },
+        Message::GetData(inv) => {
+            let (found, missing): (Vec<_>, Vec<_>) = inv.objects.into_iter()
+                .partition(|object| object.obj_type == INV_VECT_BLOCK && chain.block(&object.hash).is_some());
+            let mut replies: Vec<_> = found.iter().map(|object| Message::Block(chain.block(&object.hash).unwrap().clone())).collect();
+            if !missing.is_empty() {
+                replies.push(Message::NotFound(Inv { objects: missing }));
+            }
+            replies
+        },
+        _ => vec![],
+    };
+    Ok(replies)
+}
+
+fn local_version(shared: &Shared, remote: &Version) -> Version {
+    let services = shared.faults.iter().find_map(|fault| match fault {
+        Fault::Services(services) => Some(*services),
+        _ => None,
+    }).unwrap_or(NODE_NETWORK | NODE_WITNESS | NODE_COMPACT_FILTERS);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    Version {
+        version: WTXID_RELAY_VERSION,
+        services,
+        timestamp,
+        recv_addr: remote.tx_addr.clone(),
+        tx_addr: remote.recv_addr.clone(),
+        nonce: timestamp as u64,
+        user_agent: USER_AGENT.to_string(),
+        start_height: shared.chain.height() as i32,
+        relay: false,
+    }
+}
+
+// heights from the start height to the stop hash, if the stop hash is on the chain
+fn filter_range(chain: &SyntheticChain, locator: &FilterLocator) -> Option<(usize, usize)> {
+    let stop = chain.height_of(&locator.hash_stop)?;
+    let start = locator.start_height as usize;
+    (start <= stop).then_some((start, stop))
+}
+
+fn served_filters(shared: &Shared, start: usize, stop: usize) -> Vec<Vec<u8>> {
+    (start..=stop).map(|height| served_filter(shared, height)).collect()
+}
+
+fn served_filter(shared: &Shared, height: usize) -> Vec<u8> {
+    let block = shared.chain.block_at(height).unwrap();
+    for fault in shared.faults.iter() {
+        if let Fault::WrongFilter { height: wrong_height, elements } = fault {
+            if *wrong_height == height {
+                let mut content = Vec::new();
+                let mut writer = BlockFilterWriter::new(&mut content, block);
+                for element in elements {
+                    writer.add_element(element);
+                }
+                writer.finish().unwrap();
+                return content;
+            }
+        }
+    }
+    shared.chain.filter(height).unwrap().clone()
+}
+
+// filter headers are recomputed from the served filters so that a wrong filter is committed to consistently
+fn served_filter_header(shared: &Shared, height: usize) -> Hash256 {
+    let lies = shared.faults.iter().any(|fault| matches!(fault, Fault::WrongFilter { height: wrong_height, .. } if *wrong_height <= height));
+    if !lies {
+        return shared.chain.filter_header(height).unwrap();
+    }
+    (0..=height).fold(Hash256::default(), |prev_header, height| filter_header(&served_filter(shared, height), &prev_header))
+}
+
+fn frame(message: &Message) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    message.write(&mut bytes, REGTEST_MAGIC)?;
+    Ok(bytes)
+}
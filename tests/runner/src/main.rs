@@ -1,6 +1,6 @@
 use std::env;
 use std::path::PathBuf;
-use artifacts::node_test::test_node;
+use artifacts::node_test::{test_node, test_node_offline};
 use artifacts::store_test::test_store;
 use wasmtime::component::*;
 use wasmtime::{Config, Engine, Store};
@@ -8,9 +8,15 @@ use wasmtime_wasi::{ DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi_http::{self, WasiHttpCtx, WasiHttpView};
 
 mod artifacts;
+mod fake_peer;
 
 fn main() {
-    test_node();
-    //test_store();
+    // `runner offline` skips the tests that need the bitcoind of test-runner.sh
+    let offline = env::args().any(|arg| arg == "offline");
+    test_store();
+    test_node_offline();
+    if !offline {
+        test_node();
+    }
 }
 
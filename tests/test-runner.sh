@@ -72,6 +72,8 @@ echo -n "- sending to address"
 wait-for-node bitcoind "bitcoin-cli -regtest -rpcwait -rpcport=$rpcport  -rpcuser=$rpcuser -rpcpassword=$rpcpassword sendtoaddress $address 2"
 mine-block
 echo -n "- Running Test."
+# the runner links the node crate for its fake peer, so refresh the generated bindings first
+cargo component build --package node &&
 cargo run --package runner --bin runner &&
 docker compose -f "./docker-compose.yml" down 
 